@@ -1,16 +1,47 @@
+/*! Attaching a debugger to the bootloader.
+
+[`wait_for_debugger`] logs the bootloader's image base, which the debugger needs to load the
+bootloader's symbols, and [`park`] spins until the debugger takes over. See
+`docs/debugging.md`.
+*/
+use core::arch::asm;
+
+use log::info;
+use uefi::{prelude::BootServices, proto::loaded_image::LoadedImage, Handle};
+
+/// The address that the bootloader was loaded at.
+pub fn image_base(image_handle: Handle, boot_services: &BootServices) -> u64 {
+    let loaded_image = boot_services
+        .open_protocol_exclusive::<LoadedImage>(image_handle)
+        .unwrap();
+    let (image_base, _) = loaded_image.info();
+    image_base as u64
+}
+
+/** Log the bootloader's image base, so that a debugger can be attached.
+
+Returns straight away. Call [`park`] afterwards to wait for the debugger.
+*/
+pub fn wait_for_debugger(image_handle: Handle, boot_services: &BootServices) {
+    let image_base = image_base(image_handle, boot_services);
+    info!("waiting for debugger... (image base = {:#x})", image_base);
+}
+
+/** Spin forever, until a debugger jumps past the loop.
+
+Not inlined, so that the loop is always in this function and the debugger can jump to the
+line after it.
+*/
+#[inline(never)]
+pub fn park() {
+    unsafe { asm!("2: jmp 2b") };
+}
+
+/// [`wait_for_debugger`] followed by [`park`].
 #[macro_export]
 macro_rules! wait_for_debugger {
     ($image_handle:expr, $system_table:expr) => {
-        let image_base: u64 = {
-            let loaded_image = $system_table
-                .boot_services()
-                .open_protocol_exclusive::<LoadedImage>($image_handle)
-                .unwrap();
-            let (image_base, _) = loaded_image.info();
-            image_base as u64
-        };
-
-        info!("waiting for debugger... (image base = {:#x})", image_base);
-        unsafe { asm!("2: jmp 2b") };
+        $crate::debug::wait_for_debugger($image_handle, $system_table.boot_services());
+        $crate::debug::park();
     };
 }
@@ -0,0 +1,259 @@
+/*! Reasons that the bootloader gives up.
+
+Every failure before `exit_boot_services` is a [`BootError`]. `main` prints the error's
+[`Display`](core::fmt::Display) message and exits with [`BootError::status`], so that a failed
+boot explains itself on the console.
+
+[`SerialDiscoveryError`](common::pci::serial::SerialDiscoveryError) isn't fatal: when the serial
+controller can't be found via PCI, the bootloader logs why and falls back to COM1.
+*/
+use alloc::string::String;
+use core::fmt;
+
+use common::{exe, paging::PagingMode};
+use uefi::{CStr16, Status};
+
+#[derive(Debug)]
+pub enum BootError {
+    /// The processor isn't using 4-level paging.
+    UnsupportedPagingMode(PagingMode),
+
+    /// A file on the boot volume exists but couldn't be opened.
+    FileOpen {
+        file_name: &'static CStr16,
+        status: Status,
+    },
+
+    /// A file on the boot volume couldn't be read.
+    FileRead {
+        file_name: &'static CStr16,
+        status: Status,
+    },
+
+    /// A file on the boot volume is larger than the buffer it's read into.
+    FileTooLarge {
+        file_name: &'static CStr16,
+        max_size: usize,
+    },
+
+    /// `boot.cfg` isn't valid UTF-8.
+    BootConfigEncoding,
+
+    /// `boot.cfg` has a line that isn't a known `key = value` setting.
+    BootConfigLine(String),
+
+    KernelNotFound(&'static CStr16),
+
+    /// The kernel isn't a valid learn-os executable.
+    KernelParse(exe::Error),
+
+    /// The kernel has no executable segment, so it has no entrypoint.
+    KernelNoEntrypoint,
+
+    /// The kernel's entrypoint isn't where the bootloader jumps to.
+    KernelEntrypoint {
+        expected: u64,
+        actual: u64,
+    },
+
+    /// Kernel verification is enabled, but the bootloader was built without a key.
+    SigningKeyMissing,
+
+    SignatureNotFound(&'static CStr16),
+
+    /// The signature file isn't exactly one digest long.
+    SignatureLength {
+        file_name: &'static CStr16,
+        expected: usize,
+    },
+
+    /// The kernel's signature doesn't match.
+    SignatureInvalid,
+}
+
+impl BootError {
+    /** The error for a boot volume file that `File::open` failed on with `status`.
+
+    `None` when the file doesn't exist, so that callers can decide whether that's an error.
+    */
+    pub const fn file_open(file_name: &'static CStr16, status: Status) -> Option<BootError> {
+        match status {
+            Status::NOT_FOUND => None,
+            status => Some(BootError::FileOpen { file_name, status }),
+        }
+    }
+
+    /** The status that the bootloader exits with.
+
+    See Note [Kernel signatures] for why signature failures are security violations.
+    */
+    pub const fn status(&self) -> Status {
+        match self {
+            BootError::UnsupportedPagingMode(_) => Status::UNSUPPORTED,
+            BootError::FileOpen { .. }
+            | BootError::FileRead { .. }
+            | BootError::FileTooLarge { .. }
+            | BootError::BootConfigEncoding
+            | BootError::BootConfigLine(_) => Status::ABORTED,
+            BootError::KernelNotFound(_) => Status::NOT_FOUND,
+            BootError::KernelParse(_)
+            | BootError::KernelNoEntrypoint
+            | BootError::KernelEntrypoint { .. } => Status::LOAD_ERROR,
+            BootError::SigningKeyMissing
+            | BootError::SignatureNotFound(_)
+            | BootError::SignatureLength { .. }
+            | BootError::SignatureInvalid => Status::SECURITY_VIOLATION,
+        }
+    }
+}
+
+/* `BootError` owns a `String`, so a `BootError` returned from a function can't be dropped in a
+constant. These checks forget them instead.
+*/
+const _: () = {
+    let file_name = uefi::cstr16!("boot.cfg");
+
+    let error = BootError::file_open(file_name, Status::NOT_FOUND);
+    let not_found = error.is_none();
+    core::mem::forget(error);
+    assert!(not_found);
+
+    let error = BootError::file_open(file_name, Status::ACCESS_DENIED);
+    let access_denied = matches!(
+        error,
+        Some(BootError::FileOpen {
+            status: Status::ACCESS_DENIED,
+            ..
+        })
+    );
+    core::mem::forget(error);
+    assert!(access_denied);
+};
+
+const _: () = {
+    const fn exits_with(error: BootError, status: Status) -> bool {
+        let matches = error.status().0 == status.0;
+        core::mem::forget(error);
+        matches
+    }
+
+    let file_name = uefi::cstr16!("kernel.bin");
+    assert!(exits_with(
+        BootError::UnsupportedPagingMode(PagingMode::FiveLevel),
+        Status::UNSUPPORTED
+    ));
+    assert!(exits_with(
+        BootError::FileOpen {
+            file_name,
+            status: Status::ACCESS_DENIED
+        },
+        Status::ABORTED
+    ));
+    assert!(exits_with(
+        BootError::FileRead {
+            file_name,
+            status: Status::DEVICE_ERROR
+        },
+        Status::ABORTED
+    ));
+    assert!(exits_with(
+        BootError::FileTooLarge {
+            file_name,
+            max_size: 0
+        },
+        Status::ABORTED
+    ));
+    assert!(exits_with(BootError::BootConfigEncoding, Status::ABORTED));
+    assert!(exits_with(
+        BootError::BootConfigLine(String::new()),
+        Status::ABORTED
+    ));
+    assert!(exits_with(
+        BootError::KernelNotFound(file_name),
+        Status::NOT_FOUND
+    ));
+    assert!(exits_with(
+        BootError::KernelParse(exe::Error::Version { actual: 2 }),
+        Status::LOAD_ERROR
+    ));
+    assert!(exits_with(
+        BootError::KernelNoEntrypoint,
+        Status::LOAD_ERROR
+    ));
+    assert!(exits_with(
+        BootError::KernelEntrypoint {
+            expected: 0x1000,
+            actual: 0x2000
+        },
+        Status::LOAD_ERROR
+    ));
+    assert!(exits_with(
+        BootError::SigningKeyMissing,
+        Status::SECURITY_VIOLATION
+    ));
+    assert!(exits_with(
+        BootError::SignatureNotFound(file_name),
+        Status::SECURITY_VIOLATION
+    ));
+    assert!(exits_with(
+        BootError::SignatureLength {
+            file_name,
+            expected: 32
+        },
+        Status::SECURITY_VIOLATION
+    ));
+    assert!(exits_with(
+        BootError::SignatureInvalid,
+        Status::SECURITY_VIOLATION
+    ));
+};
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootError::UnsupportedPagingMode(PagingMode::FiveLevel) => write!(
+                f,
+                "5-level paging is not supported\n\
+                Please disable LA57 (5-level paging) in the firmware settings."
+            ),
+            BootError::UnsupportedPagingMode(paging_mode) => match paging_mode.validate() {
+                Err(missing_feature) => {
+                    write!(f, "expected 4-level paging, but {}", missing_feature)
+                }
+                Ok(()) => write!(f, "paging mode {:?} is not supported", paging_mode),
+            },
+            BootError::FileOpen { file_name, status } => {
+                write!(f, "failed to open {}: {:?}", file_name, status)
+            }
+            BootError::FileRead { file_name, status } => {
+                write!(f, "failed to read {}: {:?}", file_name, status)
+            }
+            BootError::FileTooLarge {
+                file_name,
+                max_size,
+            } => write!(f, "{} is larger than {} bytes", file_name, max_size),
+            BootError::BootConfigEncoding => write!(f, "boot.cfg isn't valid UTF-8"),
+            BootError::BootConfigLine(line) => write!(f, "invalid line in boot.cfg: {}", line),
+            BootError::KernelNotFound(file_name) => write!(f, "{} not found", file_name),
+            BootError::KernelParse(error) => {
+                write!(f, "kernel is not a learn-os executable: {:?}", error)
+            }
+            BootError::KernelNoEntrypoint => write!(f, "kernel has no executable segment"),
+            BootError::KernelEntrypoint { expected, actual } => write!(
+                f,
+                "kernel code is loaded at {:#x}, but the bootloader jumps to {:#x}",
+                actual, expected
+            ),
+            BootError::SigningKeyMissing => write!(
+                f,
+                "kernel verification is enabled, but the bootloader has no key"
+            ),
+            BootError::SignatureNotFound(file_name) => write!(f, "{} not found", file_name),
+            BootError::SignatureLength {
+                file_name,
+                expected,
+            } => write!(f, "{} isn't {} bytes long", file_name, expected),
+            BootError::SignatureInvalid => write!(f, "kernel signature is invalid"),
+        }
+    }
+}
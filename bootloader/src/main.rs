@@ -2,21 +2,24 @@
 #![no_main]
 
 pub mod debug;
+pub mod error;
 
 extern crate alloc;
 
 use core::{
     arch::asm,
     borrow::{Borrow, BorrowMut},
-    ptr::NonNull,
 };
 
-use alloc::vec::Vec;
-use log::info;
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{info, warn};
 use uefi::{
     prelude::*,
     proto::{
-        loaded_image::LoadedImage,
+        console::gop::GraphicsOutput,
         media::file::{File, FileAttribute, FileInfo, FileMode},
     },
     table::{
@@ -24,54 +27,111 @@ use uefi::{
             AllocateType, EventType, MemoryMap, MemoryType, OpenProtocolAttributes,
             OpenProtocolParams, TimerTrigger, Tpl, PAGE_SIZE,
         },
+        cfg::{ACPI2_GUID, ACPI_GUID},
         runtime::Time,
     },
     CStr16,
 };
 
 use common::{
-    exe::v0,
-    paging::{PageMap, PageMapFlags},
-    registers::{CR0, CR3, CR4, IA32_EFER},
+    boot_info::{
+        coalesce_regions, FramebufferInfo, MemoryRegion, MemoryRegionKind, PixelFormat,
+        SerialDevice,
+    },
+    buffer::{self, AttemptError},
+    exe, hmac,
+    paging::{PageMap, PageMapFlags, PageSize, PagingMode},
+    pci::serial::{self, SerialDiscoveryError},
+    registers::{
+        execute_disable_supported, local_apic_supported, PatMemoryType, CR3, IA32_APIC_BASE,
+        IA32_EFER, IA32_PAT,
+    },
+    BootInfo,
+};
+use error::BootError;
+use uefi_pci::{
+    BistHeaderLatencyCacheLine, HeaderType, PciConfigurationAddress, PciRootBridgeIo, COMMAND_IO,
+    COMMAND_MEM,
 };
-use uefi_pci::{PciConfigurationAddress, PciRootBridgeIo};
 
 /* Note [The kernel's entrypoint]
 
 The kernel runs in its own virtual address space. The kernel code starts at `KERNEL_ENTRYPOINT`, and
 everything between 0x0 and `KERNEL_ENTRYPOINT` is the kernel's stack.
+
+An executable's entrypoint is the load address of its first executable segment (for v0, its code
+segment). `switch_to_kernel` can't read the entrypoint from the header: it jumps after switching to
+the kernel's stack, when its own stack frame is gone, so the jump target has to be a constant.
+Instead, `map_kernel` checks that the kernel's entrypoint is `KERNEL_ENTRYPOINT`, so that the two
+can't drift apart.
 */
 const KERNEL_ENTRYPOINT: u64 = 0x1000;
 
+/* Note [The kernel's heap]
+
+The kernel's heap is a fixed-size region of writable memory at the start of the higher half of the
+virtual address space (PML4 index 256). It's far away from the kernel's stack and code, and from the
+identity-mapped pages at UEFI-chosen physical addresses, so it can't overlap with them.
+
+It's big enough for the framebuffer console's back buffer, which is 4 bytes per pixel (8MiB at
+1920x1080).
+*/
+const KERNEL_HEAP_ADDRESS: u64 = 0xffff_8000_0000_0000;
+const KERNEL_HEAP_SIZE: usize = 16 * 1024 * 1024;
+
 #[entry]
 fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi::helpers::init(&mut system_table).unwrap();
 
     uefi::println!("Booting...");
 
-    {
-        let image_base: u64 = {
-            let loaded_image = system_table
-                .boot_services()
-                .open_protocol_exclusive::<LoadedImage>(image_handle)
-                .unwrap();
-            let (image_base, _) = loaded_image.info();
-            image_base as u64
-        };
+    /* `PageMap` only does 4-level paging, and the kernel's page map is built with it. Some
+    firmware boots with 5-level paging (CR4.LA57 = 1), which can't be turned off without
+    leaving long mode, so the bootloader gives up instead.
 
-        info!("image base: {:#x}", image_base);
+    Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 4.5
+    */
+    let paging_mode = PagingMode::read();
+    if paging_mode.validate().is_err() {
+        return boot_failed(BootError::UnsupportedPagingMode(paging_mode));
     }
 
-    if check_boot_interruption(&mut system_table) {
+    info!(
+        "image base: {:#x}",
+        debug::image_base(image_handle, system_table.boot_services())
+    );
+
+    let boot_interrupted = check_boot_interruption(&mut system_table);
+    if boot_interrupted {
         browse_memory_map(&mut system_table);
     }
 
+    summarize_memory_map(&mut system_table);
+
     let mut page_map: PageMap;
-    let switch_to_kernel_page_addr = {
-        let kernel_info = match load_kernel(image_handle, &mut system_table, cstr16!("kernel.bin"))
-        {
+    let switch_to_kernel_page_addr;
+    let switch_to_kernel_page_count;
+    let framebuffer;
+    let acpi_rsdp_address;
+    let local_apic_address;
+    let serial_device;
+    let boot_info_storage;
+    {
+        let boot_config = match read_boot_config(image_handle, &system_table) {
             Err(err) => {
-                return err;
+                return boot_failed(err);
+            }
+            Ok(value) => value,
+        };
+
+        let kernel_info = match load_kernel(
+            image_handle,
+            &mut system_table,
+            cstr16!("kernel.bin"),
+            boot_config.verify_kernel,
+        ) {
+            Err(err) => {
+                return boot_failed(err);
             }
             Ok(value) => value,
         };
@@ -83,6 +143,7 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
                 .unwrap()
         };
 
+        // See Note [4KiB pages].
         assert!(
             PAGE_SIZE == PageMap::PAGE_SIZE,
             "UEFI page size ({}B) is incompatible with page table's page size ({}B)",
@@ -90,14 +151,76 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             PageMap::PAGE_SIZE
         );
         page_map = PageMap::new(&mut allocate_pages);
+        // The kernel uses it to find its page tables. See `PageMap::adopt_active`.
+        page_map.install_self_map();
         info!("initialised page table");
 
         map_stack(&mut allocate_pages, &mut page_map);
         info!("set up page table entries for stack");
 
-        map_kernel(&mut allocate_pages, &mut page_map, &kernel_info);
+        map_heap(&mut allocate_pages, &mut page_map);
+        info!("set up page table entries for heap");
+
+        if let Err(err) = map_kernel(&mut allocate_pages, &mut page_map, &kernel_info) {
+            return boot_failed(err);
+        }
 
-        let switch_to_kernel_page_addr = map_switch_to_kernel(&mut allocate_pages, &mut page_map);
+        (switch_to_kernel_page_addr, switch_to_kernel_page_count) =
+            map_switch_to_kernel(&mut allocate_pages, &mut page_map);
+
+        framebuffer = get_framebuffer(image_handle, system_table.boot_services());
+        if let Some(framebuffer) = &framebuffer {
+            page_map.map_identity(
+                &mut allocate_pages,
+                framebuffer.address,
+                framebuffer.size.next_multiple_of(PAGE_SIZE as u64),
+                PageMapFlags::W | PageMapFlags::WRITE_COMBINING,
+            );
+            info!("set up page table entries for framebuffer");
+        }
+
+        acpi_rsdp_address = get_acpi_rsdp(&system_table);
+        if let Some(acpi_rsdp_address) = acpi_rsdp_address {
+            map_acpi_rsdp(&mut allocate_pages, &mut page_map, acpi_rsdp_address);
+            info!("set up page table entries for ACPI RSDP");
+        }
+
+        local_apic_address = get_local_apic();
+        if let Some(address) = local_apic_address {
+            page_map.map_mmio(&mut allocate_pages, address, address, PAGE_SIZE as u64);
+            info!("set up page table entries for local APIC");
+        }
+
+        serial_device = get_serial_controller(image_handle, system_table.boot_services())
+            .unwrap_or_else(|error| {
+                warn!("{}, falling back to COM1 ({:#x})", error, COM1_PORT);
+                SerialDevice::Io(COM1_PORT)
+            });
+        if let SerialDevice::Mmio(address) = serial_device {
+            map_serial_device(&mut allocate_pages, &mut page_map, address);
+            info!("set up page table entries for serial device");
+        }
+
+        boot_info_storage = BootInfoStorage::allocate(
+            &mut allocate_pages,
+            &mut page_map,
+            system_table.boot_services(),
+            &boot_config.command_line,
+        );
+        info!("set up page table entries for boot info");
+
+        let usable_memory_size = map_usable_memory(
+            &mut allocate_pages,
+            &mut page_map,
+            system_table.boot_services(),
+        );
+        info!("set up page table entries for usable memory");
+        assert!(
+            page_map.size() as u64 >= usable_memory_size,
+            "only {}B of {}B of usable memory was mapped",
+            page_map.size(),
+            usable_memory_size
+        );
 
         // Safety: `kernel_info` is not used after this.
         unsafe {
@@ -107,42 +230,92 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
                 .unwrap();
         }
 
-        switch_to_kernel_page_addr
-    };
+        enable_execute_disable(&mut page_map);
+        enable_write_combining();
+    }
 
-    // TODO: map the rest of available memory?
-    info!("total memory mapped: {}B", page_map.size());
+    let page_map_stats = page_map.statistics();
+    info!(
+        "total memory mapped: {}B ({} 4KiB, {} 2MiB and {} 1GiB pages, using {} page tables)",
+        page_map_stats.mapped_bytes,
+        page_map_stats.pages_4kib,
+        page_map_stats.pages_2mib,
+        page_map_stats.pages_1gib,
+        page_map_stats.table_count()
+    );
 
-    /* 4-level paging requires:
+    if boot_interrupted {
+        browse_page_map(&mut system_table, &page_map);
+    }
 
-    * CR0.PG = 1
-    * CR4.PAE = 1
-    * IA32_EFER.LME = 1
-    * CR4.LA57 = 0
+    let (_system_table, memory_map) =
+        unsafe { system_table.exit_boot_services(MemoryType::LOADER_DATA) };
 
-    Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 4.5
-    */
-    let cr0 = CR0::read();
-    let cr4 = CR4::read();
-    let ia32_efer = IA32_EFER::read();
-    assert!(
-        cr0.pg() && cr4.pae() && ia32_efer.lme() && !cr4.la57(),
-        "4-level paging isn't enabled"
+    // Logging is unavailable from here on, because boot services have exited.
+
+    let boot_info_address = boot_info_storage.write(
+        &memory_map,
+        BootInfo {
+            page_size: PAGE_SIZE as u64,
+            switch_to_kernel_page_address: switch_to_kernel_page_addr,
+            switch_to_kernel_page_count,
+            serial_device_port: match serial_device {
+                SerialDevice::Io(port) => port,
+                SerialDevice::Mmio(_) => 0,
+            },
+            serial_device_mmio_address: match serial_device {
+                SerialDevice::Io(_) => 0,
+                SerialDevice::Mmio(address) => address,
+            },
+            heap_address: KERNEL_HEAP_ADDRESS,
+            heap_size: KERNEL_HEAP_SIZE as u64,
+            memory_map_address: 0,
+            memory_map_len: 0,
+            framebuffer: framebuffer.unwrap_or(FramebufferInfo {
+                address: 0,
+                size: 0,
+                width: 0,
+                height: 0,
+                stride: 0,
+                pixel_format: PixelFormat::Bgr,
+            }),
+            acpi_rsdp_address: acpi_rsdp_address.unwrap_or(0),
+            local_apic_address: local_apic_address.unwrap_or(0),
+            command_line_address: 0,
+            command_line_len: 0,
+        },
     );
 
-    let serial_controller_port = get_serial_controller(image_handle, system_table.boot_services());
+    // Boot services have exited, so a panic can't report anything, but it still stops the jump.
+    let boot_info_last_byte = boot_info_address + core::mem::size_of::<BootInfo>() as u64 - 1;
+    for address in [boot_info_address, boot_info_last_byte] {
+        assert!(
+            page_map.translate(address).is_some(),
+            "boot info address {:#x} isn't mapped in the kernel's page map",
+            address
+        );
+    }
 
-    let (_system_table, _memory_map) =
-        unsafe { system_table.exit_boot_services(MemoryType::LOADER_DATA) };
+    unsafe { switch_to_kernel(page_map, boot_info_address) }
+}
 
-    unsafe { switch_to_kernel(page_map, switch_to_kernel_page_addr, serial_controller_port) }
+/// Report why the boot failed, and get the status that `main` exits with.
+fn boot_failed(error: BootError) -> Status {
+    uefi::println!("error: {}", error);
+    error.status()
 }
 
-unsafe fn switch_to_kernel(
-    page_map: PageMap,
-    switch_to_kernel_page_addr: u64,
-    serial_device_port: u16,
-) -> ! {
+/** Switch to the kernel's page map and stack, and call the kernel with the boot info.
+
+`boot_info_virtual_addr` is the kernel's address for the [`BootInfo`]. The kernel dereferences
+it straight away, so the whole struct has to be mapped in `page_map`: `main` checks this
+before the call, so that the check's code doesn't have to be mapped too. See also: Note [Kernel
+entrypoint arguments].
+*/
+// The labels in the `asm!` block are global, so it must only be emitted once.
+#[inline(never)]
+#[allow(named_asm_labels)]
+unsafe fn switch_to_kernel(page_map: PageMap, boot_info_virtual_addr: u64) -> ! {
     let mut cr3 = CR3::read();
 
     cr3.set_address(page_map.address());
@@ -157,28 +330,35 @@ unsafe fn switch_to_kernel(
      */
     asm!("cli");
 
-    cr3.write();
+    /* Switch to the kernel's page map, set up the kernel's stack, and call the kernel. The stack
+    grows down from the kernel's entrypoint.
 
-    // Set up the kernel's stack.
-    asm!("mov rbp, {0}", "mov rsp, {0}", in(reg) KERNEL_ENTRYPOINT);
+    Everything after the CR3 write runs on the kernel's page map, so it's all in this block,
+    between labels that `map_switch_to_kernel` uses. See Note [Mapping switch_to_kernel].
 
-    /* Have the compiler generate the call to the kernel. Previously this was hand-written
-    assembly. I think the current way is less error-prone.
+    The argument is loaded into `rdi` by hand, in the same block that changes the stack. When the
+    compiler generated the call, it was free to load the argument from the function's stack
+    frame (e.g. in debug mode), and after the stack switch that frame is on the wrong stack.
 
-    The `transmute` call has to be inline. If it's `let`-bound then it will live in the
-    function's stack frame in debug mode (the compiler won't automatically inline it).
-    We just changed the stack, so the function call will try to jump to a garbage address
-    that was read from the kernel stack.
-
-    The `KernelFn` type must match the signature of [`kernel::kernel`].
+    The kernel checks that its entrypoint has the `KernelEntrypoint` type, which is `sysv64`: the
+    first argument is passed in `rdi`. `KERNEL_ENTRYPOINT` is page aligned, so the stack is 16-byte
+    aligned at the `call`, as `sysv64` requires.
     */
-    type KernelFn = extern "sysv64" fn(usize, u64, u16) -> !;
-
-    core::mem::transmute::<u64, KernelFn>(KERNEL_ENTRYPOINT)(
-        // See also: Note [Kernel entrypoint arguments]
-        PAGE_SIZE,
-        switch_to_kernel_page_addr,
-        serial_device_port,
+    // See also: Note [Kernel entrypoint arguments]
+    asm!(
+        ".globl switch_to_kernel_start",
+        "switch_to_kernel_start:",
+        "mov cr3, {cr3}",
+        "mov rbp, {entrypoint}",
+        "mov rsp, {entrypoint}",
+        "call {entrypoint}",
+        "ud2",
+        ".globl switch_to_kernel_end",
+        "switch_to_kernel_end:",
+        cr3 = in(reg) cr3.value(),
+        entrypoint = in(reg) KERNEL_ENTRYPOINT,
+        in("rdi") boot_info_virtual_addr,
+        options(noreturn),
     )
 }
 
@@ -188,11 +368,206 @@ struct KernelInfo {
     allocated_pages: usize,
 }
 
+/// Settings read from `boot.cfg`. See Note [Boot configuration].
+#[derive(Default)]
+struct BootConfig {
+    /// See Note [Kernel signatures].
+    verify_kernel: bool,
+
+    /// Passed to the kernel through [`BootInfo::command_line`].
+    command_line: String,
+}
+
+/* Note [Boot configuration]
+
+`boot.cfg` is an optional file in the root of the boot volume. Each line is `key = value`. Blank
+lines and lines that start with `#` are ignored.
+
+Keys:
+
+* `verify_kernel` (`true` or `false`, default `false`): only boot a correctly signed kernel. See
+  Note [Kernel signatures].
+* `command_line` (default empty): options for the kernel, e.g. `command_line = log_level=debug`.
+  Everything after the first `=` is the command line, so it can contain `=`s of its own.
+
+Unknown keys and invalid values stop the boot, so that a typo can't silently turn off
+verification.
+*/
+fn parse_boot_config(text: &str) -> Result<BootConfig, &str> {
+    let mut boot_config = BootConfig::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or(line)?;
+        match (key.trim(), value.trim()) {
+            ("verify_kernel", "true") => boot_config.verify_kernel = true,
+            ("verify_kernel", "false") => boot_config.verify_kernel = false,
+            ("command_line", command_line) => boot_config.command_line = command_line.to_string(),
+            _ => return Err(line),
+        }
+    }
+
+    Ok(boot_config)
+}
+
+fn read_boot_config(
+    image_handle: Handle,
+    system_table: &SystemTable<Boot>,
+) -> Result<BootConfig, BootError> {
+    let file_name = cstr16!("boot.cfg");
+
+    let mut buffer = [0; 1024];
+    let Some(len) = read_boot_volume_file(image_handle, system_table, file_name, &mut buffer)?
+    else {
+        info!("no {}, using the default boot configuration", file_name);
+        return Ok(BootConfig::default());
+    };
+
+    let Ok(text) = core::str::from_utf8(&buffer[..len]) else {
+        return Err(BootError::BootConfigEncoding);
+    };
+
+    parse_boot_config(text).map_err(|line| BootError::BootConfigLine(line.to_string()))
+}
+
+/** Read a whole file from the boot volume into `buffer`.
+
+Returns the file's size, or `None` when the file doesn't exist. Files larger than `buffer` are an
+error.
+*/
+fn read_boot_volume_file(
+    image_handle: Handle,
+    system_table: &SystemTable<Boot>,
+    file_name: &'static CStr16,
+    buffer: &mut [u8],
+) -> Result<Option<usize>, BootError> {
+    let mut fs = system_table
+        .boot_services()
+        .get_image_file_system(image_handle)
+        .unwrap();
+
+    let mut root = fs.open_volume().unwrap();
+
+    let mut file = match root.open(file_name, FileMode::Read, FileAttribute::empty()) {
+        Ok(file) => file.into_regular_file().unwrap(),
+        Err(err) => {
+            return match BootError::file_open(file_name, err.status()) {
+                None => Ok(None),
+                Some(error) => Err(error),
+            };
+        }
+    };
+
+    let read_error = |err: uefi::Error<Option<usize>>| BootError::FileRead {
+        file_name,
+        status: err.status(),
+    };
+
+    let len = file.read(buffer).map_err(read_error)?;
+
+    // A full buffer might mean that the file was truncated.
+    if len == buffer.len() && file.read(&mut [0u8]).map_err(read_error)? != 0 {
+        return Err(BootError::FileTooLarge {
+            file_name,
+            max_size: buffer.len(),
+        });
+    }
+
+    file.close();
+    Ok(Some(len))
+}
+
+/* Note [Kernel signatures]
+
+When `verify_kernel = true` is set in `boot.cfg` (see Note [Boot configuration]), the bootloader
+only runs a kernel that was signed with `KERNEL_SIGNING_KEY`.
+
+The signature is stored in `kernel.sig`, next to `kernel.bin`. It's exactly 32 bytes: the raw
+HMAC-SHA256 of the entire `kernel.bin` file, keyed with the bytes of `KERNEL_SIGNING_KEY`. The key
+is compiled into the bootloader from the `KERNEL_SIGNING_KEY` environment variable. To sign a
+kernel:
+
+```
+openssl dgst -sha256 -mac HMAC -macopt key:"$KERNEL_SIGNING_KEY" -binary kernel.bin > kernel.sig
+```
+
+The signature is checked right after `kernel.bin` is read, before any of it is interpreted. The
+bootloader refuses to boot (it prints an error and exits with `EFI_SECURITY_VIOLATION`) when:
+
+* The bootloader was built without `KERNEL_SIGNING_KEY`.
+* `kernel.sig` is missing, or isn't 32 bytes long.
+* The signature doesn't match.
+
+HMAC is a symmetric scheme: the key that checks signatures can also make them, and anyone who can
+read `bootx64.efi` can extract it. So this catches corrupted and casually modified kernels, not a
+determined attacker. That would need a public key signature scheme, such as Ed25519.
+*/
+const KERNEL_SIGNING_KEY: Option<&str> = option_env!("KERNEL_SIGNING_KEY");
+
+/// See Note [Kernel signatures].
+fn verify_kernel_signature(
+    image_handle: Handle,
+    system_table: &SystemTable<Boot>,
+    kernel: &[u8],
+) -> Result<(), BootError> {
+    let signature_file_name = cstr16!("kernel.sig");
+
+    let Some(key) = KERNEL_SIGNING_KEY else {
+        return Err(BootError::SigningKeyMissing);
+    };
+
+    // One byte larger than a signature, so that longer files are detected.
+    let mut signature = [0; hmac::DIGEST_SIZE + 1];
+    let signature_len = read_boot_volume_file(
+        image_handle,
+        system_table,
+        signature_file_name,
+        &mut signature,
+    )
+    .map_err(|err| match err {
+        // A longer file is detected as a length error, not a read error.
+        BootError::FileTooLarge { file_name, .. } => BootError::SignatureLength {
+            file_name,
+            expected: hmac::DIGEST_SIZE,
+        },
+        err => err,
+    })?;
+    let signature: &[u8; hmac::DIGEST_SIZE] = match signature_len {
+        Some(hmac::DIGEST_SIZE) => signature[..hmac::DIGEST_SIZE].try_into().unwrap(),
+        Some(_) => {
+            return Err(BootError::SignatureLength {
+                file_name: signature_file_name,
+                expected: hmac::DIGEST_SIZE,
+            });
+        }
+        None => {
+            return Err(BootError::SignatureNotFound(signature_file_name));
+        }
+    };
+
+    if !hmac::digests_equal(&hmac::hmac_sha256(key.as_bytes(), kernel), signature) {
+        return Err(BootError::SignatureInvalid);
+    }
+
+    info!("kernel signature is valid");
+    Ok(())
+}
+
+/** Read the kernel into memory.
+
+When `verify_signature` is true, the kernel must have a valid signature (see Note [Kernel
+signatures]).
+*/
 fn load_kernel(
     image_handle: Handle,
     system_table: &mut SystemTable<Boot>,
-    kernel_file_name: &CStr16,
-) -> Result<KernelInfo, uefi::Status> {
+    kernel_file_name: &'static CStr16,
+    verify_signature: bool,
+) -> Result<KernelInfo, BootError> {
     let mut kernel_file = {
         let mut fs = system_table
             .boot_services()
@@ -203,16 +578,10 @@ fn load_kernel(
 
         match root.open(kernel_file_name, FileMode::Read, FileAttribute::empty()) {
             Ok(file) => file.into_regular_file().unwrap(),
-            Err(err) => match err.status() {
-                Status::NOT_FOUND => {
-                    uefi::println!("error: {} not found", kernel_file_name);
-                    return Err(Status::ABORTED);
-                }
-                _ => {
-                    uefi::println!("error: failed to open {}: {}", kernel_file_name, err);
-                    return Err(Status::ABORTED);
-                }
-            },
+            Err(err) => {
+                return Err(BootError::file_open(kernel_file_name, err.status())
+                    .unwrap_or(BootError::KernelNotFound(kernel_file_name)));
+            }
         }
     };
 
@@ -244,9 +613,12 @@ fn load_kernel(
 
         // Since the file name will be more than zero characters, the grow loop will be triggered at least once.
         // It's less efficient, but I'm okay paying that cost to test the grow loop.
-        let kernel_file_info = alloc_growing_ref(system_table, min_fileinfo_size, |storage| {
-            kernel_file.get_info::<FileInfo>(storage)
-        })
+        let kernel_file_info = alloc_growing_ref(
+            system_table,
+            MemoryType::BOOT_SERVICES_DATA,
+            min_fileinfo_size,
+            |storage| kernel_file.get_info::<FileInfo>(storage),
+        )
         .unwrap();
 
         kernel_file_info.as_ref().file_size() as usize
@@ -275,6 +647,10 @@ fn load_kernel(
 
     info!("finished reading kernel into memory");
 
+    if verify_signature {
+        verify_kernel_signature(image_handle, system_table, kernel_buffer)?;
+    }
+
     Ok(KernelInfo {
         physical_address: kernel_addr,
         size: kernel_size,
@@ -301,39 +677,19 @@ fn map_stack(allocate_pages: &mut dyn FnMut(usize) -> u64, page_map: &mut PageMa
     }
 }
 
-fn map_kernel_segment(
-    allocate_pages: &mut dyn FnMut(usize) -> u64,
-    page_map: &mut PageMap,
-    segment_info: v0::SegmentInfo,
-    segment_data: &[u8],
-    flags: PageMapFlags,
-) {
-    let segment_pages = ((segment_info.size as usize) + PAGE_SIZE - 1) / PAGE_SIZE;
-
-    let base_virtual_addr: u64 = segment_info.load_address;
-    let base_physical_addr: u64 = allocate_pages(segment_pages);
-
-    let mut offset: u64 = 0;
-    for _page in 0..segment_pages {
-        let page_virtual_addr = base_virtual_addr + offset;
-        let page_physical_addr = base_physical_addr + offset;
-
-        let page_buffer: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(page_physical_addr as *mut u8, PAGE_SIZE) };
-
-        for i in 0..PAGE_SIZE {
-            match segment_data.get(offset as usize + i).copied() {
-                None => {
-                    page_buffer[i] = 0;
-                }
-                Some(value) => {
-                    page_buffer[i] = value;
-                }
-            }
-        }
-
-        page_map.set(allocate_pages, page_virtual_addr, page_physical_addr, flags);
+/// See Note [The kernel's heap]
+fn map_heap(allocate_pages: &mut dyn FnMut(usize) -> u64, page_map: &mut PageMap) {
+    let heap_num_pages = (KERNEL_HEAP_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+    let heap_physical_address = allocate_pages(heap_num_pages);
 
+    let mut offset = 0;
+    for _page in 0..heap_num_pages {
+        page_map.set(
+            allocate_pages,
+            KERNEL_HEAP_ADDRESS + offset,
+            heap_physical_address + offset,
+            PageMapFlags::W,
+        );
         offset += PAGE_SIZE as u64;
     }
 }
@@ -342,57 +698,80 @@ fn map_kernel(
     allocate_pages: &mut dyn FnMut(usize) -> u64,
     page_map: &mut PageMap,
     kernel_info: &KernelInfo,
-) {
+) -> Result<(), BootError> {
     let kernel_buffer: &[u8] = unsafe {
         core::slice::from_raw_parts(kernel_info.physical_address as *const u8, kernel_info.size)
     };
 
-    let kernel_exe: v0::Exe =
-        v0::Exe::parse(kernel_buffer).expect("kernel is not a v0 learn-os executable");
+    let kernel_exe: exe::Exe = exe::Exe::parse(kernel_buffer).map_err(BootError::KernelParse)?;
+
+    // See Note [The kernel's entrypoint].
+    let entrypoint = kernel_exe
+        .entrypoint()
+        .ok_or(BootError::KernelNoEntrypoint)?;
+    if entrypoint != KERNEL_ENTRYPOINT {
+        return Err(BootError::KernelEntrypoint {
+            expected: KERNEL_ENTRYPOINT,
+            actual: entrypoint,
+        });
+    }
+    info!("kernel entrypoint: {:#x}", entrypoint);
 
-    map_kernel_segment(
-        allocate_pages,
-        page_map,
-        kernel_exe.code_info(),
-        kernel_exe.code(),
-        PageMapFlags::X,
-    );
-    map_kernel_segment(
-        allocate_pages,
-        page_map,
-        kernel_exe.rodata_info(),
-        kernel_exe.rodata(),
-        PageMapFlags::default(),
-    );
-    map_kernel_segment(
-        allocate_pages,
-        page_map,
-        kernel_exe.rwdata_info(),
-        kernel_exe.rwdata(),
-        PageMapFlags::W,
-    );
+    exe::map_all_segments(allocate_pages, page_map, &kernel_exe);
 
     info!("finished setting up page map for kernel");
+    Ok(())
 }
 
+/* Note [Mapping switch_to_kernel]
+
+`switch_to_kernel` writes the kernel's page map to CR3, so every instruction that runs after the
+write has to be identity-mapped in the kernel's page map. There's no way to get the address of
+the end of a Rust function, so those instructions are all in one `asm!` block, between the global
+labels `switch_to_kernel_start` and `switch_to_kernel_end`, and `map_switch_to_kernel` maps the
+pages between the labels. The rest of `switch_to_kernel` runs on UEFI's page map, so its size
+doesn't matter.
+*/
+
+/** Returns the address of the first page mapped for `switch_to_kernel`, and the number of pages.
+
+See Note [Mapping switch_to_kernel].
+*/
 fn map_switch_to_kernel(
     allocate_pages: &mut dyn FnMut(usize) -> u64,
     page_map: &mut PageMap,
-) -> u64 {
-    let switch_to_kernel_addr: u64 = unsafe {
-        let addr: u64;
-        asm!("2: lea {0}, {1}", out(reg) addr, sym switch_to_kernel);
-        addr
+) -> (u64, u64) {
+    let (switch_to_kernel_addr, switch_to_kernel_end_addr): (u64, u64) = unsafe {
+        let start: u64;
+        let end: u64;
+        asm!(
+            "lea {start}, [rip + switch_to_kernel_start]",
+            "lea {end}, [rip + switch_to_kernel_end]",
+            start = out(reg) start,
+            end = out(reg) end,
+            options(nomem, nostack, preserves_flags),
+        );
+        (start, end)
     };
+    assert!(
+        switch_to_kernel_addr < switch_to_kernel_end_addr,
+        "switch_to_kernel's labels are out of order"
+    );
 
-    /* The address of the 4KiB aligned page in which the `switch_to_kernel` function resides.
+    info!(
+        "switch_to_kernel address: {:#x} (end: {:#x})",
+        switch_to_kernel_addr, switch_to_kernel_end_addr
+    );
+
+    /* The address of the 4KiB aligned page in which the switch to the kernel's page map starts.
 
     If paging is enabled, then UEFI virtual memory is identity-mapped (<https://uefi.org/specs/UEFI/2.9_A/02_Overview.html?highlight=identity#ia-32-platforms>).
     Therefore this address is also the phsical address of the page.
 
-    As long as the `switch_to_kernel` function stays under 4KiB in length, this page is
-    the only one that needs to be identity-mapped into the kernel's virtual address space.
-    Ideally, the kernel would remove the mapping after the switch.
+    The switch is usually contained in this page, but the compiler is free to place it across
+    a page boundary. Every page between this one and the page containing
+    `switch_to_kernel_end_addr` needs to be identity-mapped into the kernel's virtual address
+    space. Ideally, the kernel would remove the mapping after the switch.
 
     I think the only constraint on `switch_to_kernel`'s location is that it doesn't overlap
     with the kernel's virtual address. If we work at the granularity of a page: `switch_to_kernel`'s virtual address isn't in the first page of the kernel.
@@ -406,33 +785,487 @@ fn map_switch_to_kernel(
         "switch_to_kernel overlaps with start of kernel"
     );
 
+    let switch_to_kernel_last_page_addr: u64 = (switch_to_kernel_end_addr - 1) & !0xfff;
+    let switch_to_kernel_page_count =
+        (switch_to_kernel_last_page_addr - switch_to_kernel_page_addr) / PAGE_SIZE as u64 + 1;
+
     info!(
-        "switch_to_kernel page address: {:#x}",
-        switch_to_kernel_page_addr
+        "switch_to_kernel page address: {:#x} ({} pages)",
+        switch_to_kernel_page_addr, switch_to_kernel_page_count
     );
 
-    /* The page in which `switch_to_kernel` needs to be identity-mapped because that
+    /* The pages in which `switch_to_kernel` resides need to be identity-mapped because that
     function is going to set `page_map` as the active virtual memory map. After this
     happens, instruction fetches need to return the remaining instructions of
     `switch_to_kernel`. When this region of code isn't mapped, the instruction fetches
     will cause page faults.
     */
-    page_map.set(
+    page_map.map_identity(
         allocate_pages,
         switch_to_kernel_page_addr,
-        switch_to_kernel_page_addr,
+        switch_to_kernel_page_count * PAGE_SIZE as u64,
         PageMapFlags::X,
     );
+
+    assert!(
+        page_map.translate(switch_to_kernel_addr) == Some(switch_to_kernel_addr)
+            && page_map.translate(switch_to_kernel_end_addr - 1)
+                == Some(switch_to_kernel_end_addr - 1),
+        "switch_to_kernel isn't identity-mapped"
+    );
     info!("finished setting up page map for context switch");
 
-    switch_to_kernel_page_addr
+    (switch_to_kernel_page_addr, switch_to_kernel_page_count)
+}
+
+/** Identity-map a memory-mapped serial device's registers, uncached.
+
+A PC16500D has 8 byte-wide registers, which can straddle a page boundary.
+*/
+fn map_serial_device(
+    allocate_pages: &mut dyn FnMut(usize) -> u64,
+    page_map: &mut PageMap,
+    address: u64,
+) {
+    let first_page = address & !(PAGE_SIZE as u64 - 1);
+    let last_page = (address + 7) & !(PAGE_SIZE as u64 - 1);
+    page_map.map_mmio(
+        allocate_pages,
+        first_page,
+        first_page,
+        last_page - first_page + PAGE_SIZE as u64,
+    );
+}
+
+/* Note [Enabling execute-disable]
+
+Page table entries mark non-code pages execute-disable (XD), but the processor only honours XD
+when IA32_EFER.NXE is set. When NXE is clear, XD is a reserved bit, and any page walk that sees
+it raises a page fault. NXE has to be set before the switch to the kernel's page map, which
+happens in `switch_to_kernel`.
+
+If the processor doesn't support execute-disable (CPUID.80000001H:EDX.NX), NXE can't be set,
+so XD is cleared from every entry instead. The kernel still runs, but without its code/data
+separation.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section
+4.1.1 and Section 4.6.
+*/
+
+/** Make the page map's execute-disable bits take effect.
+
+See Note [Enabling execute-disable].
+*/
+fn enable_execute_disable(page_map: &mut PageMap) {
+    if !execute_disable_supported() {
+        warn!("execute-disable isn't supported, so all memory will be executable");
+        page_map.clear_execute_disable();
+        return;
+    }
+
+    let mut ia32_efer = IA32_EFER::read();
+    if !ia32_efer.nxe() {
+        ia32_efer.set_nxe(true);
+        // Safety: execute-disable is supported, and no page map in use relies on XD being reserved.
+        unsafe { ia32_efer.write() };
+    }
+    assert!(IA32_EFER::read().nxe(), "IA32_EFER.NXE isn't set");
+    info!("enabled execute-disable");
+}
+
+/* Note [Enabling write-combining]
+
+The framebuffer is mapped with `PageMapFlags::WRITE_COMBINING`, which sets the PAT bit and
+selects PAT entry `IA32_PAT::WRITE_COMBINING_INDEX`. That entry is WB after a reset, so it has to
+be reprogrammed to WC before the switch to the kernel's page map, or the framebuffer would be
+cached as WB.
+
+Changing a PAT entry changes the memory type of every mapping that selects it. UEFI's page map
+uses the default memory types (PAT = 0 in every entry), so nothing that's mapped while the
+bootloader runs selects the entry.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section
+12.12.
+*/
+
+/// Set the PAT entry for write-combining pages. See Note [Enabling write-combining].
+fn enable_write_combining() {
+    let mut pat = IA32_PAT::read();
+    if pat.entry(IA32_PAT::WRITE_COMBINING_INDEX) != Some(PatMemoryType::WriteCombining) {
+        pat.set_entry(
+            IA32_PAT::WRITE_COMBINING_INDEX,
+            PatMemoryType::WriteCombining,
+        );
+        // Safety: no mapping in use selects the entry.
+        unsafe { pat.write() };
+    }
+    assert_eq!(
+        IA32_PAT::read().entry(IA32_PAT::WRITE_COMBINING_INDEX),
+        Some(PatMemoryType::WriteCombining),
+        "PAT entry {} isn't write-combining",
+        IA32_PAT::WRITE_COMBINING_INDEX
+    );
+    info!("enabled write-combining");
+}
+
+/* Note [Mapping usable memory]
+
+All the memory that the kernel can allocate (see `memory_region_kind`) is identity-mapped and
+writable, so the kernel can use a physical frame as soon as it allocates it (e.g. as a new page
+table). 2MiB pages are used wherever a 2MiB aligned chunk of a region is unmapped, and 4KiB pages
+are used for the rest.
+
+A page that's already mapped is skipped. This happens when an identity-mapped address overlaps
+with the kernel's own mappings (e.g. its stack and code at low virtual addresses).
+*/
+
+/** Identity-map the memory that the kernel can allocate.
+
+Returns the total size of that memory, in bytes.
+
+See Note [Mapping usable memory].
+*/
+fn map_usable_memory(
+    allocate_pages: &mut dyn FnMut(usize) -> u64,
+    page_map: &mut PageMap,
+    boot_services: &BootServices,
+) -> u64 {
+    let usable_regions: Vec<(u64, u64)> = {
+        let mut buffer = Vec::new();
+        let memory_map = read_memory_map(boot_services, &mut buffer);
+        memory_map
+            .entries()
+            .filter(|memory_descriptor| {
+                memory_region_kind(memory_descriptor.ty) == MemoryRegionKind::Usable
+            })
+            .map(|memory_descriptor| (memory_descriptor.phys_start, memory_descriptor.page_count))
+            .collect()
+    };
+
+    let huge_page_size = PageSize::Size2MiB.bytes();
+    let mut usable_memory_size = 0;
+    for (region_start, page_count) in usable_regions {
+        let region_end = region_start + page_count * PAGE_SIZE as u64;
+        usable_memory_size += region_end - region_start;
+
+        let mut address = region_start;
+        while address < region_end {
+            let huge_page_fits = address & (huge_page_size - 1) == 0
+                && address + huge_page_size <= region_end
+                && (address..address + huge_page_size)
+                    .step_by(PAGE_SIZE)
+                    .all(|page_address| page_map.translate(page_address).is_none());
+
+            if huge_page_fits {
+                page_map.set_2mib(allocate_pages, address, address, PageMapFlags::W);
+                address += huge_page_size;
+            } else {
+                if page_map.translate(address).is_none() {
+                    page_map.set(allocate_pages, address, address, PageMapFlags::W);
+                }
+                address += PAGE_SIZE as u64;
+            }
+        }
+    }
+
+    usable_memory_size
+}
+
+/** Find the ACPI Root System Description Pointer in the UEFI configuration table.
+
+The ACPI 2.0 RSDP is preferred, because it points to the XSDT (64-bit table addresses). The
+ACPI 1.0 RSDP is the fallback.
+
+Reference: UEFI Specification 2.10, Section 4.6.1
+*/
+fn get_acpi_rsdp(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let find = |guid| {
+        system_table
+            .config_table()
+            .iter()
+            .find(|entry| entry.guid == guid)
+            .map(|entry| entry.address as u64)
+    };
+
+    if let Some(address) = find(ACPI2_GUID) {
+        info!("found ACPI 2.0 RSDP at {:#x}", address);
+        Some(address)
+    } else if let Some(address) = find(ACPI_GUID) {
+        info!("found ACPI 1.0 RSDP at {:#x}", address);
+        Some(address)
+    } else {
+        info!("no ACPI RSDP");
+        None
+    }
+}
+
+/** Identity-map the page(s) containing the ACPI RSDP, read-only.
+
+The RSDP isn't page aligned, so it can share a page with something that's already mapped
+(or straddle two pages). Pages that are already mapped are left alone.
+*/
+fn map_acpi_rsdp(
+    allocate_pages: &mut dyn FnMut(usize) -> u64,
+    page_map: &mut PageMap,
+    address: u64,
+) {
+    // The size of the ACPI 2.0 RSDP, which is larger than the ACPI 1.0 RSDP.
+    const RSDP_SIZE: u64 = 36;
+
+    let first_page = address & !(PAGE_SIZE as u64 - 1);
+    let last_page = (address + RSDP_SIZE - 1) & !(PAGE_SIZE as u64 - 1);
+
+    let mut page_address = first_page;
+    while page_address <= last_page {
+        if page_map.translate(page_address).is_none() {
+            page_map.set(
+                allocate_pages,
+                page_address,
+                page_address,
+                PageMapFlags::default(),
+            );
+        }
+        page_address += PAGE_SIZE as u64;
+    }
+}
+
+/// The local APIC's *physical* address, when the processor has one and it's enabled.
+fn get_local_apic() -> Option<u64> {
+    if !local_apic_supported() {
+        warn!("processor doesn't have a local APIC");
+        return None;
+    }
+
+    // Safety: the processor has a local APIC.
+    let apic_base = unsafe { IA32_APIC_BASE::read() };
+    if !apic_base.enabled() {
+        warn!("local APIC is disabled");
+        return None;
+    }
+
+    info!("local APIC address: {:#x}", apic_base.address());
+    Some(apic_base.address())
+}
+
+/// Find the linear framebuffer of the current graphics mode, if there is one.
+fn get_framebuffer(image_handle: Handle, boot_services: &BootServices) -> Option<FramebufferInfo> {
+    let handle = match boot_services.get_handle_for_protocol::<GraphicsOutput>() {
+        Err(err) => {
+            info!("no graphics output protocol: {}", err);
+            return None;
+        }
+        Ok(handle) => handle,
+    };
+
+    /* Opened non-exclusively for the same reason as the PCI root bridge protocol in
+    `get_serial_controller`. UEFI's console uses the GOP, and opening it exclusively
+    would disconnect the console.
+    */
+    let mut gop = unsafe {
+        boot_services
+            .open_protocol::<GraphicsOutput>(
+                OpenProtocolParams {
+                    handle,
+                    agent: image_handle,
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )
+            .unwrap()
+    };
+
+    let mode_info = gop.current_mode_info();
+    let pixel_format = match mode_info.pixel_format() {
+        uefi::proto::console::gop::PixelFormat::Rgb => PixelFormat::Rgb,
+        uefi::proto::console::gop::PixelFormat::Bgr => PixelFormat::Bgr,
+        other => {
+            info!("unsupported framebuffer pixel format: {:?}", other);
+            return None;
+        }
+    };
+    let (width, height) = mode_info.resolution();
+    let stride = mode_info.stride();
+
+    let mut frame_buffer = gop.frame_buffer();
+    let address = frame_buffer.as_mut_ptr() as u64;
+    let size = frame_buffer.size() as u64;
+
+    info!(
+        "framebuffer: {}x{} ({:?}) at {:#x}, {}B",
+        width, height, pixel_format, address, size
+    );
+
+    Some(FramebufferInfo {
+        address,
+        size,
+        width: width as u32,
+        height: height as u32,
+        stride: stride as u32,
+        pixel_format,
+    })
+}
+
+/** Identity-mapped memory for the [`BootInfo`] and the memory map that it points to.
+
+This memory has to be allocated and mapped before exiting boot services, but the final memory
+map is only available afterwards. See also: Note [Kernel entrypoint arguments]
+*/
+struct BootInfoStorage {
+    boot_info_address: u64,
+    memory_map_address: u64,
+    memory_map_capacity: usize,
+    command_line_address: u64,
+    command_line_len: usize,
+}
+
+impl BootInfoStorage {
+    fn allocate(
+        allocate_pages: &mut dyn FnMut(usize) -> u64,
+        page_map: &mut PageMap,
+        boot_services: &BootServices,
+        command_line: &str,
+    ) -> Self {
+        let boot_info_pages = (core::mem::size_of::<BootInfo>() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let boot_info_address = allocate_pages(boot_info_pages);
+        page_map.map_identity(
+            allocate_pages,
+            boot_info_address,
+            (boot_info_pages * PAGE_SIZE) as u64,
+            PageMapFlags::default(),
+        );
+
+        /* The memory map will grow between now and `exit_boot_services`, because allocations
+        can split free regions. Leave plenty of room for that.
+        */
+        let memory_map_sizes = boot_services.memory_map_size();
+        let memory_map_capacity = memory_map_sizes.map_size / memory_map_sizes.entry_size + 64;
+        let memory_map_pages =
+            (memory_map_capacity * core::mem::size_of::<MemoryRegion>() + PAGE_SIZE - 1)
+                / PAGE_SIZE;
+        let memory_map_address = allocate_pages(memory_map_pages);
+        page_map.map_identity(
+            allocate_pages,
+            memory_map_address,
+            (memory_map_pages * PAGE_SIZE) as u64,
+            PageMapFlags::default(),
+        );
+
+        // The command line is already known, so it's copied now.
+        let command_line_address = if command_line.is_empty() {
+            0
+        } else {
+            let command_line_pages = (command_line.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+            let command_line_address = allocate_pages(command_line_pages);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    command_line.as_ptr(),
+                    command_line_address as *mut u8,
+                    command_line.len(),
+                );
+            }
+            page_map.map_identity(
+                allocate_pages,
+                command_line_address,
+                (command_line_pages * PAGE_SIZE) as u64,
+                PageMapFlags::default(),
+            );
+            command_line_address
+        };
+
+        BootInfoStorage {
+            boot_info_address,
+            memory_map_address,
+            memory_map_capacity,
+            command_line_address,
+            command_line_len: command_line.len(),
+        }
+    }
+
+    /** Store the boot info and the final memory map.
+
+    Returns the kernel's virtual address for the boot info.
+    */
+    fn write(self, memory_map: &MemoryMap, mut boot_info: BootInfo) -> u64 {
+        let memory_map_buffer: &mut [MemoryRegion] = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.memory_map_address as *mut MemoryRegion,
+                self.memory_map_capacity,
+            )
+        };
+
+        let mut memory_map_len = 0;
+        for memory_descriptor in memory_map.entries() {
+            assert!(
+                memory_map_len < self.memory_map_capacity,
+                "memory map has more than {} entries",
+                self.memory_map_capacity
+            );
+
+            memory_map_buffer[memory_map_len] = MemoryRegion {
+                kind: memory_region_kind(memory_descriptor.ty),
+                physical_start: memory_descriptor.phys_start,
+                page_count: memory_descriptor.page_count,
+            };
+            memory_map_len += 1;
+        }
+
+        boot_info.memory_map_address = self.memory_map_address;
+        boot_info.memory_map_len = memory_map_len as u64;
+        boot_info.command_line_address = self.command_line_address;
+        boot_info.command_line_len = self.command_line_len as u64;
+
+        unsafe {
+            (self.boot_info_address as *mut BootInfo).write(boot_info);
+        }
+
+        self.boot_info_address
+    }
+}
+
+fn memory_region_kind(memory_type: MemoryType) -> MemoryRegionKind {
+    match memory_type {
+        // Boot services memory is free once boot services have exited.
+        MemoryType::CONVENTIONAL
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::BOOT_SERVICES_DATA => MemoryRegionKind::Usable,
+        MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => MemoryRegionKind::Bootloader,
+        MemoryType::ACPI_RECLAIM => MemoryRegionKind::AcpiReclaimable,
+        _ => MemoryRegionKind::Reserved,
+    }
 }
 
 pub struct PciHeader {
     pub vendor_id: u16,
     pub device_id: u16,
+
+    /** The command register (low 16 bits) and the status register (high 16 bits).
+
+    The command register is only 16 bits wide; it's read as part of a 32-bit value here. Use
+    [`PciRootBridgeIo::set_command_bits`] and [`PciRootBridgeIo::clear_command_bits`] to change it.
+    */
     pub command: u32,
+
+    /// See Note [Serial controller class code].
+    pub prog_if: u8,
+    pub subclass: u8,
+    pub class: u8,
+
+    /** The status register.
+
+    The same value as the high 16 bits of `command`.
+    */
+    pub status: u16,
+
+    pub cache_line_size: u8,
+    pub latency_timer: u8,
     pub header_type: u8,
+    pub bist: u8,
+
+    /// The legacy IRQ line that firmware routed the function's interrupt pin to.
+    pub interrupt_line: u8,
+
+    /// The interrupt pin that the function uses: 0 for none, and 1 to 4 for `INTA#` to `INTD#`.
+    pub interrupt_pin: u8,
 }
 
 fn pci_header_read(
@@ -440,47 +1273,85 @@ fn pci_header_read(
     bus: u8,
     device: u8,
     function: u8,
-) -> PciHeader {
-    let vendor_and_device_ids = pci_root_bridge
-        .pci_read_u32(PciConfigurationAddress {
-            bus,
-            device,
-            function,
-            register: 0x0,
-        })
-        .unwrap();
-
-    let command = pci_root_bridge
-        .pci_read_u32(PciConfigurationAddress {
-            bus,
-            device,
-            function,
-            register: 0x4,
-        })
-        .unwrap();
-
-    let header_type = pci_root_bridge
-        .pci_read_u8(PciConfigurationAddress {
+) -> uefi::Result<PciHeader> {
+    let vendor_and_device_ids = pci_root_bridge.pci_read_u32(PciConfigurationAddress {
+        bus,
+        device,
+        function,
+        register: 0x0,
+    })?;
+
+    let command = pci_root_bridge.pci_read_u32(PciConfigurationAddress {
+        bus,
+        device,
+        function,
+        register: 0x4,
+    })?;
+
+    let class_code = pci_root_bridge.pci_read_u32(PciConfigurationAddress {
+        bus,
+        device,
+        function,
+        register: 0x8,
+    })?;
+
+    let bist_header_latency_cache_line = BistHeaderLatencyCacheLine::from_u32(
+        pci_root_bridge.pci_read_u32(PciConfigurationAddress {
             bus,
             device,
             function,
-            register: 0xe,
-        })
-        .unwrap();
+            register: 0xc,
+        })?,
+    );
 
-    PciHeader {
+    // Every header layout has the interrupt line and pin at 0x3c and 0x3d.
+    let interrupt_line = pci_root_bridge.pci_read_u8(PciConfigurationAddress {
+        bus,
+        device,
+        function,
+        register: 0x3c,
+    })?;
+
+    let interrupt_pin = pci_root_bridge.pci_read_u8(PciConfigurationAddress {
+        bus,
+        device,
+        function,
+        register: 0x3d,
+    })?;
+
+    Ok(PciHeader {
         vendor_id: (vendor_and_device_ids & 0xffff) as u16,
         device_id: (vendor_and_device_ids >> 16) as u16,
         command,
-        header_type,
-    }
+        prog_if: (class_code >> 8) as u8,
+        subclass: (class_code >> 16) as u8,
+        class: (class_code >> 24) as u8,
+        status: (command >> 16) as u16,
+        cache_line_size: bist_header_latency_cache_line.cache_line_size,
+        latency_timer: bist_header_latency_cache_line.latency_timer,
+        header_type: bist_header_latency_cache_line.header_type,
+        bist: bist_header_latency_cache_line.bist,
+        interrupt_line,
+        interrupt_pin,
+    })
 }
 
-/// Find the serial controller's I/O port via PCI.
-fn get_serial_controller(image_handle: Handle, boot_services: &BootServices) -> u16 {
+/// The I/O port of the first legacy serial port, used when there's no serial controller on PCI.
+const COM1_PORT: u16 = 0x3f8;
+
+/** Find the serial controller's registers via PCI.
+
+Fails when there's no PCI root bridge, when a configuration space access fails, or when no PCI
+function is a 16550-compatible serial controller with a usable BAR0. See
+[`serial::find_serial_controller`].
+*/
+fn get_serial_controller(
+    image_handle: Handle,
+    boot_services: &BootServices,
+) -> Result<SerialDevice, SerialDiscoveryError<Status>> {
     let handle = boot_services
         .get_handle_for_protocol::<PciRootBridgeIo>()
-        .unwrap();
+        .map_err(|error| SerialDiscoveryError::NoRootBridge(error.status()))?;
 
     /* `open_protocol` is unsafe because it gives back a protocol interface that could be
     uninstalled by other code, invalidating the Rust reference. To reflect this, I'm using
@@ -512,14 +1383,21 @@ fn get_serial_controller(image_handle: Handle, boot_services: &BootServices) ->
                 },
                 OpenProtocolAttributes::GetProtocol,
             )
-            .unwrap();
-
-        {
-            let pci_header = pci_header_read(&pci_root_bridge, 0, 0, 0);
+            .map_err(|error| SerialDiscoveryError::NoRootBridge(error.status()))?;
+
+        let read_u32 = |(bus, device, function), register| {
+            pci_root_bridge
+                .pci_read_u32(PciConfigurationAddress {
+                    bus,
+                    device,
+                    function,
+                    register,
+                })
+                .map_err(|error| error.status())
+        };
 
+        if cfg!(debug_assertions) {
             // 0x8086 for Intel, woohoo!
-            assert_eq!(pci_header.vendor_id, 0x8086);
-
             /* I was reading the 82371FB (PIIX) and 82371SB (PIIX3) datasheet because it
             was the first thing I saw on in the [440FX resources](https://web.archive.org/web/20041127232037/https://www.intel.com/design/archives/chipsets/440/index.htm),
             (QEMU's default chipset) with PCI in its name. So I was expecting to see 0x7000 because I
@@ -530,36 +1408,56 @@ fn get_serial_controller(image_handle: Handle, boot_services: &BootServices) ->
             I was looking at the wrong datasheet; the first listing in the 440FX resources
             is for the [82441FX PCI and Memory Controller](https://web.archive.org/web/20030706082243/http://intel.com/design/chipsets/datashts/29054901.pdf).
             Section 3.2.3 lists the device identification register (DID) with a default value of 0x1237.
+
+            This only holds for QEMU's default machine, so it's only checked in debug builds, and a
+            different host bridge only skips PCI discovery.
             */
-            assert_eq!(pci_header.device_id, 0x1237);
+            serial::check_host_bridge(read_u32, 0x8086, 0x1237)?;
         }
 
-        let serial_controller_pci_header = pci_header_read(&pci_root_bridge, 0, 3, 0);
-        assert_eq!(serial_controller_pci_header.vendor_id, 0x1b36);
-        assert_eq!(serial_controller_pci_header.device_id, 0x0002);
+        let functions = pci_root_bridge
+            .tree_functions(0)
+            .map(|location| location.map_err(|error| error.status()));
+
+        // The firmware normally enables decoding already, but the kernel relies on it.
+        let enable = |(bus, device, function), serial_device| {
+            let command_bit = match serial_device {
+                SerialDevice::Io(_) => COMMAND_IO,
+                SerialDevice::Mmio(_) => COMMAND_MEM,
+            };
+            pci_root_bridge
+                .set_command_bits(
+                    PciConfigurationAddress {
+                        bus,
+                        device,
+                        function,
+                        register: 0x0,
+                    },
+                    command_bit,
+                )
+                .map_err(|error| error.status())
+        };
 
-        let serial_controller_bar0_value = pci_root_bridge
-            .pci_read_u32(PciConfigurationAddress {
-                bus: 0,
-                device: 3,
-                function: 0,
-                register: 0x10,
-            })
-            .unwrap();
-        assert!(
-            serial_controller_bar0_value <= u16::MAX as u32,
-            "serial controller BAR0 is not a 16-bit value"
-        );
-        assert_eq!(
-            serial_controller_bar0_value & 0x1,
-            0x1,
-            "serial controller BAR0 is not in I/O space"
-        );
+        let ((bus, device, function), serial_device) =
+            serial::find_serial_controller(functions, read_u32, enable)?;
+        info!("serial controller at {bus}:{device}:{function}, {serial_device:x?}");
+        Ok(serial_device)
+    }
+}
 
-        let serial_controller_io_address: u16 = (serial_controller_bar0_value & 0xfffffff0) as u16;
+/** Every PCI function reachable from bus 0, with its header.
 
-        serial_controller_io_address
-    }
+Bridges are followed to their secondary buses (see [`uefi_pci::tree_functions`]), and functions
+1 to 7 are only checked on multi-function devices.
+*/
+fn pci_functions(
+    pci_root_bridge: &PciRootBridgeIo,
+) -> impl Iterator<Item = uefi::Result<(u8, u8, u8, PciHeader)>> + '_ {
+    pci_root_bridge.tree_functions(0).map(move |location| {
+        let (bus, device, function) = location?;
+        let pci_header = pci_header_read(pci_root_bridge, bus, device, function)?;
+        Ok((bus, device, function, pci_header))
+    })
 }
 
 fn pci_device_enumerate(pci_root_bridge: &PciRootBridgeIo) {
@@ -586,28 +1484,23 @@ fn pci_device_enumerate(pci_root_bridge: &PciRootBridgeIo) {
             "VID:DID {:x}:{:x}",
             pci_header.vendor_id, pci_header.device_id
         );
-        info!("header type: {:#x}", pci_header.header_type);
+        info!(
+            "header type: {:#x} ({:?})",
+            pci_header.header_type,
+            HeaderType::from_u8(pci_header.header_type)
+        );
+        info!(
+            "interrupt line: {}, interrupt pin: {}",
+            pci_header.interrupt_line, pci_header.interrupt_pin
+        );
     };
 
-    for bus in 0..=255 {
-        for device in 0..=31 {
-            let function = 0;
-
-            let pci_header = pci_header_read(pci_root_bridge, bus, device, function);
-            if pci_header.vendor_id != 0xffff {
-                print_pci_header(bus, device, function, &pci_header);
-
-                if pci_header.header_type & 0x80 == 0x80 {
-                    // multi-function device
-
-                    for function in 1..=7 {
-                        let pci_header = pci_header_read(pci_root_bridge, bus, device, function);
-                        if pci_header.vendor_id != 0xffff {
-                            print_pci_header(bus, device, function, &pci_header);
-                        }
-                    }
-                }
+    for result in pci_functions(pci_root_bridge) {
+        match result {
+            Ok((bus, device, function, pci_header)) => {
+                print_pci_header(bus, device, function, &pci_header)
             }
+            Err(error) => warn!("PCI configuration access failed: {:?}", error.status()),
         }
     }
 }
@@ -615,21 +1508,33 @@ fn pci_device_enumerate(pci_root_bridge: &PciRootBridgeIo) {
 fn page_map_debug(page_map: &PageMap) {
     page_map.debug(
         &mut |index, pml4e| {
-            info!("pml4e {}: {:#x}", index, pml4e.value());
+            info!("pml4e {}: table={}", index, pml4e.decode());
         },
         &mut |index, pdpte| {
-            info!("  pdpte {}: {:#x}", index, pdpte.value());
+            info!("  pdpte {}: table={}", index, pdpte.decode());
         },
         &mut |index, pde| {
-            info!("    pde {}: {:#x}", index, pde.value());
+            info!("    pde {}: table={}", index, pde.decode());
         },
-        &mut |index, virtual_address, pte| {
-            info!(
-                "      pte {} ({:#x}): {:#x}",
-                index,
-                virtual_address,
-                pte.value()
-            );
+        &mut |index, virtual_address, page_size, entry| match page_size {
+            PageSize::Size4KiB => {
+                info!(
+                    "      pte {} ({:#x}): frame={}",
+                    index, virtual_address, entry
+                );
+            }
+            PageSize::Size2MiB => {
+                info!(
+                    "    pde {} ({:#x}, {}): frame={}",
+                    index, virtual_address, page_size, entry
+                );
+            }
+            PageSize::Size1GiB => {
+                info!(
+                    "  pdpte {} ({:#x}, {}): frame={}",
+                    index, virtual_address, page_size, entry
+                );
+            }
         },
     );
 }
@@ -666,47 +1571,52 @@ impl<'a, T: ?Sized> BorrowMut<T> for PooledRef<'a, T> {
     }
 }
 
-fn alloc_growing_ref<T: ?Sized, E: core::fmt::Debug>(
-    system_table: &mut SystemTable<Boot>,
-    initial_size: usize,
-    mut f: impl FnMut(&mut [u8]) -> uefi::Result<&mut T, E>,
-) -> uefi::Result<PooledRef<T>, E> {
-    let mut storage_size = initial_size;
-    let mut storage_addr: NonNull<u8>;
+/** Call `f` with a pool buffer of `memory_type`, growing the buffer until `f` stops failing with
+`BUFFER_TOO_SMALL`. See [`buffer::grow`].
 
-    let mut buffer: &mut [u8];
-    let buffer_typed: &mut T;
+UEFI functions that fill a caller-provided buffer usually report the size they need, which
+`uefi` returns as the error's data.
 
+`memory_type` decides whether the buffer outlives boot services. After `exit_boot_services`,
+`BOOT_SERVICES_DATA` is free memory that the kernel may reuse, while `LOADER_DATA` and
+`ACPI_RECLAIM` stay allocated until the kernel frees them (see
+[`MemoryRegionKind`](common::boot_info::MemoryRegionKind)).
+
+Reference: UEFI Specification 2.10, Section 7.2 (Memory Allocation Services), Table 7-6
+*/
+fn alloc_growing_ref<T: ?Sized>(
+    system_table: &mut SystemTable<Boot>,
+    memory_type: MemoryType,
+    initial_size: usize,
+    mut f: impl FnMut(&mut [u8]) -> uefi::Result<&mut T, Option<usize>>,
+) -> uefi::Result<PooledRef<T>, Option<usize>> {
     let boot_services = system_table.boot_services();
-    loop {
-        storage_addr = boot_services
-            .allocate_pool(MemoryType::BOOT_SERVICES_DATA, storage_size)
+    let (storage_addr, buffer_typed) = buffer::grow(initial_size, |storage_size| {
+        let storage_addr = boot_services
+            .allocate_pool(memory_type, storage_size)
             .unwrap();
-        buffer = unsafe { core::slice::from_raw_parts_mut(storage_addr.as_ptr(), storage_size) };
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(storage_addr.as_ptr(), storage_size) };
 
         info!("trying get_info with buffer size {}", storage_size);
         match f(buffer) {
             Ok(value) => {
                 info!("alloc_growing_ref succeeded");
-                buffer_typed = value;
-                break;
+                Ok((storage_addr, value))
             }
             Err(err) => {
                 info!("alloc_growing_ref failed (buffer too small)");
                 unsafe { boot_services.free_pool(storage_addr.as_ptr()).unwrap() };
 
                 match err.status() {
-                    Status::BUFFER_TOO_SMALL => {
-                        storage_size += 100;
-                        continue;
-                    }
-                    _ => {
-                        return Err(err);
-                    }
+                    Status::BUFFER_TOO_SMALL => Err(AttemptError::TooSmall {
+                        required_size: *err.data(),
+                    }),
+                    _ => Err(AttemptError::Other(err)),
                 }
             }
         }
-    }
+    })?;
 
     Ok(PooledRef {
         system_table,
@@ -756,16 +1666,22 @@ fn with_memory_map<T>(
     system_table: &mut SystemTable<Boot>,
     f: impl FnOnce(&mut SystemTable<Boot>, &mut MemoryMap) -> T,
 ) -> T {
-    let boot_services = system_table.boot_services();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut memory_map = read_memory_map(system_table.boot_services(), &mut buffer);
+    f(system_table, &mut memory_map)
+}
 
+/// Get the current memory map, growing `buffer` until it fits.
+fn read_memory_map<'buf>(
+    boot_services: &BootServices,
+    buffer: &'buf mut Vec<u8>,
+) -> MemoryMap<'buf> {
     let memory_map_sizes = boot_services.memory_map_size();
-    let mut buffer: Vec<u8> = core::iter::repeat(0_u8)
-        .take(memory_map_sizes.map_size)
-        .collect();
-    let mut memory_map: MemoryMap;
+    buffer.clear();
+    buffer.extend(core::iter::repeat(0_u8).take(memory_map_sizes.map_size));
 
     loop {
-        match boot_services.memory_map(&mut buffer) {
+        match boot_services.memory_map(buffer) {
             Err(err) => match err.status() {
                 Status::BUFFER_TOO_SMALL => {
                     buffer.extend(core::iter::repeat(0_u8).take(memory_map_sizes.entry_size));
@@ -775,46 +1691,97 @@ fn with_memory_map<T>(
                     panic!("unexpected error: {}", err);
                 }
             },
-            Ok(new_memory_map) => {
-                memory_map = new_memory_map;
+            Ok(_) => {
                 break;
             }
         }
     }
 
-    f(system_table, &mut memory_map)
+    /* Returning the memory map from inside the loop doesn't borrow check: the returned map
+    borrows `buffer` for `'buf`, which the borrow checker then extends over the `Err` branch's
+    `buffer.extend`. Once the loop has found a large enough buffer, reading the map again works.
+    */
+    boot_services.memory_map(buffer).unwrap()
+}
+
+/** Log the memory map as a table of contiguous ranges, and the total size of each memory type.
+
+Adjacent entries of the same type are merged (see [`coalesce_regions`]), so this is much shorter
+than [`browse_memory_map`]'s output.
+*/
+fn summarize_memory_map(system_table: &mut SystemTable<Boot>) {
+    with_memory_map(system_table, |_system_table, memory_map| {
+        memory_map.sort();
+
+        // One entry per memory type: (type, pages).
+        let mut totals: Vec<(MemoryType, u64)> = Vec::new();
+
+        info!("{:>18} {:>18} {:>10} type", "start", "end", "pages");
+        let entries = memory_map
+            .entries()
+            .map(|entry| (entry.ty, entry.phys_start, entry.page_count));
+        for (ty, physical_start, page_count) in coalesce_regions(PAGE_SIZE as u64, entries) {
+            info!(
+                "{:#18x} {:#18x} {:>10} {:?}",
+                physical_start,
+                physical_start + page_count * PAGE_SIZE as u64,
+                page_count,
+                ty
+            );
+
+            match totals.iter_mut().find(|(total_ty, _)| *total_ty == ty) {
+                Some((_, total_pages)) => *total_pages += page_count,
+                None => totals.push((ty, page_count)),
+            }
+        }
+
+        for (ty, pages) in totals {
+            info!("total {:?}: {}B", ty, pages * PAGE_SIZE as u64);
+        }
+    })
+}
+
+/// An answer to [`prompt_next_entry`].
+enum BrowseAction {
+    Print,
+    Skip,
+    Quit,
+}
+
+/// Ask whether to print the next entry, and wait until `y`, `n` or `q` is pressed.
+fn prompt_next_entry(system_table: &mut SystemTable<Boot>) -> BrowseAction {
+    let wait_for_key_event = system_table.stdin().wait_for_key_event().unwrap();
+    let mut events = [wait_for_key_event];
+    loop {
+        uefi::println!("print next entry? (y = yes, n = no/next, q = skip to end)");
+        system_table
+            .boot_services()
+            .wait_for_event(&mut events)
+            .unwrap();
+        if let Some(uefi::proto::console::text::Key::Printable(c)) =
+            system_table.stdin().read_key().unwrap()
+        {
+            if c == 'y' {
+                return BrowseAction::Print;
+            } else if c == 'n' {
+                return BrowseAction::Skip;
+            } else if c == 'q' {
+                return BrowseAction::Quit;
+            }
+        }
+    }
 }
 
 fn browse_memory_map(system_table: &mut SystemTable<Boot>) {
     with_memory_map(system_table, |system_table, memory_map| {
         memory_map.sort();
 
-        let wait_for_key_event = system_table.stdin().wait_for_key_event().unwrap();
-        let mut events = [wait_for_key_event];
         info!("found {} memory map entries", memory_map.entries().len());
-        'outer: for (index, memory_map_entry) in memory_map.entries().enumerate() {
-            'inner: loop {
-                uefi::println!("print next entry? (y = yes, n = no/next, q = skip to end)");
-                system_table
-                    .boot_services()
-                    .wait_for_event(&mut events)
-                    .unwrap();
-                match system_table.stdin().read_key().unwrap() {
-                    Some(uefi::proto::console::text::Key::Printable(c)) => {
-                        if c == 'y' {
-                            break 'inner;
-                        } else if c == 'n' {
-                            continue 'outer;
-                        } else if c == 'q' {
-                            break 'outer;
-                        } else {
-                            continue 'inner;
-                        }
-                    }
-                    _ => {
-                        continue 'inner;
-                    }
-                }
+        for (index, memory_map_entry) in memory_map.entries().enumerate() {
+            match prompt_next_entry(system_table) {
+                BrowseAction::Print => {}
+                BrowseAction::Skip => continue,
+                BrowseAction::Quit => break,
             }
 
             uefi::println!("memory map entry {}", index);
@@ -826,3 +1793,32 @@ fn browse_memory_map(system_table: &mut SystemTable<Boot>) {
         }
     })
 }
+
+/** Step through the pages that `page_map` maps, like [`browse_memory_map`].
+
+Each entry is a leaf mapping: a 4KiB page, or a 2MiB or 1GiB huge page.
+*/
+fn browse_page_map(system_table: &mut SystemTable<Boot>, page_map: &PageMap) {
+    let mut pages = Vec::new();
+    page_map.debug(
+        &mut |_, _| {},
+        &mut |_, _| {},
+        &mut |_, _| {},
+        &mut |_, virtual_address, page_size, entry| {
+            pages.push((virtual_address, page_size, entry));
+        },
+    );
+
+    info!("found {} mapped pages", pages.len());
+    for (virtual_address, page_size, entry) in pages {
+        match prompt_next_entry(system_table) {
+            BrowseAction::Print => {}
+            BrowseAction::Skip => continue,
+            BrowseAction::Quit => break,
+        }
+
+        uefi::println!("page {:#x}", virtual_address);
+        uefi::println!("  size: {}", page_size);
+        uefi::println!("  frame: {}", entry);
+    }
+}
@@ -0,0 +1,341 @@
+/*! Information that the bootloader passes to the kernel.
+
+The bootloader fills in a [`BootInfo`], maps it into the kernel's address space, and passes
+its virtual address to the kernel's entrypoint. Both crates use these definitions, and they're
+compiled for different targets, so every type here is `#[repr(C)]`.
+*/
+
+/** Everything the kernel needs to know about the machine it was booted on.
+
+All addresses are virtual addresses in the kernel's address space, unless stated otherwise.
+*/
+#[repr(C)]
+pub struct BootInfo {
+    /** The system's page size, according to the bootloader.
+
+    Always [`crate::paging::PageMap::PAGE_SIZE`]. See Note [4KiB pages].
+    */
+    pub page_size: u64,
+
+    /** Virtual address of the page allocated for the page table "pivot" function.
+
+    A function that changes the page table needs to have the same virtual memory address
+    before and after the switch. The bootloader sets up the kernel's page table, so it has
+    code for this. The bootloader code that switches to the kernel's page table is also
+    mapped in the kernel's page table.
+
+    Once running, the kernel should unmap that page so that it can have the full virtual
+    address space to itself. Therefore it needs to know the virtual address of the page
+    that it will unmap.
+    */
+    pub switch_to_kernel_page_address: u64,
+
+    /** Number of pages mapped for the pivot function, starting at `switch_to_kernel_page_address`.
+
+    Usually 1, but the function can cross a page boundary.
+    */
+    pub switch_to_kernel_page_count: u64,
+
+    /** I/O port for a PC16500D serial device.
+
+    The bootloader uses UEFI's PCI protocols to discover a serial device so that I don't
+    have to reimplement PCI handling in the kernel (for now).
+    */
+    pub serial_device_port: u16,
+
+    /** *Physical* address of a memory-mapped PC16500D serial device, or 0 when the device is
+    accessed via `serial_device_port`.
+
+    The bootloader identity-maps the device's registers, so this is also their virtual address.
+    */
+    pub serial_device_mmio_address: u64,
+
+    /// Address of the writable region the bootloader mapped for the kernel's heap.
+    pub heap_address: u64,
+
+    /// Size of the heap region, in bytes.
+    pub heap_size: u64,
+
+    /// Address of the memory map's first [`MemoryRegion`].
+    pub memory_map_address: u64,
+
+    /// Number of entries in the memory map.
+    pub memory_map_len: u64,
+
+    /// The linear framebuffer. Its `address` is 0 when there's no usable framebuffer.
+    pub framebuffer: FramebufferInfo,
+
+    /// *Physical* address of the ACPI RSDP, or 0 when the firmware didn't provide one.
+    pub acpi_rsdp_address: u64,
+
+    /** *Physical* address of the local APIC's registers, or 0 when there's no enabled local
+    APIC.
+
+    The bootloader identity-maps the registers uncached, so this is also their virtual address.
+    */
+    pub local_apic_address: u64,
+
+    /// Address of the kernel's UTF-8 command line. See [`BootInfo::command_line`].
+    pub command_line_address: u64,
+
+    /// Length of the command line, in bytes. 0 when there's no command line.
+    pub command_line_len: u64,
+}
+
+/** The kernel entrypoint's signature, which the bootloader calls and the kernel defines.
+
+The only argument is the [`BootInfo`]'s virtual address in the kernel's address space. The
+System V ABI passes it in `rdi`, so it survives the bootloader switching to the kernel's stack.
+The bootloader maps the `BootInfo` for the lifetime of the kernel, hence `'static`.
+
+Both sides name this type instead of spelling out the signature, so changing it on one side is
+a type error on the other.
+*/
+pub type KernelEntrypoint = extern "sysv64" fn(&'static BootInfo) -> !;
+
+// The argument has to fit in a single register.
+const _: () = assert!(core::mem::size_of::<&BootInfo>() == 8);
+
+// The bootloader puts the `BootInfo` at the start of a page. Keep it within that page, so
+// that it can't straddle a mapped and an unmapped page.
+const _: () = assert!(core::mem::size_of::<BootInfo>() <= 4096);
+const _: () = assert!(core::mem::align_of::<BootInfo>() == 8);
+
+impl BootInfo {
+    /** The physical memory map, as it was when the bootloader exited UEFI boot services.
+
+    # Safety
+
+    `memory_map_address` and `memory_map_len` must describe a valid array of [`MemoryRegion`]s
+    in the current address space.
+    */
+    pub unsafe fn memory_map(&self) -> &[MemoryRegion] {
+        core::slice::from_raw_parts(
+            self.memory_map_address as *const MemoryRegion,
+            self.memory_map_len as usize,
+        )
+    }
+
+    pub fn framebuffer(&self) -> Option<&FramebufferInfo> {
+        if self.framebuffer.address == 0 {
+            None
+        } else {
+            Some(&self.framebuffer)
+        }
+    }
+
+    pub fn serial_device(&self) -> SerialDevice {
+        if self.serial_device_mmio_address == 0 {
+            SerialDevice::Io(self.serial_device_port)
+        } else {
+            SerialDevice::Mmio(self.serial_device_mmio_address)
+        }
+    }
+
+    pub fn acpi_rsdp_address(&self) -> Option<u64> {
+        if self.acpi_rsdp_address == 0 {
+            None
+        } else {
+            Some(self.acpi_rsdp_address)
+        }
+    }
+
+    /** The options the kernel was booted with, or `""` when there aren't any. See
+    [`command_line_options`].
+
+    # Safety
+
+    `command_line_address` and `command_line_len` must describe a valid string in the current
+    address space, unless `command_line_len` is 0.
+    */
+    pub unsafe fn command_line(&self) -> &str {
+        if self.command_line_len == 0 {
+            return "";
+        }
+
+        let bytes = core::slice::from_raw_parts(
+            self.command_line_address as *const u8,
+            self.command_line_len as usize,
+        );
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+/** Split a kernel command line into options.
+
+Options are separated by whitespace. An option is either `key=value`, which is returned as
+`(key, Some(value))`, or a bare flag, which is returned as `(flag, None)`. Only the first `=`
+separates the key from the value.
+
+## Example
+
+```rust
+# use common::boot_info::command_line_options;
+let options: Vec<_> = command_line_options("  log_level=debug no_framebuffer\ttest=a=b ").collect();
+assert_eq!(
+    options,
+    [
+        ("log_level", Some("debug")),
+        ("no_framebuffer", None),
+        ("test", Some("a=b")),
+    ]
+);
+
+assert_eq!(command_line_options("").count(), 0);
+assert_eq!(command_line_options("empty=").collect::<Vec<_>>(), [("empty", Some(""))]);
+```
+*/
+pub fn command_line_options(command_line: &str) -> impl Iterator<Item = (&str, Option<&str>)> + '_ {
+    command_line
+        .split_whitespace()
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        })
+}
+
+/// How to reach a PC16500D serial device's registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialDevice {
+    /// The registers are I/O ports, starting at this port.
+    Io(u16),
+
+    /// The registers are bytes of memory, starting at this address.
+    Mmio(u64),
+}
+
+/// A range of physical memory.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub kind: MemoryRegionKind,
+
+    /// 4KiB aligned physical address of the region's first page.
+    pub physical_start: u64,
+
+    /// Size of the region, in 4KiB pages.
+    pub page_count: u64,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Free memory that the kernel can allocate.
+    Usable,
+
+    /** Memory allocated by the bootloader.
+
+    This includes the kernel's segments, stack and heap, its page tables, the bootloader's code
+    (which contains the page table pivot function), and the [`BootInfo`] itself.
+    */
+    Bootloader,
+
+    /// Holds ACPI tables. Becomes usable once the kernel is finished with the tables.
+    AcpiReclaimable,
+
+    /// Must not be used (firmware runtime services, ACPI NVS, memory-mapped I/O, faulty memory, etc.).
+    Reserved,
+}
+
+/// A linear framebuffer.
+#[repr(C)]
+pub struct FramebufferInfo {
+    pub address: u64,
+
+    /// Size of the framebuffer, in bytes.
+    pub size: u64,
+
+    /// Width of the visible area, in pixels.
+    pub width: u32,
+
+    /// Height of the visible area, in pixels.
+    pub height: u32,
+
+    /// Pixels per scan line. May be larger than `width`.
+    pub stride: u32,
+
+    pub pixel_format: PixelFormat,
+}
+
+/// The layout of a 32-bit pixel.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Byte 0 is red, byte 1 is green, byte 2 is blue, byte 3 is reserved.
+    Rgb,
+
+    /// Byte 0 is blue, byte 1 is green, byte 2 is red, byte 3 is reserved.
+    Bgr,
+}
+
+/** Merge runs of physically contiguous regions of the same kind.
+
+`regions` yields `(kind, physical_start, page_count)` and must be sorted by `physical_start`.
+Regions are merged when one ends (at `physical_start + page_count * page_size`) exactly where
+the next one starts. This works for any kind of region: [`MemoryRegion`]s, or the bootloader's
+UEFI memory descriptors.
+
+## Example
+
+```rust
+# use common::boot_info::{coalesce_regions, MemoryRegionKind::*};
+let regions = [
+    (Usable, 0x0, 2),
+    (Usable, 0x2000, 1),
+    (Bootloader, 0x3000, 1),
+    // Not contiguous with the previous region.
+    (Bootloader, 0x8000, 1),
+    (Usable, 0x9000, 1),
+    (Usable, 0xa000, 3),
+];
+assert_eq!(
+    coalesce_regions(4096, regions).collect::<Vec<_>>(),
+    [
+        (Usable, 0x0, 3),
+        (Bootloader, 0x3000, 1),
+        (Bootloader, 0x8000, 1),
+        (Usable, 0x9000, 4),
+    ]
+);
+```
+*/
+pub fn coalesce_regions<K: PartialEq, I: IntoIterator<Item = (K, u64, u64)>>(
+    page_size: u64,
+    regions: I,
+) -> CoalesceRegions<K, I::IntoIter> {
+    CoalesceRegions {
+        page_size,
+        regions: regions.into_iter(),
+        next: None,
+    }
+}
+
+/// See [`coalesce_regions`].
+pub struct CoalesceRegions<K, I> {
+    page_size: u64,
+    regions: I,
+
+    /// A region that was read but not merged into the previous one.
+    next: Option<(K, u64, u64)>,
+}
+
+impl<K: PartialEq, I: Iterator<Item = (K, u64, u64)>> Iterator for CoalesceRegions<K, I> {
+    type Item = (K, u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (kind, physical_start, mut page_count) =
+            self.next.take().or_else(|| self.regions.next())?;
+
+        for region in self.regions.by_ref() {
+            let end = physical_start + page_count * self.page_size;
+            if region.0 == kind && region.1 == end {
+                page_count += region.2;
+            } else {
+                self.next = Some(region);
+                break;
+            }
+        }
+
+        Some((kind, physical_start, page_count))
+    }
+}
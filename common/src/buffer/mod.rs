@@ -0,0 +1,98 @@
+/*! Retrying functions that fill a caller-provided buffer.
+
+Functions like UEFI's `File::get_info` fail when the buffer they're given is too small, and
+usually report the size they need. [`grow`] retries them with larger buffers until they succeed.
+It doesn't allocate the buffers itself, so the bootloader can use UEFI's pool allocator.
+*/
+
+/// Why an attempt to fill a buffer failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AttemptError<E> {
+    /// The buffer is too small. The function may have reported the size it needs.
+    TooSmall { required_size: Option<usize> },
+
+    /// Any other error. [`grow`] stops retrying and returns it.
+    Other(E),
+}
+
+/** The buffer size to try after a buffer turned out to be too small.
+
+The size that the function reported is used when there is one. Otherwise the buffer is doubled,
+so that [`grow`] only needs a logarithmic number of attempts.
+*/
+pub const fn next_buffer_size(current_size: usize, required_size: Option<usize>) -> usize {
+    match required_size {
+        Some(required_size) if required_size > current_size => required_size,
+        _ => current_size * 2,
+    }
+}
+
+const _: () = assert!(next_buffer_size(80, Some(132)) == 132);
+const _: () = assert!(next_buffer_size(80, None) == 160);
+const _: () = assert!(next_buffer_size(80, Some(80)) == 160);
+
+/** Call `attempt` with a buffer size, starting at `initial_size`, until it stops failing with
+[`AttemptError::TooSmall`]. See [`next_buffer_size`].
+
+`attempt` is responsible for allocating a buffer of the size it's given, and for freeing it
+when it fails.
+
+## Example
+
+```rust
+# use common::buffer::{grow, AttemptError};
+// A function that needs 100 bytes, and says so.
+let mut sizes = Vec::new();
+let result: Result<usize, ()> = grow(10, |size| {
+    sizes.push(size);
+    if size < 100 {
+        Err(AttemptError::TooSmall { required_size: Some(100) })
+    } else {
+        Ok(size)
+    }
+});
+assert_eq!(result, Ok(100));
+assert_eq!(sizes, [10, 100]);
+
+// When the function doesn't say how much it needs, the buffer doubles.
+let mut sizes = Vec::new();
+let result: Result<usize, ()> = grow(10, |size| {
+    sizes.push(size);
+    if size < 100 {
+        Err(AttemptError::TooSmall { required_size: None })
+    } else {
+        Ok(size)
+    }
+});
+assert_eq!(result, Ok(160));
+assert_eq!(sizes, [10, 20, 40, 80, 160]);
+
+// Any other error ends the retries.
+let mut sizes = Vec::new();
+let result: Result<usize, &str> = grow(10, |size| {
+    sizes.push(size);
+    if size < 20 {
+        Err(AttemptError::TooSmall { required_size: None })
+    } else {
+        Err(AttemptError::Other("device error"))
+    }
+});
+assert_eq!(result, Err("device error"));
+assert_eq!(sizes, [10, 20]);
+```
+*/
+pub fn grow<T, E>(
+    initial_size: usize,
+    mut attempt: impl FnMut(usize) -> Result<T, AttemptError<E>>,
+) -> Result<T, E> {
+    let mut size = initial_size;
+    loop {
+        match attempt(size) {
+            Ok(value) => return Ok(value),
+            Err(AttemptError::TooSmall { required_size }) => {
+                size = next_buffer_size(size, required_size);
+            }
+            Err(AttemptError::Other(error)) => return Err(error),
+        }
+    }
+}
@@ -1 +1,318 @@
 pub mod v0;
+pub mod v1;
+
+use crate::paging::PageMap;
+
+/** Whether two segments' pages overlap.
+
+Segments are mapped a page at a time, so a segment that's `size` bytes long at the 4KiB aligned
+`load_address` occupies `ceil(size / 4096)` pages. Empty segments occupy no pages.
+*/
+fn pages_overlap(a_load_address: u64, a_size: u64, b_load_address: u64, b_size: u64) -> bool {
+    let pages_end = |load_address: u64, size: u64| {
+        load_address.saturating_add(size).saturating_add(0xfff) & !0xfff
+    };
+
+    a_size != 0
+        && b_size != 0
+        && a_load_address < pages_end(b_load_address, b_size)
+        && b_load_address < pages_end(a_load_address, a_size)
+}
+
+/** The length of the magic bytes and version field that start every executable.
+
+See [`v0::write_magic_and_version`] and [`v1::write_magic_and_version`].
+*/
+pub const MAGIC_AND_VERSION_SIZE: usize = 10;
+
+/// Write the magic bytes and `version` to the start of `buffer`.
+fn write_magic_and_version(buffer: &mut [u8], version: u16) {
+    buffer[0..8].copy_from_slice(&v0::MAGIC_BYTES);
+    buffer[8..MAGIC_AND_VERSION_SIZE].copy_from_slice(&version.to_le_bytes());
+}
+
+/// Check an executable's magic bytes, and read its version field.
+fn read_version(buffer: &[u8]) -> Result<u16, Error> {
+    let (Some(magic_bytes), Some(version)) = (buffer.get(0..8), buffer.get(8..10)) else {
+        return Err(Error::Length {
+            actual_length: buffer.len(),
+        });
+    };
+
+    if magic_bytes != v0::MAGIC_BYTES {
+        return Err(Error::MagicBytes {
+            expected: v0::MAGIC_BYTES,
+            actual: magic_bytes.try_into().unwrap(),
+        });
+    }
+
+    Ok(u16::from_le_bytes(version.try_into().unwrap()))
+}
+
+/** The version of the executable in `buffer`, so that it can be given to the right parser.
+
+`None` when the buffer doesn't start with the magic bytes. The version isn't checked, so this
+can return versions that [`Exe::parse`] doesn't support.
+
+## Example
+
+```rust
+# use common::exe::{detect_version, v0, v1, MAGIC_AND_VERSION_SIZE};
+let mut image = [0; MAGIC_AND_VERSION_SIZE];
+v0::write_magic_and_version(&mut image);
+assert_eq!(detect_version(&image), Some(v0::VERSION));
+assert_eq!(detect_version(&image), Some(0));
+
+v1::write_magic_and_version(&mut image);
+assert_eq!(detect_version(&image), Some(v1::VERSION));
+assert_eq!(detect_version(&image), Some(1));
+
+image[0] = b'L';
+assert_eq!(detect_version(&image), None);
+assert_eq!(detect_version(&image[..9]), None);
+```
+*/
+pub fn detect_version(buffer: &[u8]) -> Option<u16> {
+    read_version(buffer).ok()
+}
+
+/** An executable of any supported version.
+
+Both versions start with the same magic bytes and a 2 byte version field, so the version can be
+read before the rest of the header is parsed.
+*/
+pub enum Exe<'a> {
+    V0(v0::Exe<'a>),
+    V1(v1::Exe<'a>),
+}
+
+impl<'a> Exe<'a> {
+    /// Create an [`Exe`] view on a buffer, using the parser for the buffer's version.
+    pub fn parse(buffer: &'a [u8]) -> Result<Exe<'a>, Error> {
+        let version = read_version(buffer)?;
+
+        match version {
+            v0::VERSION => v0::Exe::parse(buffer).map(Exe::V0).map_err(Error::V0),
+            v1::VERSION => v1::Exe::parse(buffer).map(Exe::V1).map_err(Error::V1),
+            _ => Err(Error::Version { actual: version }),
+        }
+    }
+
+    /** Every segment's info and file data.
+
+    A v0 executable's segments are its code (`RX`), rodata (`R`) and rwdata (`RW`) segments,
+    with a `mem_size` equal to their `size`.
+    */
+    pub fn segments(&self) -> Segments<'_> {
+        match self {
+            Exe::V0(exe) => {
+                let segment = |info: v0::SegmentInfo, flags, data| {
+                    (
+                        v1::SegmentInfo {
+                            start: info.start,
+                            size: info.size,
+                            load_address: info.load_address,
+                            mem_size: info.size,
+                            flags,
+                        },
+                        data,
+                    )
+                };
+                Segments::V0(
+                    [
+                        segment(exe.code_info(), v1::SegmentFlags::RX, exe.code()),
+                        segment(exe.rodata_info(), v1::SegmentFlags::R, exe.rodata()),
+                        segment(exe.rwdata_info(), v1::SegmentFlags::RW, exe.rwdata()),
+                    ]
+                    .into_iter(),
+                )
+            }
+            Exe::V1(exe) => Segments::V1(exe, 0),
+        }
+    }
+
+    /// The program's entrypoint, if it has one.
+    pub fn entrypoint(&self) -> Option<u64> {
+        match self {
+            Exe::V0(exe) => Some(exe.code_info().load_address),
+            Exe::V1(exe) => exe.entrypoint(),
+        }
+    }
+}
+
+/// See [`Exe::segments`].
+pub enum Segments<'a> {
+    V0(core::array::IntoIter<(v1::SegmentInfo, &'a [u8]), 3>),
+
+    /// The executable, and the index of the next segment.
+    V1(&'a v1::Exe<'a>, u16),
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = (v1::SegmentInfo, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Segments::V0(segments) => segments.next(),
+            Segments::V1(exe, index) => {
+                if *index < exe.segment_count() {
+                    let segment = exe.segment(*index);
+                    *index += 1;
+                    Some(segment)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/** Load every segment of `exe` into freshly allocated pages, and map them in `page_map` with
+the segment's permissions (see [`v1::SegmentFlags::page_map_flags`]).
+
+Each segment's memory past the end of its file data (e.g. `.bss`) is zeroed.
+
+Panics if two segments' pages overlap. The parsers already reject such executables, so this
+only catches a parser bug.
+
+Like [`PageMap`], this assumes that physical memory is identity-mapped: the segment data is
+copied through the physical addresses that `allocate_pages` returns.
+
+## Example
+
+```rust
+# use common::exe::{map_all_segments, Exe};
+# use common::exe::v1::{Header, SegmentFlags, SegmentInfo, MAGIC_BYTES, VERSION};
+# use common::paging::{PageMap, PageMapFlags};
+# let mut page_pool = common::paging::test_support::PagePool::new();
+# let mut allocate_pages = |count: usize| page_pool.allocate(count);
+let segments = [
+    (0x1000, 0x1800, SegmentFlags::RX, vec![0x90; 0x1800]),
+    (0x4000, 0x1000, SegmentFlags::R, vec![1, 2, 3]),
+    (0x6000, 0x3000, SegmentFlags::RW, vec![]),
+];
+
+let mut image = Vec::new();
+image.extend_from_slice(&MAGIC_BYTES);
+image.extend_from_slice(&VERSION.to_le_bytes());
+image.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+let mut start = Header::encoded_size(segments.len());
+for (load_address, mem_size, flags, data) in &segments {
+    let info = SegmentInfo {
+        start: start as u64,
+        size: data.len() as u64,
+        load_address: *load_address,
+        mem_size: *mem_size,
+        flags: *flags,
+    };
+    image.extend_from_slice(&info.to_bytes());
+    start += data.len();
+}
+for (_, _, _, data) in &segments {
+    image.extend_from_slice(data);
+}
+
+let exe = Exe::parse(&image).unwrap();
+let mut page_map = PageMap::new(&mut allocate_pages);
+map_all_segments(&mut allocate_pages, &mut page_map, &exe);
+
+for (load_address, mem_size, flags, _) in &segments {
+    for address in (*load_address..load_address + mem_size).step_by(0x1000) {
+        assert!(page_map.translate(address).is_some());
+        assert_eq!(page_map.flags(address), Some(flags.page_map_flags()));
+    }
+}
+assert_eq!(page_map.flags(0x1000), Some(PageMapFlags::X));
+assert_eq!(page_map.flags(0x4000), Some(PageMapFlags::default()));
+assert_eq!(page_map.flags(0x8000), Some(PageMapFlags::W));
+assert_eq!(page_map.translate(0x3000), None);
+assert_eq!(page_map.translate(0x9000), None);
+
+// The file data is copied, and the rest of the segment is zeroed.
+let read = |address: u64| unsafe { *(page_map.translate(address).unwrap() as *const u8) };
+assert_eq!(read(0x17ff), 0x90);
+assert_eq!(read(0x4002), 3);
+assert_eq!(read(0x4003), 0);
+assert_eq!(read(0x8fff), 0);
+assert_eq!(page_map.check_invariants(52), Ok(()));
+```
+*/
+pub fn map_all_segments(
+    allocate_pages: &mut dyn FnMut(usize) -> u64,
+    page_map: &mut PageMap,
+    exe: &Exe,
+) {
+    for (a, (a_info, _)) in exe.segments().enumerate() {
+        for (b, (b_info, _)) in exe.segments().enumerate().skip(a + 1) {
+            assert!(
+                !pages_overlap(
+                    a_info.load_address,
+                    a_info.mem_size,
+                    b_info.load_address,
+                    b_info.mem_size,
+                ),
+                "segments {} and {} overlap",
+                a,
+                b
+            );
+        }
+    }
+
+    for (segment_info, segment_data) in exe.segments() {
+        map_segment(allocate_pages, page_map, segment_info, segment_data);
+    }
+}
+
+fn map_segment(
+    allocate_pages: &mut dyn FnMut(usize) -> u64,
+    page_map: &mut PageMap,
+    segment_info: v1::SegmentInfo,
+    segment_data: &[u8],
+) {
+    let flags = segment_info.flags.page_map_flags();
+
+    let segment_pages = (segment_info.mem_size as usize).div_ceil(PageMap::PAGE_SIZE);
+
+    let base_virtual_addr: u64 = segment_info.load_address;
+    let base_physical_addr: u64 = allocate_pages(segment_pages);
+
+    let mut offset: u64 = 0;
+    for _page in 0..segment_pages {
+        let page_virtual_addr = base_virtual_addr + offset;
+        let page_physical_addr = base_physical_addr + offset;
+
+        let page_buffer: &mut [u8] = unsafe {
+            core::slice::from_raw_parts_mut(page_physical_addr as *mut u8, PageMap::PAGE_SIZE)
+        };
+
+        for (i, byte) in page_buffer.iter_mut().enumerate() {
+            *byte = segment_data.get(offset as usize + i).copied().unwrap_or(0);
+        }
+
+        page_map.set(allocate_pages, page_virtual_addr, page_physical_addr, flags);
+
+        offset += PageMap::PAGE_SIZE as u64;
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Buffer is too small to contain a version number.
+    Length {
+        actual_length: usize,
+    },
+
+    /// Executable's magic bytes are incorrect.
+    MagicBytes {
+        expected: [u8; 8],
+        actual: [u8; 8],
+    },
+
+    /// Executable's version isn't supported.
+    Version {
+        actual: u16,
+    },
+
+    V0(v0::Error),
+    V1(v1::Error),
+}
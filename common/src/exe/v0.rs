@@ -2,6 +2,15 @@
 pub const MAGIC_BYTES: [u8; 8] = [0x6c, 0x65, 0x61, 0x72, 0x6e, 0x2d, 0x6f, 0x73];
 pub const VERSION: u16 = 0;
 
+/** Write the magic bytes and [`VERSION`] to the start of `buffer`, so that a builder can't stamp
+a header with another version's number.
+
+Panics when `buffer` is shorter than [`super::MAGIC_AND_VERSION_SIZE`].
+*/
+pub fn write_magic_and_version(buffer: &mut [u8]) {
+    super::write_magic_and_version(buffer, VERSION)
+}
+
 const VERSION_OFFSET: usize = 8;
 const CODE_INFO_OFFSET: usize = 10;
 const RODATA_INFO_OFFSET: usize = 34;
@@ -13,7 +22,54 @@ pub struct Exe<'a> {
 }
 
 impl<'a> Exe<'a> {
-    /// Create an [`Exe`] view on a buffer, if the buffer is valid.
+    /** Create an [`Exe`] view on a buffer, if the buffer is valid.
+
+    Every segment's load address must be 4KiB aligned, and no two segments can share a page.
+
+    ## Example
+
+    ```rust
+    # use common::exe::v0::{Error, Exe, Header, Segment, MAGIC_BYTES, VERSION};
+    fn exe_with_load_addresses(code: u64, rodata: u64, rwdata: u64) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&MAGIC_BYTES);
+        buffer.extend_from_slice(&VERSION.to_le_bytes());
+        for load_address in [code, rodata, rwdata] {
+            let start = Header::ENCODED_SIZE as u64;
+            let size = 0x1800;
+            for field in [start, size, load_address] {
+                buffer.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        buffer.resize(Header::ENCODED_SIZE + 0x1800, 0);
+        buffer
+    }
+
+    assert!(Exe::parse(&exe_with_load_addresses(0x1000, 0x3000, 0x5000)).is_ok());
+
+    // rodata's first page is code's second page.
+    assert!(matches!(
+        Exe::parse(&exe_with_load_addresses(0x1000, 0x2000, 0x5000)),
+        Err(Error::SegmentVirtualOverlap {
+            a: Segment::Code,
+            b: Segment::Rodata
+        })
+    ));
+    assert!(matches!(
+        Exe::parse(&exe_with_load_addresses(0x1000, 0x3000, 0x1000)),
+        Err(Error::SegmentVirtualOverlap {
+            a: Segment::Code,
+            b: Segment::Rwdata
+        })
+    ));
+    assert!(matches!(
+        Exe::parse(&exe_with_load_addresses(0x1000, 0x3800, 0x5000)),
+        Err(Error::SegmentAlignment {
+            segment: Segment::Rodata
+        })
+    ));
+    ```
+    */
     pub fn parse(buffer: &'a [u8]) -> Result<Exe<'a>, Error> {
         if buffer.len() < Header::ENCODED_SIZE {
             return Err(Error::Length {
@@ -38,6 +94,31 @@ impl<'a> Exe<'a> {
             });
         }
 
+        let segments = [
+            (Segment::Code, exe.code_info()),
+            (Segment::Rodata, exe.rodata_info()),
+            (Segment::Rwdata, exe.rwdata_info()),
+        ];
+
+        for (segment, info) in &segments {
+            if info.load_address & 0xfff != 0 {
+                return Err(Error::SegmentAlignment { segment: *segment });
+            }
+        }
+
+        for (index, (a, a_info)) in segments.iter().enumerate() {
+            for (b, b_info) in &segments[index + 1..] {
+                if super::pages_overlap(
+                    a_info.load_address,
+                    a_info.size,
+                    b_info.load_address,
+                    b_info.size,
+                ) {
+                    return Err(Error::SegmentVirtualOverlap { a: *a, b: *b });
+                }
+            }
+        }
+
         Ok(exe)
     }
 
@@ -130,6 +211,20 @@ pub enum Error {
 
     /// Executable's version iis incorrect.
     Version { expected: u16, actual: u16 },
+
+    /// A segment's load address isn't 4KiB aligned.
+    SegmentAlignment { segment: Segment },
+
+    /// Two segments would be loaded into the same page.
+    SegmentVirtualOverlap { a: Segment, b: Segment },
+}
+
+/// The segments of a v0 executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Code,
+    Rodata,
+    Rwdata,
 }
 
 pub struct Header {
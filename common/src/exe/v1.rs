@@ -0,0 +1,381 @@
+/*! Version 1 of the executable format: a header followed by any number of segments.
+
+```text
+offset  size                 field
+0       8                    magic bytes (`learn-os`)
+8       2                    version (1)
+10      2                    segment count (n)
+12      n * 40               segment infos (see `SegmentInfo`)
+```
+
+Unlike [v0](super::v0), each segment carries its own permissions, and can be larger in memory
+than in the file (e.g. for `.bss`).
+
+The program's entrypoint is the load address of the first executable segment.
+*/
+
+use crate::paging::PageMapFlags;
+
+pub use super::v0::MAGIC_BYTES;
+pub const VERSION: u16 = 1;
+
+/** Write the magic bytes and [`VERSION`] to the start of `buffer`, so that a builder can't stamp
+a header with another version's number.
+
+Panics when `buffer` is shorter than [`super::MAGIC_AND_VERSION_SIZE`].
+*/
+pub fn write_magic_and_version(buffer: &mut [u8]) {
+    super::write_magic_and_version(buffer, VERSION)
+}
+
+const VERSION_OFFSET: usize = 8;
+const SEGMENT_COUNT_OFFSET: usize = 10;
+const SEGMENT_INFOS_OFFSET: usize = 12;
+
+pub struct Exe<'a> {
+    /* Safety: the buffer must be at least `Header::encoded_size(segment_count)` bytes long,
+    and every segment's data must be in bounds.
+    */
+    buffer: &'a [u8],
+}
+
+impl<'a> Exe<'a> {
+    /** Create an [`Exe`] view on a buffer, if the buffer is valid.
+
+    ## Example
+
+    Build an image with 1, 3, and 5 segments, then read the segments back:
+
+    ```rust
+    # use common::exe::v1::{Error, Exe, Header, SegmentFlags, SegmentInfo, MAGIC_BYTES, VERSION};
+    for segment_count in [1, 3, 5] {
+        let header_size = Header::encoded_size(segment_count);
+        let datas: Vec<Vec<u8>> = (0..segment_count)
+            .map(|index| vec![index as u8; 10 + index])
+            .collect();
+
+        let mut infos = Vec::new();
+        let mut start = header_size;
+        for (index, data) in datas.iter().enumerate() {
+            infos.push(SegmentInfo {
+                start: start as u64,
+                size: data.len() as u64,
+                load_address: 0x1000 * (index as u64 + 1),
+                mem_size: 0x1000,
+                flags: if index == 0 { SegmentFlags::RX } else { SegmentFlags::RW },
+            });
+            start += data.len();
+        }
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&MAGIC_BYTES);
+        image.extend_from_slice(&VERSION.to_le_bytes());
+        image.extend_from_slice(&(segment_count as u16).to_le_bytes());
+        for info in &infos {
+            image.extend_from_slice(&info.to_bytes());
+        }
+        for data in &datas {
+            image.extend_from_slice(data);
+        }
+
+        let exe = Exe::parse(&image).unwrap();
+        assert_eq!(exe.segment_count() as usize, segment_count);
+        assert_eq!(exe.entrypoint(), Some(0x1000));
+
+        let segments: Vec<_> = exe.segments().collect();
+        assert_eq!(segments.len(), segment_count);
+        for ((info, data), (expected_info, expected_data)) in
+            segments.iter().zip(infos.iter().zip(datas.iter()))
+        {
+            assert_eq!(info, expected_info);
+            assert_eq!(data, expected_data);
+        }
+    }
+
+    // Segments can't share a page.
+    let infos = [(0x1000, 0x1800), (0x2000, 0x10)].map(|(load_address, mem_size)| SegmentInfo {
+        start: Header::encoded_size(2) as u64,
+        size: 0,
+        load_address,
+        mem_size,
+        flags: SegmentFlags::RX,
+    });
+    let mut image = Vec::new();
+    image.extend_from_slice(&MAGIC_BYTES);
+    image.extend_from_slice(&VERSION.to_le_bytes());
+    image.extend_from_slice(&2u16.to_le_bytes());
+    for info in &infos {
+        image.extend_from_slice(&info.to_bytes());
+    }
+    assert!(matches!(
+        Exe::parse(&image),
+        Err(Error::SegmentVirtualOverlap { a: 0, b: 1 })
+    ));
+    ```
+    */
+    pub fn parse(buffer: &'a [u8]) -> Result<Exe<'a>, Error> {
+        let minimum_length = Header::encoded_size(0);
+        if buffer.len() < minimum_length {
+            return Err(Error::Length {
+                minimum_length,
+                actual_length: buffer.len(),
+            });
+        }
+
+        let exe = Exe { buffer };
+
+        if exe.magic_bytes() != MAGIC_BYTES {
+            return Err(Error::MagicBytes {
+                expected: MAGIC_BYTES,
+                actual: exe.magic_bytes(),
+            });
+        }
+
+        if exe.version() != VERSION {
+            return Err(Error::Version {
+                expected: VERSION,
+                actual: exe.version(),
+            });
+        }
+
+        let minimum_length = Header::encoded_size(exe.segment_count() as usize);
+        if buffer.len() < minimum_length {
+            return Err(Error::Length {
+                minimum_length,
+                actual_length: buffer.len(),
+            });
+        }
+
+        for index in 0..exe.segment_count() {
+            let info = exe.segment_info(index);
+
+            let in_bounds = info
+                .start
+                .checked_add(info.size)
+                .is_some_and(|end| end <= buffer.len() as u64);
+            if !in_bounds {
+                return Err(Error::SegmentBounds { index });
+            }
+
+            if info.mem_size < info.size {
+                return Err(Error::SegmentMemorySize { index });
+            }
+
+            if info.load_address & 0xfff != 0 {
+                return Err(Error::SegmentAlignment { index });
+            }
+        }
+
+        for a in 0..exe.segment_count() {
+            let a_info = exe.segment_info(a);
+            for b in (a + 1)..exe.segment_count() {
+                let b_info = exe.segment_info(b);
+                if super::pages_overlap(
+                    a_info.load_address,
+                    a_info.mem_size,
+                    b_info.load_address,
+                    b_info.mem_size,
+                ) {
+                    return Err(Error::SegmentVirtualOverlap { a, b });
+                }
+            }
+        }
+
+        Ok(exe)
+    }
+
+    /// Read the magic bytes from the program header.
+    pub fn magic_bytes(&self) -> [u8; 8] {
+        self.buffer[0..8].try_into().unwrap()
+    }
+
+    /// Read the version from the program header.
+    pub fn version(&self) -> u16 {
+        u16::from_le_bytes(
+            self.buffer[VERSION_OFFSET..(VERSION_OFFSET + 2)]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /// Read the number of segments from the program header.
+    pub fn segment_count(&self) -> u16 {
+        u16::from_le_bytes(
+            self.buffer[SEGMENT_COUNT_OFFSET..(SEGMENT_COUNT_OFFSET + 2)]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /** Read a segment's info from the program header.
+
+    Panics if `index` isn't less than [`Exe::segment_count`].
+    */
+    pub fn segment_info(&self, index: u16) -> SegmentInfo {
+        assert!(index < self.segment_count(), "segment index out of range");
+
+        let offset = SEGMENT_INFOS_OFFSET + index as usize * SegmentInfo::ENCODED_SIZE;
+        SegmentInfo::from(
+            <[u8; SegmentInfo::ENCODED_SIZE]>::try_from(
+                &self.buffer[offset..(offset + SegmentInfo::ENCODED_SIZE)],
+            )
+            .unwrap(),
+        )
+    }
+
+    /** Read a segment's info, and get its file data.
+
+    Panics if `index` isn't less than [`Exe::segment_count`].
+    */
+    pub fn segment(&self, index: u16) -> (SegmentInfo, &'a [u8]) {
+        let info = self.segment_info(index);
+        let start = info.start as usize;
+        let size = info.size as usize;
+        let buffer: &'a [u8] = self.buffer;
+        (info, &buffer[start..(start + size)])
+    }
+
+    /// Every segment's info and file data, in header order.
+    pub fn segments(&self) -> impl Iterator<Item = (SegmentInfo, &'a [u8])> + '_ {
+        (0..self.segment_count()).map(|index| self.segment(index))
+    }
+
+    /// The load address of the first executable segment, if there is one.
+    pub fn entrypoint(&self) -> Option<u64> {
+        self.segments()
+            .find(|(info, _)| info.flags.contains(SegmentFlags::X))
+            .map(|(info, _)| info.load_address)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Buffer is smaller than the header.
+    Length {
+        minimum_length: usize,
+        actual_length: usize,
+    },
+
+    /// Executable's magic bytes are incorrect.
+    MagicBytes { expected: [u8; 8], actual: [u8; 8] },
+
+    /// Executable's version is incorrect.
+    Version { expected: u16, actual: u16 },
+
+    /// A segment's data extends past the end of the buffer.
+    SegmentBounds { index: u16 },
+
+    /// A segment's `mem_size` is smaller than its `size`.
+    SegmentMemorySize { index: u16 },
+
+    /// A segment's load address isn't 4KiB aligned.
+    SegmentAlignment { index: u16 },
+
+    /// Two segments would be loaded into the same page.
+    SegmentVirtualOverlap { a: u16, b: u16 },
+}
+
+pub struct Header;
+
+impl Header {
+    /// The size of a header with `segment_count` segments on disk.
+    pub const fn encoded_size(segment_count: usize) -> usize {
+        // magic bytes
+        8 +
+        // version
+        2 +
+        // segment count
+        2 +
+        // segment infos
+        segment_count * SegmentInfo::ENCODED_SIZE
+    }
+}
+
+/// Segment permissions. The bits match ELF's `PF_X`, `PF_W` and `PF_R`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFlags(pub u32);
+
+impl SegmentFlags {
+    pub const X: Self = SegmentFlags(0b001);
+    pub const W: Self = SegmentFlags(0b010);
+    pub const R: Self = SegmentFlags(0b100);
+
+    pub const RX: Self = SegmentFlags(Self::R.0 | Self::X.0);
+    pub const RW: Self = SegmentFlags(Self::R.0 | Self::W.0);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /** The page permissions for a segment with these flags.
+
+    Every mapped page is readable, so `R` doesn't change anything.
+    */
+    pub fn page_map_flags(self) -> PageMapFlags {
+        let mut flags = PageMapFlags::default();
+        if self.contains(Self::W) {
+            flags = flags | PageMapFlags::W;
+        }
+        if self.contains(Self::X) {
+            flags = flags | PageMapFlags::X;
+        }
+        flags
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Byte at which the segment's data begins, relative to the start of the binary.
+    pub start: u64,
+
+    /// Size of the segment's data in the file, in bytes.
+    pub size: u64,
+
+    /** Virtual address at which the segment should be loaded.
+
+    Must be 4KiB aligned.
+    */
+    pub load_address: u64,
+
+    /** Size of the segment in memory, in bytes.
+
+    At least `size`. The memory after the segment's data is zeroed.
+    */
+    pub mem_size: u64,
+
+    pub flags: SegmentFlags,
+}
+
+impl SegmentInfo {
+    /** The size of a segment info entry on disk.
+
+    `start`, `size`, `load_address` and `mem_size` are 8 bytes each. `flags` is 4 bytes,
+    followed by 4 reserved bytes that must be 0.
+    */
+    pub const ENCODED_SIZE: usize = 8 + 8 + 8 + 8 + 4 + 4;
+
+    /// Encode the segment info for a program header.
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_SIZE] {
+        let mut bytes = [0; Self::ENCODED_SIZE];
+        bytes[0..8].copy_from_slice(&self.start.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.load_address.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.mem_size.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.0.to_le_bytes());
+        bytes
+    }
+}
+
+impl From<[u8; SegmentInfo::ENCODED_SIZE]> for SegmentInfo {
+    fn from(value: [u8; SegmentInfo::ENCODED_SIZE]) -> Self {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(value[offset..offset + 8].try_into().unwrap());
+        SegmentInfo {
+            start: u64_at(0),
+            size: u64_at(8),
+            load_address: u64_at(16),
+            mem_size: u64_at(24),
+            flags: SegmentFlags(u32::from_le_bytes(value[32..36].try_into().unwrap())),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+/*! Physical frame allocation.
+
+[`FrameAllocator`] hands out 4KiB aligned physical pages ("frames") from the regions of
+memory that the firmware reported as usable. It's the kernel's counterpart to UEFI's
+`allocate_pages`, which stops being available after `exit_boot_services`.
+*/
+use crate::paging::PageMap;
+
+const PAGE_SIZE: u64 = PageMap::PAGE_SIZE as u64;
+
+/// A run of contiguous free pages.
+#[derive(Clone, Copy)]
+struct Region {
+    start: u64,
+    page_count: u64,
+}
+
+impl Region {
+    const EMPTY: Region = Region {
+        start: 0,
+        page_count: 0,
+    };
+
+    fn end(&self) -> u64 {
+        self.start + self.page_count * PAGE_SIZE
+    }
+}
+
+/** A first-fit physical frame allocator.
+
+Free memory is tracked as a list of regions, sorted by address. The list lives in a fixed-size
+array so that the allocator can be used before there's a heap. Freed frames are merged with
+their neighbours, so the list only grows when freeing splits up memory.
+
+## Example
+
+```rust
+# use common::frame_alloc::FrameAllocator;
+let mut frame_allocator = FrameAllocator::new([(0x10_0000, 16), (0x40_0000, 256)]).unwrap();
+assert_eq!(frame_allocator.allocate(32), Some(0x40_0000));
+```
+
+Allocations never overlap, and freed frames are reused:
+
+```rust
+# use common::frame_alloc::FrameAllocator;
+let mut frame_allocator = FrameAllocator::new([(0x10_0000, 4), (0x20_0000, 8)]).unwrap();
+
+let mut allocations = Vec::new();
+for count in [3, 1, 5, 2] {
+    let address = frame_allocator.allocate(count).unwrap();
+    allocations.push((address, address + count as u64 * 4096));
+}
+for (i, (start, end)) in allocations.iter().enumerate() {
+    for (other_start, other_end) in &allocations[i + 1..] {
+        assert!(end <= other_start || other_end <= start);
+    }
+}
+assert_eq!(frame_allocator.free_page_count(), 1);
+
+// Exhausted: only 1 page is left.
+assert_eq!(frame_allocator.allocate(2), None);
+assert_eq!(frame_allocator.allocate(1), Some(0x20_7000));
+assert_eq!(frame_allocator.allocate(1), None);
+
+frame_allocator.free(allocations[1].0, 1).unwrap();
+assert_eq!(frame_allocator.allocate(1), Some(allocations[1].0));
+
+// Zero pages can't be allocated, because they'd share an address with the next allocation.
+assert_eq!(frame_allocator.allocate(0), None);
+```
+
+Regions that don't touch each other are tracked separately, up to [`FrameAllocator::MAX_REGIONS`]:
+
+```rust
+# use common::frame_alloc::{FrameAllocator, TooManyRegions};
+let regions = (0..FrameAllocator::MAX_REGIONS as u64).map(|i| (i * 0x2000, 1));
+let mut frame_allocator = FrameAllocator::new(regions).unwrap();
+
+let extra = FrameAllocator::MAX_REGIONS as u64 * 0x2000;
+assert_eq!(
+    frame_allocator.free(extra, 1),
+    Err(TooManyRegions { start: extra, page_count: 1 })
+);
+
+// Filling a gap merges two regions, which always succeeds.
+assert_eq!(frame_allocator.free(0x1000, 1), Ok(()));
+assert_eq!(frame_allocator.free(extra, 1), Ok(()));
+```
+*/
+pub struct FrameAllocator {
+    /// Invariant: the first `region_count` entries are non-empty, disjoint, non-adjacent, and sorted by address.
+    regions: [Region; Self::MAX_REGIONS],
+    region_count: usize,
+}
+
+impl FrameAllocator {
+    /// The maximum number of disjoint free regions that can be tracked.
+    pub const MAX_REGIONS: usize = 128;
+
+    /** Create an allocator from a list of usable regions.
+
+    Each region is a 4KiB aligned physical start address and a page count. Adjacent regions are
+    merged, so a memory map can have more than [`FrameAllocator::MAX_REGIONS`] entries as long
+    as they don't leave too many gaps.
+    */
+    pub fn new(regions: impl IntoIterator<Item = (u64, u64)>) -> Result<Self, TooManyRegions> {
+        let mut frame_allocator = FrameAllocator {
+            regions: [Region::EMPTY; Self::MAX_REGIONS],
+            region_count: 0,
+        };
+
+        for (start, page_count) in regions {
+            frame_allocator.free(start, page_count as usize)?;
+        }
+
+        Ok(frame_allocator)
+    }
+
+    /** Allocate `count` contiguous pages.
+
+    Returns the physical address of the first page, or `None` if `count` is 0 or there's no
+    free run of memory that's large enough.
+    */
+    pub fn allocate(&mut self, count: usize) -> Option<u64> {
+        if count == 0 {
+            return None;
+        }
+        let count = count as u64;
+
+        let index = self.regions[0..self.region_count]
+            .iter()
+            .position(|region| region.page_count >= count)?;
+
+        let region = &mut self.regions[index];
+        let address = region.start;
+        region.start += count * PAGE_SIZE;
+        region.page_count -= count;
+
+        if region.page_count == 0 {
+            self.remove(index);
+        }
+
+        Some(address)
+    }
+
+    /** Return `count` pages starting at `address` to the allocator.
+
+    Fails when the pages don't touch an existing free region and the allocator is already
+    tracking [`FrameAllocator::MAX_REGIONS`] regions. The pages stay allocated in that case.
+
+    Panics if any of the pages are already free.
+    */
+    pub fn free(&mut self, address: u64, count: usize) -> Result<(), TooManyRegions> {
+        assert!(
+            address & 0xfff == 0,
+            "address {:#x} is not 4KiB aligned",
+            address
+        );
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        let freed = Region {
+            start: address,
+            page_count: count as u64,
+        };
+
+        // The index of the first region that starts after the freed region.
+        let index = self.regions[0..self.region_count]
+            .iter()
+            .position(|region| region.start > freed.start)
+            .unwrap_or(self.region_count);
+
+        if index > 0 {
+            let previous = &self.regions[index - 1];
+            assert!(
+                previous.end() <= freed.start,
+                "pages {:#x}-{:#x} are already free",
+                freed.start,
+                freed.end()
+            );
+        }
+
+        if index < self.region_count {
+            let next = &self.regions[index];
+            assert!(
+                freed.end() <= next.start,
+                "pages {:#x}-{:#x} are already free",
+                freed.start,
+                freed.end()
+            );
+        }
+
+        let merges_previous = index > 0 && self.regions[index - 1].end() == freed.start;
+        let merges_next = index < self.region_count && freed.end() == self.regions[index].start;
+
+        match (merges_previous, merges_next) {
+            (true, true) => {
+                let next_page_count = self.regions[index].page_count;
+                self.regions[index - 1].page_count += freed.page_count + next_page_count;
+                self.remove(index);
+            }
+            (true, false) => {
+                self.regions[index - 1].page_count += freed.page_count;
+            }
+            (false, true) => {
+                let next = &mut self.regions[index];
+                next.start = freed.start;
+                next.page_count += freed.page_count;
+            }
+            (false, false) => {
+                self.insert(index, freed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The total number of free pages.
+    pub fn free_page_count(&self) -> u64 {
+        self.regions[0..self.region_count]
+            .iter()
+            .map(|region| region.page_count)
+            .sum()
+    }
+
+    /** Adapt this allocator to the `allocate_pages` callback used by [`PageMap`].
+
+    The callback panics when physical memory runs out.
+    */
+    pub fn allocate_pages_fn(&mut self) -> impl FnMut(usize) -> u64 + '_ {
+        |count| match self.allocate(count) {
+            Some(address) => address,
+            None => panic!("out of physical memory (requested {} pages)", count),
+        }
+    }
+
+    fn insert(&mut self, index: usize, region: Region) -> Result<(), TooManyRegions> {
+        if self.region_count == Self::MAX_REGIONS {
+            return Err(TooManyRegions {
+                start: region.start,
+                page_count: region.page_count,
+            });
+        }
+
+        self.regions
+            .copy_within(index..self.region_count, index + 1);
+        self.regions[index] = region;
+        self.region_count += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.regions
+            .copy_within((index + 1)..self.region_count, index);
+        self.region_count -= 1;
+        self.regions[self.region_count] = Region::EMPTY;
+    }
+}
+
+/** A [`FrameAllocator`] is already tracking [`FrameAllocator::MAX_REGIONS`] regions, so it can't
+take the `page_count` pages at `start`.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyRegions {
+    pub start: u64,
+    pub page_count: u64,
+}
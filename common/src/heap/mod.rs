@@ -0,0 +1,339 @@
+/*! A first-fit heap allocator for a single region of memory.
+
+The kernel's global allocator is a [`Heap`] (see `kernel::heap`). It lives here so that its
+behaviour can be tested on the host.
+
+The heap is a linked list of free blocks, sorted by address. Each free block stores its size and
+a pointer to the next free block in its first 16 bytes. Allocation is first-fit, and freed blocks
+are merged with adjacent free blocks.
+
+# Alignment
+
+Every block's address and size is a multiple of [`BLOCK_ALIGN`] (16 bytes):
+
+* The heap region is shrunk to a 16-byte aligned start and a multiple of 16 bytes in length.
+* Allocation sizes are rounded up to a multiple of 16 bytes, and are at least 16 bytes so that
+  the block can hold a free list entry when it's deallocated.
+
+When an allocation needs a larger alignment, its start address is rounded up within a free block.
+Because of the invariant, the padding before the allocation and the space left over after it
+are both either empty or large enough to become free blocks of their own, so no memory is lost.
+
+# Statistics and running out of memory
+
+[`Heap::stats`] reports how much of the heap is in use, counting whole blocks. When an
+allocation can't be satisfied, [`out_of_memory`] calls an OOM hook with the failed
+allocation's layout and the heap's statistics, or panics when there's no hook.
+*/
+use core::{alloc::Layout, ptr::null_mut};
+
+/// The alignment and minimum size of every heap block.
+pub const BLOCK_ALIGN: usize = core::mem::size_of::<FreeBlock>();
+
+const _: () = assert!(BLOCK_ALIGN.is_power_of_two());
+const _: () = assert!(BLOCK_ALIGN >= core::mem::align_of::<FreeBlock>());
+
+/// How much of the heap is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes in allocated blocks.
+    pub allocated: usize,
+
+    /// Bytes in free blocks.
+    pub free: usize,
+
+    /// The largest that `allocated` has been.
+    pub peak_allocated: usize,
+}
+
+/// Called when an allocation fails. See [`out_of_memory`].
+pub type OomHook = fn(Layout, HeapStats) -> !;
+
+/** Report a failed allocation: call `hook`, or panic when there's no hook.
+
+## Example
+
+```rust
+# use common::heap::{out_of_memory, Heap, HeapStats};
+# use std::alloc::Layout;
+# let memory: &mut [u128] = Box::leak(vec![0u128; 8].into_boxed_slice());
+let mut heap = Heap::empty();
+unsafe { heap.init(memory.as_mut_ptr() as usize, 128) };
+
+let layout = Layout::from_size_align(64, 16).unwrap();
+while !heap.allocate(layout).is_null() {}
+assert_eq!(heap.stats().free, 0);
+
+fn hook(layout: Layout, stats: HeapStats) -> ! {
+    panic!("hook: {} bytes with {} of {} free", layout.size(), stats.free, stats.allocated);
+}
+let result = std::panic::catch_unwind(|| out_of_memory(Some(hook), layout, heap.stats()));
+let message = *result.unwrap_err().downcast::<String>().unwrap();
+assert_eq!(message, "hook: 64 bytes with 0 of 128 free");
+
+let result = std::panic::catch_unwind(|| out_of_memory(None, layout, heap.stats()));
+let message = *result.unwrap_err().downcast::<String>().unwrap();
+assert!(message.starts_with("out of memory: 64 bytes (align 16)"));
+```
+*/
+pub fn out_of_memory(hook: Option<OomHook>, layout: Layout, stats: HeapStats) -> ! {
+    match hook {
+        Some(hook) => hook(layout, stats),
+        None => panic!(
+            "out of memory: {} bytes (align {}) with {} of {} bytes free",
+            layout.size(),
+            layout.align(),
+            stats.free,
+            stats.allocated + stats.free
+        ),
+    }
+}
+
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+fn align_down(address: usize, align: usize) -> usize {
+    address & !(align - 1)
+}
+
+/// A free list entry, stored at the start of the free block it describes.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+impl FreeBlock {
+    fn start(&self) -> usize {
+        self as *const FreeBlock as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
+}
+
+/** A first-fit free list allocator.
+
+## Example
+
+```rust
+# use common::heap::Heap;
+# use std::alloc::Layout;
+# let memory: &mut [u128] = Box::leak(vec![0u128; 16].into_boxed_slice());
+# let base = memory.as_mut_ptr() as usize;
+let mut heap = Heap::empty();
+unsafe { heap.init(base, 256) };
+
+let layout = |size| Layout::from_size_align(size, 16).unwrap();
+let a = heap.allocate(layout(32)) as usize;
+let b = heap.allocate(layout(20)) as usize;
+let c = heap.allocate(layout(64)) as usize;
+
+// First fit, with sizes rounded up to 16 bytes.
+assert_eq!([a, b, c], [base, base + 32, base + 64]);
+
+// A freed block is reused.
+unsafe { heap.deallocate(b as *mut u8, layout(20)) };
+assert_eq!(heap.allocate(layout(32)) as usize, b);
+
+// The free space is fragmented: 32 bytes at `a`, and 192 bytes from `c` to the end.
+unsafe {
+    heap.deallocate(a as *mut u8, layout(32));
+    heap.deallocate(c as *mut u8, layout(64));
+}
+assert!(heap.allocate(layout(208)).is_null());
+assert_eq!(heap.allocate(layout(48)) as usize, c);
+
+// Once everything is freed, the blocks merge back into one.
+unsafe {
+    heap.deallocate(b as *mut u8, layout(32));
+    heap.deallocate(c as *mut u8, layout(48));
+}
+assert_eq!(heap.allocate(layout(256)) as usize, base);
+assert!(heap.allocate(layout(16)).is_null());
+unsafe { heap.deallocate(base as *mut u8, layout(256)) };
+
+// Larger alignments leave the padding free.
+let aligned = heap.allocate(Layout::from_size_align(16, 128).unwrap()) as usize;
+assert_eq!(aligned % 128, 0);
+let before = heap.allocate(layout(16)) as usize;
+assert!(before + 16 <= aligned || before >= aligned + 16);
+```
+*/
+pub struct Heap {
+    /// The lowest-addressed free block.
+    head: *mut FreeBlock,
+
+    /// The total size of the heap's memory.
+    size: usize,
+
+    allocated: usize,
+    peak_allocated: usize,
+}
+
+impl Heap {
+    pub const fn empty() -> Self {
+        Heap {
+            head: null_mut(),
+            size: 0,
+            allocated: 0,
+            peak_allocated: 0,
+        }
+    }
+
+    /** How much of the heap is in use. Sizes are rounded up to whole blocks.
+
+    ## Example
+
+    ```rust
+    # use common::heap::{Heap, HeapStats};
+    # use std::alloc::Layout;
+    # let memory: &mut [u128] = Box::leak(vec![0u128; 16].into_boxed_slice());
+    let mut heap = Heap::empty();
+    unsafe { heap.init(memory.as_mut_ptr() as usize, 256) };
+    let stats = |allocated, free, peak_allocated| HeapStats { allocated, free, peak_allocated };
+    assert_eq!(heap.stats(), stats(0, 256, 0));
+
+    let small = Layout::from_size_align(10, 1).unwrap();
+    let large = Layout::from_size_align(100, 8).unwrap();
+    let a = heap.allocate(small);
+    let b = heap.allocate(large);
+    assert_eq!(heap.stats(), stats(128, 128, 128));
+
+    unsafe { heap.deallocate(b, large) };
+    assert_eq!(heap.stats(), stats(16, 240, 128));
+
+    unsafe { heap.deallocate(a, small) };
+    assert_eq!(heap.stats(), stats(0, 256, 128));
+
+    // A failed allocation doesn't change anything.
+    assert!(heap.allocate(Layout::from_size_align(512, 16).unwrap()).is_null());
+    assert_eq!(heap.stats(), stats(0, 256, 128));
+    ```
+    */
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            allocated: self.allocated,
+            free: self.size - self.allocated,
+            peak_allocated: self.peak_allocated,
+        }
+    }
+
+    /** Add a region of memory to the heap.
+
+    # Safety
+
+    The region must be writable and unused by anything else.
+    */
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let start = align_up(heap_start, BLOCK_ALIGN);
+        let end = align_down(heap_start + heap_size, BLOCK_ALIGN);
+        if start < end {
+            self.free(start, end - start);
+            self.size += end - start;
+        }
+    }
+
+    /// The size of the block that will be used for an allocation.
+    fn block_size(layout: Layout) -> usize {
+        align_up(layout.size().max(BLOCK_ALIGN), BLOCK_ALIGN)
+    }
+
+    /** Allocate a block of memory that fits `layout`.
+
+    Returns a null pointer when there's no free block that's large enough.
+    */
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = Self::block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        let mut previous: *mut FreeBlock = null_mut();
+        let mut current = self.head;
+
+        // Safety: every pointer in the free list points to a valid `FreeBlock`.
+        unsafe {
+            while !current.is_null() {
+                let block_start = (*current).start();
+                let block_end = (*current).end();
+
+                let allocation_start = align_up(block_start, align);
+                let allocation_end = allocation_start + size;
+
+                if allocation_end <= block_end {
+                    // Unlink the block, then give back whatever the allocation doesn't use.
+                    let next = (*current).next;
+                    if previous.is_null() {
+                        self.head = next;
+                    } else {
+                        (*previous).next = next;
+                    }
+
+                    if allocation_start > block_start {
+                        self.free(block_start, allocation_start - block_start);
+                    }
+
+                    if block_end > allocation_end {
+                        self.free(allocation_end, block_end - allocation_end);
+                    }
+
+                    self.allocated += size;
+                    self.peak_allocated = self.peak_allocated.max(self.allocated);
+
+                    return allocation_start as *mut u8;
+                }
+
+                previous = current;
+                current = (*current).next;
+            }
+        }
+
+        null_mut()
+    }
+
+    /** Return a block of memory to the heap.
+
+    # Safety
+
+    `ptr` must have been returned by [`Heap::allocate`] with the same `layout`.
+    */
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let size = Self::block_size(layout);
+        self.allocated -= size;
+        self.free(ptr as usize, size)
+    }
+
+    /** Insert a free block into the list, merging it with its neighbours.
+
+    # Safety
+
+    `start` and `size` must be multiples of [`BLOCK_ALIGN`], and the memory must be unused.
+    */
+    unsafe fn free(&mut self, start: usize, size: usize) {
+        debug_assert!(start.is_multiple_of(BLOCK_ALIGN) && size.is_multiple_of(BLOCK_ALIGN));
+
+        let mut previous: *mut FreeBlock = null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (*next).start() < start {
+            previous = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        if !next.is_null() && (*block).end() == (*next).start() {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if previous.is_null() {
+            self.head = block;
+        } else if (*previous).end() == start {
+            (*previous).size += (*block).size;
+            (*previous).next = (*block).next;
+        } else {
+            (*previous).next = block;
+        }
+    }
+}
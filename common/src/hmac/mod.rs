@@ -0,0 +1,288 @@
+/*! SHA-256 and HMAC-SHA256.
+
+The bootloader uses these to check that `kernel.bin` was signed with a key it knows about. There's
+no dynamic allocation, so they work before (and after) UEFI boot services are available.
+
+References:
+
+* [FIPS 180-4](https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.180-4.pdf), Section 6.2 (SHA-256)
+* [RFC 2104](https://www.rfc-editor.org/rfc/rfc2104) (HMAC)
+*/
+
+/// The size of a SHA-256 digest, in bytes.
+pub const DIGEST_SIZE: usize = 32;
+
+/// SHA-256 processes messages in blocks of this many bytes.
+const BLOCK_SIZE: usize = 64;
+
+/// The first 32 bits of the fractional parts of the cube roots of the first 64 primes.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// The first 32 bits of the fractional parts of the square roots of the first 8 primes.
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/** An incremental SHA-256 hash.
+
+## Example
+
+```rust
+# use common::hmac::Sha256;
+let mut hash = Sha256::new();
+hash.update(b"a");
+hash.update(b"bc");
+assert_eq!(
+    hash.finalize(),
+    [
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22,
+        0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00,
+        0x15, 0xad,
+    ]
+);
+```
+*/
+pub struct Sha256 {
+    state: [u32; 8],
+
+    /// Bytes that don't fill a block yet.
+    buffer: [u8; BLOCK_SIZE],
+    buffer_len: usize,
+
+    /// Total message length, in bytes.
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub const fn new() -> Self {
+        Sha256 {
+            state: INITIAL_STATE,
+            buffer: [0; BLOCK_SIZE],
+            buffer_len: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if self.buffer_len > 0 {
+            let count = core::cmp::min(BLOCK_SIZE - self.buffer_len, data.len());
+            self.buffer[self.buffer_len..self.buffer_len + count].copy_from_slice(&data[..count]);
+            self.buffer_len += count;
+            data = &data[count..];
+
+            if self.buffer_len < BLOCK_SIZE {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffer_len = 0;
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+
+        let remainder = blocks.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffer_len = remainder.len();
+    }
+
+    pub fn finalize(mut self) -> [u8; DIGEST_SIZE] {
+        let bit_length = self.length * 8;
+
+        // Padding is a 1 bit, then 0 bits until the length is 8 bytes short of a block boundary.
+        let padding_len = if self.buffer_len < BLOCK_SIZE - 8 {
+            BLOCK_SIZE - 8 - self.buffer_len
+        } else {
+            2 * BLOCK_SIZE - 8 - self.buffer_len
+        };
+        let mut padding = [0; BLOCK_SIZE];
+        padding[0] = 0x80;
+        self.update(&padding[..padding_len]);
+        self.update(&bit_length.to_be_bytes());
+        debug_assert_eq!(self.buffer_len, 0);
+
+        let mut digest = [0; DIGEST_SIZE];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Hash one block (FIPS 180-4, Section 6.2.2).
+    fn compress(&mut self, block: &[u8; BLOCK_SIZE]) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for t in 16..64 {
+            let s0 = w[t - 15].rotate_right(7) ^ w[t - 15].rotate_right(18) ^ (w[t - 15] >> 3);
+            let s1 = w[t - 2].rotate_right(17) ^ w[t - 2].rotate_right(19) ^ (w[t - 2] >> 10);
+            w[t] = w[t - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[t - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for t in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[t])
+                .wrapping_add(w[t]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+/** The SHA-256 digest of `data`.
+
+## Example
+
+```rust
+# use common::hmac::sha256;
+assert_eq!(
+    sha256(b""),
+    [
+        0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9,
+        0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52,
+        0xb8, 0x55,
+    ]
+);
+
+// 56 bytes, so the padding needs an extra block.
+assert_eq!(
+    sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+    [
+        0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e, 0x60,
+        0x39, 0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4, 0x19, 0xdb,
+        0x06, 0xc1,
+    ]
+);
+```
+*/
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finalize()
+}
+
+/** The HMAC-SHA256 of `message` under `key`.
+
+## Example
+
+From [RFC 4231](https://www.rfc-editor.org/rfc/rfc4231), test case 2:
+
+```rust
+# use common::hmac::hmac_sha256;
+assert_eq!(
+    hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+    [
+        0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75,
+        0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec,
+        0x38, 0x43,
+    ]
+);
+
+// Test case 6: a key that's longer than a block.
+assert_eq!(
+    hmac_sha256(
+        &[0xaa; 131],
+        b"Test Using Larger Than Block-Size Key - Hash Key First"
+    ),
+    [
+        0x60, 0xe4, 0x31, 0x59, 0x1e, 0xe0, 0xb6, 0x7f, 0x0d, 0x8a, 0x26, 0xaa, 0xcb, 0xf5, 0xb7,
+        0x7f, 0x8e, 0x0b, 0xc6, 0x21, 0x37, 0x28, 0xc5, 0x14, 0x05, 0x46, 0x04, 0x0f, 0x0e, 0xe3,
+        0x7f, 0x54,
+    ]
+);
+```
+*/
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; DIGEST_SIZE] {
+    // Keys longer than a block are hashed first. Shorter keys are padded with zeros.
+    let mut block_key = [0; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block_key[..DIGEST_SIZE].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner_key = [0x36; BLOCK_SIZE];
+    let mut outer_key = [0x5c; BLOCK_SIZE];
+    for ((inner, outer), key) in inner_key
+        .iter_mut()
+        .zip(outer_key.iter_mut())
+        .zip(block_key.iter())
+    {
+        *inner ^= key;
+        *outer ^= key;
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&inner_key);
+    inner.update(message);
+    let inner_digest = inner.finalize();
+
+    let mut outer = Sha256::new();
+    outer.update(&outer_key);
+    outer.update(&inner_digest);
+    outer.finalize()
+}
+
+/** Compare two digests in constant time.
+
+Comparing with `==` returns as soon as a byte differs, so its timing would reveal how much of a
+forged signature is correct.
+
+## Example
+
+```rust
+# use common::hmac::{digests_equal, sha256};
+assert!(digests_equal(&sha256(b"kernel"), &sha256(b"kernel")));
+assert!(!digests_equal(&sha256(b"kernel"), &sha256(b"kernal")));
+```
+*/
+pub fn digests_equal(a: &[u8; DIGEST_SIZE], b: &[u8; DIGEST_SIZE]) -> bool {
+    let mut difference = 0;
+    for (a, b) in a.iter().zip(b.iter()) {
+        difference |= a ^ b;
+    }
+    // Keep the compiler from turning the loop into an early-exit comparison.
+    core::hint::black_box(difference) == 0
+}
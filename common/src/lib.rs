@@ -1,5 +1,15 @@
 #![no_std]
 
+pub mod boot_info;
+pub mod buffer;
 pub mod exe;
+pub mod frame_alloc;
+pub mod heap;
+pub mod hmac;
+pub mod line_editor;
+pub mod mmio;
 pub mod paging;
+pub mod pci;
 pub mod registers;
+
+pub use boot_info::BootInfo;
@@ -0,0 +1,91 @@
+/*! Reading a line of input from a terminal, with echo and backspace.
+
+The terminal on the other end of a serial port doesn't echo or edit anything itself. It sends
+each key as it's pressed, so [`read_line`] echoes the bytes back and handles backspace.
+*/
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/** Read bytes from `read_byte` into `buffer` until CR or LF, and return the line's length.
+
+* Every byte that's added to the line is passed to `echo`, so that the user sees what they
+  typed. The line ending is echoed as CR LF.
+* Backspace (0x08) and delete (0x7f) erase the last byte of the line, and echo
+  backspace-space-backspace to erase it from the terminal. They do nothing when the line is
+  empty.
+* When `buffer` is full, further bytes are dropped without being echoed until the line ends.
+  Backspace still works.
+
+The line ending isn't stored in `buffer`. A terminal that sends CR LF for Enter produces an
+extra, empty line.
+
+## Example
+
+```rust
+# use common::line_editor::read_line;
+let mut input = b"lsx\x08 -l\x7f\x7fa\r".iter().copied();
+let mut echoed = Vec::new();
+let mut buffer = [0; 16];
+
+let len = read_line(
+    &mut || input.next().unwrap(),
+    &mut |bytes| echoed.extend_from_slice(bytes),
+    &mut buffer,
+);
+assert_eq!(&buffer[..len], b"ls a");
+assert_eq!(echoed, b"lsx\x08 \x08 -l\x08 \x08\x08 \x08a\r\n");
+
+// Backspace on an empty line does nothing.
+let mut input = b"\x08\x7fok\n".iter().copied();
+let mut echoed = Vec::new();
+let len = read_line(
+    &mut || input.next().unwrap(),
+    &mut |bytes| echoed.extend_from_slice(bytes),
+    &mut buffer,
+);
+assert_eq!(&buffer[..len], b"ok");
+assert_eq!(echoed, b"ok\r\n");
+
+// Input past the end of the buffer is dropped, but backspace still works.
+let mut input = b"abcdef\x08xy\r".iter().copied();
+let mut echoed = Vec::new();
+let mut buffer = [0; 4];
+let len = read_line(
+    &mut || input.next().unwrap(),
+    &mut |bytes| echoed.extend_from_slice(bytes),
+    &mut buffer,
+);
+assert_eq!(&buffer[..len], b"abcx");
+assert_eq!(echoed, b"abcd\x08 \x08x\r\n");
+```
+*/
+pub fn read_line(
+    read_byte: &mut dyn FnMut() -> u8,
+    echo: &mut dyn FnMut(&[u8]),
+    buffer: &mut [u8],
+) -> usize {
+    let mut len = 0;
+
+    loop {
+        match read_byte() {
+            b'\r' | b'\n' => {
+                echo(b"\r\n");
+                return len;
+            }
+            BACKSPACE | DELETE => {
+                if len > 0 {
+                    len -= 1;
+                    echo(&[BACKSPACE, b' ', BACKSPACE]);
+                }
+            }
+            byte => {
+                if len < buffer.len() {
+                    buffer[len] = byte;
+                    len += 1;
+                    echo(&[byte]);
+                }
+            }
+        }
+    }
+}
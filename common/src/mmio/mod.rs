@@ -0,0 +1,93 @@
+/*! Volatile accesses to memory-mapped registers.
+
+The compiler assumes that memory only changes when the program writes to it, so it can merge,
+reorder or remove ordinary loads and stores. A device register can change by itself, and
+reading or writing it can have side effects, so every access has to happen exactly as written.
+These functions wrap [`core::ptr::read_volatile`] and [`core::ptr::write_volatile`], which the
+compiler never elides or reorders with respect to other volatile accesses.
+
+# Safety
+
+Every function here requires that `address` is mapped, readable (or writable, for the writes)
+and aligned to the access size. Device memory should be mapped uncached (see
+[`crate::paging::PageMapFlags::UNCACHED`]), otherwise the processor's caches can combine or
+delay the accesses even though the compiler doesn't.
+
+## Example
+
+```rust
+# use common::mmio;
+let mut buffer = [0u64; 2];
+let address = buffer.as_mut_ptr() as u64;
+unsafe {
+    mmio::write_volatile_u64(address, 0x0123_4567_89ab_cdef);
+    assert_eq!(mmio::read_volatile_u64(address), 0x0123_4567_89ab_cdef);
+
+    // Little endian.
+    assert_eq!(mmio::read_volatile_u32(address), 0x89ab_cdef);
+    assert_eq!(mmio::read_volatile_u16(address + 4), 0x4567);
+    assert_eq!(mmio::read_volatile_u8(address + 7), 0x01);
+
+    mmio::write_volatile_u8(address + 8, 0xaa);
+    mmio::write_volatile_u16(address + 10, 0xbbcc);
+    mmio::write_volatile_u32(address + 12, 0xdead_beef);
+}
+assert_eq!(buffer[1], 0xdead_beef_bbcc_00aa);
+```
+*/
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn read_volatile_u8(address: u64) -> u8 {
+    core::ptr::read_volatile(address as *const u8)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn read_volatile_u16(address: u64) -> u16 {
+    core::ptr::read_volatile(address as *const u16)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn read_volatile_u32(address: u64) -> u32 {
+    core::ptr::read_volatile(address as *const u32)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn read_volatile_u64(address: u64) -> u64 {
+    core::ptr::read_volatile(address as *const u64)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn write_volatile_u8(address: u64, value: u8) {
+    core::ptr::write_volatile(address as *mut u8, value)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn write_volatile_u16(address: u64, value: u16) {
+    core::ptr::write_volatile(address as *mut u16, value)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn write_volatile_u32(address: u64, value: u32) {
+    core::ptr::write_volatile(address as *mut u32, value)
+}
+
+/// # Safety
+///
+/// See the [module documentation](self).
+pub unsafe fn write_volatile_u64(address: u64, value: u64) {
+    core::ptr::write_volatile(address as *mut u64, value)
+}
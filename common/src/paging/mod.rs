@@ -1,6 +1,9 @@
-use core::ops::BitOr;
+#[doc(hidden)]
+pub mod test_support;
 
-use crate::registers::CR3;
+use core::ops::{BitAnd, BitOr};
+
+use crate::registers::{CR0, CR3, CR4, IA32_EFER};
 
 #[derive(Debug)]
 struct PageMapIndices {
@@ -10,7 +13,7 @@ struct PageMapIndices {
     pt: usize,
 }
 
-fn address_to_page_map_indices(virtual_address: u64) -> PageMapIndices {
+const fn address_to_page_map_indices(virtual_address: u64) -> PageMapIndices {
     let mut offset = 12 + 3 * 9;
     let mut mask = 0b111111111 << offset;
     let pml4_index: u64 = (virtual_address & mask) >> offset;
@@ -35,15 +38,106 @@ fn address_to_page_map_indices(virtual_address: u64) -> PageMapIndices {
     }
 }
 
-fn page_map_indices_to_address(indices: PageMapIndices) -> u64 {
+/** Whether a virtual address is canonical for 4-level paging.
+
+Only 48 bits of a virtual address are translated. Bits 48 to 63 must all be copies of bit 47;
+the processor faults on accesses to addresses where they aren't.
+
+## Example
+
+```rust
+# use common::paging::is_canonical;
+assert!(is_canonical(0x0000_7fff_ffff_f000));
+assert!(is_canonical(0xffff_8000_0000_0000));
+assert!(!is_canonical(0x0000_8000_0000_0000));
+assert!(!is_canonical(0xfff0_8000_0000_0000));
+```
+*/
+pub const fn is_canonical(virtual_address: u64) -> bool {
+    let upper_bits = virtual_address >> 47;
+    upper_bits == 0 || upper_bits == 0x1_ffff
+}
+
+/// The inverse of [`address_to_page_map_indices`]. The result is canonical (see [`is_canonical`]).
+const fn page_map_indices_to_address(indices: PageMapIndices) -> u64 {
     let mut value: u64 = 0;
     value |= (indices.pml4 as u64) << (12 + 3 * 9);
     value |= (indices.pdpt as u64) << (12 + 2 * 9);
     value |= (indices.pd as u64) << (12 + 9);
     value |= (indices.pt as u64) << 12;
+
+    // Sign-extend bit 47.
+    if value & (1 << 47) != 0 {
+        value |= 0xffff_0000_0000_0000;
+    }
+
     value
 }
 
+/* Compile-time tests for `address_to_page_map_indices` and `page_map_indices_to_address`.
+
+The indices are checked against hand-computed examples, and every address in a spread of
+canonical addresses round-trips to its page address.
+*/
+const _: () = {
+    const fn indices_are(
+        virtual_address: u64,
+        pml4: usize,
+        pdpt: usize,
+        pd: usize,
+        pt: usize,
+    ) -> bool {
+        let indices = address_to_page_map_indices(virtual_address);
+        indices.pml4 == pml4 && indices.pdpt == pdpt && indices.pd == pd && indices.pt == pt
+    }
+
+    assert!(indices_are(0x0, 0, 0, 0, 0));
+    assert!(indices_are(0xfff, 0, 0, 0, 0));
+    assert!(indices_are(0x1000, 0, 0, 0, 1));
+    assert!(indices_are(0x20_0000, 0, 0, 1, 0));
+    assert!(indices_are(0x4000_0000, 0, 1, 0, 0));
+    assert!(indices_are(0x80_0000_0000, 1, 0, 0, 0));
+    assert!(indices_are(0x0000_7fff_ffff_ffff, 255, 511, 511, 511));
+    assert!(indices_are(0xffff_8000_0000_0000, 256, 0, 0, 0));
+    assert!(indices_are(0xffff_ffff_ffff_f000, 511, 511, 511, 511));
+    // 0x1234_5678_9000 = 0b000100100 011010001 010110011 110001001 (+ 12 zero bits)
+    assert!(indices_are(0x1234_5678_9abc, 0x24, 0xd1, 0xb3, 0x189));
+
+    let mut virtual_address: u64 = 0;
+    while virtual_address < 0x0000_8000_0000_0000 {
+        let lower_half = virtual_address;
+        let higher_half = virtual_address | 0xffff_8000_0000_0000;
+
+        assert!(
+            page_map_indices_to_address(address_to_page_map_indices(lower_half))
+                == lower_half & !0xfff
+        );
+        assert!(
+            page_map_indices_to_address(address_to_page_map_indices(higher_half))
+                == higher_half & !0xfff
+        );
+
+        // A large odd stride, so that every index and the page offset take on many values.
+        virtual_address += 0x0000_0123_4567_89ab;
+    }
+};
+
+/// The bits of a page map entry that hold a physical address (bits 12 to 51).
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/** Whether a physical address fits in 52 bits, the architectural maximum physical address width.
+
+A processor's actual width (MAXPHYADDR) can be smaller, and is reported by CPUID leaf 0x80000008.
+Bits between MAXPHYADDR and 51 are reserved, so this check doesn't catch every invalid address.
+*/
+const fn fits_in_52_bits(address: u64) -> bool {
+    address & !(ADDRESS_MASK | 0xfff) == 0
+}
+
+const _: () = assert!(fits_in_52_bits(0x000f_ffff_ffff_f000));
+const _: () = assert!(!fits_in_52_bits(0x0010_0000_0000_0000));
+const _: () = assert!(!fits_in_52_bits(1 << 63));
+
 unsafe fn init_memory<T: Copy>(data: *mut T, len: usize, value: T) {
     let entries = core::slice::from_raw_parts_mut(data, len).iter_mut();
     for entry in entries {
@@ -51,32 +145,293 @@ unsafe fn init_memory<T: Copy>(data: *mut T, len: usize, value: T) {
     }
 }
 
-/** Memory mapping permissions.
+/// Allocate a page for a page table and fill it with non-present entries.
+fn allocate_table<'a, T>(allocate_pages: &mut dyn FnMut(usize) -> u64) -> (u64, &'a mut [T]) {
+    let address = allocate_pages(1);
+    unsafe {
+        init_memory(address as *mut u64, 512, 0);
+        (
+            address,
+            core::slice::from_raw_parts_mut(address as *mut T, 512),
+        )
+    }
+}
+
+/// The amount of memory mapped by a leaf entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// Mapped by a [`PTE`].
+    Size4KiB,
+
+    /// Mapped by a [`PDE`] with the PS bit set.
+    Size2MiB,
+
+    /// Mapped by a [`PDPTE`] with the PS bit set.
+    Size1GiB,
+}
+
+impl PageSize {
+    /// The page size, in bytes.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 4096,
+            PageSize::Size2MiB => 2 * 1024 * 1024,
+            PageSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl core::fmt::Display for PageSize {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PageSize::Size4KiB => f.write_str("4KiB"),
+            PageSize::Size2MiB => f.write_str("2MiB"),
+            PageSize::Size1GiB => f.write_str("1GiB"),
+        }
+    }
+}
+
+/** Memory mapping permissions and caching behaviour.
 
-The default (`PageMapFlags::default()`) is read-only. Use the associated constants
-with bitwise OR to add more permissions.
+The default (`PageMapFlags::default()`, also [`PageMapFlags::R`]) is read-only and uses
+write-back caching. Use the associated constants with bitwise OR to add more permissions or to
+change how the page is cached.
 
 ## Example
 
 ```rust
+# use common::paging::PageMapFlags;
 let rwx = PageMapFlags::W | PageMapFlags::X;
 ```
+
+## x86 page table bits
+
+x86 has no "readable" bit: every present page is readable. So the flags map to a PTE like this:
+
+* [`PageMapFlags::NONE`]: not present (P = 0), marked as a guard page (see [`PTE::guard`]).
+  Any access faults.
+* [`PageMapFlags::R`]: P = 1, R/W = 0, XD = 1.
+* [`PageMapFlags::W`]: P = 1, R/W = 1, XD = 1.
+* [`PageMapFlags::X`]: P = 1, R/W = 0, XD = 0.
+* [`PageMapFlags::UNCACHED`]: PCD = 1. [`PageMapFlags::WRITE_THROUGH`]: PWT = 1.
+  [`PageMapFlags::WRITE_COMBINING`]: PAT = 1.
+
+Every constant except [`PageMapFlags::NONE`] includes read access, because it can't be removed
+from a present page. `NONE | flags` is the same as `flags`.
+
+```rust
+# use common::paging::{PageMapFlags, PTE};
+let address = 0x20_0000;
+let present = 1;
+let writable = 1 << 1;
+let pwt = 1 << 3;
+let pcd = 1 << 4;
+let pat = 1 << 7;
+let xd = 1 << 63;
+
+assert_eq!(PTE::from_flags(address, PageMapFlags::default()).value(), address | xd | present);
+assert_eq!(PTE::from_flags(address, PageMapFlags::R).value(), address | xd | present);
+assert_eq!(
+    PTE::from_flags(address, PageMapFlags::W).value(),
+    address | xd | writable | present
+);
+assert_eq!(PTE::from_flags(address, PageMapFlags::X).value(), address | present);
+assert_eq!(
+    PTE::from_flags(address, PageMapFlags::W | PageMapFlags::X).value(),
+    address | writable | present
+);
+assert_eq!(
+    PTE::from_flags(address, PageMapFlags::W | PageMapFlags::UNCACHED).value(),
+    address | xd | pcd | writable | present
+);
+assert_eq!(
+    PTE::from_flags(address, PageMapFlags::WRITE_THROUGH).value(),
+    address | xd | pwt | present
+);
+assert_eq!(
+    PTE::from_flags(address, PageMapFlags::W | PageMapFlags::WRITE_COMBINING).value(),
+    address | xd | pat | writable | present
+);
+
+let guard = PTE::from_flags(address, PageMapFlags::NONE);
+assert!(!guard.present());
+assert!(guard.is_guard());
+```
 */
-#[derive(Default, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PageMapFlags {
+    readable: bool,
     writeable: bool,
     executable: bool,
+    uncached: bool,
+    write_through: bool,
+    write_combining: bool,
+}
+
+impl Default for PageMapFlags {
+    fn default() -> Self {
+        Self::R
+    }
 }
 
 impl PageMapFlags {
+    /// Whether the page can be read. Only [`PageMapFlags::NONE`] isn't readable.
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    /// Whether the page can be written.
+    pub fn writable(&self) -> bool {
+        self.writeable
+    }
+
+    /// Whether instructions can be fetched from the page.
+    pub fn executable(&self) -> bool {
+        self.executable
+    }
+
+    /** Whether every permission and caching flag in `other` is also in `self`.
+
+    ## Example
+
+    ```rust
+    # use common::paging::PageMapFlags;
+    let flags = PageMapFlags::W | PageMapFlags::UNCACHED;
+    assert!(flags.contains(PageMapFlags::R));
+    assert!(flags.contains(PageMapFlags::W));
+    assert!(flags.contains(PageMapFlags::UNCACHED));
+    assert!(flags.contains(PageMapFlags::W | PageMapFlags::UNCACHED));
+    assert!(!flags.contains(PageMapFlags::X));
+    assert!(!flags.contains(PageMapFlags::W | PageMapFlags::X));
+
+    // Everything contains `NONE`, but `NONE` only contains itself.
+    assert!(flags.contains(PageMapFlags::NONE));
+    assert!(PageMapFlags::NONE.contains(PageMapFlags::NONE));
+    assert!(!PageMapFlags::NONE.contains(PageMapFlags::R));
+
+    // Intersection keeps the flags that both sides have.
+    let rwx = PageMapFlags::W | PageMapFlags::X;
+    assert_eq!(rwx & PageMapFlags::W, PageMapFlags::W);
+    assert_eq!(rwx & PageMapFlags::UNCACHED, PageMapFlags::R);
+    assert_eq!(PageMapFlags::W & PageMapFlags::X, PageMapFlags::R);
+    assert_eq!(PageMapFlags::W & PageMapFlags::NONE, PageMapFlags::NONE);
+
+    let intersection = flags & rwx;
+    assert!(intersection.writable());
+    assert!(!intersection.executable());
+    assert!(flags.contains(intersection) && rwx.contains(intersection));
+    ```
+    */
+    pub fn contains(&self, other: PageMapFlags) -> bool {
+        *self & other == other
+    }
+
+    /** The flags of a present leaf entry, given its value and the mask of its PAT bit.
+
+    `writeable` and `executable` are the permissions of the whole walk to the entry.
+    */
+    fn from_leaf(value: u64, pat_mask: u64, writeable: bool, executable: bool) -> Self {
+        PageMapFlags {
+            readable: true,
+            writeable,
+            executable,
+            uncached: value & EntryFlags::PCD == EntryFlags::PCD,
+            write_through: value & EntryFlags::PWT == EntryFlags::PWT,
+            write_combining: value & pat_mask == pat_mask,
+        }
+    }
+
+    /** No access at all, for guard pages.
+
+    The page is left unmapped (but marked, see [`PTE::guard`]), so any access to it faults.
+    */
+    pub const NONE: PageMapFlags = PageMapFlags {
+        readable: false,
+        writeable: false,
+        executable: false,
+        uncached: false,
+        write_through: false,
+        write_combining: false,
+    };
+
+    pub const R: PageMapFlags = PageMapFlags {
+        readable: true,
+        writeable: false,
+        executable: false,
+        uncached: false,
+        write_through: false,
+        write_combining: false,
+    };
+
     pub const W: PageMapFlags = PageMapFlags {
+        readable: true,
         writeable: true,
         executable: false,
+        uncached: false,
+        write_through: false,
+        write_combining: false,
     };
 
     pub const X: PageMapFlags = PageMapFlags {
+        readable: true,
         writeable: false,
         executable: true,
+        uncached: false,
+        write_through: false,
+        write_combining: false,
+    };
+
+    /** Disable caching for the page (sets the PCD bit in the page's PTE).
+
+    Memory-mapped device registers must be mapped uncached. Otherwise reads can return stale
+    values from the cache, and writes can sit in the cache instead of reaching the device.
+    */
+    pub const UNCACHED: PageMapFlags = PageMapFlags {
+        readable: true,
+        writeable: false,
+        executable: false,
+        uncached: true,
+        write_through: false,
+        write_combining: false,
+    };
+
+    /** Use write-through caching for the page (sets the PWT bit in the page's PTE).
+
+    Writes go straight to memory, while reads may still be served from the cache.
+    */
+    pub const WRITE_THROUGH: PageMapFlags = PageMapFlags {
+        readable: true,
+        writeable: false,
+        executable: false,
+        uncached: false,
+        write_through: true,
+        write_combining: false,
+    };
+
+    /** Use write-combining for the page (sets the PAT bit in the page's PTE).
+
+    Writes are buffered and combined into larger bursts, which makes it the fastest memory
+    type for framebuffers. Can't be combined with [`PageMapFlags::UNCACHED`] or
+    [`PageMapFlags::WRITE_THROUGH`].
+
+    The PAT bit selects entry [`crate::registers::IA32_PAT::WRITE_COMBINING_INDEX`] of the page
+    attribute table, which is write-back by default. That entry must be set to
+    [`crate::registers::PatMemoryType::WriteCombining`] before this flag has any effect:
+
+    ```rust,no_run
+    # use common::{paging::PageMapFlags, registers::{IA32_PAT, PatMemoryType}};
+    let mut pat = IA32_PAT::read();
+    pat.set_entry(IA32_PAT::WRITE_COMBINING_INDEX, PatMemoryType::WriteCombining);
+    unsafe { pat.write() };
+    ```
+    */
+    pub const WRITE_COMBINING: PageMapFlags = PageMapFlags {
+        readable: true,
+        writeable: false,
+        executable: false,
+        uncached: false,
+        write_through: false,
+        write_combining: true,
     };
 }
 
@@ -85,38 +440,393 @@ impl BitOr for PageMapFlags {
 
     fn bitor(self, rhs: Self) -> Self::Output {
         Self {
+            readable: self.readable || rhs.readable,
             writeable: self.writeable || rhs.writeable,
             executable: self.executable || rhs.executable,
+            uncached: self.uncached || rhs.uncached,
+            write_through: self.write_through || rhs.write_through,
+            write_combining: self.write_combining || rhs.write_combining,
+        }
+    }
+}
+
+/// The flags that are in both sides. See [`PageMapFlags::contains`].
+impl BitAnd for PageMapFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self {
+            readable: self.readable && rhs.readable,
+            writeable: self.writeable && rhs.writeable,
+            executable: self.executable && rhs.executable,
+            uncached: self.uncached && rhs.uncached,
+            write_through: self.write_through && rhs.write_through,
+            write_combining: self.write_combining && rhs.write_combining,
+        }
+    }
+}
+
+/** The processor's paging mode.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 4.1.1
+(Table 4-1).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// CR0.PG = 0.
+    Disabled,
+
+    /// CR0.PG = 1, CR4.PAE = 0.
+    ThirtyTwoBit,
+
+    /// CR0.PG = 1, CR4.PAE = 1, IA32_EFER.LME = 0.
+    Pae,
+
+    /// CR0.PG = 1, CR4.PAE = 1, IA32_EFER.LME = 1, CR4.LA57 = 0. The only mode [`PageMap`] supports.
+    FourLevel,
+
+    /// CR0.PG = 1, CR4.PAE = 1, IA32_EFER.LME = 1, CR4.LA57 = 1.
+    FiveLevel,
+}
+
+impl PagingMode {
+    /// Read the current paging mode from the control registers.
+    pub fn read() -> Self {
+        Self::from_registers(CR0::read(), CR4::read(), IA32_EFER::read())
+    }
+
+    /** The paging mode selected by a set of register values.
+
+    ## Example
+
+    ```rust
+    # use common::{paging::PagingMode, registers::{CR0, CR4, IA32_EFER}};
+    let pg = CR0::from_value(1 << 31);
+    let pae = 1 << 5;
+    let la57 = 1 << 12;
+    let lme = IA32_EFER::from_value(1 << 8);
+
+    assert_eq!(
+        PagingMode::from_registers(pg, CR4::from_value(pae), lme),
+        PagingMode::FourLevel
+    );
+    assert_eq!(
+        PagingMode::from_registers(pg, CR4::from_value(pae | la57), lme),
+        PagingMode::FiveLevel
+    );
+    assert_eq!(
+        PagingMode::from_registers(pg, CR4::from_value(pae), IA32_EFER::from_value(0)),
+        PagingMode::Pae
+    );
+    assert_eq!(
+        PagingMode::from_registers(CR0::from_value(0), CR4::from_value(pae | la57), lme),
+        PagingMode::Disabled
+    );
+    ```
+    */
+    pub fn from_registers(cr0: CR0, cr4: CR4, ia32_efer: IA32_EFER) -> Self {
+        if !cr0.pg() {
+            PagingMode::Disabled
+        } else if !cr4.pae() {
+            PagingMode::ThirtyTwoBit
+        } else if !ia32_efer.lme() {
+            PagingMode::Pae
+        } else if !cr4.la57() {
+            PagingMode::FourLevel
+        } else {
+            PagingMode::FiveLevel
+        }
+    }
+
+    /** Check that this is [`PagingMode::FourLevel`], the only mode [`PageMap`] supports.
+
+    Otherwise, returns the first register bit that's in the wrong state.
+
+    ## Example
+
+    ```rust
+    # use common::{paging::{MissingFeature, PagingMode}, registers::{CR0, CR4, IA32_EFER}};
+    let validate = |cr0, cr4, ia32_efer| {
+        PagingMode::from_registers(
+            CR0::from_value(cr0),
+            CR4::from_value(cr4),
+            IA32_EFER::from_value(ia32_efer),
+        )
+        .validate()
+    };
+    let pg = 1 << 31;
+    let pae = 1 << 5;
+    let la57 = 1 << 12;
+    let lme = 1 << 8;
+
+    assert_eq!(validate(pg, pae, lme), Ok(()));
+    assert_eq!(validate(0, pae, lme), Err(MissingFeature::Pg));
+    assert_eq!(validate(pg, 0, lme), Err(MissingFeature::Pae));
+    assert_eq!(validate(pg, pae, 0), Err(MissingFeature::Lme));
+    assert_eq!(validate(pg, pae | la57, lme), Err(MissingFeature::La57));
+    assert_eq!(validate(0, 0, 0), Err(MissingFeature::Pg));
+    ```
+    */
+    pub fn validate(self) -> Result<(), MissingFeature> {
+        match self {
+            PagingMode::Disabled => Err(MissingFeature::Pg),
+            PagingMode::ThirtyTwoBit => Err(MissingFeature::Pae),
+            PagingMode::Pae => Err(MissingFeature::Lme),
+            PagingMode::FourLevel => Ok(()),
+            PagingMode::FiveLevel => Err(MissingFeature::La57),
+        }
+    }
+}
+
+/// Why a [`PagingMode`] isn't 4-level paging. See [`PagingMode::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingFeature {
+    /// CR0.PG is clear: paging is disabled.
+    Pg,
+
+    /// CR4.PAE is clear: page map entries are 32 bits wide.
+    Pae,
+
+    /// IA32_EFER.LME is clear: the processor isn't in IA-32e mode.
+    Lme,
+
+    /// CR4.LA57 is set: the processor uses 5-level paging.
+    La57,
+}
+
+impl core::fmt::Display for MissingFeature {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MissingFeature::Pg => f.write_str("paging is disabled (CR0.PG = 0)"),
+            MissingFeature::Pae => f.write_str("PAE is disabled (CR4.PAE = 0)"),
+            MissingFeature::Lme => f.write_str("IA-32e mode is disabled (IA32_EFER.LME = 0)"),
+            MissingFeature::La57 => f.write_str("5-level paging is enabled (CR4.LA57 = 1)"),
         }
     }
 }
 
-/// A 4-level page table structure for x86-64.
+/** The PML4 entry that a recursive self-map uses.
+
+When PML4 entry 511 points back at the PML4, the active page map's tables appear in the top
+512GiB of the address space. See [`PageMap::adopt_active`].
+*/
+pub const SELF_MAP_INDEX: usize = 511;
+
+/// The virtual address of the active PML4, through the recursive self-map.
+pub const SELF_MAP_PML4_ADDRESS: u64 = 0xffff_ffff_ffff_f000;
+
+/** A 4-level page table structure for x86-64.
+
+5-level paging isn't supported: the PML4 is always the root table, so a [`PageMap`] can only be
+used when the processor is in [`PagingMode::FourLevel`].
+*/
 #[repr(C)]
 pub struct PageMap {
     /// The page map's physical address.
     address: u64,
 }
 
+/* Note [4KiB pages]
+
+Everything assumes 4KiB pages: it's the smallest x86-64 page size, and the size of UEFI's pages
+on x64. The bootloader allocates UEFI pages and maps them with [`PageMap`], and the mapping loops
+on both sides step by [`PageMap::PAGE_SIZE`], so a different size would silently map the wrong
+memory.
+
+The bootloader checks that UEFI's `PAGE_SIZE` matches before it builds the kernel's page map,
+and passes the size on in [`crate::BootInfo::page_size`], which the kernel checks in turn.
+*/
+
+// See Note [4KiB pages]. A page table's 512 8-byte entries fill exactly one page.
+const _: () = assert!(PageMap::PAGE_SIZE == 4096);
+const _: () = assert!(PageMap::PAGE_SIZE == 512 * core::mem::size_of::<u64>());
+
 impl PageMap {
+    /// The size of a 4KiB page, in bytes. See Note [4KiB pages].
     pub const PAGE_SIZE: usize = 4096;
 
     pub fn new(allocate_pages: &mut dyn FnMut(usize) -> u64) -> Self {
-        let pml4_address: u64 = allocate_pages(1);
+        Self::try_new(&mut |count| Some(allocate_pages(count))).unwrap()
+    }
+
+    /** Like [`PageMap::new`], for allocators that can run out of memory.
+
+    Returns `None` when `allocate_pages` returns `None`.
+    */
+    pub fn try_new(allocate_pages: &mut dyn FnMut(usize) -> Option<u64>) -> Option<Self> {
+        let pml4_address: u64 = allocate_pages(1)?;
         unsafe {
             init_memory(pml4_address as *mut u64, 512, 0);
         }
 
-        PageMap {
+        Some(PageMap {
             address: pml4_address,
-        }
+        })
     }
 
     /// Read the page table assigned to the [`CR3`] register.
     pub fn from_cr3() -> Self {
-        let cr3 = CR3::read();
         Self {
-            address: cr3.address(),
+            address: Self::current_root_address(),
+        }
+    }
+
+    /** Use the PML4 table at `address` as a page map.
+
+    For page maps that aren't loaded into [`CR3`], e.g. one that's being built for another
+    address space.
+
+    # Safety
+
+    `address` must point at a valid PML4 table, and every table that it refers to must be
+    accessible at its physical address while the page map is used (see [`PageMap::set`]).
+
+    ## Example
+
+    ```rust
+    # use common::paging::{EntryFlags, PageMap, PageMapFlags, PML4E, PDPTE, PDE, PTE};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_table = || page_pool.allocate(1);
+    // Map 0x40_0000 to 0x9000 by hand.
+    let (pml4, pdpt, pd, pt) = (allocate_table(), allocate_table(), allocate_table(), allocate_table());
+    let flags = EntryFlags::new().writable(true);
+    unsafe {
+        *(pml4 as *mut PML4E) = PML4E::with_flags(pdpt, flags);
+        *(pdpt as *mut PDPTE) = PDPTE::with_flags(pd, flags);
+        *(pd as *mut PDE).add(2) = PDE::with_flags(pt, flags);
+        *(pt as *mut PTE) = PTE::with_flags(0x9000, flags);
+    }
+
+    let page_map = unsafe { PageMap::from_address(pml4) };
+    assert_eq!(page_map.address(), pml4);
+    assert_eq!(page_map.translate(0x40_0123), Some(0x9123));
+    assert_eq!(page_map.flags(0x40_0000), Some(PageMapFlags::W | PageMapFlags::X));
+    assert_eq!(page_map.translate(0x40_1000), None);
+    assert_eq!(page_map.size(), 4096);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub unsafe fn from_address(address: u64) -> Self {
+        Self { address }
+    }
+
+    /** The physical address of the PML4 table in the [`CR3`] register.
+
+    For code that only needs to compare roots, without a [`PageMap`]. To read CR3's cache bits
+    as well, use [`CR3::read`].
+
+    Reading CR3 is a privileged instruction, so this faults outside of ring 0:
+
+    ```rust,no_run
+    # use common::paging::PageMap;
+    # let kernel_page_map = PageMap::from_cr3();
+    assert_eq!(
+        PageMap::current_root_address(),
+        kernel_page_map.address(),
+        "not running on the kernel's page map"
+    );
+    ```
+    */
+    pub fn current_root_address() -> u64 {
+        CR3::read().address()
+    }
+
+    /** Use the page map in CR3, after checking that it has a recursive self-map.
+
+    Code that accesses the active page map through its recursive window (see
+    [`SELF_MAP_PML4_ADDRESS`]) relies on PML4 entry [`SELF_MAP_INDEX`] pointing back at the
+    PML4. This checks the entry up front, so that a page map with something else at the window
+    is reported instead of corrupted.
+
+    The entry is only read through the window, because the active page map's tables aren't
+    necessarily identity-mapped (the kernel's aren't). When nothing is mapped at the window, the
+    read page faults, so the page map must have been given a self-map (see
+    [`PageMap::install_self_map`]).
+
+    Reading CR3 is a privileged instruction, so this faults outside of ring 0.
+    */
+    pub fn adopt_active() -> Result<Self, AdoptError> {
+        let page_map = Self::from_cr3();
+
+        let window_pml4e = unsafe {
+            (SELF_MAP_PML4_ADDRESS as *const PML4E)
+                .add(SELF_MAP_INDEX)
+                .read_volatile()
+        };
+        page_map.check_self_map_entry(&window_pml4e)?;
+
+        Ok(page_map)
+    }
+
+    /** Point PML4 entry [`SELF_MAP_INDEX`] back at the PML4, so that the page map's tables
+    appear at [`SELF_MAP_PML4_ADDRESS`] and below when it's active. The entry is writable and
+    execute-disable.
+
+    Like [`PageMap::set`], this accesses page tables via their physical addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::W);
+
+    page_map.install_self_map();
+    assert_eq!(page_map.check_self_map(), Ok(()));
+    assert_eq!(page_map.size(), 0x1000);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn install_self_map(&mut self) {
+        let address = self.address;
+        self.pml4_mut()[SELF_MAP_INDEX] = PML4E::with_flags(
+            address,
+            EntryFlags::new().writable(true).execute_disable(true),
+        );
+    }
+
+    /** Check that PML4 entry [`SELF_MAP_INDEX`] points back at the PML4.
+
+    Like [`PageMap::set`], this accesses page tables via their physical addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{AdoptError, EntryFlags, PageMap, PML4E, SELF_MAP_INDEX};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    assert_eq!(page_map.check_self_map(), Err(AdoptError::MissingSelfMap));
+
+    let other_table = allocate_pages(1);
+    let flags = EntryFlags::new().writable(true).execute_disable(true);
+    page_map.pml4_mut()[SELF_MAP_INDEX] = PML4E::with_flags(other_table, flags);
+    assert_eq!(
+        page_map.check_self_map(),
+        Err(AdoptError::WrongSelfMap { address: other_table })
+    );
+
+    let address = page_map.address();
+    page_map.pml4_mut()[SELF_MAP_INDEX] = PML4E::with_flags(address, flags);
+    assert_eq!(page_map.check_self_map(), Ok(()));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn check_self_map(&self) -> Result<(), AdoptError> {
+        self.check_self_map_entry(&self.pml4()[SELF_MAP_INDEX])
+    }
+
+    fn check_self_map_entry(&self, pml4e: &PML4E) -> Result<(), AdoptError> {
+        if !pml4e.present() {
+            Err(AdoptError::MissingSelfMap)
+        } else if pml4e.pdpt_address() != self.address {
+            Err(AdoptError::WrongSelfMap {
+                address: pml4e.pdpt_address(),
+            })
+        } else {
+            Ok(())
         }
     }
 
@@ -126,277 +836,2205 @@ impl PageMap {
 
     /// The total amount of mapped memory, in bytes.
     pub fn size(&self) -> usize {
-        let mut total = 0;
+        self.statistics().mapped_bytes
+    }
+
+    /** Count the page map's tables and pages in one walk.
 
-        for pdpt in self.pml4().iter().filter_map(|pml4e| pml4e.pdpt()) {
-            for pd in pdpt.iter().filter_map(|pdpte| pdpte.pd()) {
-                for pt in pd.iter().filter_map(|pde| pde.pt()) {
-                    for _pte in pt.iter().filter(|pte| pte.present()) {
-                        total += Self::PAGE_SIZE;
+    A self-map entry (see [`SELF_MAP_INDEX`]) points back at the PML4, so it isn't counted as a
+    PDPT.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{EntryFlags, PageMap, PageMapFlags, PageMapStats, PML4E, SELF_MAP_INDEX};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    assert_eq!(page_map.statistics(), PageMapStats::default());
+    assert_eq!(page_map.statistics().table_count(), 1);
+
+    // Two PTs: one for 0x1000 and 0x2000, and one for 0x40_0000.
+    for address in [0x1000, 0x2000, 0x40_0000] {
+        page_map.set(&mut allocate_pages, address, address, PageMapFlags::W);
+    }
+    page_map.set_2mib(&mut allocate_pages, 0x60_0000, 0x60_0000, PageMapFlags::W);
+
+    // A 1GiB page at 0x4000_0000 (XD, PS, W and P).
+    let pdpt = page_map.pml4_mut()[0].pdpt_mut().unwrap();
+    unsafe { *(&mut pdpt[1] as *mut _ as *mut u64) = 1 << 63 | 0x4000_0000 | 0x83 };
+
+    // The self-map isn't a PDPT.
+    let address = page_map.address();
+    page_map.pml4_mut()[SELF_MAP_INDEX] = PML4E::with_flags(address, EntryFlags::new());
+
+    let stats = page_map.statistics();
+    assert_eq!(
+        stats,
+        PageMapStats {
+            pdpts: 1,
+            pds: 1,
+            pts: 2,
+            pages_4kib: 3,
+            pages_2mib: 1,
+            pages_1gib: 1,
+            mapped_bytes: 3 * 0x1000 + 0x20_0000 + 0x4000_0000,
+        }
+    );
+    assert_eq!(stats.table_count(), 5);
+    assert_eq!(page_map.size(), stats.mapped_bytes);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn statistics(&self) -> PageMapStats {
+        let mut stats = PageMapStats::default();
+
+        for (pml4_index, pml4e) in self.pml4().iter().enumerate() {
+            if pml4_index == SELF_MAP_INDEX && pml4e.pdpt_address() == self.address {
+                continue;
+            }
+            let Some(pdpt) = pml4e.pdpt() else {
+                continue;
+            };
+            stats.pdpts += 1;
+
+            for pdpte in pdpt.iter() {
+                if pdpte.present() && pdpte.page_size() {
+                    stats.pages_1gib += 1;
+                    stats.mapped_bytes += PageSize::Size1GiB.bytes() as usize;
+                } else if let Some(pd) = pdpte.pd() {
+                    stats.pds += 1;
+
+                    for pde in pd.iter() {
+                        if pde.present() && pde.page_size() {
+                            stats.pages_2mib += 1;
+                            stats.mapped_bytes += PageSize::Size2MiB.bytes() as usize;
+                        } else if let Some(pt) = pde.pt() {
+                            stats.pts += 1;
+
+                            for _pte in pt.iter().filter(|pte| pte.present()) {
+                                stats.pages_4kib += 1;
+                                stats.mapped_bytes += Self::PAGE_SIZE;
+                            }
+                        }
                     }
                 }
             }
         }
 
-        total
+        stats
+    }
+
+    /** Find the physical address that a virtual address maps to.
+
+    Returns `None` when the address isn't mapped. Like [`PageMap::set`], this accesses page
+    tables via their physical addresses.
+    */
+    pub fn translate(&self, virtual_address: u64) -> Option<u64> {
+        let page_map_indices = address_to_page_map_indices(virtual_address);
+
+        let pdpt = self.pml4()[page_map_indices.pml4].pdpt()?;
+        let pdpte = &pdpt[page_map_indices.pdpt];
+        if pdpte.present() && pdpte.page_size() {
+            let page_mask = PageSize::Size1GiB.bytes() - 1;
+            let page_address = pdpte.value() & ADDRESS_MASK & !page_mask;
+            return Some(page_address | (virtual_address & page_mask));
+        }
+
+        let pd = pdpte.pd()?;
+        let pde = &pd[page_map_indices.pd];
+        if pde.present() && pde.page_size() {
+            let page_mask = PageSize::Size2MiB.bytes() - 1;
+            let page_address = pde.value() & ADDRESS_MASK & !page_mask;
+            return Some(page_address | (virtual_address & page_mask));
+        }
+
+        let pt = pde.pt()?;
+        let pte = &pt[page_map_indices.pt];
+        if pte.present() {
+            Some(pte.page_address() | (virtual_address & 0xfff))
+        } else {
+            None
+        }
+    }
+
+    /** Read back the flags of the page that a virtual address is in.
+
+    A page is only writable if every entry on the way to it is writable, and only executable if
+    none of them disable execution. The caching flags come from the leaf entry. A guard page
+    (see [`PTE::guard`]) has [`PageMapFlags::NONE`].
+
+    Returns `None` when the address isn't mapped. Like [`PageMap::set`], this accesses page
+    tables via their physical addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+
+    let cases = [
+        PageMapFlags::default(),
+        PageMapFlags::W,
+        PageMapFlags::X,
+        PageMapFlags::W | PageMapFlags::X,
+        PageMapFlags::W | PageMapFlags::UNCACHED,
+        PageMapFlags::X | PageMapFlags::WRITE_THROUGH,
+        PageMapFlags::W | PageMapFlags::WRITE_COMBINING,
+        PageMapFlags::NONE,
+    ];
+    for (index, flags) in cases.into_iter().enumerate() {
+        let address = 0x1000 * (index as u64 + 1);
+        page_map.set(&mut allocate_pages, address, address, flags);
+    }
+    page_map.set_2mib(
+        &mut allocate_pages,
+        0x4000_0000,
+        0x20_0000,
+        PageMapFlags::W | PageMapFlags::WRITE_COMBINING,
+    );
+
+    for (index, flags) in cases.into_iter().enumerate() {
+        let address = 0x1000 * (index as u64 + 1);
+        assert_eq!(page_map.flags(address + 0x123), Some(flags));
+    }
+    assert!(!page_map.flags(0x1000).unwrap().writable());
+    assert!(page_map.flags(0x4000).unwrap().executable());
+    assert_eq!(
+        page_map.flags(0x4010_0000),
+        Some(PageMapFlags::W | PageMapFlags::WRITE_COMBINING)
+    );
+    assert_eq!(page_map.flags(0x10_0000), None);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn flags(&self, virtual_address: u64) -> Option<PageMapFlags> {
+        let page_map_indices = address_to_page_map_indices(virtual_address);
+
+        let pml4e = &self.pml4()[page_map_indices.pml4];
+        let mut writeable = pml4e.writable();
+        let mut executable = pml4e.executable();
+
+        let pdpt = pml4e.pdpt()?;
+        let pdpte = &pdpt[page_map_indices.pdpt];
+        writeable &= pdpte.writable();
+        executable &= pdpte.executable();
+        if pdpte.present() && pdpte.page_size() {
+            // In an entry that maps a page, the PAT bit is bit 12, because bit 7 is PS.
+            return Some(PageMapFlags::from_leaf(
+                pdpte.value(),
+                1 << 12,
+                writeable,
+                executable,
+            ));
+        }
+
+        let pd = pdpte.pd()?;
+        let pde = &pd[page_map_indices.pd];
+        writeable &= pde.writable();
+        executable &= pde.executable();
+        if pde.present() && pde.page_size() {
+            return Some(PageMapFlags::from_leaf(
+                pde.value(),
+                1 << 12,
+                writeable,
+                executable,
+            ));
+        }
+
+        let pt = pde.pt()?;
+        let pte = &pt[page_map_indices.pt];
+        if pte.is_guard() {
+            Some(PageMapFlags::NONE)
+        } else if pte.present() {
+            Some(PageMapFlags::from_leaf(
+                pte.value(),
+                1 << 7,
+                writeable && pte.writable(),
+                executable && pte.executable(),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /** The raw entries that the page walk for `virtual_address` reads, from the PML4E down.
+
+    An entry is `None` when the walk stops before reaching it: because the entry above it isn't
+    present, or maps a huge page. The last entry that's `Some` is where the translation
+    succeeded or failed. Like [`PageMap::set`], this accesses page tables via their physical
+    addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{MappingPath, PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set(&mut allocate_pages, 0x1000, 0x9000, PageMapFlags::W);
+    page_map.set_2mib(&mut allocate_pages, 0x40_0000, 0x60_0000, PageMapFlags::R);
+
+    // Mapped by a PTE.
+    let path = page_map.lookup(0x1000);
+    assert!(path.pml4e.is_some() && path.pdpte.is_some() && path.pde.is_some());
+    assert_eq!(path.pte.map(|pte| pte & 0xf_ffff_ffff_f000), Some(0x9000));
+
+    // The PT exists, but this PTE isn't present.
+    let path = page_map.lookup(0x2000);
+    assert_eq!(path.pte, Some(0));
+
+    // No PT for this 2MiB region, so the walk stops at the PDE.
+    let path = page_map.lookup(0x20_0000);
+    assert_eq!((path.pde, path.pte), (Some(0), None));
+
+    // A 2MiB page is mapped by its PDE, so there's no PTE.
+    let path = page_map.lookup(0x40_0000);
+    assert_eq!(path.pde.map(|pde| pde & 0xf_ffff_ffe0_0000), Some(0x60_0000));
+    assert_eq!(path.pte, None);
+
+    // Nothing in this PML4E's 512GiB.
+    let path = page_map.lookup(0x80_0000_0000);
+    assert_eq!(
+        path,
+        MappingPath {
+            pml4e: Some(0),
+            pdpte: None,
+            pde: None,
+            pte: None
+        }
+    );
+    ```
+    */
+    pub fn lookup(&self, virtual_address: u64) -> MappingPath {
+        let page_map_indices = address_to_page_map_indices(virtual_address);
+        let mut path = MappingPath {
+            pml4e: None,
+            pdpte: None,
+            pde: None,
+            pte: None,
+        };
+
+        let pml4e = &self.pml4()[page_map_indices.pml4];
+        path.pml4e = Some(pml4e.value());
+
+        let Some(pdpt) = pml4e.pdpt() else {
+            return path;
+        };
+        let pdpte = &pdpt[page_map_indices.pdpt];
+        path.pdpte = Some(pdpte.value());
+
+        let Some(pd) = pdpte.pd() else {
+            return path;
+        };
+        let pde = &pd[page_map_indices.pd];
+        path.pde = Some(pde.value());
+
+        let Some(pt) = pde.pt() else {
+            return path;
+        };
+        path.pte = Some(pt[page_map_indices.pt].value());
+
+        path
+    }
+
+    /** Find `len` bytes of unmapped virtual memory, at or after `start_hint`.
+
+    Returns the address of the first run of `ceil(len / 4096)` consecutive 4KiB pages that are
+    canonical (see [`is_canonical`]), not mapped, and not guard pages (see [`PTE::guard`]).
+    A run never crosses the non-canonical gap: a `start_hint` inside the gap starts the search
+    at the higher half. PML4 entries that point back at the PML4 (a recursive self-map) are
+    treated as mapped. Returns `None` when there's no such run below the top of the address
+    space.
+
+    Missing tables are skipped as a whole, so large unmapped regions are cheap to search. Like
+    [`PageMap::set`], this accesses page tables via their physical addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::default());
+    page_map.set(&mut allocate_pages, 0x4000, 0x4000, PageMapFlags::NONE);
+
+    // 0x2000 and 0x3000 are free, but 0x4000 is a guard page.
+    assert_eq!(page_map.find_free_range(0x2000, 0x1000), Some(0x2000));
+    assert_eq!(page_map.find_free_range(0x2001, 0x1000), Some(0x5000));
+    assert_eq!(page_map.find_free_range(0x1000, 0x0), Some(0x0));
+
+    // Hints are rounded up to a page boundary.
+    assert_eq!(page_map.find_free_range(0x1000, 0x1001), Some(0x2000));
+
+    // Ranges don't cross the non-canonical gap.
+    assert_eq!(
+        page_map.find_free_range(0x2000, 0x0000_7fff_ffff_f000),
+        Some(0xffff_8000_0000_0000)
+    );
+    assert_eq!(
+        page_map.find_free_range(0x1000, 0x0000_8000_0000_0000),
+        Some(0xffff_8000_0000_0000)
+    );
+    assert_eq!(page_map.find_free_range(0x2000, 0xffff_ffff_ffff_f000), None);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn find_free_range(&self, len: usize, start_hint: u64) -> Option<u64> {
+        assert!(len > 0, "can't find an empty range");
+
+        const HIGHER_HALF_START: u64 = 0xffff_8000_0000_0000;
+        const LOWER_HALF_END: u64 = 0x0000_8000_0000_0000;
+
+        let len = (len as u64).checked_add(0xfff)? & !0xfff;
+
+        let mut address = start_hint.checked_add(0xfff)? & !0xfff;
+        if !is_canonical(address) {
+            address = HIGHER_HALF_START;
+        }
+
+        let mut run_start = address;
+        loop {
+            let (free, region_size) = self.region_at(address);
+
+            // `None` when the region is the last one in the address space.
+            let region_end = (address & !(region_size - 1)).checked_add(region_size);
+
+            if free {
+                let run_len = match region_end {
+                    Some(region_end) => region_end - run_start,
+                    None => (u64::MAX - run_start) + 1,
+                };
+                if run_len >= len {
+                    return Some(run_start);
+                }
+            }
+
+            address = region_end?;
+            if address == LOWER_HALF_END {
+                address = HIGHER_HALF_START;
+                run_start = address;
+            } else if !free {
+                run_start = address;
+            }
+        }
+    }
+
+    /** Whether the virtual address is free for [`PageMap::find_free_range`], and the size of the
+    largest aligned region around it that's free or used as a whole.
+    */
+    fn region_at(&self, virtual_address: u64) -> (bool, u64) {
+        let page_map_indices = address_to_page_map_indices(virtual_address);
+
+        let pml4e = &self.pml4()[page_map_indices.pml4];
+        let pml4e_size = 512 * PageSize::Size1GiB.bytes();
+        if pml4e.present() && pml4e.pdpt_address() == self.address {
+            return (false, pml4e_size);
+        }
+        let Some(pdpt) = pml4e.pdpt() else {
+            return (true, pml4e_size);
+        };
+
+        let pdpte = &pdpt[page_map_indices.pdpt];
+        let Some(pd) = pdpte.pd() else {
+            return (!pdpte.present(), PageSize::Size1GiB.bytes());
+        };
+
+        let pde = &pd[page_map_indices.pd];
+        let Some(pt) = pde.pt() else {
+            return (!pde.present(), PageSize::Size2MiB.bytes());
+        };
+
+        let pte = &pt[page_map_indices.pt];
+        (
+            !pte.present() && !pte.is_guard(),
+            PageSize::Size4KiB.bytes(),
+        )
     }
 
     pub fn pml4_mut(&mut self) -> &mut [PML4E; 512] {
-        unsafe { core::mem::transmute(self.address) }
+        unsafe { &mut *(self.address as *mut [PML4E; 512]) }
     }
 
     pub fn pml4(&self) -> &[PML4E; 512] {
-        unsafe { core::mem::transmute(self.address) }
+        unsafe { &*(self.address as *const [PML4E; 512]) }
     }
 
-    /// Map a virtual page address to a physical page address.
+    /** Map a virtual page address to a physical page address.
+
+    With [`PageMapFlags::NONE`], the page is made a guard page instead (see [`PTE::guard`]).
+
+    Returns the page's previous PTE, so that a caller that's remapping a page can find out what
+    it was mapped to (e.g. to free the old frame). The previous PTE isn't present when the page
+    wasn't mapped.
+
+    Panics if either address isn't 4KiB aligned, or if `virtual_page_address` isn't canonical
+    (see [`is_canonical`]). Only debug builds say which address was invalid: formatting the
+    message pulls in code that release builds of the kernel shouldn't carry. Use
+    [`PageMap::try_set`] to handle invalid addresses instead.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_page = || page_pool.allocate(1);
+    let mut allocate_pages = |count: usize| {
+        assert_eq!(count, 1);
+        allocate_page()
+    };
+    let mut page_map = PageMap::new(&mut allocate_pages);
+
+    let previous = page_map.set(&mut allocate_pages, 0x1000, 0x5000, PageMapFlags::default());
+    assert!(!previous.present());
+
+    let previous = page_map.set(&mut allocate_pages, 0x1000, 0x7000, PageMapFlags::W);
+    assert!(previous.present());
+    assert_eq!(previous.page_address(), 0x5000);
+    assert_eq!(page_map.translate(0x1000), Some(0x7000));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+
+    An intermediate entry is executable while any page beneath it is executable:
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+
+    // Two PTs under the same PDPTE.
+    page_map.set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::X);
+    page_map.set(&mut allocate_pages, 0x40_0000, 0x2000, PageMapFlags::W);
+    page_map.set(&mut allocate_pages, 0x40_1000, 0x3000, PageMapFlags::W);
+
+    // (PML4E, PDPTE, PDE 0, PDE 2) execute-disable bits.
+    let execute_disable = |page_map: &PageMap| {
+        let pml4e = &page_map.pml4()[0];
+        let pdpte = &pml4e.pdpt().unwrap()[0];
+        let pd = pdpte.pd().unwrap();
+        (
+            pml4e.execute_disable(),
+            pdpte.execute_disable(),
+            pd[0].execute_disable(),
+            pd[2].execute_disable(),
+        )
+    };
+    assert_eq!(execute_disable(&page_map), (false, false, false, true));
+
+    page_map.set(&mut allocate_pages, 0x40_1000, 0x3000, PageMapFlags::X);
+    assert_eq!(execute_disable(&page_map), (false, false, false, false));
+
+    // Making the first page non-executable leaves the path to the other one executable.
+    page_map.set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::R);
+    assert_eq!(execute_disable(&page_map), (false, false, true, false));
+
+    page_map.set(&mut allocate_pages, 0x40_1000, 0x3000, PageMapFlags::NONE);
+    assert_eq!(execute_disable(&page_map), (true, true, true, true));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
     pub fn set(
         &mut self,
         allocate_pages: &mut dyn FnMut(usize) -> u64,
         virtual_page_address: u64,
         physical_page_address: u64,
         flags: PageMapFlags,
-    ) {
-        assert_eq!(
-            virtual_page_address & !0xfff,
-            virtual_page_address,
+    ) -> PTE {
+        debug_assert!(
+            virtual_page_address & 0xfff == 0,
             "virtual address {:#x} isn't 4KiB aligned",
             virtual_page_address
         );
 
-        assert_eq!(
-            physical_page_address & !0xfff,
-            physical_page_address,
+        debug_assert!(
+            is_canonical(virtual_page_address),
+            "virtual address {:#x} isn't canonical",
+            virtual_page_address
+        );
+
+        debug_assert!(
+            physical_page_address & 0xfff == 0,
             "physical address {:#x} isn't 4KiB aligned",
             physical_page_address
         );
 
-        // All levels of the page table are created in read-only mode.
-        let default_execute_disable = true;
-        let default_writeable = false;
+        match self.try_set(
+            &mut |count| Some(allocate_pages(count)),
+            virtual_page_address,
+            physical_page_address,
+            flags,
+        ) {
+            Ok(previous) => previous,
+            Err(_) => panic!("invalid page mapping"),
+        }
+    }
 
-        // The requested permissions for this page
-        let writeable = flags.writeable;
-        let executable = flags.executable;
+    /** Like [`PageMap::set`], but returns an error instead of panicking.
 
-        let page_map_indices = address_to_page_map_indices(virtual_page_address);
+    Returns the page's previous PTE. Fails with:
 
-        // This could be better. `present()` followed by `unwrap()` looks like an antipattern.
-        let pml4 = self.pml4_mut();
-        let pml4e: &mut PML4E = &mut pml4[page_map_indices.pml4];
+    * [`MapError::Misaligned`] when either address isn't 4KiB aligned.
+    * [`MapError::NonCanonical`] when `virtual_page_address` isn't canonical.
+    * [`MapError::OutOfMemory`] when a page table couldn't be allocated. The page isn't mapped
+      in that case, but any tables that were allocated before the failure stay in the page map.
+    * [`MapError::HugePageConflict`] when a 2MiB or 1GiB page already covers
+      `virtual_page_address`. Nothing in the page map is changed in that case.
+
+    ## Examples
+
+    ```rust
+    # use common::paging::{MapError, PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_page = || page_pool.allocate(1);
+    // An allocator that runs out of memory after 3 pages.
+    let mut remaining_pages = 3;
+    let mut allocate_pages = |count: usize| {
+        assert_eq!(count, 1);
+        if remaining_pages == 0 {
+            None
+        } else {
+            remaining_pages -= 1;
+            Some(allocate_page())
+        }
+    };
+
+    // Uses 1 page for the PML4.
+    let mut page_map = PageMap::try_new(&mut allocate_pages).unwrap();
+
+    // Invalid addresses are rejected before anything is allocated.
+    assert!(matches!(
+        page_map.try_set(&mut allocate_pages, 0x1800, 0x1000, PageMapFlags::default()),
+        Err(MapError::Misaligned)
+    ));
+    assert!(matches!(
+        page_map.try_set(&mut allocate_pages, 0x1000, 0x1001, PageMapFlags::default()),
+        Err(MapError::Misaligned)
+    ));
+    assert!(matches!(
+        page_map.try_set(&mut allocate_pages, 0x8000_0000_0000, 0x1000, PageMapFlags::default()),
+        Err(MapError::NonCanonical)
+    ));
+
+    // Needs 3 pages for the PDPT, PD, and PT, but only 2 are left.
+    assert!(matches!(
+        page_map.try_set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::default()),
+        Err(MapError::OutOfMemory)
+    ));
+    assert_eq!(page_map.translate(0x1000), None);
+    ```
+
+    A 4KiB page can't be mapped inside a huge page, and a failed attempt doesn't change the huge
+    page's permissions:
+
+    ```rust
+    # use common::paging::{MapError, PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set_2mib(&mut allocate_pages, 0x20_0000, 0x20_0000, PageMapFlags::R);
+
+    assert!(matches!(
+        page_map.try_set(
+            &mut |count| Some(allocate_pages(count)),
+            0x20_1000,
+            0x1000,
+            PageMapFlags::W
+        ),
+        Err(MapError::HugePageConflict)
+    ));
+    assert_eq!(page_map.flags(0x20_1000), Some(PageMapFlags::R));
+    assert_eq!(page_map.translate(0x20_1000), Some(0x20_1000));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn try_set(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> Option<u64>,
+        virtual_page_address: u64,
+        physical_page_address: u64,
+        flags: PageMapFlags,
+    ) -> Result<PTE, MapError> {
+        if virtual_page_address & 0xfff != 0 || physical_page_address & 0xfff != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        if !is_canonical(virtual_page_address) {
+            return Err(MapError::NonCanonical);
+        }
+
+        // All levels of the page table are created in read-only mode.
+        let table_flags = EntryFlags::new().execute_disable(true);
+
+        // The requested permissions for this page
+        let writeable = flags.writeable;
+
+        let page_map_indices = address_to_page_map_indices(virtual_page_address);
+
+        // Checked before any entry is touched, so that a conflict leaves the page map unchanged.
+        if self.huge_page_at(&page_map_indices) {
+            return Err(MapError::HugePageConflict);
+        }
+
+        let pd = self.pd_mut_or_allocate(allocate_pages, &page_map_indices, writeable)?;
+        let pde = &mut pd[page_map_indices.pd];
+        if !pde.present() {
+            let pt_address = allocate_pages(1).ok_or(MapError::OutOfMemory)?;
+            unsafe {
+                init_memory(pt_address as *mut u64, 512, 0);
+            }
+            *pde = PDE::with_flags(pt_address, table_flags);
+        }
+        if writeable {
+            pde.set_writable(true);
+        }
+
+        // The PDE is present and isn't a 2MiB page, so it points at a PT.
+        let pt = pde.pt_mut().unwrap();
+        let pte = PTE::from_flags(physical_page_address, flags);
+        let executable = pte.present() && pte.executable();
+        let previous = core::mem::replace(&mut pt[page_map_indices.pt], pte);
+
+        if executable {
+            self.allow_execute(&page_map_indices);
+        } else if previous.present() && previous.executable() {
+            self.update_execute_disable(&page_map_indices);
+        }
+
+        Ok(previous)
+    }
+
+    /** Move the 4KiB page mapped at `old_virtual_address` to `new_virtual_address`.
+
+    The physical page at `old_virtual_address` is mapped at `new_virtual_address` with `flags`,
+    and then `old_virtual_address` is unmapped. Returns the physical page's address. Fails with:
+
+    * [`MapError::Misaligned`] when either address isn't 4KiB aligned.
+    * [`MapError::NonCanonical`] when `new_virtual_address` isn't canonical.
+    * [`MapError::NotMapped`] when no 4KiB page is mapped at `old_virtual_address`.
+    * [`MapError::AlreadyMapped`] when something is mapped at `new_virtual_address`.
+    * [`MapError::OutOfMemory`] when a page table couldn't be allocated.
+
+    The page map is unchanged when `remap` fails, apart from page tables allocated before an
+    [`MapError::OutOfMemory`] failure.
+
+    Like [`PageMap::set`], this accesses page tables via their physical addresses. When the page
+    map is the active one (its root is in CR3), both addresses are invalidated with
+    [`crate::registers::invlpg`] after the change, so no stale translation survives in the TLB.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{MapError, PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| Some(page_pool.allocate(count));
+    let mut page_map = PageMap::try_new(&mut allocate_pages).unwrap();
+    page_map.try_set(&mut allocate_pages, 0x1000, 0x9000, PageMapFlags::W).unwrap();
+
+    assert!(matches!(
+        page_map.remap(&mut allocate_pages, 0x1000, 0x40_0000, PageMapFlags::R),
+        Ok(0x9000)
+    ));
+    assert_eq!(page_map.translate(0x40_0123), Some(0x9123));
+    assert_eq!(page_map.flags(0x40_0000), Some(PageMapFlags::R));
+    assert_eq!(page_map.translate(0x1000), None);
+
+    // The source has to be mapped, and the destination has to be free.
+    assert!(matches!(
+        page_map.remap(&mut allocate_pages, 0x1000, 0x2000, PageMapFlags::R),
+        Err(MapError::NotMapped)
+    ));
+    page_map.try_set(&mut allocate_pages, 0x2000, 0xa000, PageMapFlags::R).unwrap();
+    assert!(matches!(
+        page_map.remap(&mut allocate_pages, 0x2000, 0x40_0000, PageMapFlags::R),
+        Err(MapError::AlreadyMapped)
+    ));
+    assert_eq!(page_map.translate(0x2000), Some(0xa000));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn remap(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> Option<u64>,
+        old_virtual_address: u64,
+        new_virtual_address: u64,
+        flags: PageMapFlags,
+    ) -> Result<u64, MapError> {
+        if old_virtual_address & 0xfff != 0 || new_virtual_address & 0xfff != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        if !is_canonical(new_virtual_address) {
+            return Err(MapError::NonCanonical);
+        }
+
+        let old_page_map_indices = address_to_page_map_indices(old_virtual_address);
+        let physical_page_address = self
+            .pt_mut(&old_page_map_indices)
+            .map(|pt| &pt[old_page_map_indices.pt])
+            .filter(|pte| pte.present())
+            .ok_or(MapError::NotMapped)?
+            .page_address();
+
+        if self.translate(new_virtual_address).is_some() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        self.try_set(
+            allocate_pages,
+            new_virtual_address,
+            physical_page_address,
+            flags,
+        )?;
+
+        let old_pte = core::mem::replace(
+            &mut self.pt_mut(&old_page_map_indices).unwrap()[old_page_map_indices.pt],
+            PTE::unset(),
+        );
+        if old_pte.present() && old_pte.executable() {
+            self.update_execute_disable(&old_page_map_indices);
+        }
+
+        if self.is_active() {
+            unsafe {
+                crate::registers::invlpg(old_virtual_address);
+                crate::registers::invlpg(new_virtual_address);
+            }
+        }
+
+        Ok(physical_page_address)
+    }
+
+    /** Whether this page map's root is in CR3.
+
+    Always false on a hosted target (as in doctests): reading CR3 faults outside of ring 0, and a
+    hosted [`PageMap`] is never the one that the processor uses.
+    */
+    fn is_active(&self) -> bool {
+        cfg!(any(target_os = "none", target_os = "uefi"))
+            && self.address == Self::current_root_address()
+    }
+
+    /** Map a 2MiB virtual page to a 2MiB physical page, using a single PDE.
+
+    Both addresses must be 2MiB aligned, and nothing can be mapped in the 2MiB virtual range yet.
+    */
+    pub fn set_2mib(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> u64,
+        virtual_page_address: u64,
+        physical_page_address: u64,
+        flags: PageMapFlags,
+    ) {
+        let page_mask = PageSize::Size2MiB.bytes() - 1;
+
+        assert_eq!(
+            virtual_page_address & !page_mask,
+            virtual_page_address,
+            "virtual address {:#x} isn't 2MiB aligned",
+            virtual_page_address
+        );
+
+        assert!(
+            is_canonical(virtual_page_address),
+            "virtual address {:#x} isn't canonical",
+            virtual_page_address
+        );
+
+        assert_eq!(
+            physical_page_address & !page_mask,
+            physical_page_address,
+            "physical address {:#x} isn't 2MiB aligned",
+            physical_page_address
+        );
+
+        assert!(
+            !(flags.write_combining && (flags.uncached || flags.write_through)),
+            "write-combining can't be combined with other memory types"
+        );
+
+        assert!(flags.readable, "guard pages must be 4KiB pages");
+
+        let writeable = flags.writeable;
+        let executable = flags.executable;
+
+        let page_map_indices = address_to_page_map_indices(virtual_page_address);
+
+        let pd = self
+            .pd_mut_or_allocate(
+                &mut |count| Some(allocate_pages(count)),
+                &page_map_indices,
+                writeable,
+            )
+            .expect("can't map a 2MiB page inside a 1GiB page");
+        let pde = &mut pd[page_map_indices.pd];
+        assert!(
+            !pde.present(),
+            "virtual address {:#x} is already mapped",
+            virtual_page_address
+        );
+
+        let mut new_pde = PDE::with_flags(
+            physical_page_address,
+            EntryFlags::new()
+                .execute_disable(!executable)
+                .pcd(flags.uncached)
+                .pwt(flags.write_through)
+                .writable(writeable),
+        );
+        new_pde.set_page_size(true);
+        if flags.write_combining {
+            // In an entry that maps a page, the PAT bit is bit 12, because bit 7 is PS.
+            new_pde.0 |= 1 << 12;
+        }
+        *pde = new_pde;
+
+        if executable {
+            self.allow_execute(&page_map_indices);
+        }
+    }
+
+    /** Map `len` bytes starting at `start` to the same physical addresses.
+
+    2MiB pages are used for every 2MiB aligned chunk of the range that has no page table yet,
+    and 4KiB pages are used for the rest. Guard pages ([`PageMapFlags::NONE`]) are always 4KiB
+    pages.
+
+    Panics if `start` or `len` aren't 4KiB aligned, or if any page in the range is already mapped
+    (the memory that was mapped there would become inaccessible).
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags, PageSize};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+
+    // 4KiB pages from 0x1f_f000 to 0x20_0000, one 2MiB page, then 4KiB pages up to 0x40_3000.
+    let start = 0x1f_f000;
+    let len = 0x20_4000;
+    page_map.map_identity(&mut allocate_pages, start, len, PageMapFlags::W);
+
+    for address in [start, start + 0x123, 0x20_0000, 0x2a_bcde, 0x40_0000, start + len - 1] {
+        assert_eq!(page_map.translate(address), Some(address));
+    }
+    assert_eq!(page_map.translate(start - 1), None);
+    assert_eq!(page_map.translate(start + len), None);
+    assert_eq!(page_map.size() as u64, len);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn map_identity(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> u64,
+        start: u64,
+        len: u64,
+        flags: PageMapFlags,
+    ) {
+        assert_eq!(
+            start & !0xfff,
+            start,
+            "address {:#x} isn't 4KiB aligned",
+            start
+        );
+        assert_eq!(len & !0xfff, len, "length {:#x} isn't 4KiB aligned", len);
+
+        let huge_page_size = PageSize::Size2MiB.bytes();
+        let end = start + len;
+
+        let mut address = start;
+        while address < end {
+            let huge_page_fits = flags.readable
+                && address & (huge_page_size - 1) == 0
+                && address + huge_page_size <= end
+                && !self.has_pd_entry(address);
+
+            if huge_page_fits {
+                self.set_2mib(allocate_pages, address, address, flags);
+                address += huge_page_size;
+            } else {
+                assert!(
+                    self.translate(address).is_none(),
+                    "page {:#x} is already mapped",
+                    address
+                );
+                self.set(allocate_pages, address, address, flags);
+                address += Self::PAGE_SIZE as u64;
+            }
+        }
+    }
+
+    /** Map `len` bytes of device memory (e.g. a BAR) at `physical_start` to `virtual_start`.
+
+    The pages are writable, uncached ([`PageMapFlags::UNCACHED`]) and not executable, which is
+    what memory-mapped device registers need. 4KiB pages are used throughout.
+
+    Panics if `virtual_start`, `physical_start` or `len` aren't 4KiB aligned, or if any page in
+    the range is already mapped.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.map_mmio(&mut allocate_pages, 0x40_0000, 0xfebf_0000, 0x2000);
+
+    assert_eq!(page_map.translate(0x40_1004), Some(0xfebf_1004));
+    assert_eq!(page_map.translate(0x40_2000), None);
+    assert_eq!(page_map.flags(0x40_0000), Some(PageMapFlags::W | PageMapFlags::UNCACHED));
+
+    let pd = page_map.pml4()[0].pdpt().unwrap()[0].pd().unwrap();
+    for pte in &pd[2].pt().unwrap()[0..2] {
+        let pte = pte.decode();
+        assert!(pte.present && pte.writable && pte.pcd && pte.execute_disable);
+    }
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn map_mmio(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> u64,
+        virtual_start: u64,
+        physical_start: u64,
+        len: u64,
+    ) {
+        for address in [virtual_start, physical_start] {
+            assert_eq!(
+                address & !0xfff,
+                address,
+                "address {:#x} isn't 4KiB aligned",
+                address
+            );
+        }
+        assert_eq!(len & !0xfff, len, "length {:#x} isn't 4KiB aligned", len);
+
+        for offset in (0..len).step_by(Self::PAGE_SIZE) {
+            let virtual_address = virtual_start + offset;
+            assert!(
+                self.translate(virtual_address).is_none(),
+                "page {:#x} is already mapped",
+                virtual_address
+            );
+            self.set(
+                allocate_pages,
+                virtual_address,
+                physical_start + offset,
+                PageMapFlags::W | PageMapFlags::UNCACHED,
+            );
+        }
+    }
+
+    /** Create the page tables for `num_pages` 4KiB pages starting at `virtual_start`, without
+    mapping the pages.
+
+    The PML4E, PDPTE and PDE on the way to each page are made present, but each PTE is left
+    unset ([`PTE::unset`]), so an access to a reserved page causes a page fault. [`PageMap::set`]
+    maps a reserved page without allocating any tables. This is for demand paging, where the
+    page fault handler backs the page with a frame.
+
+    Panics if `virtual_start` isn't 4KiB aligned or isn't canonical, if a page in the range is
+    already mapped, or if the range overlaps a 2MiB or 1GiB page.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+
+    // Crosses from the first PT into the second.
+    let start = 0x1f_e000;
+    page_map.reserve(&mut allocate_pages, start, 4);
+
+    let pd = page_map.pml4()[0].pdpt().unwrap()[0].pd().unwrap();
+    for (pde, pte_indices) in [(&pd[0], 510..512), (&pd[1], 0..2)] {
+        let pt = pde.pt().unwrap();
+        assert!(pte_indices.into_iter().all(|index| !pt[index].present()));
+    }
+    for page in 0..4 {
+        assert_eq!(page_map.translate(start + page * 0x1000), None);
+    }
+    assert_eq!(page_map.size(), 0);
+
+    // Filling in a reserved page doesn't allocate.
+    let mut no_allocation = |_| panic!("reserved pages already have page tables");
+    page_map.set(&mut no_allocation, start, 0x5000, PageMapFlags::W);
+    assert_eq!(page_map.translate(start), Some(0x5000));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn reserve(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> u64,
+        virtual_start: u64,
+        num_pages: usize,
+    ) {
+        assert_eq!(
+            virtual_start & !0xfff,
+            virtual_start,
+            "virtual address {:#x} isn't 4KiB aligned",
+            virtual_start
+        );
+
+        // All levels of the page table are created in read-only mode.
+        let table_flags = EntryFlags::new().execute_disable(true);
+
+        for page in 0..num_pages as u64 {
+            let virtual_page_address = virtual_start + page * Self::PAGE_SIZE as u64;
+            assert!(
+                is_canonical(virtual_page_address),
+                "virtual address {:#x} isn't canonical",
+                virtual_page_address
+            );
+
+            let page_map_indices = address_to_page_map_indices(virtual_page_address);
+
+            let pd = self
+                .pd_mut_or_allocate(
+                    &mut |count| Some(allocate_pages(count)),
+                    &page_map_indices,
+                    false,
+                )
+                .expect("can't reserve a 4KiB page inside a 1GiB page");
+            let pde = &mut pd[page_map_indices.pd];
+            if !pde.present() {
+                let (pt_address, _) = allocate_table::<PTE>(allocate_pages);
+                *pde = PDE::with_flags(pt_address, table_flags);
+            }
+
+            let pt = pde
+                .pt_mut()
+                .expect("can't reserve a 4KiB page inside a 2MiB page");
+            let pte = &mut pt[page_map_indices.pt];
+            assert!(
+                !pte.present(),
+                "virtual address {:#x} is already mapped",
+                virtual_page_address
+            );
+            *pte = PTE::unset();
+        }
+    }
+
+    /// Whether the PD entry that covers `virtual_address` is in use (including by a 1GiB page).
+    fn has_pd_entry(&self, virtual_address: u64) -> bool {
+        let page_map_indices = address_to_page_map_indices(virtual_address);
+
+        let Some(pdpt) = self.pml4()[page_map_indices.pml4].pdpt() else {
+            return false;
+        };
+        let pdpte = &pdpt[page_map_indices.pdpt];
+        if pdpte.present() && pdpte.page_size() {
+            return true;
+        }
+
+        match pdpte.pd() {
+            None => false,
+            Some(pd) => pd[page_map_indices.pd].present(),
+        }
+    }
+
+    /// Whether a 2MiB or 1GiB page covers `page_map_indices`.
+    fn huge_page_at(&self, page_map_indices: &PageMapIndices) -> bool {
+        let Some(pdpt) = self.pml4()[page_map_indices.pml4].pdpt() else {
+            return false;
+        };
+        let pdpte = &pdpt[page_map_indices.pdpt];
+        if pdpte.present() && pdpte.page_size() {
+            return true;
+        }
+
+        match pdpte.pd() {
+            None => false,
+            Some(pd) => {
+                let pde = &pd[page_map_indices.pd];
+                pde.present() && pde.page_size()
+            }
+        }
+    }
+
+    /** Get the PD that covers `page_map_indices`, allocating a PDPT and PD if they're missing.
+
+    When `writeable` is true, the PML4E and PDPTE on the way to the PD are made writable.
+
+    ## Errors
+
+    * [`MapError::HugePageConflict`] when a 1GiB page covers `page_map_indices`. No entries are
+      changed in that case.
+    * [`MapError::OutOfMemory`] when a table couldn't be allocated.
+    */
+    fn pd_mut_or_allocate(
+        &mut self,
+        allocate_pages: &mut dyn FnMut(usize) -> Option<u64>,
+        page_map_indices: &PageMapIndices,
+        writeable: bool,
+    ) -> Result<&mut [PDE], MapError> {
+        // All levels of the page table are created in read-only mode.
+        let table_flags = EntryFlags::new().execute_disable(true);
+
+        let in_1gib_page = self.pml4()[page_map_indices.pml4]
+            .pdpt()
+            .map(|pdpt| &pdpt[page_map_indices.pdpt])
+            .is_some_and(|pdpte| pdpte.present() && pdpte.page_size());
+        if in_1gib_page {
+            return Err(MapError::HugePageConflict);
+        }
+
+        // This could be better. `present()` followed by `unwrap()` looks like an antipattern.
+        let pml4 = self.pml4_mut();
+        let pml4e: &mut PML4E = &mut pml4[page_map_indices.pml4];
         if !pml4e.present() {
-            let pdpt_address = allocate_pages(1);
+            let pdpt_address = allocate_pages(1).ok_or(MapError::OutOfMemory)?;
             unsafe {
                 init_memory(pdpt_address as *mut u64, 512, 0);
             }
 
-            *pml4e = PML4E::new(
-                default_execute_disable,
-                pdpt_address,
-                false,
-                false,
-                false,
-                default_writeable,
-            );
+            *pml4e = PML4E::with_flags(pdpt_address, table_flags);
         }
         if writeable {
             pml4e.set_writable(true);
         }
-        if executable {
-            pml4e.set_execute_disable(false);
-        }
 
         let pdpt = pml4e.pdpt_mut().unwrap();
         let pdpte = &mut pdpt[page_map_indices.pdpt];
         if !pdpte.present() {
-            let pd_address = allocate_pages(1);
+            let pd_address = allocate_pages(1).ok_or(MapError::OutOfMemory)?;
             unsafe {
                 init_memory(pd_address as *mut u64, 512, 0);
             }
-            *pdpte = PDPTE::new(
-                default_execute_disable,
-                pd_address,
-                false,
-                false,
-                false,
-                default_writeable,
-            );
+            *pdpte = PDPTE::with_flags(pd_address, table_flags);
         }
         if writeable {
             pdpte.set_writable(true);
         }
-        if executable {
-            pdpte.set_execute_disable(false);
+
+        // The PDPTE is present and isn't a 1GiB page, so it points at a PD.
+        Ok(pdpte.pd_mut().unwrap())
+    }
+
+    /// The PT that a 4KiB page's PTE is in, if there is one.
+    fn pt_mut(&mut self, page_map_indices: &PageMapIndices) -> Option<&mut [PTE]> {
+        self.pml4_mut()[page_map_indices.pml4].pdpt_mut()?[page_map_indices.pdpt].pd_mut()?
+            [page_map_indices.pd]
+            .pt_mut()
+    }
+
+    /* Note [Execute-disable in intermediate entries]
+
+    An intermediate entry's execute-disable bit applies to everything beneath it. It has to be
+    clear if any page beneath it is executable, and it should be set otherwise so that it doesn't
+    over-permission the pages that aren't executable.
+
+    Mapping an executable page can only clear bits, so [`PageMap::allow_execute`] clears them on
+    the page's path without looking at any other entries. Only unmapping an executable page (or
+    making it non-executable) can set them, and only then does
+    [`PageMap::update_execute_disable`] rescan the tables on the path.
+    */
+
+    /** Clear the execute-disable bits on the path to a new executable page. See Note
+    [Execute-disable in intermediate entries].
+    */
+    fn allow_execute(&mut self, page_map_indices: &PageMapIndices) {
+        let pml4e = &mut self.pml4_mut()[page_map_indices.pml4];
+        pml4e.set_execute_disable(false);
+
+        let pdpte = &mut pml4e.pdpt_mut().unwrap()[page_map_indices.pdpt];
+        pdpte.set_execute_disable(false);
+
+        // A PDE that maps a 2MiB page has its own execute-disable bit.
+        let pde = &mut pdpte.pd_mut().unwrap()[page_map_indices.pd];
+        if !pde.page_size() {
+            pde.set_execute_disable(false);
         }
+    }
 
-        let pd = pdpte.pd_mut().unwrap();
-        let pde = &mut pd[page_map_indices.pd];
-        if !pde.present() {
-            let pt_address = allocate_pages(1);
-            unsafe {
-                init_memory(pt_address as *mut u64, 512, 0);
+    /** Recompute the execute-disable bits of the intermediate entries that lead to a page,
+    after an executable page was removed from beneath them. See Note [Execute-disable in
+    intermediate entries].
+
+    Each entry on the path to the page is recomputed from its child table, starting at the
+    bottom.
+    */
+    fn update_execute_disable(&mut self, page_map_indices: &PageMapIndices) {
+        let pml4e = &mut self.pml4_mut()[page_map_indices.pml4];
+
+        let pdpt_executable = {
+            let pdpt = pml4e.pdpt_mut().unwrap();
+            let pdpte = &mut pdpt[page_map_indices.pdpt];
+
+            let pd_executable = {
+                let pd = pdpte.pd_mut().unwrap();
+                let pde = &mut pd[page_map_indices.pd];
+
+                // A PDE that maps a 2MiB page has its own execute-disable bit.
+                if let Some(pt_executable) = pde
+                    .pt()
+                    .map(|pt| pt.iter().any(|pte| pte.present() && !pte.execute_disable()))
+                {
+                    pde.set_execute_disable(!pt_executable);
+                }
+
+                pd.iter().any(|pde| pde.present() && !pde.execute_disable())
+            };
+            pdpte.set_execute_disable(!pd_executable);
+
+            pdpt.iter()
+                .any(|pdpte| pdpte.present() && !pdpte.execute_disable())
+        };
+        pml4e.set_execute_disable(!pdpt_executable);
+    }
+
+    /** Clear the execute-disable bit of every entry, making every mapped page executable.
+
+    For processors that don't support execute-disable (see
+    [`crate::registers::execute_disable_supported`]), where the bit is reserved. Like
+    [`PageMap::set`], this accesses page tables via their physical addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{PageMap, PageMapFlags};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::W);
+    page_map.set_2mib(&mut allocate_pages, 0x20_0000, 0x20_0000, PageMapFlags::default());
+    assert!(!page_map.flags(0x1000).unwrap().executable());
+
+    page_map.clear_execute_disable();
+    assert_eq!(page_map.flags(0x1000), Some(PageMapFlags::W | PageMapFlags::X));
+    assert_eq!(page_map.flags(0x20_0000), Some(PageMapFlags::X));
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn clear_execute_disable(&mut self) {
+        for pml4e in self.pml4_mut().iter_mut() {
+            let Some(pdpt) = pml4e.pdpt_mut() else {
+                continue;
+            };
+            for pdpte in pdpt.iter_mut() {
+                if let Some(pd) = pdpte.pd_mut() {
+                    for pde in pd.iter_mut() {
+                        if let Some(pt) = pde.pt_mut() {
+                            for pte in pt.iter_mut().filter(|pte| pte.present()) {
+                                pte.set_execute_disable(false);
+                            }
+                        }
+                        if pde.present() {
+                            pde.set_execute_disable(false);
+                        }
+                    }
+                }
+                if pdpte.present() {
+                    pdpte.set_execute_disable(false);
+                }
             }
-            *pde = PDE::new(
-                default_execute_disable,
-                pt_address,
-                false,
-                false,
-                false,
-                default_writeable,
-            );
+            pml4e.set_execute_disable(false);
         }
-        if writeable {
-            pde.set_writable(true);
+    }
+
+    /** Create a new page map with the same mappings as this one.
+
+    The clone gets its own PML4, PDPTs, PDs and PTs, but its leaf entries point at the same
+    physical pages as this map's leaf entries. The leaves are shared, not copied: changing a
+    mapping in one map doesn't affect the other, but writes to a mapped page are visible
+    through both. This is the layout needed for address spaces that share the kernel's
+    mappings.
+
+    Like [`PageMap::set`], this accesses page tables via their physical addresses, so it
+    only works while physical memory is identity-mapped (e.g. in the bootloader), and not
+    on the active page map.
+
+    A self-map entry (see [`SELF_MAP_INDEX`]) isn't copied as a PDPT: the clone's entry points
+    at the clone's own PML4 instead.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{EntryFlags, PageMap, PageMapFlags, PML4E, SELF_MAP_INDEX};
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set(&mut allocate_pages, 0x1000, 0x5000, PageMapFlags::W);
+    page_map.set(&mut allocate_pages, 0x40_0000, 0x6000, PageMapFlags::X);
+    page_map.set_2mib(&mut allocate_pages, 0x60_0000, 0x20_0000, PageMapFlags::W);
+    let address = page_map.address();
+    let flags = EntryFlags::new().writable(true).execute_disable(true);
+    page_map.pml4_mut()[SELF_MAP_INDEX] = PML4E::with_flags(address, flags);
+
+    let clone = page_map.clone_structure(&mut allocate_pages);
+    assert_ne!(clone.address(), page_map.address());
+    assert_eq!(clone.check_self_map(), Ok(()));
+    assert_eq!(clone.statistics(), page_map.statistics());
+
+    // Every page outside the self-map's window is mapped the same way.
+    let mappings = |page_map: &PageMap| {
+        let mut mappings = Vec::new();
+        page_map.debug(
+            &mut |_, _| {},
+            &mut |_, _| {},
+            &mut |_, _| {},
+            &mut |_, virtual_address, page_size, entry| {
+                if (virtual_address >> 39) & 0x1ff != SELF_MAP_INDEX as u64 {
+                    mappings.push((virtual_address, page_size, entry));
+                }
+            },
+        );
+        mappings
+    };
+    assert_eq!(mappings(&clone).len(), 3);
+    assert_eq!(mappings(&clone), mappings(&page_map));
+    assert_eq!(clone.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn clone_structure(&self, allocate_pages: &mut dyn FnMut(usize) -> u64) -> PageMap {
+        let mut clone = PageMap::new(allocate_pages);
+        let clone_address = clone.address;
+
+        for (pml4_index, (pml4e, new_pml4e)) in self
+            .pml4()
+            .iter()
+            .zip(clone.pml4_mut().iter_mut())
+            .enumerate()
+        {
+            if pml4_index == SELF_MAP_INDEX
+                && pml4e.present()
+                && pml4e.pdpt_address() == self.address
+            {
+                *new_pml4e = PML4E(pml4e.value() & !pml4e.pdpt_address() | clone_address);
+                continue;
+            }
+
+            match pml4e.pdpt() {
+                None => {
+                    *new_pml4e = PML4E(pml4e.value());
+                }
+                Some(pdpt) => {
+                    let (new_pdpt_address, new_pdpt) = allocate_table::<PDPTE>(allocate_pages);
+                    *new_pml4e = PML4E(pml4e.value() & !pml4e.pdpt_address() | new_pdpt_address);
+
+                    for (pdpte, new_pdpte) in pdpt.iter().zip(new_pdpt.iter_mut()) {
+                        match pdpte.pd() {
+                            None => {
+                                *new_pdpte = PDPTE(pdpte.value());
+                            }
+                            Some(pd) => {
+                                let (new_pd_address, new_pd) =
+                                    allocate_table::<PDE>(allocate_pages);
+                                *new_pdpte =
+                                    PDPTE(pdpte.value() & !pdpte.pd_address() | new_pd_address);
+
+                                for (pde, new_pde) in pd.iter().zip(new_pd.iter_mut()) {
+                                    match pde.pt() {
+                                        None => {
+                                            *new_pde = PDE(pde.value());
+                                        }
+                                        Some(pt) => {
+                                            let (new_pt_address, new_pt) =
+                                                allocate_table::<PTE>(allocate_pages);
+                                            *new_pde =
+                                                PDE(pde.value() & !pde.pt_address()
+                                                    | new_pt_address);
+
+                                            for (pte, new_pte) in pt.iter().zip(new_pt.iter_mut()) {
+                                                *new_pte = PTE(pte.value());
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
         }
-        if executable {
-            pde.set_execute_disable(false);
+
+        clone
+    }
+
+    /** Visit every present entry in the page map.
+
+    `debug_pml4e`, `debug_pdpte` and `debug_pde` are called for entries that point at another
+    table. `debug_page` is called for every entry that maps a page: PTEs, and PDEs / PDPTEs
+    that map huge pages (PS = 1). It receives the entry's index in its table, the virtual
+    address of the page, the page's size, and the decoded entry, whose address is the page's
+    frame.
+    */
+    pub fn debug(
+        &self,
+        debug_pml4e: &mut dyn FnMut(usize, &PML4E),
+        debug_pdpte: &mut dyn FnMut(usize, &PDPTE),
+        debug_pde: &mut dyn FnMut(usize, &PDE),
+        debug_page: &mut dyn FnMut(usize, u64, PageSize, DecodedEntry),
+    ) {
+        // In an entry that maps a huge page, bit 12 is PAT, not part of the frame's address.
+        let huge_page_entry = |mut decoded: DecodedEntry, page_size: PageSize| {
+            decoded.address &= !(page_size.bytes() - 1);
+            decoded
+        };
+
+        for (pml4_index, pml4e) in self.pml4().iter().enumerate() {
+            if let Some(pdpt) = pml4e.pdpt() {
+                debug_pml4e(pml4_index, pml4e);
+
+                for (pdpt_index, pdpte) in pdpt.iter().enumerate() {
+                    if pdpte.present() && pdpte.page_size() {
+                        debug_page(
+                            pdpt_index,
+                            page_map_indices_to_address(PageMapIndices {
+                                pml4: pml4_index,
+                                pdpt: pdpt_index,
+                                pd: 0,
+                                pt: 0,
+                            }),
+                            PageSize::Size1GiB,
+                            huge_page_entry(pdpte.decode(), PageSize::Size1GiB),
+                        );
+                    } else if let Some(pd) = pdpte.pd() {
+                        debug_pdpte(pdpt_index, pdpte);
+
+                        for (pd_index, pde) in pd.iter().enumerate() {
+                            if pde.present() && pde.page_size() {
+                                debug_page(
+                                    pd_index,
+                                    page_map_indices_to_address(PageMapIndices {
+                                        pml4: pml4_index,
+                                        pdpt: pdpt_index,
+                                        pd: pd_index,
+                                        pt: 0,
+                                    }),
+                                    PageSize::Size2MiB,
+                                    huge_page_entry(pde.decode(), PageSize::Size2MiB),
+                                );
+                            } else if let Some(pt) = pde.pt() {
+                                debug_pde(pd_index, pde);
+
+                                for (pt_index, pte) in pt.iter().enumerate() {
+                                    if pte.present() {
+                                        debug_page(
+                                            pt_index,
+                                            page_map_indices_to_address(PageMapIndices {
+                                                pml4: pml4_index,
+                                                pdpt: pdpt_index,
+                                                pd: pd_index,
+                                                pt: pt_index,
+                                            }),
+                                            PageSize::Size4KiB,
+                                            pte.decode(),
+                                        )
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
         }
+    }
 
-        let pt = pde.pt_mut().unwrap();
-        pt[page_map_indices.pt] = PTE::new(
-            !executable,
-            physical_page_address,
-            false,
-            false,
-            false,
-            writeable,
-        );
+    /** Check the page map's structural invariants, returning the first one that's broken.
+
+    A test oracle for the methods that change the page map. The invariants are:
+
+    * Only PML4 entry [`SELF_MAP_INDEX`] may point back at the PML4 (a recursive self-map), and
+      when that entry is present it must. The self-map isn't descended into.
+    * A present entry's address fits in `max_physical_address_width` bits. On the machine that
+      uses the page map, that's [`crate::registers::max_physical_address_width`]. Tests that
+      allocate page tables on the host can pass 52, the architectural maximum.
+    * A huge page's frame is aligned to the page's size. Entry addresses are always 4KiB
+      aligned, because the low 12 bits of an entry are flags.
+    * An executable page has no execute-disable bit set on the path to it, because
+      execute-disable in an intermediate entry applies to everything beneath it.
+
+    Virtual addresses aren't checked, because every address that a 4-level page map can
+    translate is canonical (see [`is_canonical`]).
+
+    Like [`PageMap::set`], this accesses page tables via their physical addresses.
+
+    ## Example
+
+    ```rust
+    # use common::paging::{EntryFlags, Invariant, PageMap, PageMapFlags, PDPTE, PML4E};
+    # use common::paging::SELF_MAP_INDEX;
+    # let mut page_pool = common::paging::test_support::PagePool::new();
+    # let mut allocate_pages = |count: usize| page_pool.allocate(count);
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    page_map.set(&mut allocate_pages, 0x1000, 0x1000, PageMapFlags::X);
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+
+    let pdpte = &mut page_map.pml4_mut()[0].pdpt_mut().unwrap()[0];
+    pdpte.set_execute_disable(true);
+    assert_eq!(
+        page_map.check_invariants(52),
+        Err(Invariant::ExecutableUnderExecuteDisable { virtual_address: 0x1000 })
+    );
+
+    // Entry 511 is reserved for the self-map.
+    let mut page_map = PageMap::new(&mut allocate_pages);
+    let other_table = allocate_pages(1);
+    let flags = EntryFlags::new().writable(true).execute_disable(true);
+    page_map.pml4_mut()[SELF_MAP_INDEX] = PML4E::with_flags(other_table, flags);
+    assert_eq!(
+        page_map.check_invariants(52),
+        Err(Invariant::SelfMap { pml4_index: 511 })
+    );
+    page_map.install_self_map();
+    assert_eq!(page_map.check_invariants(52), Ok(()));
+    ```
+    */
+    pub fn check_invariants(&self, max_physical_address_width: u8) -> Result<(), Invariant> {
+        let address_limit = 1 << max_physical_address_width;
+
+        let check_address = |virtual_address: u64, address: u64| {
+            if address < address_limit {
+                Ok(())
+            } else {
+                Err(Invariant::AddressTooWide {
+                    virtual_address,
+                    address,
+                })
+            }
+        };
+
+        let check_page = |virtual_address: u64,
+                          page_size: PageSize,
+                          decoded: DecodedEntry,
+                          parent_execute_disable: bool| {
+            // In an entry that maps a huge page, bit 12 is PAT, not part of the frame's address.
+            let address = match page_size {
+                PageSize::Size4KiB => decoded.address,
+                PageSize::Size2MiB | PageSize::Size1GiB => decoded.address & !(1 << 12),
+            };
+            check_address(virtual_address, address)?;
+            if address & (page_size.bytes() - 1) != 0 {
+                return Err(Invariant::MisalignedHugePage {
+                    virtual_address,
+                    address,
+                });
+            }
+            if parent_execute_disable && !decoded.execute_disable {
+                return Err(Invariant::ExecutableUnderExecuteDisable { virtual_address });
+            }
+            Ok(())
+        };
+
+        for (pml4_index, pml4e) in self.pml4().iter().enumerate() {
+            if !pml4e.present() {
+                continue;
+            }
+            let indices = |pdpt, pd, pt| {
+                page_map_indices_to_address(PageMapIndices {
+                    pml4: pml4_index,
+                    pdpt,
+                    pd,
+                    pt,
+                })
+            };
+
+            let points_at_root = pml4e.pdpt_address() == self.address;
+            if points_at_root != (pml4_index == SELF_MAP_INDEX) {
+                return Err(Invariant::SelfMap { pml4_index });
+            }
+            if points_at_root {
+                continue;
+            }
+            check_address(indices(0, 0, 0), pml4e.pdpt_address())?;
+            let Some(pdpt) = pml4e.pdpt() else {
+                continue;
+            };
+
+            for (pdpt_index, pdpte) in pdpt.iter().enumerate() {
+                if !pdpte.present() {
+                    continue;
+                }
+                let pdpt_execute_disable = pml4e.execute_disable() || pdpte.execute_disable();
+                if pdpte.page_size() {
+                    check_page(
+                        indices(pdpt_index, 0, 0),
+                        PageSize::Size1GiB,
+                        pdpte.decode(),
+                        pml4e.execute_disable(),
+                    )?;
+                    continue;
+                }
+                check_address(indices(pdpt_index, 0, 0), pdpte.pd_address())?;
+                let Some(pd) = pdpte.pd() else {
+                    continue;
+                };
+
+                for (pd_index, pde) in pd.iter().enumerate() {
+                    if !pde.present() {
+                        continue;
+                    }
+                    if pde.page_size() {
+                        check_page(
+                            indices(pdpt_index, pd_index, 0),
+                            PageSize::Size2MiB,
+                            pde.decode(),
+                            pdpt_execute_disable,
+                        )?;
+                        continue;
+                    }
+                    check_address(indices(pdpt_index, pd_index, 0), pde.pt_address())?;
+                    let Some(pt) = pde.pt() else {
+                        continue;
+                    };
+
+                    for (pt_index, pte) in pt.iter().enumerate() {
+                        if pte.present() {
+                            check_page(
+                                indices(pdpt_index, pd_index, pt_index),
+                                PageSize::Size4KiB,
+                                pte.decode(),
+                                pdpt_execute_disable || pde.execute_disable(),
+                            )?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts from one walk of a [`PageMap`]. See [`PageMap::statistics`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageMapStats {
+    pub pdpts: usize,
+    pub pds: usize,
+    pub pts: usize,
+
+    pub pages_4kib: usize,
+    pub pages_2mib: usize,
+    pub pages_1gib: usize,
+
+    /// The total amount of mapped memory, in bytes.
+    pub mapped_bytes: usize,
+}
+
+impl PageMapStats {
+    /// The number of page tables, including the PML4. Each one takes a 4KiB page.
+    pub fn table_count(&self) -> usize {
+        1 + self.pdpts + self.pds + self.pts
+    }
+}
+
+/// The raw page map entries on the way to a virtual address. See [`PageMap::lookup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingPath {
+    pub pml4e: Option<u64>,
+    pub pdpte: Option<u64>,
+    pub pde: Option<u64>,
+    pub pte: Option<u64>,
+}
+
+/** The error code that the processor pushes for a page fault (#PF, vector 14).
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 4.7
+and Figure 4-12.
+
+## Example
+
+```rust
+# use common::paging::PageFaultError;
+let error = PageFaultError::from_value(0b00000);
+assert!(!error.protection_violation());
+assert!(!error.caused_by_write());
+assert!(!error.user_mode());
+assert!(!error.reserved_bit());
+assert!(!error.instruction_fetch());
+
+assert!(PageFaultError::from_value(0b00001).protection_violation());
+assert!(PageFaultError::from_value(0b00010).caused_by_write());
+assert!(PageFaultError::from_value(0b00100).user_mode());
+assert!(PageFaultError::from_value(0b01000).reserved_bit());
+assert!(PageFaultError::from_value(0b10000).instruction_fetch());
+
+let error = PageFaultError::from_value(0b11111);
+assert!(error.protection_violation() && error.caused_by_write() && error.user_mode());
+assert!(error.reserved_bit() && error.instruction_fetch());
+```
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(u64);
+
+impl PageFaultError {
+    pub const fn from_value(value: u64) -> Self {
+        PageFaultError(value)
+    }
+
+    pub const fn value(&self) -> u64 {
+        self.0
+    }
+
+    const fn get_mask(&self, mask: u64) -> bool {
+        self.0 & mask == mask
+    }
+
+    /// The page was present, so the fault was a permission check. Otherwise it wasn't present.
+    pub const fn protection_violation(&self) -> bool {
+        self.get_mask(1)
+    }
+
+    /// The access was a write. Otherwise it was a read.
+    pub const fn caused_by_write(&self) -> bool {
+        self.get_mask(1 << 1)
+    }
+
+    /// The access was made in user mode (CPL 3). Otherwise it was made in supervisor mode.
+    pub const fn user_mode(&self) -> bool {
+        self.get_mask(1 << 2)
+    }
+
+    /// A page map entry on the way to the address had a reserved bit set.
+    pub const fn reserved_bit(&self) -> bool {
+        self.get_mask(1 << 3)
     }
 
-    pub fn debug(
-        &self,
-        debug_pml4e: &mut dyn FnMut(usize, &PML4E),
-        debug_pdpte: &mut dyn FnMut(usize, &PDPTE),
-        debug_pde: &mut dyn FnMut(usize, &PDE),
-        debug_pte: &mut dyn FnMut(usize, u64, &PTE),
-    ) {
-        for (pml4_index, pml4e) in self.pml4().iter().enumerate() {
-            if let Some(pdpt) = pml4e.pdpt() {
-                debug_pml4e(pml4_index, pml4e);
+    /// The access was an instruction fetch.
+    pub const fn instruction_fetch(&self) -> bool {
+        self.get_mask(1 << 4)
+    }
+}
 
-                for (pdpt_index, pdpte) in pdpt.iter().enumerate() {
-                    if let Some(pd) = pdpte.pd() {
-                        debug_pdpte(pdpt_index, pdpte);
+/** A page fault's address (from [`crate::registers::CR2`]) and error code, for logging.
 
-                        for (pd_index, pde) in pd.iter().enumerate() {
-                            if let Some(pt) = pde.pt() {
-                                debug_pde(pd_index, pde);
+## Example
 
-                                for (pt_index, pte) in pt.iter().enumerate() {
-                                    if pte.present() {
-                                        debug_pte(
-                                            pt_index,
-                                            page_map_indices_to_address(PageMapIndices {
-                                                pml4: pml4_index,
-                                                pdpt: pdpt_index,
-                                                pd: pd_index,
-                                                pt: pt_index,
-                                            }),
-                                            pte,
-                                        )
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+```rust
+# use common::paging::{PageFault, PageFaultError};
+let fault = PageFault {
+    address: 0xdead_b000,
+    error: PageFaultError::from_value(0b010),
+};
+assert_eq!(
+    fault.to_string(),
+    "write to non-present page at 0xdeadb000 from kernel mode"
+);
+
+let fault = PageFault {
+    address: 0x40_0000,
+    error: PageFaultError::from_value(0b10101),
+};
+assert_eq!(
+    fault.to_string(),
+    "instruction fetch from protected page at 0x400000 from user mode"
+);
+```
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    pub address: u64,
+    pub error: PageFaultError,
+}
+
+impl core::fmt::Display for PageFault {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let access = if self.error.instruction_fetch() {
+            "instruction fetch from"
+        } else if self.error.caused_by_write() {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.error.protection_violation() {
+            "protected"
+        } else {
+            "non-present"
+        };
+        let mode = if self.error.user_mode() {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(
+            f,
+            "{} {} page at {:#x} from {} mode",
+            access, page, self.address, mode
+        )?;
+        if self.error.reserved_bit() {
+            write!(f, " (reserved bit set)")?;
         }
+        Ok(())
     }
 }
 
-/** A PML4 entry.
+/// Why [`PageMap::adopt_active`] rejected the active page map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdoptError {
+    /// PML4 entry [`SELF_MAP_INDEX`] isn't present.
+    MissingSelfMap,
 
-Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Table 4-15 and Figure 4-11.
+    /// PML4 entry [`SELF_MAP_INDEX`] points at `address` instead of the PML4.
+    WrongSelfMap { address: u64 },
+}
+
+/// Why [`PageMap::try_set`] or [`PageMap::remap`] couldn't map a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// An address isn't aligned to the page size.
+    Misaligned,
+
+    /// The virtual address isn't canonical (see [`is_canonical`]).
+    NonCanonical,
+
+    /// A page table couldn't be allocated.
+    OutOfMemory,
+
+    /// Nothing is mapped at the source address.
+    NotMapped,
+
+    /// Something is already mapped at the destination address.
+    AlreadyMapped,
+
+    /// A 2MiB or 1GiB page already covers the virtual address.
+    HugePageConflict,
+}
+
+/// A broken [`PageMap`] invariant. See [`PageMap::check_invariants`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    /// A PML4 entry other than entry [`SELF_MAP_INDEX`] points at the PML4, or entry
+    /// [`SELF_MAP_INDEX`] is present and doesn't.
+    SelfMap { pml4_index: usize },
+
+    /// The entry for the region at `virtual_address` has an address that's too wide.
+    AddressTooWide { virtual_address: u64, address: u64 },
+
+    /// The huge page at `virtual_address` has a frame that isn't aligned to its size.
+    MisalignedHugePage { virtual_address: u64, address: u64 },
+
+    /// The page at `virtual_address` is executable, but an entry above it is execute-disable.
+    ExecutableUnderExecuteDisable { virtual_address: u64 },
+}
+
+/** The flags shared by every kind of page map entry.
+
+A builder for the bits that [`PML4E`], [`PDPTE`], [`PDE`] and [`PTE`] have in common, so that
+entries can be created with named flags instead of positional `bool`s. Every flag starts
+cleared, and the present bit is always set.
+
+## Example
+
+```rust
+# use common::paging::{EntryFlags, PML4E, PDPTE, PDE, PTE};
+let address = 0x1000;
+let flags = EntryFlags::new().execute_disable(true).pwt(true).writable(true);
+
+// XD (bit 63), the address, PWT (bit 3), R/W (bit 1) and P (bit 0).
+assert_eq!(PTE::with_flags(address, flags).value(), 0x8000_0000_0000_100b);
+
+assert_eq!(
+    PML4E::with_flags(address, flags).value(),
+    PML4E::new(true, address, false, true, false, true).value()
+);
+assert_eq!(
+    PDPTE::with_flags(address, flags).value(),
+    PDPTE::new(true, address, false, true, false, true).value()
+);
+assert_eq!(
+    PDE::with_flags(address, flags).value(),
+    PDE::new(true, address, false, true, false, true).value()
+);
+assert_eq!(
+    PTE::with_flags(address, flags).value(),
+    PTE::new(true, address, false, true, false, true).value()
+);
+assert_eq!(
+    PTE::with_flags(address, EntryFlags::new().pcd(true).user(true)).value(),
+    PTE::new(false, address, true, false, true, false).value()
+);
+```
 */
-pub struct PML4E(u64);
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EntryFlags(u64);
+
+impl EntryFlags {
+    const EXECUTE_DISABLE: u64 = 1 << 63;
+    const PCD: u64 = 1 << 4;
+    const PWT: u64 = 1 << 3;
+    const USER: u64 = 1 << 2;
+    const WRITABLE: u64 = 1 << 1;
+    const PRESENT: u64 = 1;
+
+    pub const fn new() -> Self {
+        EntryFlags(0)
+    }
 
-impl PML4E {
-    pub fn new(
-        execute_disable: bool,
-        pdpt_address: u64,
-        pcd: bool,
-        pwt: bool,
-        user: bool,
-        writable: bool,
-    ) -> Self {
+    const fn with(self, mask: u64, value: bool) -> Self {
+        if value {
+            EntryFlags(self.0 | mask)
+        } else {
+            EntryFlags(self.0 & !mask)
+        }
+    }
+
+    /// Disallow instruction fetches.
+    pub const fn execute_disable(self, value: bool) -> Self {
+        self.with(Self::EXECUTE_DISABLE, value)
+    }
+
+    /// Page-level cache disable.
+    pub const fn pcd(self, value: bool) -> Self {
+        self.with(Self::PCD, value)
+    }
+
+    /// Page-level write-through.
+    pub const fn pwt(self, value: bool) -> Self {
+        self.with(Self::PWT, value)
+    }
+
+    /// Allow user-mode accesses.
+    pub const fn user(self, value: bool) -> Self {
+        self.with(Self::USER, value)
+    }
+
+    /// Allow writes.
+    pub const fn writable(self, value: bool) -> Self {
+        self.with(Self::WRITABLE, value)
+    }
+
+    /** The value of a present entry with these flags that points at `address`.
+
+    Every entry type's constructor goes through this, so they all accept the same addresses:
+
+    ```rust
+    # use common::paging::{EntryFlags, PML4E, PDPTE, PDE, PTE};
+    let address = 0x000f_ffff_ffff_f000;
+    let flags = EntryFlags::new();
+    assert_eq!(PML4E::with_flags(address, flags).value(), address | 1);
+    assert_eq!(PDPTE::with_flags(address, flags).value(), address | 1);
+    assert_eq!(PDE::with_flags(address, flags).value(), address | 1);
+    assert_eq!(PTE::with_flags(address, flags).value(), address | 1);
+    ```
+
+    ```rust,should_panic
+    # use common::paging::{EntryFlags, PTE};
+    PTE::with_flags(0x0010_0000_0000_0000, EntryFlags::new());
+    ```
+    */
+    fn entry_value(self, address: u64) -> u64 {
         assert!(
             // `0xfff` is `0b111111111`.
-            pdpt_address & 0xfff == 0,
+            address & 0xfff == 0,
             "address {:#x} is not 4KiB aligned",
-            pdpt_address
+            address
         );
 
         assert!(
-            pdpt_address & 0xfff << 51 == 0,
+            fits_in_52_bits(address),
             "address {:#x} uses more than 52 bits",
-            pdpt_address
+            address
         );
 
-        let mut value = pdpt_address;
+        address | self.0 | Self::PRESENT
+    }
+}
 
-        if execute_disable {
-            value |= 1 << 63;
-        }
+/** The bits that every kind of page map entry has in common, decoded.
 
-        if pcd {
-            value |= 1 << 4;
-        }
+Its [`Display`](core::fmt::Display) instance is for reading permissions at a glance: the
+address, then `W` when writable, `X` when executable, `U` when user-accessible, and `PCD` /
+`PWT` when set. A non-present entry is just `-`.
 
-        if pwt {
-            value |= 1 << 3;
-        }
+## Example
 
-        if user {
-            value |= 1 << 2;
+```rust
+# use common::paging::{DecodedEntry, EntryFlags, PDE, PTE};
+let pte = PTE::with_flags(0x12000, EntryFlags::new().writable(true).pcd(true));
+assert_eq!(
+    pte.decode(),
+    DecodedEntry {
+        present: true,
+        writable: true,
+        user: false,
+        execute_disable: false,
+        pcd: true,
+        pwt: false,
+        address: 0x12000,
+    }
+);
+assert_eq!(pte.decode().to_string(), "0x12000 W X PCD");
+
+let pde = PDE::with_flags(0x3000, EntryFlags::new().execute_disable(true).user(true).pwt(true));
+assert_eq!(pde.decode().to_string(), "0x3000 U PWT");
+```
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedEntry {
+    pub present: bool,
+    pub writable: bool,
+    pub user: bool,
+    pub execute_disable: bool,
+    pub pcd: bool,
+    pub pwt: bool,
+
+    /// Physical address of the table or page that the entry points at.
+    pub address: u64,
+}
+
+impl core::fmt::Display for DecodedEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if !self.present {
+            return write!(f, "-");
         }
 
-        if writable {
-            value |= 1 << 1;
+        write!(f, "{:#x}", self.address)?;
+        for (set, name) in [
+            (self.writable, "W"),
+            (!self.execute_disable, "X"),
+            (self.user, "U"),
+            (self.pcd, "PCD"),
+            (self.pwt, "PWT"),
+        ] {
+            if set {
+                write!(f, " {}", name)?;
+            }
         }
+        Ok(())
+    }
+}
 
-        // The present bit.
-        value |= 1;
+/** The bit operations that every kind of page map entry has in common.
 
-        Self(value)
-    }
+`$address` reads the physical address that the entry points at, and `$target` describes what's
+at that address.
+*/
+macro_rules! page_entry {
+    ($name:ident, $target:literal, $address_vis:vis $address:ident) => {
+        impl $name {
+            #[doc = concat!("Create a present entry that points at ", $target, ".")]
+            #[doc = ""]
+            #[doc = concat!("Positional version of [`", stringify!($name), "::with_flags`].")]
+            pub fn new(
+                execute_disable: bool,
+                address: u64,
+                pcd: bool,
+                pwt: bool,
+                user: bool,
+                writable: bool,
+            ) -> Self {
+                Self::with_flags(
+                    address,
+                    EntryFlags::new()
+                        .execute_disable(execute_disable)
+                        .pcd(pcd)
+                        .pwt(pwt)
+                        .user(user)
+                        .writable(writable),
+                )
+            }
 
-    pub fn value(&self) -> u64 {
-        self.0
-    }
+            #[doc = concat!("Create a present entry that points at ", $target, ".")]
+            pub fn with_flags(address: u64, flags: EntryFlags) -> Self {
+                Self(flags.entry_value(address))
+            }
 
-    pub fn set_execute_disable(&mut self, value: bool) {
-        let mask = 1 << 63;
-        if value {
-            self.0 |= mask;
-        } else {
-            self.0 &= !mask;
-        }
-    }
+            pub fn value(&self) -> u64 {
+                self.0
+            }
 
-    pub fn present(&self) -> bool {
-        self.0 & 1 == 1
-    }
+            fn get_mask(&self, mask: u64) -> bool {
+                self.0 & mask == mask
+            }
 
-    pub fn writable(&self) -> bool {
-        let mask = 0b10;
-        self.0 & mask == mask
-    }
+            fn set_mask(&mut self, mask: u64, value: bool) {
+                if value {
+                    self.0 |= mask;
+                } else {
+                    self.0 &= !mask;
+                }
+            }
 
-    pub fn set_writable(&mut self, value: bool) {
-        let mask = 0b10;
-        if value {
-            self.0 |= mask;
-        } else {
-            self.0 &= !mask;
+            pub fn present(&self) -> bool {
+                self.get_mask(EntryFlags::PRESENT)
+            }
+
+            pub fn execute_disable(&self) -> bool {
+                self.get_mask(EntryFlags::EXECUTE_DISABLE)
+            }
+
+            pub fn set_execute_disable(&mut self, value: bool) {
+                self.set_mask(EntryFlags::EXECUTE_DISABLE, value)
+            }
+
+            /// Whether instructions can be fetched through this entry (the inverse of `execute_disable`).
+            pub fn executable(&self) -> bool {
+                !self.execute_disable()
+            }
+
+            pub fn writable(&self) -> bool {
+                self.get_mask(EntryFlags::WRITABLE)
+            }
+
+            pub fn set_writable(&mut self, value: bool) {
+                self.set_mask(EntryFlags::WRITABLE, value)
+            }
+
+            #[doc = concat!("Physical address of ", $target, " that this entry points at.")]
+            $address_vis fn $address(&self) -> u64 {
+                self.0 & ADDRESS_MASK
+            }
+
+            /// The entry's flags and address. See [`DecodedEntry`].
+            pub fn decode(&self) -> DecodedEntry {
+                DecodedEntry {
+                    present: self.present(),
+                    writable: self.writable(),
+                    user: self.get_mask(EntryFlags::USER),
+                    execute_disable: self.execute_disable(),
+                    pcd: self.get_mask(EntryFlags::PCD),
+                    pwt: self.get_mask(EntryFlags::PWT),
+                    address: self.$address(),
+                }
+            }
         }
-    }
+    };
+}
 
-    fn pdpt_address(&self) -> u64 {
-        let mask = (1 << 63) | 0xfff;
-        self.0 & !mask
-    }
+/** A PML4 entry.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Table 4-15 and Figure 4-11.
+
+## Example
+
+[`PML4E`], [`PDPTE`], [`PDE`] and [`PTE`] share their bit operations, so they agree on every bit:
 
+```rust
+# use common::paging::{PML4E, PDPTE, PDE, PTE};
+macro_rules! check_entry {
+    ($name:ident) => {
+        let mut entry = $name::new(true, 0x0000_0001_2345_6000, true, true, true, true);
+        assert_eq!(entry.value(), 0x8000_0001_2345_601f);
+        assert!(entry.present() && entry.writable() && entry.execute_disable());
+
+        entry.set_writable(false);
+        assert_eq!(entry.value(), 0x8000_0001_2345_601d);
+
+        entry.set_execute_disable(false);
+        assert_eq!(entry.value(), 0x0000_0001_2345_601d);
+        assert!(entry.executable());
+    };
+}
+
+check_entry!(PML4E);
+check_entry!(PDPTE);
+check_entry!(PDE);
+check_entry!(PTE);
+```
+*/
+pub struct PML4E(u64);
+
+page_entry!(PML4E, "a PDPT", pdpt_address);
+
+impl PML4E {
     /// Get an exclusive reference to the PDPT pointed to by this entry.
     pub fn pdpt_mut(&mut self) -> Option<&mut [PDPTE]> {
         if self.present() {
@@ -432,95 +3070,23 @@ Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol
 */
 pub struct PDPTE(u64);
 
-impl PDPTE {
-    pub fn new(
-        execute_disable: bool,
-        pd_address: u64,
-        pcd: bool,
-        pwt: bool,
-        user: bool,
-        writable: bool,
-    ) -> Self {
-        assert!(
-            // `0xfff` is `0b111111111`.
-            pd_address & 0xfff == 0,
-            "address {:#x} is not 4KiB aligned",
-            pd_address
-        );
-
-        assert!(
-            pd_address & 0xfff << 51 == 0,
-            "address {:#x} uses more than 52 bits",
-            pd_address
-        );
-
-        let mut value = pd_address;
-
-        if execute_disable {
-            value |= 1 << 63;
-        }
-
-        if pcd {
-            value |= 1 << 4;
-        }
-
-        if pwt {
-            value |= 1 << 3;
-        }
-
-        if user {
-            value |= 1 << 2;
-        }
-
-        if writable {
-            value |= 1 << 1;
-        }
-
-        // The present bit.
-        value |= 1;
-
-        Self(value)
-    }
-
-    pub fn value(&self) -> u64 {
-        self.0
-    }
-
-    pub fn set_execute_disable(&mut self, value: bool) {
-        let mask = 1 << 63;
-        if value {
-            self.0 |= mask;
-        } else {
-            self.0 &= !mask;
-        }
-    }
-
-    pub fn present(&self) -> bool {
-        self.0 & 1 == 1
-    }
+page_entry!(PDPTE, "a PD", pd_address);
 
-    pub fn writable(&self) -> bool {
-        let mask = 0b10;
-        self.0 & mask == mask
-    }
+impl PDPTE {
+    /** Page size.
 
-    pub fn set_writable(&mut self, value: bool) {
-        let mask = 0b10;
-        if value {
-            self.0 |= mask;
-        } else {
-            self.0 &= !mask;
-        }
+    When set, this entry maps a 1GiB page instead of pointing at a PD.
+    */
+    pub fn page_size(&self) -> bool {
+        self.get_mask(1 << 7)
     }
 
-    fn pd_address(&self) -> u64 {
-        let mask = (1 << 63) | 0xfff;
-        self.0 & !mask
-    }
+    /** Get an exclusive reference to the PD pointed to by this entry.
 
-    /// Get an exclusive reference to the PD pointed to by this entry.
+    `None` when the entry isn't present or maps a huge page.
+    */
     pub fn pd_mut(&mut self) -> Option<&mut [PDE]> {
-        if self.present() {
+        if self.present() && !self.page_size() {
             unsafe {
                 Some(core::slice::from_raw_parts_mut(
                     self.pd_address() as *mut PDE,
@@ -532,9 +3098,12 @@ impl PDPTE {
         }
     }
 
-    /// Get a shared reference to the PD pointed to by this entry.
+    /** Get a shared reference to the PD pointed to by this entry.
+
+    `None` when the entry isn't present or maps a huge page.
+    */
     pub fn pd(&self) -> Option<&[PDE]> {
-        if self.present() {
+        if self.present() && !self.page_size() {
             unsafe {
                 Some(core::slice::from_raw_parts(
                     self.pd_address() as *const PDE,
@@ -553,95 +3122,27 @@ Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol
 */
 pub struct PDE(u64);
 
-impl PDE {
-    pub fn new(
-        execute_disable: bool,
-        pdp_table_address: u64,
-        pcd: bool,
-        pwt: bool,
-        user: bool,
-        writable: bool,
-    ) -> Self {
-        assert!(
-            // `0xfff` is `0b111111111`.
-            pdp_table_address & 0xfff == 0,
-            "address {:#x} is not 4KiB aligned",
-            pdp_table_address
-        );
-
-        assert!(
-            pdp_table_address & 0xfff << 51 == 0,
-            "address {:#x} uses more than 52 bits",
-            pdp_table_address
-        );
-
-        let mut value = pdp_table_address;
-
-        if execute_disable {
-            value |= 1 << 63;
-        }
-
-        if pcd {
-            value |= 1 << 4;
-        }
-
-        if pwt {
-            value |= 1 << 3;
-        }
-
-        if user {
-            value |= 1 << 2;
-        }
-
-        if writable {
-            value |= 1 << 1;
-        }
-
-        // The present bit.
-        value |= 1;
-
-        Self(value)
-    }
-
-    pub fn value(&self) -> u64 {
-        self.0
-    }
-
-    pub fn set_execute_disable(&mut self, value: bool) {
-        let mask = 1 << 63;
-        if value {
-            self.0 |= mask;
-        } else {
-            self.0 &= !mask;
-        }
-    }
+page_entry!(PDE, "a PT", pt_address);
 
-    pub fn present(&self) -> bool {
-        self.0 & 1 == 1
-    }
+impl PDE {
+    /** Page size.
 
-    pub fn writable(&self) -> bool {
-        let mask = 0b10;
-        self.0 & mask == mask
+    When set, this entry maps a 2MiB page instead of pointing at a PT.
+    */
+    pub fn page_size(&self) -> bool {
+        self.get_mask(1 << 7)
     }
 
-    pub fn set_writable(&mut self, value: bool) {
-        let mask = 0b10;
-        if value {
-            self.0 |= mask;
-        } else {
-            self.0 &= !mask;
-        }
+    pub fn set_page_size(&mut self, value: bool) {
+        self.set_mask(1 << 7, value)
     }
 
-    fn pt_address(&self) -> u64 {
-        let mask = (1 << 63) | 0xfff;
-        self.0 & !mask
-    }
+    /** Get an exclusive reference to the PT pointed to by this entry.
 
-    /// Get an exclusive reference to the PT pointed to by this entry.
+    `None` when the entry isn't present or maps a huge page.
+    */
     pub fn pt_mut(&mut self) -> Option<&mut [PTE]> {
-        if self.present() {
+        if self.present() && !self.page_size() {
             unsafe {
                 Some(core::slice::from_raw_parts_mut(
                     self.pt_address() as *mut PTE,
@@ -653,9 +3154,12 @@ impl PDE {
         }
     }
 
-    /// Get a shared reference to the PT pointed to by this entry.
+    /** Get a shared reference to the PT pointed to by this entry.
+
+    `None` when the entry isn't present or maps a huge page.
+    */
     pub fn pt(&self) -> Option<&[PTE]> {
-        if self.present() {
+        if self.present() && !self.page_size() {
             unsafe {
                 Some(core::slice::from_raw_parts(
                     self.pt_address() as *const PTE,
@@ -674,61 +3178,63 @@ Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol
 */
 pub struct PTE(u64);
 
+page_entry!(PTE, "the page", pub page_address);
+
 impl PTE {
-    pub fn new(
-        execute_disable: bool,
-        page_address: u64,
-        pcd: bool,
-        pwt: bool,
-        user: bool,
-        writable: bool,
-    ) -> Self {
-        assert!(
-            // `0xfff` is `0b111111111`.
-            page_address & 0xfff == 0,
-            "address {:#x} is not 4KiB aligned",
-            page_address
-        );
+    /** Create a PTE that maps a page with the given permissions and caching behaviour.
 
+    See [`PageMapFlags`] for how the flags map to PTE bits.
+    */
+    pub fn from_flags(page_address: u64, flags: PageMapFlags) -> Self {
         assert!(
-            page_address & 0xfff << 51 == 0,
-            "address {:#x} uses more than 52 bits",
-            page_address
+            !(flags.write_combining && (flags.uncached || flags.write_through)),
+            "write-combining can't be combined with other memory types"
         );
 
-        let mut value = page_address;
-
-        if execute_disable {
-            value |= 1 << 63;
+        if !flags.readable {
+            return Self::guard();
         }
 
-        if pcd {
-            value |= 1 << 4;
+        let mut pte = PTE::with_flags(
+            page_address,
+            EntryFlags::new()
+                .execute_disable(!flags.executable)
+                .pcd(flags.uncached)
+                .pwt(flags.write_through)
+                .writable(flags.writeable),
+        );
+        if flags.write_combining {
+            pte.set_pat(true);
         }
+        pte
+    }
 
-        if pwt {
-            value |= 1 << 3;
-        }
+    /// A non-present entry that marks neither a mapped page nor a guard page.
+    pub const fn unset() -> Self {
+        Self(0)
+    }
 
-        if user {
-            value |= 1 << 2;
-        }
+    /** A non-present entry that marks a guard page.
 
-        if writable {
-            value |= 1 << 1;
-        }
+    x86 can't make a present page inaccessible, so a guard page is unmapped. The processor
+    ignores every other bit of a non-present entry, so bit 9 is used to tell a guard page apart
+    from a page that was never mapped.
+    */
+    pub fn guard() -> Self {
+        Self(Self::GUARD_MASK)
+    }
 
-        // The present bit.
-        value |= 1;
+    const GUARD_MASK: u64 = 1 << 9;
 
-        Self(value)
+    pub fn is_guard(&self) -> bool {
+        !self.present() && self.get_mask(Self::GUARD_MASK)
     }
 
-    pub fn value(&self) -> u64 {
-        self.0
-    }
+    /** Set the page attribute table bit.
 
-    pub fn present(&self) -> bool {
-        self.0 & 1 == 1
+    Together with PCD and PWT, this selects the page's memory type from [`crate::registers::IA32_PAT`].
+    */
+    pub fn set_pat(&mut self, value: bool) {
+        self.set_mask(1 << 7, value)
     }
 }
@@ -0,0 +1,47 @@
+/*! Helpers for the examples in this crate's documentation.
+
+Not part of the crate's API: the examples run on the host, where page tables can live in
+ordinary memory.
+*/
+use super::PageMap;
+
+#[repr(C, align(4096))]
+struct Page([u8; PageMap::PAGE_SIZE]);
+
+/** A fixed number of zeroed, 4KiB aligned pages, which page tables can be allocated from.
+
+The pages are freed when the pool is dropped, so page maps that use them mustn't outlive it.
+*/
+pub struct PagePool {
+    pages: [Page; PagePool::CAPACITY],
+    allocated: usize,
+}
+
+impl PagePool {
+    pub const CAPACITY: usize = 64;
+
+    pub fn new() -> Self {
+        PagePool {
+            pages: [const { Page([0; PageMap::PAGE_SIZE]) }; PagePool::CAPACITY],
+            allocated: 0,
+        }
+    }
+
+    /// Allocate `count` contiguous pages, and return the first one's address.
+    pub fn allocate(&mut self, count: usize) -> u64 {
+        assert!(
+            self.allocated + count <= Self::CAPACITY,
+            "the page pool only has {} pages",
+            Self::CAPACITY
+        );
+        let address = self.pages[self.allocated..].as_mut_ptr() as u64;
+        self.allocated += count;
+        address
+    }
+}
+
+impl Default for PagePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
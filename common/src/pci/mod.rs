@@ -0,0 +1,166 @@
+/*! PCI configuration space access that doesn't depend on UEFI.
+
+The bootloader uses UEFI's `PciRootBridgeIo` protocol to access configuration space, but that's
+unavailable once boot services have exited. PCI Express exposes every function's configuration
+space as a 4KiB block of physical memory (the Enhanced Configuration Access Mechanism, or ECAM),
+so the kernel can use plain memory accesses instead.
+
+Reference: PCI Express Base Specification, Revision 4.0, Section 7.2.2
+*/
+pub mod serial;
+
+use crate::mmio;
+
+/// The location of a register in a PCI function's configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamAddress {
+    pub bus: u8,
+
+    /// Less than 32.
+    pub device: u8,
+
+    /// Less than 8.
+    pub function: u8,
+
+    /// Byte offset into the function's 4KiB configuration space.
+    pub register: u16,
+}
+
+impl EcamAddress {
+    /** The address's offset from the ECAM base.
+
+    This is the ECAM counterpart to `uefi_pci::PciConfigurationAddress::to_u64`.
+
+    ## Example
+
+    ```rust
+    # use common::pci::EcamAddress;
+    let address = EcamAddress { bus: 1, device: 2, function: 3, register: 0x10 };
+    assert_eq!(address.to_offset(), 0x0011_3010);
+    ```
+    */
+    pub const fn to_offset(&self) -> u64 {
+        assert!(self.device < 32, "PCI device number must be less than 32");
+        assert!(self.function < 8, "PCI function number must be less than 8");
+        assert!(
+            self.register < 0x1000,
+            "PCI configuration register must be less than 0x1000"
+        );
+
+        let mut value: u64 = self.register as u64;
+        value |= (self.function as u64) << 12;
+        value |= (self.device as u64) << 15;
+        value |= (self.bus as u64) << 20;
+        value
+    }
+}
+
+const _: () = assert!(
+    EcamAddress {
+        bus: 0,
+        device: 0,
+        function: 0,
+        register: 0
+    }
+    .to_offset()
+        == 0
+);
+const _: () = assert!(
+    EcamAddress {
+        bus: 0,
+        device: 31,
+        function: 7,
+        register: 0xffc
+    }
+    .to_offset()
+        == 0x000f_fffc
+);
+const _: () = assert!(
+    EcamAddress {
+        bus: 255,
+        device: 0,
+        function: 0,
+        register: 0x4
+    }
+    .to_offset()
+        == 0x0ff0_0004
+);
+
+/** A memory-mapped PCI Express configuration space.
+
+The ECAM base address for a PCI segment group comes from the ACPI MCFG table.
+*/
+pub struct Ecam {
+    base: u64,
+}
+
+impl Ecam {
+    /** # Safety
+
+    `base` must be the virtual address of an ECAM region, and the region must be mapped uncached
+    (see [`crate::paging::PageMapFlags::UNCACHED`]) for every bus that's accessed through it.
+    */
+    pub unsafe fn new(base: u64) -> Self {
+        Ecam { base }
+    }
+
+    /// The virtual address of a configuration register.
+    pub fn address(&self, address: EcamAddress) -> u64 {
+        self.base + address.to_offset()
+    }
+
+    pub fn read_u8(&self, address: EcamAddress) -> u8 {
+        unsafe { mmio::read_volatile_u8(self.address(address)) }
+    }
+
+    pub fn read_u16(&self, address: EcamAddress) -> u16 {
+        assert!(
+            address.register & 0x1 == 0,
+            "register {:#x} is not 2B aligned",
+            address.register
+        );
+        unsafe { mmio::read_volatile_u16(self.address(address)) }
+    }
+
+    pub fn read_u32(&self, address: EcamAddress) -> u32 {
+        assert!(
+            address.register & 0x3 == 0,
+            "register {:#x} is not 4B aligned",
+            address.register
+        );
+        unsafe { mmio::read_volatile_u32(self.address(address)) }
+    }
+
+    /** # Safety
+
+    Writing to configuration space changes how the device behaves (e.g. where its BARs are
+    decoded), which can invalidate memory that's currently in use.
+    */
+    pub unsafe fn write_u8(&self, address: EcamAddress, value: u8) {
+        mmio::write_volatile_u8(self.address(address), value)
+    }
+
+    /// # Safety
+    ///
+    /// See [`Ecam::write_u8`].
+    pub unsafe fn write_u16(&self, address: EcamAddress, value: u16) {
+        assert!(
+            address.register & 0x1 == 0,
+            "register {:#x} is not 2B aligned",
+            address.register
+        );
+        mmio::write_volatile_u16(self.address(address), value)
+    }
+
+    /// # Safety
+    ///
+    /// See [`Ecam::write_u8`].
+    pub unsafe fn write_u32(&self, address: EcamAddress, value: u32) {
+        assert!(
+            address.register & 0x3 == 0,
+            "register {:#x} is not 4B aligned",
+            address.register
+        );
+        mmio::write_volatile_u32(self.address(address), value)
+    }
+}
@@ -0,0 +1,391 @@
+/*! Finding a 16550-compatible serial controller on PCI.
+
+The bootloader searches for the serial controller through UEFI's `PciRootBridgeIo` protocol. The
+search only needs configuration space reads, so it's written against closures instead, and a
+failed read is returned as [`SerialDiscoveryError::Pci`] rather than panicking.
+
+A configuration register is identified by its function's [`Location`] and its byte offset.
+*/
+use core::fmt;
+
+use crate::boot_info::SerialDevice;
+
+/// A PCI function's bus, device and function numbers.
+pub type Location = (u8, u8, u8);
+
+/** Why the serial controller couldn't be found via PCI.
+
+`E` is the error returned by a failed configuration space access.
+
+## Example
+
+```rust
+# use common::pci::serial::{Bar0Problem, SerialDiscoveryError};
+let error = SerialDiscoveryError::<&str>::UnusableBar0 {
+    location: (0, 3, 0),
+    bar0: 0xfebf_1006,
+    problem: Bar0Problem::ReservedType,
+};
+assert_eq!(
+    error.to_string(),
+    "serial controller at 0:3:0 has a reserved BAR0 type (0xfebf1006)"
+);
+
+let error = SerialDiscoveryError::<&str>::UnexpectedHostBridge {
+    vendor_id: 0x8086,
+    device_id: 0x29c0,
+};
+assert_eq!(error.to_string(), "unexpected PCI host bridge 8086:29c0");
+
+let error = SerialDiscoveryError::Pci("DEVICE_ERROR");
+assert_eq!(error.to_string(), "PCI configuration access failed: \"DEVICE_ERROR\"");
+
+let error = SerialDiscoveryError::NoRootBridge("NOT_FOUND");
+assert_eq!(error.to_string(), "no PCI root bridge: \"NOT_FOUND\"");
+
+let error = SerialDiscoveryError::<&str>::NotFound;
+assert_eq!(error.to_string(), "no serial controller found on PCI");
+```
+*/
+#[derive(Debug, PartialEq, Eq)]
+pub enum SerialDiscoveryError<E> {
+    /// There's no PCI root bridge, or it couldn't be opened.
+    NoRootBridge(E),
+
+    /// The host bridge (bus 0, device 0, function 0) isn't the expected one. See
+    /// [`check_host_bridge`].
+    UnexpectedHostBridge { vendor_id: u16, device_id: u16 },
+
+    /// No PCI function is a serial controller.
+    NotFound,
+
+    /// The serial controller at `location` (bus, device, function) has a BAR0 that can't be used.
+    UnusableBar0 {
+        location: Location,
+        bar0: u32,
+        problem: Bar0Problem,
+    },
+
+    /// A PCI configuration space access failed.
+    Pci(E),
+}
+
+/// Why a serial controller's BAR0 can't be used. See Note [Serial controller BAR0].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar0Problem {
+    /// An I/O BAR with a port above 0xffff.
+    PortTooWide,
+
+    /// A memory BAR with the reserved type `01` or `11`.
+    ReservedType,
+
+    /// A memory BAR with address 0, which the firmware didn't assign.
+    Unassigned,
+}
+
+impl<E: fmt::Debug> fmt::Display for SerialDiscoveryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialDiscoveryError::NoRootBridge(error) => {
+                write!(f, "no PCI root bridge: {:?}", error)
+            }
+            SerialDiscoveryError::UnexpectedHostBridge {
+                vendor_id,
+                device_id,
+            } => write!(
+                f,
+                "unexpected PCI host bridge {:x}:{:x}",
+                vendor_id, device_id
+            ),
+            SerialDiscoveryError::NotFound => write!(f, "no serial controller found on PCI"),
+            SerialDiscoveryError::UnusableBar0 {
+                location: (bus, device, function),
+                bar0,
+                problem,
+            } => write!(
+                f,
+                "serial controller at {}:{}:{} has {} ({:#x})",
+                bus, device, function, problem, bar0
+            ),
+            SerialDiscoveryError::Pci(error) => {
+                write!(f, "PCI configuration access failed: {:?}", error)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Bar0Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bar0Problem::PortTooWide => write!(f, "no 16-bit I/O space BAR0"),
+            Bar0Problem::ReservedType => write!(f, "a reserved BAR0 type"),
+            Bar0Problem::Unassigned => write!(f, "an unassigned BAR0"),
+        }
+    }
+}
+
+/** Check that the host bridge (bus 0, device 0, function 0) has the given vendor and device IDs.
+
+`read_u32` reads a dword from a function's configuration space.
+
+## Example
+
+```rust
+# use common::pci::serial::{check_host_bridge, SerialDiscoveryError};
+// QEMU's default machine has an 82441FX host bridge.
+let read_u32 = |_location: (u8, u8, u8), _register: u8| Ok::<_, &str>(0x1237_8086);
+assert_eq!(check_host_bridge(read_u32, 0x8086, 0x1237), Ok(()));
+assert_eq!(
+    check_host_bridge(read_u32, 0x8086, 0x29c0),
+    Err(SerialDiscoveryError::UnexpectedHostBridge { vendor_id: 0x8086, device_id: 0x1237 })
+);
+
+let read_u32 = |_location: (u8, u8, u8), _register: u8| Err::<u32, _>("device error");
+assert_eq!(
+    check_host_bridge(read_u32, 0x8086, 0x1237),
+    Err(SerialDiscoveryError::Pci("device error"))
+);
+```
+*/
+pub fn check_host_bridge<E>(
+    mut read_u32: impl FnMut(Location, u8) -> Result<u32, E>,
+    vendor_id: u16,
+    device_id: u16,
+) -> Result<(), SerialDiscoveryError<E>> {
+    let ids = read_u32((0, 0, 0), 0x0).map_err(SerialDiscoveryError::Pci)?;
+    let (actual_vendor_id, actual_device_id) = (ids as u16, (ids >> 16) as u16);
+    if (actual_vendor_id, actual_device_id) != (vendor_id, device_id) {
+        return Err(SerialDiscoveryError::UnexpectedHostBridge {
+            vendor_id: actual_vendor_id,
+            device_id: actual_device_id,
+        });
+    }
+    Ok(())
+}
+
+/* Note [Serial controller class code]
+
+The class code register (offset 0x8) identifies what kind of device a PCI function is, independent
+of its vendor:
+
+* Bits 24-31 (base class): 0x07 = simple communications controller.
+* Bits 16-23 (subclass): 0x00 = serial controller.
+* Bits 8-15 (programming interface): 0x02 = 16550-compatible. 0x03 to 0x06 are the 16650, 16750,
+  16850 and 16950, which are 16550 supersets.
+
+The kernel's serial driver is for 16550-compatible UARTs, so any function with a matching class
+code will do. QEMU's `pci-serial` device (VID:DID `1b36:0002`) is also accepted by ID, in case its
+class code ever changes.
+
+Reference: PCI Code and ID Assignment Specification, Section 1.8
+*/
+
+/** Whether a function is a serial controller, from its vendor and device ID register (offset
+0x0) and its class code register (offset 0x8).
+
+See Note [Serial controller class code].
+*/
+pub const fn is_serial_controller(vendor_and_device_ids: u32, class_code: u32) -> bool {
+    let class = (class_code >> 24) as u8;
+    let subclass = (class_code >> 16) as u8;
+    let prog_if = (class_code >> 8) as u8;
+    let is_16550_compatible =
+        class == 0x07 && subclass == 0x00 && prog_if >= 0x02 && prog_if <= 0x06;
+    let is_qemu_pci_serial = vendor_and_device_ids == 0x0002_1b36;
+
+    is_16550_compatible || is_qemu_pci_serial
+}
+
+const _: () = {
+    assert!(is_serial_controller(0x7020_8086, 0x0700_0200));
+    assert!(is_serial_controller(0x7020_8086, 0x0700_0600));
+    assert!(!is_serial_controller(0x7020_8086, 0x0700_0100));
+    assert!(!is_serial_controller(0x7020_8086, 0x0701_0200));
+    assert!(is_serial_controller(0x0002_1b36, 0x0000_0000));
+};
+
+/* Note [Serial controller BAR0]
+
+A serial controller's registers are at the address in BAR0 (configuration register 0x10). Bit 0
+says which address space the BAR is in:
+
+* 1: I/O space. Bits 2-31 are the port (only the low 16 bits are used on x86).
+* 0: memory space. Bits 1-2 are the BAR's type: `00` is a 32-bit address, and `10` is a 64-bit
+  address whose upper half is in BAR1. Bits 4-31 are the address.
+
+PCIe UARTs often only have a memory BAR. Their registers are mapped uncached, one byte per
+register, like the I/O ports of a legacy UART.
+
+Reference: PCI Local Bus Specification 3.0, Section 6.2.5.1
+*/
+
+/** Decode a serial controller's BAR0 (and BAR1, for a 64-bit memory BAR0).
+
+See Note [Serial controller BAR0].
+*/
+pub const fn decode_serial_bar0(bar0: u32, bar1: u32) -> Result<SerialDevice, Bar0Problem> {
+    if bar0 & 0x1 == 0x1 {
+        if bar0 > u16::MAX as u32 {
+            return Err(Bar0Problem::PortTooWide);
+        }
+        return Ok(SerialDevice::Io((bar0 & 0xffff_fffc) as u16));
+    }
+
+    let address = match (bar0 >> 1) & 0b11 {
+        0b00 => (bar0 & 0xffff_fff0) as u64,
+        0b10 => (bar0 & 0xffff_fff0) as u64 | (bar1 as u64) << 32,
+        _ => return Err(Bar0Problem::ReservedType),
+    };
+    if address == 0 {
+        return Err(Bar0Problem::Unassigned);
+    }
+    Ok(SerialDevice::Mmio(address))
+}
+
+const _: () = {
+    assert!(matches!(
+        decode_serial_bar0(0x0000_c001, 0),
+        Ok(SerialDevice::Io(0xc000))
+    ));
+    // Bits 2-3 are part of an I/O BAR's port.
+    assert!(matches!(
+        decode_serial_bar0(0x0000_c009, 0),
+        Ok(SerialDevice::Io(0xc008))
+    ));
+    assert!(matches!(
+        decode_serial_bar0(0x0001_0001, 0),
+        Err(Bar0Problem::PortTooWide)
+    ));
+    assert!(matches!(
+        decode_serial_bar0(0xfebf_1000, 0x1234),
+        Ok(SerialDevice::Mmio(0xfebf_1000))
+    ));
+    assert!(matches!(
+        decode_serial_bar0(0xfebf_100c, 0x1),
+        Ok(SerialDevice::Mmio(0x1_febf_1000))
+    ));
+    assert!(matches!(
+        decode_serial_bar0(0xfebf_1006, 0),
+        Err(Bar0Problem::ReservedType)
+    ));
+    assert!(matches!(
+        decode_serial_bar0(0x0000_0000, 0),
+        Err(Bar0Problem::Unassigned)
+    ));
+    assert!(matches!(
+        decode_serial_bar0(0x0000_0004, 0),
+        Err(Bar0Problem::Unassigned)
+    ));
+};
+
+/** Find the first serial controller among `functions` that has a usable BAR0, and enable it.
+
+`read_u32` reads a dword from a function's configuration space, and `enable` turns on the
+function's decoding of the address space that its registers are in. The controller's location
+is returned with its registers.
+
+See Note [Serial controller class code] and Note [Serial controller BAR0]. When a serial
+controller is found but its BAR0 can't be used, the search continues, and the last such
+controller is reported if nothing else is found. Any failed access ends the search.
+
+## Example
+
+```rust
+# use std::collections::HashMap;
+# use common::boot_info::SerialDevice;
+# use common::pci::serial::{find_serial_controller, Bar0Problem, SerialDiscoveryError};
+// A network controller at 0:1.0, a serial controller with an I/O BAR0 at 0:2.0, a serial
+// controller with an unassigned BAR0 at 0:3.0, and a function whose class code can't be read at
+// 0:4.0.
+let mut config_space = HashMap::new();
+config_space.insert(((0, 1, 0), 0x8), Ok(0x0200_0000));
+config_space.insert(((0, 2, 0), 0x8), Ok(0x0700_0200));
+config_space.insert(((0, 2, 0), 0x10), Ok(0x0000_c001));
+config_space.insert(((0, 3, 0), 0x8), Ok(0x0700_0200));
+config_space.insert(((0, 3, 0), 0x10), Ok(0x0000_0000));
+config_space.insert(((0, 4, 0), 0x8), Err("device error"));
+let read_u32 = |location: (u8, u8, u8), register: u8| {
+    config_space.get(&(location, register)).copied().unwrap_or(Ok(0))
+};
+
+let mut enabled = Vec::new();
+let result = find_serial_controller(
+    [Ok((0, 1, 0)), Ok((0, 2, 0))],
+    read_u32,
+    |location, serial_device| {
+        enabled.push((location, serial_device));
+        Ok(())
+    },
+);
+assert_eq!(result, Ok(((0, 2, 0), SerialDevice::Io(0xc000))));
+assert_eq!(enabled, [((0, 2, 0), SerialDevice::Io(0xc000))]);
+
+// A controller with an unusable BAR0 is skipped, but reported when there's no other.
+let enable = |_location, _serial_device| Ok(());
+assert_eq!(
+    find_serial_controller([Ok((0, 3, 0)), Ok((0, 2, 0))], read_u32, enable),
+    Ok(((0, 2, 0), SerialDevice::Io(0xc000)))
+);
+assert_eq!(
+    find_serial_controller([Ok((0, 1, 0)), Ok((0, 3, 0))], read_u32, enable),
+    Err(SerialDiscoveryError::UnusableBar0 {
+        location: (0, 3, 0),
+        bar0: 0,
+        problem: Bar0Problem::Unassigned,
+    })
+);
+assert_eq!(
+    find_serial_controller([Ok((0, 1, 0))], read_u32, enable),
+    Err(SerialDiscoveryError::NotFound)
+);
+
+// Failed accesses end the search: while enumerating functions, while reading a function's
+// registers, and while enabling the controller.
+assert_eq!(
+    find_serial_controller([Ok((0, 1, 0)), Err("bus error"), Ok((0, 2, 0))], read_u32, enable),
+    Err(SerialDiscoveryError::Pci("bus error"))
+);
+assert_eq!(
+    find_serial_controller([Ok((0, 4, 0)), Ok((0, 2, 0))], read_u32, enable),
+    Err(SerialDiscoveryError::Pci("device error"))
+);
+assert_eq!(
+    find_serial_controller([Ok((0, 2, 0))], read_u32, |_, _| Err("write failed")),
+    Err(SerialDiscoveryError::Pci("write failed"))
+);
+```
+*/
+pub fn find_serial_controller<E>(
+    functions: impl IntoIterator<Item = Result<Location, E>>,
+    mut read_u32: impl FnMut(Location, u8) -> Result<u32, E>,
+    mut enable: impl FnMut(Location, SerialDevice) -> Result<(), E>,
+) -> Result<(Location, SerialDevice), SerialDiscoveryError<E>> {
+    let mut result = Err(SerialDiscoveryError::NotFound);
+    for location in functions {
+        let location = location.map_err(SerialDiscoveryError::Pci)?;
+        let mut read = |register| read_u32(location, register).map_err(SerialDiscoveryError::Pci);
+
+        if !is_serial_controller(read(0x0)?, read(0x8)?) {
+            continue;
+        }
+
+        let bar0 = read(0x10)?;
+        let bar1 = read(0x14)?;
+        let serial_device = match decode_serial_bar0(bar0, bar1) {
+            Ok(serial_device) => serial_device,
+            Err(problem) => {
+                result = Err(SerialDiscoveryError::UnusableBar0 {
+                    location,
+                    bar0,
+                    problem,
+                });
+                continue;
+            }
+        };
+
+        enable(location, serial_device).map_err(SerialDiscoveryError::Pci)?;
+        return Ok((location, serial_device));
+    }
+
+    result
+}
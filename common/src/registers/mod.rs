@@ -1,4 +1,4 @@
-use core::arch::asm;
+use core::arch::{asm, x86_64::__cpuid};
 
 /** The CR0 register.
 
@@ -18,6 +18,11 @@ impl CR0 {
         Self(value)
     }
 
+    /// Wrap a CR0 value that was read earlier, or built by hand.
+    pub const fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
     /** Set the contents of the CR0 register.
 
     # Safety
@@ -35,6 +40,16 @@ impl CR0 {
         self.0 & mask == mask
     }
 
+    /// Set paging.
+    pub fn set_pg(&mut self, value: bool) {
+        let mask = 1 << 31;
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+
     /// Protection enable.
     pub fn pe(&self) -> bool {
         let mask = 1;
@@ -42,6 +57,30 @@ impl CR0 {
     }
 }
 
+/** The CR2 register: the linear address that caused the most recent page fault.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 2.5.
+*/
+#[derive(Clone, Copy)]
+pub struct CR2(u64);
+
+impl CR2 {
+    /** Get the contents of the CR2 register.
+
+    Only meaningful in a page fault handler, before anything else can fault.
+    */
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe { asm!("mov {value}, cr2", value = out(reg) value) };
+        Self(value)
+    }
+
+    /// The virtual address whose access faulted.
+    pub fn address(&self) -> u64 {
+        self.0
+    }
+}
+
 /** The contents of the CR3 register when used with 4-level paging and PCIDs disabled.
 
 Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Table 4-12.
@@ -60,6 +99,11 @@ impl CR3 {
         Self(value)
     }
 
+    /// Wrap a CR3 value that was read earlier, or built by hand.
+    pub const fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
     /** Set the contents of the CR3 register.
 
     # Safety
@@ -139,12 +183,125 @@ impl CR3 {
         }
     }
 
+    /// The value that [`CR3::write`] writes.
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    /// Physical address of the 4KiB aligned PML4 table.
+    pub fn address(&self) -> u64 {
+        self.0 & !0xfff
+    }
+
+    /** Set PML4 table address.
+
+    PWT and PCD are preserved. All other bits below the address are reserved (Table 4-12), so
+    they're cleared.
+
+    ## Example
+
+    ```rust
+    # use common::registers::CR3;
+    // PWT and PCD are set, along with reserved bits 5-11.
+    let mut cr3 = CR3::from_value(0x1_2345_6ff8);
+    cr3.set_address(0x5000);
+    assert_eq!(cr3.value(), 0x5018);
+    assert_eq!(cr3.address(), 0x5000);
+    assert!(cr3.pwt() && cr3.pcd());
+    ```
+    */
+    pub fn set_address(&mut self, value: u64) {
+        assert_eq!(
+            value & !0xfff,
+            value,
+            "address {:#x} isn't 4KiB aligned",
+            value
+        );
+        let pwt_and_pcd = (1 << 3) | (1 << 4);
+        self.0 &= pwt_and_pcd;
+        self.0 |= value;
+    }
+}
+
+/** The contents of the CR3 register when used with 4-level paging and PCIDs enabled.
+
+PCIDs are enabled by setting CR4.PCIDE (see [`CR4::set_pcide`]). In this mode the low 12 bits of
+CR3 are the current process-context identifier (PCID), instead of the PWT and PCD flags that
+[`CR3`] reads. Use [`CR3`] when PCIDs are disabled.
+
+# TLB behaviour
+
+With PCIDs enabled the processor tags each TLB entry with the PCID that was current when it was
+created, and only uses the entries tagged with the current PCID (plus global pages). This means
+switching address spaces doesn't have to throw away the TLB.
+
+* [`CR3Pcid::write`] invalidates all non-global TLB entries tagged with the *new* PCID. Entries for
+  other PCIDs are retained. (Without PCIDs, writing CR3 invalidates all non-global entries.)
+* [`CR3Pcid::write_no_flush`] sets bit 63 of the value that's written, which tells the processor
+  not to invalidate anything. This is only correct when the TLB entries for the PCID are known to
+  match the page map that's being switched to.
+* `invlpg` only invalidates the page's entries for the *current* PCID (and global entries for the
+  page). Stale entries tagged with other PCIDs survive, so changing a page map that's also
+  cached under another PCID requires `invpcid` or a flushing CR3 write when switching back to it.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Table 4-13 and Section 4.10.4.1.
+*/
+#[derive(Clone, Copy)]
+pub struct CR3Pcid(u64);
+
+impl CR3Pcid {
+    /** Get the contents of the CR3 register.
+
+    To set the contents of the CR3 register, use the [`CR3Pcid::write`] method.
+    */
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe { asm!("mov {value}, cr3", value = out(reg) value) };
+        Self(value)
+    }
+
+    /** Set the contents of the CR3 register, invalidating the TLB entries for its PCID.
+
+    # Safety
+
+    See [`CR3::write`]. CR4.PCIDE must be set.
+    */
+    #[inline(always)]
+    pub unsafe fn write(&self) {
+        asm!("mov cr3, {value}", value = in(reg) self.0)
+    }
+
+    /** Set the contents of the CR3 register without invalidating any TLB entries.
+
+    # Safety
+
+    See [`CR3::write`]. CR4.PCIDE must be set, and the TLB entries tagged with this PCID must
+    be consistent with the page map at [`CR3Pcid::address`].
+    */
+    #[inline(always)]
+    pub unsafe fn write_no_flush(&self) {
+        let value = self.0 | (1 << 63);
+        asm!("mov cr3, {value}", value = in(reg) value)
+    }
+
+    /// Process-context identifier.
+    pub fn pcid(&self) -> u16 {
+        (self.0 & 0xfff) as u16
+    }
+
+    /// Set the process-context identifier.
+    pub fn set_pcid(&mut self, value: u16) {
+        assert!(value <= 0xfff, "PCID {:#x} is more than 12 bits", value);
+        self.0 &= !0xfff;
+        self.0 |= value as u64;
+    }
+
     /// Physical address of the 4KiB aligned PML4 table.
     pub fn address(&self) -> u64 {
         self.0 & !0xfff
     }
 
-    /// Set PML4 table address.
+    /// Set PML4 table address, preserving the PCID.
     pub fn set_address(&mut self, value: u64) {
         assert_eq!(
             value & !0xfff,
@@ -175,6 +332,11 @@ impl CR4 {
         Self(value)
     }
 
+    /// Wrap a CR4 value that was read earlier, or built by hand.
+    pub const fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
     /** Set the contents of the CR4 register.
 
     # Safety
@@ -192,49 +354,485 @@ impl CR4 {
         self.0 & mask == mask
     }
 
+    /// Set physical address extension.
+    pub fn set_pae(&mut self, value: bool) {
+        let mask = 1 << 5;
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+
     /// 57-bit linear addresses.
     pub fn la57(&self) -> bool {
         let mask = 1 << 12;
         self.0 & mask == mask
     }
+
+    /** PCID enable.
+
+    See [`CR3Pcid`].
+    */
+    pub fn pcide(&self) -> bool {
+        let mask = 1 << 17;
+        self.0 & mask == mask
+    }
+
+    /** Set PCID enable.
+
+    The change only takes effect once the register is written with [`CR4::write`].
+    PCIDE can only be set while the current PCID (CR3 bits 0-11) is 0 and the processor is in
+    IA-32e mode; otherwise the write causes a general-protection fault.
+    */
+    pub fn set_pcide(&mut self, value: bool) {
+        let mask = 1 << 17;
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
 }
 
-/** The IA32_EFER MSR.
+/** A model-specific register.
 
 References:
 
 * Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 2.8.7. (working with MSRs)
 * Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 4, Table 2-2. (MSR details)
 */
-#[allow(non_camel_case_types)]
-pub struct IA32_EFER(u64);
+pub struct Msr(pub u32);
 
-impl IA32_EFER {
-    const REGISTER_ADDRESS: u32 = 0xc000_0080;
+impl Msr {
+    /** Read the MSR.
 
-    pub fn read() -> Self {
+    # Safety
+
+    The MSR must exist on this processor, otherwise `rdmsr` causes a general-protection fault.
+    */
+    pub unsafe fn read(&self) -> u64 {
         let value_low: u32;
         let value_high: u32;
 
-        unsafe {
-            asm!(
-                "rdmsr",
-                in("ecx") Self::REGISTER_ADDRESS,
-                out("edx") value_high,
-                out("eax") value_low,
-            );
-        }
+        asm!(
+            "rdmsr",
+            in("ecx") self.0,
+            out("edx") value_high,
+            out("eax") value_low,
+        );
 
         let mut value = value_high as u64;
         value <<= 32;
         value |= value_low as u64;
 
+        value
+    }
+
+    /** Write the MSR.
+
+    # Safety
+
+    The MSR must exist on this processor, and `value` must be valid for it. Refer to the MSR's
+    documentation for the effects of changing it.
+    */
+    pub unsafe fn write(&self, value: u64) {
+        asm!(
+            "wrmsr",
+            in("ecx") self.0,
+            in("edx") (value >> 32) as u32,
+            in("eax") value as u32,
+        );
+    }
+}
+
+/** The IA32_EFER MSR.
+
+References:
+
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 2.8.7. (working with MSRs)
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 4, Table 2-2. (MSR details)
+*/
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+pub struct IA32_EFER(u64);
+
+impl IA32_EFER {
+    const REGISTER_ADDRESS: u32 = 0xc000_0080;
+
+    pub fn read() -> Self {
+        // Safety: IA32_EFER exists on all x86-64 processors.
+        Self(unsafe { Msr(Self::REGISTER_ADDRESS).read() })
+    }
+
+    /// Wrap a IA32_EFER value that was read earlier, or built by hand.
+    pub const fn from_value(value: u64) -> Self {
         Self(value)
     }
 
+    /** Set the contents of the IA32_EFER MSR.
+
+    # Safety
+
+    Clearing LME or NXE while paging is enabled breaks address translation. Refer to Intel® 64
+    and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 2.2.1.
+    */
+    pub unsafe fn write(&self) {
+        Msr(Self::REGISTER_ADDRESS).write(self.0)
+    }
+
+    /// System Call Extensions. Enables the `syscall` and `sysret` instructions.
+    pub fn sce(&self) -> bool {
+        let mask = 1;
+        self.0 & mask == mask
+    }
+
+    /// Set System Call Extensions.
+    pub fn set_sce(&mut self, value: bool) {
+        let mask = 1;
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+
     /// IA-32e Mode Enable.
     pub fn lme(&self) -> bool {
         let mask = 1 << 8;
         self.0 & mask == mask
     }
+
+    /// Set IA-32e Mode Enable.
+    pub fn set_lme(&mut self, value: bool) {
+        let mask = 1 << 8;
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+
+    /** Execute Disable Bit Enable.
+
+    The XD bit of page map entries is only honoured when this is set. When it's clear, XD is a
+    reserved bit and setting it in a present entry causes a page fault.
+    */
+    pub fn nxe(&self) -> bool {
+        let mask = 1 << 11;
+        self.0 & mask == mask
+    }
+
+    /** Set Execute Disable Bit Enable.
+
+    Only processors that support execute-disable (see [`execute_disable_supported`]) allow this
+    bit to be set.
+    */
+    pub fn set_nxe(&mut self, value: bool) {
+        let mask = 1 << 11;
+        if value {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+    }
+}
+
+/** The IA32_APIC_BASE MSR: where the local APIC's registers are, and whether it's enabled.
+
+References:
+
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 11.4.4 and
+  Figure 11-5.
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 4, Table 2-2.
+
+## Example
+
+```rust
+# use common::registers::IA32_APIC_BASE;
+let apic_base = IA32_APIC_BASE::from_value(0xfee0_0900);
+assert_eq!(apic_base.address(), 0xfee0_0000);
+assert!(apic_base.enabled());
+assert!(apic_base.bsp());
+
+let apic_base = IA32_APIC_BASE::from_value(0xfee0_0000);
+assert!(!apic_base.enabled());
+assert!(!apic_base.bsp());
+```
+*/
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+pub struct IA32_APIC_BASE(u64);
+
+impl IA32_APIC_BASE {
+    const REGISTER_ADDRESS: u32 = 0x1b;
+
+    /** Get the contents of the IA32_APIC_BASE MSR.
+
+    # Safety
+
+    The processor must have a local APIC (see [`local_apic_supported`]).
+    */
+    pub unsafe fn read() -> Self {
+        Self(Msr(Self::REGISTER_ADDRESS).read())
+    }
+
+    /// Wrap a IA32_APIC_BASE value that was read earlier, or built by hand.
+    pub const fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
+    /// *Physical* address of the local APIC's 4KiB page of registers.
+    pub fn address(&self) -> u64 {
+        self.0 & 0x000f_ffff_ffff_f000
+    }
+
+    /// APIC global enable. When it's clear, the local APIC is disabled.
+    pub fn enabled(&self) -> bool {
+        let mask = 1 << 11;
+        self.0 & mask == mask
+    }
+
+    /// This processor is the bootstrap processor.
+    pub fn bsp(&self) -> bool {
+        let mask = 1 << 8;
+        self.0 & mask == mask
+    }
+}
+
+/** Whether the processor has a local APIC (CPUID.01H:EDX.APIC, bit 9).
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 2A, `CPUID`.
+*/
+pub fn local_apic_supported() -> bool {
+    let edx = __cpuid(0x1).edx;
+    let mask = 1 << 9;
+    edx & mask == mask
+}
+
+/** Whether the processor supports execute-disable (CPUID.80000001H:EDX.NX, bit 20).
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 2A, `CPUID`.
+*/
+pub fn execute_disable_supported() -> bool {
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf < 0x8000_0001 {
+        return false;
+    }
+
+    let edx = __cpuid(0x8000_0001).edx;
+    let mask = 1 << 20;
+    edx & mask == mask
+}
+
+/** The processor's physical address width (MAXPHYADDR), in bits (CPUID.80000008H:EAX[7:0]).
+
+Physical addresses in page map entries must fit in this many bits. When the leaf isn't
+supported, the width is 36 bits.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 2A, `CPUID`, and
+Vol 3A, Section 4.1.4.
+*/
+pub fn max_physical_address_width() -> u8 {
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf < 0x8000_0008 {
+        return 36;
+    }
+
+    __cpuid(0x8000_0008).eax as u8
+}
+
+/** Enable 4-level paging with the PML4 table at `pml4_address`.
+
+The processor only enters IA-32e mode when these steps happen in order:
+
+1. Set CR4.PAE. IA-32e mode uses PAE-format page map entries.
+2. Load CR3 with the PML4 table's physical address. When paging is enabled, the processor
+   starts walking the page map from CR3.
+3. Set IA32_EFER.LME. This arms IA-32e mode, but doesn't activate it. LME can only change
+   while paging is disabled.
+4. Set CR0.PG. Paging and IA-32e mode (IA32_EFER.LMA) are activated together.
+
+When 4-level paging is already enabled (e.g. by UEFI), steps 1, 3 and 4 don't change anything,
+so this only switches to the new page map.
+
+References:
+
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 4.1.2
+  (paging-mode enabling) and Section 4.5 (4-level paging)
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 10.8.5
+  (initializing IA-32e mode)
+
+# Safety
+
+See [`CR3::write`]: the code, stack and IDT must be mapped at the same addresses by the new page
+map. Paging must either be disabled, with protected mode enabled (CR0.PE) and the code running
+from an identity-mapped page, or already be 4-level paging. Changing CR4.PAE or IA32_EFER.LME
+while paging is enabled causes a general-protection fault.
+*/
+#[inline(always)]
+pub unsafe fn enable_4level_paging(pml4_address: u64) {
+    let mut cr4 = CR4::read();
+    if !cr4.pae() {
+        cr4.set_pae(true);
+        cr4.write();
+    }
+
+    let mut cr3 = CR3::read();
+    cr3.set_address(pml4_address);
+    cr3.write();
+
+    let mut ia32_efer = IA32_EFER::read();
+    if !ia32_efer.lme() {
+        ia32_efer.set_lme(true);
+        ia32_efer.write();
+    }
+
+    let mut cr0 = CR0::read();
+    if !cr0.pg() {
+        cr0.set_pg(true);
+        cr0.write();
+    }
+}
+
+/** Invalidate the TLB entries for the page that contains `virtual_address` (INVLPG).
+
+Needed after changing or removing the mapping of a page in the active page map.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 2A, INVLPG.
+
+# Safety
+
+Must run in ring 0.
+*/
+#[inline(always)]
+pub unsafe fn invlpg(virtual_address: u64) {
+    asm!("invlpg [{address}]", address = in(reg) virtual_address, options(nostack, preserves_flags))
+}
+
+/** A memory type that can be assigned to a page attribute table entry.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Table 12-10.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PatMemoryType {
+    /// Uncacheable (UC).
+    Uncacheable = 0x00,
+
+    /// Write combining (WC).
+    WriteCombining = 0x01,
+
+    /// Write-through (WT).
+    WriteThrough = 0x04,
+
+    /// Write protected (WP).
+    WriteProtected = 0x05,
+
+    /// Write-back (WB).
+    WriteBack = 0x06,
+
+    /// Uncached (UC-). Like UC, but can be overridden by a write-combining MTRR.
+    Uncached = 0x07,
+}
+
+/** The IA32_PAT MSR (page attribute table).
+
+The PAT has 8 entries, each of which is a memory type. A page's memory type comes from the entry
+selected by the PAT, PCD and PWT bits of the page's leaf entry: `index = 4 * PAT + 2 * PCD + PWT`.
+
+The power-on value maps entries 0-7 to WB, WT, UC-, UC, WB, WT, UC-, UC, which makes the PAT bit
+irrelevant until the upper four entries are reprogrammed. See [`IA32_PAT::WRITE_COMBINING_INDEX`].
+
+## Example
+
+```rust
+# use common::registers::{IA32_PAT, PatMemoryType};
+let mut pat = IA32_PAT::from_value(IA32_PAT::POWER_ON_VALUE);
+assert_eq!(pat.entry(0), Some(PatMemoryType::WriteBack));
+assert_eq!(pat.entry(3), Some(PatMemoryType::Uncacheable));
+assert_eq!(pat.entry(IA32_PAT::WRITE_COMBINING_INDEX), Some(PatMemoryType::WriteBack));
+
+pat.set_entry(IA32_PAT::WRITE_COMBINING_INDEX, PatMemoryType::WriteCombining);
+assert_eq!(pat.value(), 0x0007_0401_0007_0406);
+assert_eq!(pat.entry(IA32_PAT::WRITE_COMBINING_INDEX), Some(PatMemoryType::WriteCombining));
+
+pat.set_entry(7, PatMemoryType::WriteProtected);
+assert_eq!(pat.value(), 0x0507_0401_0007_0406);
+
+// Types 2 and 3 are reserved.
+assert_eq!(IA32_PAT::from_value(0x0302).entry(0), None);
+assert_eq!(IA32_PAT::from_value(0x0302).entry(1), None);
+```
+
+References:
+
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section 12.12.
+* Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 4, Table 2-2. (MSR details)
+*/
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+pub struct IA32_PAT(u64);
+
+impl IA32_PAT {
+    const REGISTER_ADDRESS: u32 = 0x277;
+
+    /** The entry that [`crate::paging::PageMapFlags::WRITE_COMBINING`] pages use (PAT = 1, PCD = 0, PWT = 0).
+
+    It must be set to [`PatMemoryType::WriteCombining`] before write-combining pages are used.
+    Its power-on value is WB.
+    */
+    pub const WRITE_COMBINING_INDEX: usize = 4;
+
+    /// The PAT's value after a reset: WB, WT, UC-, UC, WB, WT, UC-, UC.
+    pub const POWER_ON_VALUE: u64 = 0x0007_0406_0007_0406;
+
+    pub fn read() -> Self {
+        // Safety: IA32_PAT exists on all x86-64 processors.
+        Self(unsafe { Msr(Self::REGISTER_ADDRESS).read() })
+    }
+
+    /// Wrap an IA32_PAT value that was read earlier, or built by hand.
+    pub const fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub const fn value(&self) -> u64 {
+        self.0
+    }
+
+    /** Set the contents of the IA32_PAT MSR.
+
+    # Safety
+
+    Existing mappings that select a changed entry will change memory type. Intel® 64 and IA-32
+    Architectures Software Developer’s Manual, Vol 3A, Section 12.12.4 describes how to change
+    the PAT safely (including flushing caches and TLBs), and requires every processor to use the
+    same PAT.
+    */
+    pub unsafe fn write(&self) {
+        Msr(Self::REGISTER_ADDRESS).write(self.0)
+    }
+
+    /// The memory type of a PAT entry, or `None` when the entry holds a reserved type.
+    pub fn entry(&self, index: usize) -> Option<PatMemoryType> {
+        assert!(index < 8, "PAT index {} is out of range", index);
+        match (self.0 >> (index * 8)) & 0x7 {
+            0x00 => Some(PatMemoryType::Uncacheable),
+            0x01 => Some(PatMemoryType::WriteCombining),
+            0x04 => Some(PatMemoryType::WriteThrough),
+            0x05 => Some(PatMemoryType::WriteProtected),
+            0x06 => Some(PatMemoryType::WriteBack),
+            0x07 => Some(PatMemoryType::Uncached),
+            _ => None,
+        }
+    }
+
+    /// Set the memory type of a PAT entry.
+    pub fn set_entry(&mut self, index: usize, memory_type: PatMemoryType) {
+        assert!(index < 8, "PAT index {} is out of range", index);
+        let offset = index * 8;
+        self.0 &= !(0xff << offset);
+        self.0 |= (memory_type as u64) << offset;
+    }
 }
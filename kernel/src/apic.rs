@@ -0,0 +1,202 @@
+/*! The local APIC, and its timer.
+
+Each processor has a local APIC, which delivers interrupts to it and has a timer of its own.
+Its registers are a 4KiB page of memory-mapped registers, which the bootloader maps uncached
+(see [`BootInfo::local_apic_address`](common::BootInfo::local_apic_address)).
+
+The kernel doesn't have an IDT yet, so the timer's interrupt stays masked, and [`demo`] polls
+the timer's current count instead of handling the interrupt.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Chapter 11.
+*/
+use common::mmio;
+
+/* Note [Local APIC registers]
+
+Offsets from the local APIC's base address. Every register is 32 bits wide and 16-byte aligned,
+and has to be accessed with aligned 32-bit loads and stores.
+
+* `0x020` (ID): bits 24-31 are the local APIC's ID.
+* `0x0f0` (spurious interrupt vector): bits 0-7 are the vector delivered for a spurious
+  interrupt, and bit 8 is APIC software enable.
+* `0x320` (LVT timer): bits 0-7 are the timer's vector, bit 16 masks it, and bits 17-18 are the
+  timer mode (`00` = one-shot, `01` = periodic).
+* `0x380` (timer initial count): writing it starts the timer, and writing 0 stops it.
+* `0x390` (timer current count): read-only. Counts down from the initial count.
+* `0x3e0` (timer divide configuration): bits 0, 1 and 3. See [`divide_configuration`].
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Table 11-1,
+Section 11.5.4 and Section 11.9.
+*/
+const ID: u64 = 0x020;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0x0f0;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE_CONFIGURATION: u64 = 0x3e0;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+/** The vector for spurious interrupts.
+
+Some processors hardwire the low 4 bits of the spurious vector to 1, so `0xff` is the same
+everywhere.
+*/
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The timer's vector: the first one after the 32 that are reserved for exceptions.
+pub const TIMER_VECTOR: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Count down from the initial count once, then stop.
+    OneShot,
+
+    /// Reload the initial count every time the count reaches 0.
+    Periodic,
+}
+
+impl TimerMode {
+    /// Bits 17-18 of the LVT timer register.
+    const fn lvt_bits(self) -> u32 {
+        match self {
+            TimerMode::OneShot => 0b00 << 17,
+            TimerMode::Periodic => 0b01 << 17,
+        }
+    }
+}
+
+/** The divide configuration register's encoding of `divisor`.
+
+The timer counts down once every `divisor` bus clock cycles. `divisor` must be a power of 2
+from 1 to 128.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Figure 11-10.
+*/
+pub const fn divide_configuration(divisor: u32) -> u32 {
+    match divisor {
+        1 => 0b1011,
+        2 => 0b0000,
+        4 => 0b0001,
+        8 => 0b0010,
+        16 => 0b0011,
+        32 => 0b1000,
+        64 => 0b1001,
+        128 => 0b1010,
+        _ => panic!("divisor must be a power of 2 from 1 to 128"),
+    }
+}
+
+const _: () = assert!(divide_configuration(1) == 0b1011);
+const _: () = assert!(divide_configuration(2) == 0b0000);
+const _: () = assert!(divide_configuration(16) == 0b0011);
+const _: () = assert!(divide_configuration(128) == 0b1010);
+const _: () = assert!(TimerMode::OneShot.lvt_bits() == 0);
+const _: () = assert!(TimerMode::Periodic.lvt_bits() == 0x2_0000);
+
+/* Note [Enabling the local APIC]
+
+1. IA32_APIC_BASE.EN (bit 11) globally enables the local APIC. The firmware sets it, and the
+   bootloader only passes on the APIC's address when it's set.
+2. After a reset, the spurious interrupt vector register's software enable bit (bit 8) is
+   clear, and the APIC doesn't deliver any interrupts. [`LocalApic::enable`] sets it.
+3. The timer is programmed in the order [`LocalApic::start_timer`] uses: the divide
+   configuration, then the LVT timer entry (vector, mask and mode), then the initial count,
+   because writing the initial count starts the countdown.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 3A, Section
+11.4.3 and Section 11.5.4.
+*/
+
+/// The current processor's local APIC. See Note [Local APIC registers].
+pub struct LocalApic {
+    address: u64,
+}
+
+impl LocalApic {
+    /** # Safety
+
+    `address` must be the local APIC's base address, mapped uncached in the current address
+    space.
+    */
+    pub unsafe fn new(address: u64) -> Self {
+        LocalApic { address }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        // Safety: `LocalApic::new`'s requirements.
+        unsafe { mmio::read_volatile_u32(self.address + offset) }
+    }
+
+    fn write(&mut self, offset: u64, value: u32) {
+        // Safety: `LocalApic::new`'s requirements.
+        unsafe { mmio::write_volatile_u32(self.address + offset, value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Software-enable the local APIC. See Note [Enabling the local APIC].
+    pub fn enable(&mut self) {
+        let value = self.read(SPURIOUS_INTERRUPT_VECTOR);
+        self.write(
+            SPURIOUS_INTERRUPT_VECTOR,
+            (value & !0xff) | APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+    }
+
+    /** Start the timer, counting down from `initial_count` once every `divisor` bus clock cycles.
+
+    See Note [Enabling the local APIC] and [`divide_configuration`].
+
+    # Safety
+
+    When `masked` is false, the timer raises `vector` every time it reaches 0, so the IDT must
+    have a handler for it.
+    */
+    pub unsafe fn start_timer(
+        &mut self,
+        mode: TimerMode,
+        vector: u8,
+        masked: bool,
+        divisor: u32,
+        initial_count: u32,
+    ) {
+        self.write(TIMER_DIVIDE_CONFIGURATION, divide_configuration(divisor));
+
+        let mask = if masked { LVT_MASKED } else { 0 };
+        self.write(LVT_TIMER, mode.lvt_bits() | mask | vector as u32);
+
+        self.write(TIMER_INITIAL_COUNT, initial_count);
+    }
+
+    pub fn stop_timer(&mut self) {
+        self.write(TIMER_INITIAL_COUNT, 0);
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(TIMER_CURRENT_COUNT)
+    }
+}
+
+/** Run the timer once in one-shot mode, and log when it reaches 0.
+
+The timer's interrupt is masked, because there's no IDT to handle it, so this polls the current
+count.
+*/
+pub fn demo(local_apic: &mut LocalApic, initial_count: u32) {
+    // Safety: the timer is masked.
+    unsafe { local_apic.start_timer(TimerMode::OneShot, TIMER_VECTOR, true, 16, initial_count) };
+
+    while local_apic.timer_current_count() != 0 {
+        core::hint::spin_loop();
+    }
+
+    ::log::info!(
+        "local APIC {} timer fired after {} ticks",
+        local_apic.id(),
+        initial_count
+    );
+}
@@ -0,0 +1,45 @@
+/*! Interactive input over the serial console.
+
+[`init_console`] chooses the serial device, and [`read_line`] reads a line from it using
+[`common::line_editor`].
+*/
+use core::ptr::addr_of_mut;
+
+use crate::serial::PC16500D;
+
+static mut CONSOLE: Option<PC16500D> = None;
+
+/** Set the serial device that [`read_line`] reads from.
+
+# Safety
+
+Not thread safe.
+*/
+pub unsafe fn init_console(serial_device: PC16500D) {
+    CONSOLE = Some(serial_device)
+}
+
+/** Read a line from the serial console into `buffer`, echoing it as it's typed, and return its
+length. See [`common::line_editor::read_line`].
+
+Blocks until the line ends. Panics if [`init_console`] hasn't been called.
+*/
+pub fn read_line(buffer: &mut [u8]) -> usize {
+    // Safety: `init_console` is only called during initialisation.
+    let serial_device = unsafe { addr_of_mut!(CONSOLE).as_mut() }
+        .and_then(Option::as_mut)
+        .expect("console isn't initialised");
+
+    // `read_byte` and `echo` both need the device, so they share it through a cell.
+    let serial_device = core::cell::RefCell::new(serial_device);
+    common::line_editor::read_line(
+        // Safety: `init_console`'s caller chose a PC16500D.
+        &mut || unsafe { serial_device.borrow_mut().read_u8() },
+        &mut |bytes| {
+            for &byte in bytes {
+                unsafe { serial_device.borrow_mut().write_u8(byte) }
+            }
+        },
+        buffer,
+    )
+}
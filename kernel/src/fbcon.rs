@@ -0,0 +1,328 @@
+/*! A text console that draws to the linear framebuffer.
+
+Text is rendered with the 8x16 bitmap [`font`], so a 1024x768 framebuffer holds 128 columns
+and 48 rows. When the cursor moves past the last row, the console scrolls up by one row.
+*/
+pub mod font;
+
+use core::{alloc::Layout, ops::Range};
+
+use common::boot_info::{FramebufferInfo, PixelFormat};
+
+use font::{FIRST_CHAR, FONT, GLYPH_HEIGHT, GLYPH_WIDTH, LAST_CHAR};
+
+/// Encode a colour as a 32-bit pixel.
+pub const fn pixel_value(pixel_format: PixelFormat, red: u8, green: u8, blue: u8) -> u32 {
+    match pixel_format {
+        PixelFormat::Rgb => red as u32 | (green as u32) << 8 | (blue as u32) << 16,
+        PixelFormat::Bgr => blue as u32 | (green as u32) << 8 | (red as u32) << 16,
+    }
+}
+
+const _: () = assert!(pixel_value(PixelFormat::Rgb, 0x11, 0x22, 0x33) == 0x0033_2211);
+const _: () = assert!(pixel_value(PixelFormat::Bgr, 0x11, 0x22, 0x33) == 0x0011_2233);
+
+/// The glyph for a byte. Bytes outside of printable ASCII are drawn as `?`.
+pub const fn glyph(byte: u8) -> &'static [u8; GLYPH_HEIGHT] {
+    let byte = if FIRST_CHAR <= byte && byte <= LAST_CHAR {
+        byte
+    } else {
+        b'?'
+    };
+    &FONT[(byte - FIRST_CHAR) as usize]
+}
+
+const _: () = assert!(glyph(b' ')[8] == 0);
+const _: () = assert!(glyph(b'A')[7] == 0xfe);
+const _: () = assert!(glyph(b'~')[2] == 0x76);
+const _: () = assert!(glyph(0)[2] == glyph(b'?')[2]);
+const _: () = assert!(glyph(0xff)[2] == glyph(b'?')[2]);
+
+/** The index of pixel (`x`, `y`) in a buffer of `height` scan lines of `stride` pixels.
+
+Returns `None` when the pixel is outside the `width` by `height` visible area, so that drawing
+can't write outside of the framebuffer (or into the padding at the end of each scan line).
+*/
+pub const fn pixel_index(
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    stride: usize,
+) -> Option<usize> {
+    if x < width && y < height {
+        Some(y * stride + x)
+    } else {
+        None
+    }
+}
+
+const _: () = assert!(matches!(pixel_index(0, 0, 640, 480, 648), Some(0)));
+const _: () = assert!(matches!(pixel_index(639, 1, 640, 480, 648), Some(1287)));
+const _: () = assert!(matches!(pixel_index(639, 479, 640, 480, 648), Some(311031)));
+const _: () = assert!(pixel_index(640, 0, 640, 480, 648).is_none());
+const _: () = assert!(pixel_index(0, 480, 640, 480, 648).is_none());
+const _: () = assert!(pixel_index(usize::MAX, usize::MAX, 640, 480, 648).is_none());
+
+/** The size of a console on a `width` by `height` pixel framebuffer, in characters.
+
+Returns `None` when the framebuffer is too small to hold a single character.
+*/
+pub const fn console_size(width: usize, height: usize) -> Option<(usize, usize)> {
+    let columns = width / GLYPH_WIDTH;
+    let rows = height / GLYPH_HEIGHT;
+    if columns == 0 || rows == 0 {
+        None
+    } else {
+        Some((columns, rows))
+    }
+}
+
+const _: () = assert!(matches!(console_size(1024, 768), Some((128, 48))));
+const _: () = assert!(matches!(console_size(8, 16), Some((1, 1))));
+const _: () = assert!(matches!(console_size(1031, 783), Some((128, 48))));
+const _: () = assert!(console_size(7, 768).is_none());
+const _: () = assert!(console_size(1024, 15).is_none());
+const _: () = assert!(console_size(0, 0).is_none());
+
+/** A text console on a linear framebuffer.
+
+The console either draws straight to the framebuffer, or to a back buffer in RAM that's copied
+to the framebuffer by [`FramebufferConsole::present`]. The framebuffer is usually uncached or
+write-combining memory, so reading it back (e.g. to scroll) is very slow. With a back buffer,
+drawing and scrolling only touch RAM, and `present` only copies the scan lines that changed since
+the last `present`. Writing a line through [`core::fmt::Write`] presents it, so log records appear
+as they're written.
+*/
+pub struct FramebufferConsole<'a> {
+    /// `height` scan lines of `stride` pixels.
+    framebuffer: &'a mut [u32],
+
+    /// Same layout as `framebuffer`.
+    back_buffer: Option<&'a mut [u32]>,
+
+    /// Size of the visible area, in pixels.
+    width: usize,
+    height: usize,
+
+    stride: usize,
+
+    /// Size of the console, in characters.
+    columns: usize,
+    rows: usize,
+
+    /// Cursor position, in characters.
+    column: usize,
+    row: usize,
+
+    foreground: u32,
+    background: u32,
+
+    /// Scan lines of the back buffer that [`FramebufferConsole::present`] hasn't copied yet.
+    dirty: Range<usize>,
+}
+
+impl<'a> FramebufferConsole<'a> {
+    /** Create a console that draws to `framebuffer`, clearing the screen.
+
+    Returns `None` when the framebuffer is too small to hold a single character (see
+    [`console_size`]).
+
+    # Safety
+
+    `framebuffer` must describe a writable linear framebuffer in the current address space, and
+    nothing else may access the framebuffer while the console is alive.
+    */
+    pub unsafe fn new(framebuffer: &FramebufferInfo) -> Option<Self> {
+        Self::from_parts(framebuffer, None)
+    }
+
+    /** Create a console that draws to `back_buffer`, clearing the screen.
+
+    Nothing is displayed until [`FramebufferConsole::present`] is called. `back_buffer` must
+    hold at least `stride * height` pixels. Returns `None` in the same cases as
+    [`FramebufferConsole::new`].
+
+    # Safety
+
+    See [`FramebufferConsole::new`].
+    */
+    pub unsafe fn with_back_buffer(
+        framebuffer: &FramebufferInfo,
+        back_buffer: &'a mut [u32],
+    ) -> Option<Self> {
+        let size = framebuffer.stride as usize * framebuffer.height as usize;
+        assert!(
+            back_buffer.len() >= size,
+            "back buffer is smaller than {} pixels",
+            size
+        );
+        Self::from_parts(framebuffer, Some(&mut back_buffer[..size]))
+    }
+
+    unsafe fn from_parts(
+        framebuffer: &FramebufferInfo,
+        back_buffer: Option<&'a mut [u32]>,
+    ) -> Option<Self> {
+        let (columns, rows) =
+            console_size(framebuffer.width as usize, framebuffer.height as usize)?;
+        let stride = framebuffer.stride as usize;
+        let height = framebuffer.height as usize;
+        assert!(
+            (stride * height * 4) as u64 <= framebuffer.size,
+            "framebuffer is smaller than {}x{} pixels",
+            stride,
+            height
+        );
+
+        let mut console = FramebufferConsole {
+            framebuffer: core::slice::from_raw_parts_mut(
+                framebuffer.address as *mut u32,
+                stride * height,
+            ),
+            back_buffer,
+            width: framebuffer.width as usize,
+            height,
+            stride,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+            foreground: pixel_value(framebuffer.pixel_format, 0xff, 0xff, 0xff),
+            background: pixel_value(framebuffer.pixel_format, 0, 0, 0),
+            dirty: 0..0,
+        };
+        console.clear();
+        Some(console)
+    }
+
+    /** Allocate a back buffer for `framebuffer` from the heap.
+
+    Returns `None` when the heap doesn't have room for one, without calling the OOM hook.
+    */
+    pub fn allocate_back_buffer(framebuffer: &FramebufferInfo) -> Option<&'static mut [u32]> {
+        let len = framebuffer.stride as usize * framebuffer.height as usize;
+        if len == 0 {
+            return None;
+        }
+        let layout = Layout::array::<u32>(len).ok()?;
+
+        // Safety: `layout` isn't zero-sized.
+        let address = unsafe { alloc::alloc::alloc_zeroed(layout) } as *mut u32;
+        if address.is_null() {
+            None
+        } else {
+            Some(unsafe { core::slice::from_raw_parts_mut(address, len) })
+        }
+    }
+
+    /// The buffer that's drawn to: the back buffer if there is one, otherwise the framebuffer.
+    fn target(&mut self) -> &mut [u32] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.framebuffer,
+        }
+    }
+
+    /** Copy the back buffer's changed scan lines to the framebuffer.
+
+    Does nothing when there's no back buffer, or nothing was drawn since the last `present`.
+    */
+    pub fn present(&mut self) {
+        let dirty = core::mem::replace(&mut self.dirty, 0..0);
+        if let Some(back_buffer) = &self.back_buffer {
+            let pixels = dirty.start * self.stride..dirty.end * self.stride;
+            self.framebuffer[pixels.clone()].copy_from_slice(&back_buffer[pixels]);
+        }
+    }
+
+    /// Record that `lines` (scan lines) have changed since the last `present`.
+    fn mark_dirty(&mut self, lines: Range<usize>) {
+        if self.dirty.is_empty() {
+            self.dirty = lines;
+        } else {
+            self.dirty = self.dirty.start.min(lines.start)..self.dirty.end.max(lines.end);
+        }
+    }
+
+    /// Fill the screen with the background colour and move the cursor to the top left.
+    pub fn clear(&mut self) {
+        let background = self.background;
+        self.target().fill(background);
+        self.mark_dirty(0..self.height);
+        self.column = 0;
+        self.row = 0;
+    }
+
+    /// Draw a byte at the cursor. Handles `\n` and `\r`.
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            _ => {
+                if self.column >= self.columns {
+                    self.newline();
+                }
+                self.draw_glyph(self.column, self.row, glyph(byte));
+                self.column += 1;
+            }
+        }
+    }
+
+    /// Set a pixel. Pixels outside of the visible area are ignored.
+    pub fn set_pixel(&mut self, x: usize, y: usize, value: u32) {
+        if let Some(index) = pixel_index(x, y, self.width, self.height, self.stride) {
+            self.target()[index] = value;
+            self.mark_dirty(y..y + 1);
+        }
+    }
+
+    fn draw_glyph(&mut self, column: usize, row: usize, glyph: &[u8; GLYPH_HEIGHT]) {
+        let x = column * GLYPH_WIDTH;
+        let y = row * GLYPH_HEIGHT;
+
+        for (glyph_row, bits) in glyph.iter().enumerate() {
+            for glyph_column in 0..GLYPH_WIDTH {
+                let value = if bits & (0x80 >> glyph_column) == 0 {
+                    self.background
+                } else {
+                    self.foreground
+                };
+                self.set_pixel(x + glyph_column, y + glyph_row, value);
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Move every row of text up by one, and clear the last row. There's always at least one row.
+    fn scroll(&mut self) {
+        let row_size = GLYPH_HEIGHT * self.stride;
+        let text_size = self.rows * row_size;
+        let background = self.background;
+
+        let target = self.target();
+        target.copy_within(row_size..text_size, 0);
+        target[text_size - row_size..text_size].fill(background);
+        self.mark_dirty(0..self.rows * GLYPH_HEIGHT);
+    }
+}
+
+impl<'a> core::fmt::Write for FramebufferConsole<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        if s.contains('\n') {
+            self.present();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+/*! The kernel's heap.
+
+This module contains the kernel's [global allocator](https://doc.rust-lang.org/alloc/alloc/trait.GlobalAlloc.html),
+which makes the `alloc` crate (`Box`, `Vec`, etc.) usable. The allocator is a
+[`common::heap::Heap`] that manages a region of virtual memory that the bootloader mapped
+writable. [`init`] must be called before anything is allocated; until then every allocation
+fails.
+
+[`stats`] reports how much of the heap is in use. When an allocation can't be satisfied, the
+[OOM hook](set_oom_hook) is called with the failed allocation's layout and the heap's
+statistics. Without a hook, the kernel panics.
+*/
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub use common::heap::HeapStats;
+use common::heap::{self, Heap, OomHook};
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/** Give the heap allocator its memory.
+
+# Safety
+
+* `[heap_start, heap_start + heap_size)` must be mapped writable and unused by anything else.
+* Must only be called once.
+*/
+pub unsafe fn init(heap_start: u64, heap_size: usize) {
+    ALLOCATOR.lock().init(heap_start as usize, heap_size)
+}
+
+/// The global allocator's statistics.
+pub fn stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+static mut OOM_HOOK: Option<OomHook> = None;
+
+/** Set the function that's called when an allocation fails.
+
+# Safety
+
+Not thread safe.
+*/
+pub unsafe fn set_oom_hook(hook: OomHook) {
+    OOM_HOOK = Some(hook)
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    heap::out_of_memory(unsafe { OOM_HOOK }, layout, stats())
+}
+
+/// A [`Heap`] behind a spin lock, so that it can be the global allocator.
+struct LockedHeap {
+    locked: AtomicBool,
+    heap: UnsafeCell<Heap>,
+}
+
+// Safety: access to `heap` is synchronised by `locked`.
+unsafe impl Sync for LockedHeap {}
+
+impl LockedHeap {
+    const fn empty() -> Self {
+        LockedHeap {
+            locked: AtomicBool::new(false),
+            heap: UnsafeCell::new(Heap::empty()),
+        }
+    }
+
+    fn lock(&self) -> HeapGuard {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        HeapGuard { locked_heap: self }
+    }
+}
+
+struct HeapGuard<'a> {
+    locked_heap: &'a LockedHeap,
+}
+
+impl<'a> core::ops::Deref for HeapGuard<'a> {
+    type Target = Heap;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.locked_heap.heap.get() }
+    }
+}
+
+impl<'a> core::ops::DerefMut for HeapGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.locked_heap.heap.get() }
+    }
+}
+
+impl<'a> Drop for HeapGuard<'a> {
+    fn drop(&mut self) {
+        self.locked_heap.locked.store(false, Ordering::Release);
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
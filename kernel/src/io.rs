@@ -1,11 +1,25 @@
-use core::arch::asm;
+use core::{arch::asm, marker::PhantomData};
 
 pub struct IoPort(pub u16);
 
 impl IoPort {
-    /// Create a new [`IoPort`] relative to an existing one.
+    /** Create a new [`IoPort`] relative to an existing one.
+
+    Panics if the port number would go past 0xffff.
+    */
     pub fn add(&self, offset: u16) -> Self {
-        Self(self.0 + offset)
+        match self.checked_add(offset) {
+            Some(port) => port,
+            None => panic!("I/O port {:#x} + {:#x} is out of range", self.0, offset),
+        }
+    }
+
+    /// Like [`IoPort::add`], but `None` if the port number would go past 0xffff.
+    pub const fn checked_add(&self, offset: u16) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(port) => Some(Self(port)),
+            None => None,
+        }
     }
 
     /** Read a byte from an I/O port.
@@ -15,9 +29,7 @@ impl IoPort {
     The I/O port must be valid.
     */
     pub unsafe fn read_u8(&mut self) -> u8 {
-        let value: u8;
-        asm!("in al, dx", out("al") value, in("dx") self.0);
-        value
+        u8::read_port(self.0)
     }
 
     /** Write a byte to an I/O port.
@@ -27,6 +39,159 @@ impl IoPort {
     The I/O port must be valid.
     */
     pub unsafe fn write_u8(&mut self, value: u8) {
-        asm!("out dx, al", in("dx") self.0, in("al") value)
+        u8::write_port(self.0, value)
+    }
+}
+
+const _: () = assert!(matches!(IoPort(0x3f8).checked_add(5), Some(IoPort(0x3fd))));
+const _: () = assert!(matches!(
+    IoPort(0xfffb).checked_add(4),
+    Some(IoPort(0xffff))
+));
+const _: () = assert!(IoPort(0xfffb).checked_add(5).is_none());
+const _: () = assert!(IoPort(0xffff).checked_add(5).is_none());
+
+/** A block of memory-mapped registers.
+
+Like [`IoPort`], but the registers are accessed with volatile loads and stores. The memory must
+be mapped uncached.
+*/
+pub struct Mmio(pub *mut u8);
+
+impl Mmio {
+    /// Create a new [`Mmio`] relative to an existing one.
+    pub fn add(&self, offset: usize) -> Self {
+        Self(self.0.wrapping_add(offset))
+    }
+
+    /** Read a byte from a memory-mapped register.
+
+    # Safety
+
+    The address must be a valid memory-mapped register.
+    */
+    pub unsafe fn read_u8(&mut self) -> u8 {
+        self.0.read_volatile()
+    }
+
+    /** Write a byte to a memory-mapped register.
+
+    # Safety
+
+    The address must be a valid memory-mapped register.
+    */
+    pub unsafe fn write_u8(&mut self, value: u8) {
+        self.0.write_volatile(value)
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+/** A value that can be transferred by a single `in` or `out` instruction.
+
+Implemented for `u8`, `u16` and `u32`, which use the `al`, `ax` and `eax` forms of the
+instructions. The trait is sealed because there are no other port access widths.
+*/
+pub trait PortValue: sealed::Sealed + Copy {
+    /** Read a value from an I/O port.
+
+    # Safety
+
+    The I/O port must be valid for accesses of this width.
+    */
+    unsafe fn read_port(port: u16) -> Self;
+
+    /** Write a value to an I/O port.
+
+    # Safety
+
+    The I/O port must be valid for accesses of this width.
+    */
+    unsafe fn write_port(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u8;
+        asm!("in al, dx", out("al") value, in("dx") port);
+        value
+    }
+
+    unsafe fn write_port(port: u16, value: Self) {
+        asm!("out dx, al", in("dx") port, in("al") value)
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u16;
+        asm!("in ax, dx", out("ax") value, in("dx") port);
+        value
+    }
+
+    unsafe fn write_port(port: u16, value: Self) {
+        asm!("out dx, ax", in("dx") port, in("ax") value)
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u32;
+        asm!("in eax, dx", out("eax") value, in("dx") port);
+        value
+    }
+
+    unsafe fn write_port(port: u16, value: Self) {
+        asm!("out dx, eax", in("dx") port, in("eax") value)
+    }
+}
+
+/** An I/O port with a fixed access width.
+
+Drivers can be generic over their registers' widths by using `Pio<T>` for some `T: PortValue`.
+*/
+pub struct Pio<T: PortValue> {
+    port: u16,
+    value: PhantomData<T>,
+}
+
+impl<T: PortValue> Pio<T> {
+    pub fn new(port: u16) -> Self {
+        Pio {
+            port,
+            value: PhantomData,
+        }
+    }
+
+    /** Read a value from the I/O port.
+
+    # Safety
+
+    The I/O port must be valid for accesses of width `T`.
+    */
+    pub unsafe fn read(&mut self) -> T {
+        T::read_port(self.port)
+    }
+
+    /** Write a value to the I/O port.
+
+    # Safety
+
+    The I/O port must be valid for accesses of width `T`.
+    */
+    pub unsafe fn write(&mut self, value: T) {
+        T::write_port(self.port, value)
+    }
+}
+
+impl From<IoPort> for Pio<u8> {
+    fn from(io_port: IoPort) -> Self {
+        Pio::new(io_port.0)
     }
 }
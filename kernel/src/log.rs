@@ -0,0 +1,121 @@
+/*! The kernel's [`log`](::log) backend.
+
+Every log record is written to each registered sink (a serial port, the framebuffer console,
+etc.), so the same messages appear on every output. [`add_sink`] registers a sink, and [`init`]
+makes this module the `log` crate's logger.
+
+A sink that fails to write a record doesn't stop the record from reaching the other sinks.
+*/
+use core::{
+    cell::UnsafeCell,
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ::log::{LevelFilter, Log, Metadata, Record};
+
+/// The maximum number of sinks that can be registered.
+pub const MAX_SINKS: usize = 4;
+
+static LOGGER: Logger = Logger::empty();
+
+/** Make this module the `log` crate's logger, and only log records at or above `level`.
+
+Records logged before `init` are dropped. Sinks can be added before or after `init`.
+*/
+pub fn init(level: LevelFilter) {
+    ::log::set_logger(&LOGGER).expect("a logger is already set");
+    ::log::set_max_level(level);
+}
+
+/** Register a sink. Every subsequent log record is written to it.
+
+Panics when [`MAX_SINKS`] sinks are already registered.
+*/
+pub fn add_sink(sink: &'static mut dyn Write) {
+    let mut sinks = LOGGER.lock();
+    let slot = sinks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many log sinks");
+    *slot = Some(sink);
+}
+
+type Sinks = [Option<&'static mut dyn Write>; MAX_SINKS];
+
+/* The sinks are behind a spin lock so that records from different processors aren't
+interleaved. Logging while the lock is held (e.g. from a sink's `write_str`) deadlocks.
+*/
+struct Logger {
+    locked: AtomicBool,
+    sinks: UnsafeCell<Sinks>,
+}
+
+/* Safety: `sinks` is only accessed through `SinksGuard`, which holds the lock. The sinks
+aren't required to be `Send` (the serial device's MMIO registers are a raw pointer), but they
+live for the whole kernel and are only used by one processor at a time.
+*/
+unsafe impl Send for Logger {}
+unsafe impl Sync for Logger {}
+
+impl Logger {
+    const fn empty() -> Self {
+        Logger {
+            locked: AtomicBool::new(false),
+            sinks: UnsafeCell::new([const { None }; MAX_SINKS]),
+        }
+    }
+
+    fn lock(&self) -> SinksGuard {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SinksGuard { logger: self }
+    }
+}
+
+struct SinksGuard<'a> {
+    logger: &'a Logger,
+}
+
+impl<'a> core::ops::Deref for SinksGuard<'a> {
+    type Target = Sinks;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.logger.sinks.get() }
+    }
+}
+
+impl<'a> core::ops::DerefMut for SinksGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.logger.sinks.get() }
+    }
+}
+
+impl<'a> Drop for SinksGuard<'a> {
+    fn drop(&mut self) {
+        self.logger.locked.store(false, Ordering::Release);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= ::log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        for sink in self.lock().iter_mut().flatten() {
+            let _ = writeln!(sink, "[{:>5}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
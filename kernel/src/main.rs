@@ -1,24 +1,45 @@
 #![no_main]
 #![no_std]
+#![feature(alloc_error_handler)]
 #![feature(panic_info_message)]
 
+extern crate alloc;
+
+pub mod apic;
+pub mod console;
+pub mod fbcon;
+pub mod heap;
 pub mod io;
+pub mod log;
 pub mod panic;
+pub mod pit;
+pub mod power;
+pub mod ps2;
+pub mod qemu_exit;
 pub mod serial;
+pub mod stack;
+pub mod syscall;
+pub mod task;
 
+use alloc::boxed::Box;
 use core::{
+    alloc::Layout,
     arch::{asm, global_asm},
-    fmt::Write,
     hint::unreachable_unchecked,
 };
 
+use apic::LocalApic;
 use common::{
+    boot_info::{command_line_options, KernelEntrypoint, SerialDevice},
     paging::{self, PageMap},
     registers::CR3,
+    BootInfo,
 };
-use io::IoPort;
+use fbcon::FramebufferConsole;
+use heap::HeapStats;
+use io::{IoPort, Mmio};
 use panic::init_panic_logger;
-use serial::PC16500D;
+use serial::{SerialRegisters, PC16500D};
 
 global_asm!(
     ".section .text.entrypoint",
@@ -28,40 +49,35 @@ global_asm!(
     sym kernel
 );
 
-/** The kernel's Rust entrypoint.
-
-# Arguments
-
-* `page_size` - The system's page size, according to the bootloader.
+/// Log the failed allocation and halt. See [`heap::set_oom_hook`].
+fn out_of_memory(layout: Layout, stats: HeapStats) -> ! {
+    ::log::error!(
+        "out of memory: {} bytes (align {}) with {} of {} bytes free (peak usage {} bytes)",
+        layout.size(),
+        layout.align(),
+        stats.free,
+        stats.allocated + stats.free,
+        stats.peak_allocated
+    );
+    loop {}
+}
 
-* `switch_to_kernel_page_addr` - Virtual address of the page allocated for the page table "pivot" function.
+// The bootloader calls `kernel` through this type.
+const _: KernelEntrypoint = kernel;
 
-  A function that changes the page table needs to have the same virtual memory address
-  before and after the switch. The bootloader sets up the kernel's page table, so it has
-  code for this. The bootloader code that switches to the kernel's page table is also
-  mapped in the kernel's page table.
+/** The kernel's Rust entrypoint.
 
-  Once running, the kernel should unmap that page so that it can have the full virtual
-  address space to itself. Therefore it needs to know the virtual address of the page
-  that it will unmap.
+# Arguments
 
-* `serial_device_port` - I/O port for a PC16500D serial device.
+* `boot_info` - Information about the machine, prepared by the bootloader.
 
-  The bootloader uses UEFI's PCI protocols to discover a serial device so that I don't
-  have to reimplement PCI handling in the kernel (for now).
+  The bootloader maps the [`BootInfo`] (and everything it points to) into the kernel's
+  address space, so it's valid for the lifetime of the kernel.
 */
-pub extern "sysv64" fn kernel(
-    _page_size: usize,
-    _switch_to_kernel_page_addr: u64,
-    serial_device_port: u16,
-) -> ! {
+pub extern "sysv64" fn kernel(boot_info: &'static BootInfo) -> ! {
     /* Note [Kernel entrypoint arguments]
 
-    In short, these arguments need to be passed in registers.
-
-    The number of arguments to this function are likely to increase over time, so it would
-    be prudent to put them into a struct. This is harder than it seems, because the struct's
-    fields would need to be passed in registers.
+    In short, the only argument is a pointer, passed in a register (`rdi`).
 
     This function is called from the bootloader, which has its own stack. If this function
     is given a struct that's passed via the stack, then the struct will live in a stack frame
@@ -69,17 +85,99 @@ pub extern "sysv64" fn kernel(
     to point to the kernel's stack. This function will look for the struct in its own stack
     frame and fail to find it. Thus registers are the only consistent way to pass data across
     the bootloader-kernel boundary.
+
+    Originally each piece of information was its own register argument, which didn't scale.
+    Now the bootloader puts a `BootInfo` in a page that's mapped into the kernel's address
+    space and passes its address. Only the pointer crosses the boundary, so nothing is lost
+    when the stack changes, and `BootInfo` can grow without touching the calling convention.
     */
 
+    // Safety: the bootloader maps the stack, and the kernel hasn't grown it far yet.
+    unsafe {
+        stack::init_stack_canary();
+    }
+
+    let serial_registers = || match boot_info.serial_device() {
+        SerialDevice::Io(port) => SerialRegisters::Io(IoPort(port)),
+        SerialDevice::Mmio(address) => SerialRegisters::Mmio(Mmio(address as *mut u8)),
+    };
+
     unsafe {
-        init_panic_logger(PC16500D::new(IoPort(serial_device_port)));
+        init_panic_logger(PC16500D::new(serial_registers()));
     }
 
-    let mut serial_device = unsafe { PC16500D::new(IoPort(serial_device_port)) };
+    // See Note [4KiB pages].
+    assert_eq!(
+        boot_info.page_size,
+        PageMap::PAGE_SIZE as u64,
+        "the bootloader's page size doesn't match the kernel's"
+    );
+
+    // A missing serial device would hang the first log message.
+    let serial_device = unsafe { PC16500D::probe(serial_registers()) };
+
+    // `console::read_line` would hang on a missing serial device too.
+    if serial_device.is_some() {
+        unsafe {
+            console::init_console(PC16500D::new(serial_registers()));
+        }
+    }
+
+    let _page_map = PageMap::adopt_active().expect("the page map has no self-map");
+
+    unsafe {
+        heap::init(boot_info.heap_address, boot_info.heap_size as usize);
+        heap::set_oom_hook(out_of_memory);
+    }
+
+    if let Some(serial_device) = serial_device {
+        log::add_sink(Box::leak(Box::new(serial_device)));
+    }
+    if let Some(framebuffer) = boot_info.framebuffer() {
+        // Scrolling reads the screen back, which is slow on the write-combining framebuffer.
+        let console = match FramebufferConsole::allocate_back_buffer(framebuffer) {
+            Some(back_buffer) => unsafe {
+                FramebufferConsole::with_back_buffer(framebuffer, back_buffer)
+            },
+            None => unsafe { FramebufferConsole::new(framebuffer) },
+        };
+
+        // A framebuffer that's too small for one character gets no console.
+        if let Some(console) = console {
+            log::add_sink(Box::leak(Box::new(console)));
+        }
+    }
+    // Safety: the bootloader maps the command line along with the `BootInfo`.
+    let command_line = unsafe { boot_info.command_line() };
+    let log_level = command_line_options(command_line)
+        .find_map(|(key, value)| match (key, value) {
+            ("log_level", Some(value)) => value.parse().ok(),
+            _ => None,
+        })
+        .unwrap_or(::log::LevelFilter::Info);
+    log::init(log_level);
+
+    ::log::info!("hello from kernel!");
+
+    /* The kernel still runs on the firmware's GDT, which has no user segments, so `sysret` can't
+    be used yet. That's fine while there's no user mode to make syscalls.
+    */
+    // Safety: the heap is initialised, and `syscall` loads the current code segment.
+    unsafe {
+        syscall::init(syscall::code_selector(), 0);
+    }
 
-    let _page_map = PageMap::from_cr3();
+    // Safety: the heap is initialised, and this is the only call.
+    unsafe {
+        task::demo(3);
+    }
 
-    writeln!(serial_device, "hello from kernel!").unwrap();
+    if boot_info.local_apic_address != 0 {
+        // Safety: the bootloader maps the local APIC's registers uncached.
+        let mut local_apic = unsafe { LocalApic::new(boot_info.local_apic_address) };
+        local_apic.enable();
+        apic::demo(&mut local_apic, 1_000_000);
+    }
 
     assert!(false, "false is not true");
 
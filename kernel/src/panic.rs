@@ -2,11 +2,13 @@
 
 This module contains the [panic handler](https://doc.rust-lang.org/nomicon/panic-handler.html) definition for the kernel.
 
-[`init_panic_logger`] enables serial output for panics.
+[`init_panic_logger`] enables serial output for panics. The panic handler also reports a
+clobbered stack canary (see [`crate::stack`]). With the `qemu_test` feature, the panic
+handler exits QEMU with [`qemu_exit::FAILURE`] (see [`crate::qemu_exit`]).
 */
-use core::{hint::unreachable_unchecked, ptr::addr_of_mut};
+use core::{fmt::Write, hint::unreachable_unchecked, ptr::addr_of_mut};
 
-use crate::serial::PC16500D;
+use crate::{qemu_exit, serial::PC16500D, stack};
 
 static mut PANIC_LOGGER: Option<PC16500D> = None;
 
@@ -27,10 +29,18 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
             None => {}
             Some(serial_device) => {
                 let _ = write_panic_info(serial_device, info);
+                if !stack::stack_canary_intact() {
+                    let _ = serial_device.write_str("\nstack overflow detected.");
+                }
             }
         }
     }
 
+    if cfg!(feature = "qemu_test") {
+        // Safety: test runs attach `isa-debug-exit` at the default port.
+        unsafe { qemu_exit::qemu_exit(qemu_exit::DEFAULT_PORT, qemu_exit::FAILURE) }
+    }
+
     loop {}
 }
 
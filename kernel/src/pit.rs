@@ -0,0 +1,87 @@
+/*! The 8254 programmable interval timer (PIT).
+
+The PIT is the simplest source of periodic interrupts on a PC, so it provides the kernel's
+ticks until the local APIC timer is set up. Channel 0 is wired to IRQ0; the IRQ0 interrupt
+handler should call [`tick`].
+
+Reference: [Intel 8254 datasheet](https://www.scs.stanford.edu/10wi-cs140/pintos/specs/8254.pdf)
+*/
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::io::IoPort;
+
+/// The frequency of the PIT's input clock, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+/* Note [PIT command byte]
+
+The command byte written to port 0x43 selects how a channel counts:
+
+* Bits 6-7 (channel): `00` = channel 0.
+* Bits 4-5 (access mode): `11` = the divisor is written low byte first, then high byte.
+* Bits 1-3 (operating mode): `011` = mode 3, square wave generator. The output is high for
+  half of the divisor's count and low for the other half, and the channel reloads the divisor
+  automatically, so IRQ0 fires periodically at `BASE_FREQUENCY / divisor` Hz.
+* Bit 0 (BCD): `0` = the divisor is a 16-bit binary number.
+*/
+const CHANNEL_0_SQUARE_WAVE: u8 = 0b0011_0110;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/** The divisor that makes channel 0 fire at (approximately) `hz` Hz.
+
+The divisor is a 16-bit value, where 0 means 65536. Frequencies are clamped to the range the
+PIT can produce: from about 18.2Hz (divisor 65536) to [`BASE_FREQUENCY`] (divisor 1).
+*/
+pub const fn divisor(hz: u32) -> u16 {
+    assert!(hz > 0, "frequency must be non-zero");
+
+    // Round to the nearest divisor.
+    let divisor = (BASE_FREQUENCY + hz / 2) / hz;
+
+    if divisor == 0 {
+        1
+    } else if divisor >= 65536 {
+        // A divisor of 0 is interpreted as 65536.
+        0
+    } else {
+        divisor as u16
+    }
+}
+
+const _: () = assert!(divisor(100) == 11932);
+const _: () = assert!(divisor(1000) == 1193);
+const _: () = assert!(divisor(18) == 0);
+const _: () = assert!(divisor(BASE_FREQUENCY) == 1);
+const _: () = assert!(divisor(u32::MAX) == 1);
+
+/** Program channel 0 to generate IRQ0 at `hz` Hz.
+
+See Note [PIT command byte].
+
+# Safety
+
+Changes the frequency of IRQ0, which other code may rely on.
+*/
+pub unsafe fn init(hz: u32) {
+    let divisor = divisor(hz);
+
+    IoPort(COMMAND_PORT).write_u8(CHANNEL_0_SQUARE_WAVE);
+
+    let mut channel_0 = IoPort(CHANNEL_0_PORT);
+    channel_0.write_u8((divisor & 0xff) as u8);
+    channel_0.write_u8((divisor >> 8) as u8);
+}
+
+/// Record a timer interrupt. Called by the IRQ0 handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
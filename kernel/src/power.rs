@@ -0,0 +1,80 @@
+/*! Resetting and powering off the machine.
+
+[`reset`] asks the PS/2 (8042) controller to pulse the processor's reset line. It needs no
+ACPI tables, so it works as soon as the kernel starts. QEMU emulates the 8042 on its `pc` and
+`q35` machines, and reboots when the line is pulsed. With `-no-reboot`, QEMU exits instead,
+which makes [`reset`] a clean way to end a run.
+
+[`shutdown`] enters the ACPI S5 (soft off) sleep state. The port and sleep type it needs come
+from the ACPI tables: the FADT's PM1a control block, and the first value of the DSDT's `\_S5`
+package. QEMU exits when the guest powers off.
+
+References:
+
+* <https://wiki.osdev.org/Reboot>
+* ACPI Specification, Version 6.5, Section 4.8.3.2.1 and Section 7.4.2
+*/
+use crate::io::{IoPort, Pio};
+
+/// The PS/2 controller's status register (read) and command register (write).
+const PS2_COMMAND_PORT: u16 = 0x64;
+
+/// Status register bit: the input buffer (CPU to controller) is full.
+const PS2_STATUS_INPUT_FULL: u8 = 0x2;
+
+/// Controller command: pulse output line 0, which is wired to the processor's reset line.
+const PS2_PULSE_RESET: u8 = 0xfe;
+
+/** Reset the machine through the PS/2 controller.
+
+Waits for the controller to accept a command, then sends it the pulse reset command (0xfe).
+Loops forever if the machine doesn't reset.
+
+# Safety
+
+There must be a PS/2 controller at the standard I/O ports.
+*/
+pub unsafe fn reset() -> ! {
+    let mut command_port = IoPort(PS2_COMMAND_PORT);
+    while command_port.read_u8() & PS2_STATUS_INPUT_FULL != 0 {
+        core::hint::spin_loop();
+    }
+    command_port.write_u8(PS2_PULSE_RESET);
+
+    loop {}
+}
+
+/// PM1 control register: the sleep type (bits 10 to 12).
+const SLP_TYP_MASK: u16 = 0b111 << 10;
+
+/// PM1 control register: enter the sleep state selected by SLP_TYP.
+const SLP_EN: u16 = 1 << 13;
+
+/** The PM1 control register value that enters sleep type `slp_typ`, given the register's
+`current` value. The register's other bits are preserved.
+*/
+pub const fn sleep_control_value(current: u16, slp_typ: u8) -> u16 {
+    (current & !SLP_TYP_MASK) | (((slp_typ as u16) << 10) & SLP_TYP_MASK) | SLP_EN
+}
+
+const _: () = assert!(sleep_control_value(0x0000, 0) == 0x2000);
+const _: () = assert!(sleep_control_value(0x0000, 5) == 0x3400);
+const _: () = assert!(sleep_control_value(0x1c01, 0) == 0x2001);
+const _: () = assert!(sleep_control_value(0x0001, 7) == 0x3c01);
+
+/** Power off the machine by entering ACPI sleep state S5.
+
+`pm1a_control_block` is the FADT's `PM1a_CNT_BLK` port, and `slp_typ_a` is the first value of
+the DSDT's `\_S5` package. Loops forever if the machine doesn't power off.
+
+# Safety
+
+`pm1a_control_block` must be the PM1a control register, and ACPI mode must be enabled.
+*/
+pub unsafe fn shutdown(pm1a_control_block: u16, slp_typ_a: u8) -> ! {
+    let mut pm1a_control = Pio::<u16>::new(pm1a_control_block);
+    let current = pm1a_control.read();
+    pm1a_control.write(sleep_control_value(current, slp_typ_a));
+
+    loop {}
+}
@@ -0,0 +1,109 @@
+/*! PS/2 keyboard input.
+
+The PS/2 controller's data port (0x60) delivers one byte of a scancode at a time, and bit 0 of
+its status port (0x64) says whether a byte is waiting. Scancodes are translated to set 1 by the
+controller, which is what this module decodes. It can be polled with [`try_read_scancode`], or
+driven by the IRQ1 handler once there's an IDT.
+
+Reference: <https://wiki.osdev.org/PS/2_Keyboard>
+*/
+use crate::io::IoPort;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// Status register bit: the output buffer (controller to CPU) has a byte in it.
+const STATUS_OUTPUT_FULL: u8 = 0x1;
+
+/** Read a scancode byte, if one is waiting.
+
+# Safety
+
+There must be a PS/2 controller at the standard I/O ports.
+*/
+pub unsafe fn try_read_scancode() -> Option<u8> {
+    if IoPort(STATUS_PORT).read_u8() & STATUS_OUTPUT_FULL == 0 {
+        None
+    } else {
+        Some(IoPort(DATA_PORT).read_u8())
+    }
+}
+
+/** Scancode set 1 "make" codes (key presses) 0x00 to 0x39, translated to ASCII.
+
+0 means the key doesn't produce a character (e.g. Ctrl, Shift, Alt).
+*/
+const SET_1_ASCII: &[u8] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+
+/// Like [`SET_1_ASCII`], with Shift held.
+const SET_1_ASCII_SHIFTED: &[u8] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+const _: () = assert!(SET_1_ASCII.len() == 0x3a);
+const _: () = assert!(SET_1_ASCII_SHIFTED.len() == 0x3a);
+
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+
+/// Set on the "break" code (key release) of a key.
+const RELEASE: u8 = 0x80;
+
+/** Translate a set 1 make code to ASCII.
+
+Returns `None` for break codes and for keys that don't produce a character.
+*/
+pub const fn scancode_to_ascii(scancode: u8, shift: bool) -> Option<u8> {
+    let table = if shift {
+        SET_1_ASCII_SHIFTED
+    } else {
+        SET_1_ASCII
+    };
+
+    if (scancode as usize) < table.len() && table[scancode as usize] != 0 {
+        Some(table[scancode as usize])
+    } else {
+        None
+    }
+}
+
+const _: () = assert!(matches!(scancode_to_ascii(0x1e, false), Some(b'a')));
+const _: () = assert!(matches!(scancode_to_ascii(0x1e, true), Some(b'A')));
+const _: () = assert!(matches!(scancode_to_ascii(0x02, false), Some(b'1')));
+const _: () = assert!(matches!(scancode_to_ascii(0x02, true), Some(b'!')));
+const _: () = assert!(matches!(scancode_to_ascii(0x0b, false), Some(b'0')));
+const _: () = assert!(matches!(scancode_to_ascii(0x1c, false), Some(b'\n')));
+const _: () = assert!(matches!(scancode_to_ascii(0x28, true), Some(b'"')));
+const _: () = assert!(matches!(scancode_to_ascii(0x2b, false), Some(b'\\')));
+const _: () = assert!(matches!(scancode_to_ascii(0x35, true), Some(b'?')));
+const _: () = assert!(matches!(scancode_to_ascii(0x39, false), Some(b' ')));
+const _: () = assert!(scancode_to_ascii(LEFT_SHIFT, false).is_none());
+const _: () = assert!(scancode_to_ascii(0x1e | RELEASE, false).is_none());
+
+/// Keyboard state that's needed to decode scancodes, i.e. whether Shift is held.
+#[derive(Default)]
+pub struct Keyboard {
+    left_shift: bool,
+    right_shift: bool,
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Keyboard {
+            left_shift: false,
+            right_shift: false,
+        }
+    }
+
+    /// Process a scancode, returning the character that was typed (if any).
+    pub fn handle_scancode(&mut self, scancode: u8) -> Option<u8> {
+        match scancode {
+            LEFT_SHIFT => self.left_shift = true,
+            RIGHT_SHIFT => self.right_shift = true,
+            _ if scancode == LEFT_SHIFT | RELEASE => self.left_shift = false,
+            _ if scancode == RIGHT_SHIFT | RELEASE => self.right_shift = false,
+            _ => return scancode_to_ascii(scancode, self.left_shift || self.right_shift),
+        }
+        None
+    }
+}
@@ -0,0 +1,50 @@
+/*! Exiting QEMU with a status code, for automated test runs.
+
+QEMU's `isa-debug-exit` device terminates the emulator when a value is written to its I/O port.
+Attach it with:
+
+```text
+-device isa-debug-exit,iobase=0xf4,iosize=0x01
+```
+
+QEMU's exit status is `(value << 1) | 1` (see [`exit_status`]). An exit status of 0 can't be
+produced, so a test runner should treat one specific odd status as success, e.g. 1 (for
+[`SUCCESS`]).
+
+With the `qemu_test` feature enabled, the panic handler exits with [`FAILURE`] instead of
+looping forever.
+
+Reference: [`hw/misc/debugexit.c`](https://gitlab.com/qemu-project/qemu/-/blob/master/hw/misc/debugexit.c)
+*/
+use crate::io::IoPort;
+
+/// The port that `isa-debug-exit` is conventionally placed at.
+pub const DEFAULT_PORT: u16 = 0xf4;
+
+/// Exit status 1.
+pub const SUCCESS: u8 = 0;
+
+/// Exit status 3.
+pub const FAILURE: u8 = 1;
+
+/// The exit status that QEMU reports when `code` is written to `isa-debug-exit`.
+pub const fn exit_status(code: u8) -> u32 {
+    ((code as u32) << 1) | 1
+}
+
+const _: () = assert!(exit_status(SUCCESS) == 1);
+const _: () = assert!(exit_status(FAILURE) == 3);
+const _: () = assert!(exit_status(0x7f) == 0xff);
+
+/** Exit QEMU by writing `code` to the `isa-debug-exit` device at `port`.
+
+Loops forever if the device isn't there.
+
+# Safety
+
+`port` must be an `isa-debug-exit` device, or unused.
+*/
+pub unsafe fn qemu_exit(port: u16, code: u8) -> ! {
+    IoPort(port).write_u8(code);
+    loop {}
+}
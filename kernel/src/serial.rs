@@ -1,53 +1,513 @@
-use crate::io::IoPort;
+use core::ops::BitOr;
 
-pub struct PC16500D {
-    io_base: IoPort,
+use crate::io::{IoPort, Mmio};
+
+/** Byte-wide access to a UART's registers, by register number.
+
+PC16500D-compatible UARTs are reached through either I/O ports or memory-mapped registers (e.g.
+on PCIe cards). Register `n` is at port `base + n` or address `base + n`.
+*/
+pub trait RegisterAccess {
+    /** Read register `offset`.
+
+    # Safety
+
+    The register must exist.
+    */
+    unsafe fn read_u8(&mut self, offset: u16) -> u8;
+
+    /** Write register `offset`.
+
+    # Safety
+
+    The register must exist.
+    */
+    unsafe fn write_u8(&mut self, offset: u16, value: u8);
+}
+
+impl RegisterAccess for IoPort {
+    unsafe fn read_u8(&mut self, offset: u16) -> u8 {
+        self.add(offset).read_u8()
+    }
+
+    unsafe fn write_u8(&mut self, offset: u16, value: u8) {
+        self.add(offset).write_u8(value)
+    }
+}
+
+impl RegisterAccess for Mmio {
+    unsafe fn read_u8(&mut self, offset: u16) -> u8 {
+        self.add(offset as usize).read_u8()
+    }
+
+    unsafe fn write_u8(&mut self, offset: u16, value: u8) {
+        self.add(offset as usize).write_u8(value)
+    }
+}
+
+/// Registers that are chosen at runtime, e.g. from [`common::BootInfo::serial_device`].
+pub enum SerialRegisters {
+    Io(IoPort),
+    Mmio(Mmio),
+}
+
+impl RegisterAccess for SerialRegisters {
+    unsafe fn read_u8(&mut self, offset: u16) -> u8 {
+        match self {
+            SerialRegisters::Io(registers) => RegisterAccess::read_u8(registers, offset),
+            SerialRegisters::Mmio(registers) => RegisterAccess::read_u8(registers, offset),
+        }
+    }
+
+    unsafe fn write_u8(&mut self, offset: u16, value: u8) {
+        match self {
+            SerialRegisters::Io(registers) => RegisterAccess::write_u8(registers, offset, value),
+            SerialRegisters::Mmio(registers) => RegisterAccess::write_u8(registers, offset, value),
+        }
+    }
+}
+
+/// Transmit and receive buffers.
+const DATA: u16 = 0;
+
+/// Divisor latch, low byte (DLL). Replaces [`DATA`] while [`LcrFlags::DLAB`] is set.
+const DIVISOR_LOW: u16 = 0;
+
+/// Divisor latch, high byte (DLM). Replaces the interrupt enable register while
+/// [`LcrFlags::DLAB`] is set.
+const DIVISOR_HIGH: u16 = 1;
+
+/// Line control register (LCR).
+const LINE_CONTROL: u16 = 3;
+
+/// Modem control register (MCR).
+const MODEM_CONTROL: u16 = 4;
+
+/// Line status register (LSR).
+const LINE_STATUS: u16 = 5;
+
+/// Scratch register (SCR). It has no effect on the UART, so software can use it freely.
+const SCRATCH: u16 = 7;
+
+/// Line status register: the transmit holding register is empty.
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+/// Line status register: a received byte is ready.
+const LINE_STATUS_DATA_READY: u8 = 0x1;
+
+/** A sensible `max_spins` for [`PC16500D::write_u8_timeout`] and [`PC16500D::read_u8_timeout`].
+
+At 115200 baud a byte takes about 87µs to transmit, and a status poll takes about 1µs on port I/O,
+so this waits roughly 100ms: over a thousand byte-times, which a working UART never needs.
+Memory-mapped polls are faster, so the wait is shorter there, but still far more than a byte-time.
+*/
+pub const DEFAULT_MAX_SPINS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// The transmitter didn't become ready to accept a byte.
+    TransmitTimeout,
+
+    /// No byte was received.
+    ReceiveTimeout,
+}
+
+/** The bits of the modem control register (MCR).
+
+Reference: PC16550D datasheet, Section 8.7 (Modem Control Register).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McrFlags(u8);
+
+impl McrFlags {
+    pub const NONE: McrFlags = McrFlags(0);
+
+    /// Data terminal ready: drives the DTR output active.
+    pub const DTR: McrFlags = McrFlags(0x01);
+
+    /// Request to send: drives the RTS output active.
+    pub const RTS: McrFlags = McrFlags(0x02);
+
+    /// Drives the user-defined OUT1 output active.
+    pub const OUT1: McrFlags = McrFlags(0x04);
+
+    /// Drives the user-defined OUT2 output active. On PCs, OUT2 gates the UART's interrupt line.
+    pub const OUT2: McrFlags = McrFlags(0x08);
+
+    /** Loopback mode.
+
+    Transmitted data stays on the device and is immediately available for reading, and the
+    modem control outputs are looped back to the modem status inputs.
+    */
+    pub const LOOPBACK: McrFlags = McrFlags(0x10);
+
+    pub const fn from_bits(bits: u8) -> Self {
+        McrFlags(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether every bit of `other` is set.
+    pub const fn contains(self, other: McrFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set or clear the bits of `other`.
+    pub const fn with(self, other: McrFlags, value: bool) -> Self {
+        if value {
+            McrFlags(self.0 | other.0)
+        } else {
+            McrFlags(self.0 & !other.0)
+        }
+    }
+}
+
+impl BitOr for McrFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        McrFlags(self.0 | rhs.0)
+    }
+}
+
+const _: () = assert!(McrFlags::DTR.bits() == 1 << 0);
+const _: () = assert!(McrFlags::RTS.bits() == 1 << 1);
+const _: () = assert!(McrFlags::OUT1.bits() == 1 << 2);
+const _: () = assert!(McrFlags::OUT2.bits() == 1 << 3);
+const _: () = assert!(McrFlags::LOOPBACK.bits() == 1 << 4);
+const _: () = assert!(McrFlags::from_bits(0x1b).contains(McrFlags::DTR.with(McrFlags::OUT2, true)));
+const _: () = assert!(
+    McrFlags::from_bits(0x1f)
+        .with(McrFlags::LOOPBACK, false)
+        .bits()
+        == 0x0f
+);
+
+/// The parity setting of the line control register (LCR bits 3 to 5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+/** The bits of the line control register (LCR).
+
+The word length, stop bits and parity are multi-bit fields, so they're read back with
+[`LcrFlags::word_length`], [`LcrFlags::stop_bits`] and [`LcrFlags::parity`] instead of
+[`LcrFlags::contains`]. Combine one constant from each field, e.g. `WORD_LENGTH_8 |
+STOP_BITS_1 | PARITY_NONE` for the usual 8N1.
+
+Reference: PC16550D datasheet, Section 8.1 (Line Control Register).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LcrFlags(u8);
+
+impl LcrFlags {
+    const WORD_LENGTH_MASK: u8 = 0x03;
+    const PARITY_MASK: u8 = 0x38;
+
+    pub const WORD_LENGTH_5: LcrFlags = LcrFlags(0x00);
+    pub const WORD_LENGTH_6: LcrFlags = LcrFlags(0x01);
+    pub const WORD_LENGTH_7: LcrFlags = LcrFlags(0x02);
+    pub const WORD_LENGTH_8: LcrFlags = LcrFlags(0x03);
+
+    pub const STOP_BITS_1: LcrFlags = LcrFlags(0x00);
+
+    /// Two stop bits (one and a half for 5-bit words).
+    pub const STOP_BITS_2: LcrFlags = LcrFlags(0x04);
+
+    pub const PARITY_NONE: LcrFlags = LcrFlags(0x00);
+    pub const PARITY_ODD: LcrFlags = LcrFlags(0x08);
+    pub const PARITY_EVEN: LcrFlags = LcrFlags(0x18);
+    pub const PARITY_MARK: LcrFlags = LcrFlags(0x28);
+    pub const PARITY_SPACE: LcrFlags = LcrFlags(0x38);
+
+    /// Hold the serial output at the spacing (logic 0) state.
+    pub const BREAK: LcrFlags = LcrFlags(0x40);
+
+    /** Divisor latch access bit (DLAB).
+
+    While it's set, registers 0 and 1 are the divisor latch instead of the data and interrupt
+    enable registers. See [`PC16500D::set_divisor`].
+    */
+    pub const DLAB: LcrFlags = LcrFlags(0x80);
+
+    pub const fn from_bits(bits: u8) -> Self {
+        LcrFlags(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether every bit of `other` is set.
+    pub const fn contains(self, other: LcrFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set or clear the bits of `other`.
+    pub const fn with(self, other: LcrFlags, value: bool) -> Self {
+        if value {
+            LcrFlags(self.0 | other.0)
+        } else {
+            LcrFlags(self.0 & !other.0)
+        }
+    }
+
+    /// The number of data bits in each character (5 to 8).
+    pub const fn word_length(self) -> u8 {
+        5 + (self.0 & Self::WORD_LENGTH_MASK)
+    }
+
+    /// The number of stop bits (1 or 2).
+    pub const fn stop_bits(self) -> u8 {
+        if self.contains(Self::STOP_BITS_2) {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub const fn parity(self) -> Parity {
+        match self.0 & Self::PARITY_MASK {
+            0x08 => Parity::Odd,
+            0x18 => Parity::Even,
+            0x28 => Parity::Mark,
+            0x38 => Parity::Space,
+            _ => Parity::None,
+        }
+    }
+}
+
+impl BitOr for LcrFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        LcrFlags(self.0 | rhs.0)
+    }
 }
 
-impl PC16500D {
+const _: () = assert!(LcrFlags::WORD_LENGTH_5.word_length() == 5);
+const _: () = assert!(LcrFlags::WORD_LENGTH_6.word_length() == 6);
+const _: () = assert!(LcrFlags::WORD_LENGTH_7.word_length() == 7);
+const _: () = assert!(LcrFlags::WORD_LENGTH_8.word_length() == 8);
+const _: () = assert!(LcrFlags::STOP_BITS_1.stop_bits() == 1);
+const _: () = assert!(LcrFlags::STOP_BITS_2.bits() == 1 << 2);
+const _: () = assert!(LcrFlags::STOP_BITS_2.stop_bits() == 2);
+const _: () = assert!(matches!(LcrFlags::PARITY_NONE.parity(), Parity::None));
+const _: () = assert!(matches!(LcrFlags::PARITY_ODD.parity(), Parity::Odd));
+const _: () = assert!(matches!(LcrFlags::PARITY_EVEN.parity(), Parity::Even));
+const _: () = assert!(matches!(LcrFlags::PARITY_MARK.parity(), Parity::Mark));
+const _: () = assert!(matches!(LcrFlags::PARITY_SPACE.parity(), Parity::Space));
+const _: () = assert!(LcrFlags::BREAK.bits() == 1 << 6);
+const _: () = assert!(LcrFlags::DLAB.bits() == 1 << 7);
+// 8N1 with DLAB set, the usual value while programming the divisor.
+const _: () = assert!(LcrFlags::from_bits(0x83).word_length() == 8);
+const _: () = assert!(matches!(LcrFlags::from_bits(0x83).parity(), Parity::None));
+const _: () = assert!(LcrFlags::from_bits(0x83).with(LcrFlags::DLAB, false).bits() == 0x03);
+
+pub struct PC16500D<R: RegisterAccess = SerialRegisters> {
+    registers: R,
+}
+
+impl<R: RegisterAccess> PC16500D<R> {
     /** Initialise the serial device.
 
     # Safety
 
-    The underlying I/O port must be mapped to a PC16500D serial device.
+    The registers must belong to a PC16500D serial device.
     */
-    pub unsafe fn new(io_base: IoPort) -> Self {
-        // io_base.add(1).outb(0x0);
-        // io_base.add(2).outb(0x0);
-        // io_base.add(4).outb(0xf);
-        Self { io_base }
+    pub unsafe fn new(registers: R) -> Self {
+        // registers.write_u8(1, 0x0);
+        // registers.write_u8(2, 0x0);
+        // registers.write_u8(4, 0xf);
+        Self { registers }
+    }
+
+    /** Initialise the serial device, if there's a 16550-compatible UART at `registers`.
+
+    Use this instead of [`PC16500D::new`] when the registers come from somewhere that could be
+    wrong (e.g. BAR discovery). A driver for a missing device would hang on its first write.
+
+    The probe sequence is:
+
+    1. Write 0x55 to the scratch register and read it back, then do the same with 0xaa. Reads
+       from a port with no device behind it usually return 0xff, and a register that's stuck at
+       any single value can't return both patterns. The scratch register's original value is
+       restored afterwards.
+    2. Run a loopback self-test (see [`PC16500D::self_test`]), giving up after
+       [`DEFAULT_MAX_SPINS`] polls of the line status instead of waiting forever.
+
+    The scratch register was added in the 16450, so an original 8250 fails the probe.
+
+    # Safety
+
+    Accessing registers 0 to 7 must not have side effects on anything other than a UART, e.g.
+    the I/O ports must not belong to another device.
+    */
+    pub unsafe fn probe(mut registers: R) -> Option<Self> {
+        let scratch = registers.read_u8(SCRATCH);
+        let scratch_works = [0x55, 0xaa].into_iter().all(|pattern| {
+            registers.write_u8(SCRATCH, pattern);
+            registers.read_u8(SCRATCH) == pattern
+        });
+        registers.write_u8(SCRATCH, scratch);
+        if !scratch_works {
+            return None;
+        }
+
+        let mut device = Self::new(registers);
+        if device.self_test_timeout(DEFAULT_MAX_SPINS) {
+            Some(device)
+        } else {
+            None
+        }
     }
 
     /** Read the line status register.
 
     # Safety
 
-    The underlying I/O port must be mapped to a PC16500D serial device.
+    The registers must belong to a PC16500D serial device.
     */
     pub unsafe fn line_status(&mut self) -> u8 {
-        self.io_base.add(5).read_u8()
+        self.registers.read_u8(LINE_STATUS)
     }
 
     /** Transmit a byte using the serial device.
 
     # Safety
 
-    The underlying I/O port must be mapped to a PC16500D serial device.
+    The registers must belong to a PC16500D serial device.
     */
     pub unsafe fn write_u8(&mut self, value: u8) {
-        while self.line_status() & 0x20 == 0 {}
-        self.io_base.write_u8(value)
+        while self.line_status() & LINE_STATUS_TRANSMIT_EMPTY == 0 {}
+        self.registers.write_u8(DATA, value)
     }
 
     /** Receive a byte using the serial device.
 
     # Safety
 
-    The underlying I/O port must be mapped to a PC16500D serial device.
+    The registers must belong to a PC16500D serial device.
     */
     pub unsafe fn read_u8(&mut self) -> u8 {
-        while self.line_status() & 0x1 == 0 {}
-        self.io_base.read_u8()
+        while self.line_status() & LINE_STATUS_DATA_READY == 0 {}
+        self.registers.read_u8(DATA)
+    }
+
+    /** Like [`PC16500D::write_u8`], but gives up after polling the line status `max_spins` times.
+
+    See [`DEFAULT_MAX_SPINS`].
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn write_u8_timeout(
+        &mut self,
+        value: u8,
+        max_spins: u32,
+    ) -> Result<(), SerialError> {
+        if !self.poll_line_status(LINE_STATUS_TRANSMIT_EMPTY, max_spins) {
+            return Err(SerialError::TransmitTimeout);
+        }
+        self.registers.write_u8(DATA, value);
+        Ok(())
+    }
+
+    /** Like [`PC16500D::read_u8`], but gives up after polling the line status `max_spins` times.
+
+    See [`DEFAULT_MAX_SPINS`].
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn read_u8_timeout(&mut self, max_spins: u32) -> Result<u8, SerialError> {
+        if !self.poll_line_status(LINE_STATUS_DATA_READY, max_spins) {
+            return Err(SerialError::ReceiveTimeout);
+        }
+        Ok(self.registers.read_u8(DATA))
+    }
+
+    /// Whether `mask` is set in the line status within `max_spins` polls.
+    unsafe fn poll_line_status(&mut self, mask: u8, max_spins: u32) -> bool {
+        for _ in 0..max_spins {
+            if self.line_status() & mask == mask {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+        false
+    }
+
+    /** Read the modem control register.
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn modem_control(&mut self) -> McrFlags {
+        McrFlags(self.registers.read_u8(MODEM_CONTROL))
+    }
+
+    /** Write the modem control register.
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn set_modem_control(&mut self, value: McrFlags) {
+        self.registers.write_u8(MODEM_CONTROL, value.bits())
+    }
+
+    /** Read the line control register.
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn line_control(&mut self) -> LcrFlags {
+        LcrFlags(self.registers.read_u8(LINE_CONTROL))
+    }
+
+    /** Write the line control register.
+
+    Leaving [`LcrFlags::DLAB`] set hides the data register, so [`PC16500D::write_u8`] and
+    [`PC16500D::read_u8`] would access the divisor latch instead.
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn set_line_control(&mut self, value: LcrFlags) {
+        self.registers.write_u8(LINE_CONTROL, value.bits())
+    }
+
+    /** Set the baud rate divisor. The baud rate is 115200 / `divisor`.
+
+    Sets [`LcrFlags::DLAB`] while the divisor latch is written, and then restores the line
+    control register.
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn set_divisor(&mut self, divisor: u16) {
+        let line_control = self.line_control();
+        self.set_line_control(line_control.with(LcrFlags::DLAB, true));
+        let [low, high] = divisor.to_le_bytes();
+        self.registers.write_u8(DIVISOR_LOW, low);
+        self.registers.write_u8(DIVISOR_HIGH, high);
+        self.set_line_control(line_control.with(LcrFlags::DLAB, false));
     }
 
     /** Put the serial device into loopback mode.
@@ -56,21 +516,46 @@ impl PC16500D {
 
     # Safety
 
-    The underlying I/O port must be mapped to a PC16500D serial device.
+    The registers must belong to a PC16500D serial device.
     */
     pub unsafe fn set_loopback(&mut self, value: bool) {
-        let mut port = self.io_base.add(4);
-        let modem_control = port.read_u8();
-        let mask = 0x10;
-        if value {
-            port.write_u8(modem_control | mask);
-        } else {
-            port.write_u8(modem_control & !mask);
-        }
+        let modem_control = self.modem_control();
+        self.set_modem_control(modem_control.with(McrFlags::LOOPBACK, value));
+    }
+
+    /** Check that the device echoes data in loopback mode.
+
+    Enables loopback, transmits a test byte, and checks that the same byte is received.
+    Loopback has to be disabled again afterwards, otherwise nothing would reach the serial line;
+    this method restores the modem control register to its previous value (including the
+    loopback bit), so it doesn't change the device's configuration.
+
+    # Safety
+
+    The registers must belong to a PC16500D serial device.
+    */
+    pub unsafe fn self_test(&mut self) -> bool {
+        self.self_test_timeout(u32::MAX)
+    }
+
+    /// Like [`PC16500D::self_test`], but fails after polling the line status `max_spins` times.
+    unsafe fn self_test_timeout(&mut self, max_spins: u32) -> bool {
+        const TEST_BYTE: u8 = 0xae;
+
+        let modem_control = self.modem_control();
+
+        self.set_loopback(true);
+        let received = self
+            .write_u8_timeout(TEST_BYTE, max_spins)
+            .and_then(|()| self.read_u8_timeout(max_spins));
+
+        self.set_modem_control(modem_control);
+
+        received == Ok(TEST_BYTE)
     }
 }
 
-impl core::fmt::Write for PC16500D {
+impl<R: RegisterAccess> core::fmt::Write for PC16500D<R> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         let mut buffer = [0_u8, 0, 0, 0];
         for c in s.chars() {
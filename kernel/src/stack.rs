@@ -0,0 +1,71 @@
+/*! Detecting kernel stack overflows with a canary.
+
+The bootloader maps the kernel's stack just below the kernel (see `map_stack` in the bootloader):
+it grows down from the kernel's entrypoint towards [`STACK_BOTTOM`], and there's no guard page
+beneath it. [`init_stack_canary`] writes [`CANARY`] to the lowest 8 bytes of the stack, from
+[`STACK_BOTTOM`] up to `STACK_BOTTOM + 8`. A stack that grows all the way down overwrites the
+canary, which [`check_stack_canary`] detects.
+
+The canary is only checked when [`check_stack_canary`] is called (e.g. by the panic handler), so
+it reports an overflow after the fact. A stack frame that skips over the canary without writing
+to it isn't detected.
+
+The bottom of the stack is address 0. Rust treats an access through a null pointer as undefined
+behaviour, so the canary is read and written with inline assembly.
+*/
+use core::arch::asm;
+
+/// The lowest address of the kernel's stack.
+pub const STACK_BOTTOM: u64 = 0x0;
+
+/// The value at [`STACK_BOTTOM`] while the stack hasn't overflowed.
+pub const CANARY: u64 = 0x5afe_57ac_cafe_f00d;
+
+/** Write [`CANARY`] to the bottom of the stack.
+
+Call once, early in the kernel's initialisation.
+
+# Safety
+
+The stack must be mapped writable at [`STACK_BOTTOM`], and nothing else may use its lowest 8
+bytes.
+*/
+pub unsafe fn init_stack_canary() {
+    asm!(
+        "mov qword ptr [{address}], {canary}",
+        address = in(reg) STACK_BOTTOM,
+        canary = in(reg) CANARY,
+        options(nostack, preserves_flags)
+    );
+}
+
+/** Whether the canary at the bottom of the stack is intact.
+
+Logs "stack overflow detected." when it isn't. Only meaningful after [`init_stack_canary`].
+*/
+pub fn check_stack_canary() -> bool {
+    let intact = stack_canary_intact();
+    if !intact {
+        ::log::error!("stack overflow detected.");
+    }
+    intact
+}
+
+/** Like [`check_stack_canary`], but doesn't log.
+
+For the panic handler, which can't rely on the logger.
+*/
+pub fn stack_canary_intact() -> bool {
+    let value: u64;
+    // Safety: the stack is mapped at `STACK_BOTTOM` for the lifetime of the kernel.
+    unsafe {
+        asm!(
+            "mov {value}, qword ptr [{address}]",
+            value = out(reg) value,
+            address = in(reg) STACK_BOTTOM,
+            options(nostack, readonly, preserves_flags)
+        )
+    };
+
+    value == CANARY
+}
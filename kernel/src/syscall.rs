@@ -0,0 +1,176 @@
+/*! The `syscall` instruction's entrypoint.
+
+[`init`] enables `syscall` and points it at [`syscall_entry`], which switches to a kernel stack
+and calls [`dispatch`]. There's no user mode yet, so every syscall just logs its number and
+arguments through the kernel's [`log`](crate::log) sinks.
+
+Reference: Intel® 64 and IA-32 Architectures Software Developer’s Manual, Vol 2B, `SYSCALL` and
+`SYSRET`, and Vol 3A, Section 5.8.8.
+*/
+use alloc::vec;
+use core::arch::{asm, global_asm};
+
+use common::registers::{Msr, IA32_EFER};
+
+/// The target of `syscall`.
+const IA32_LSTAR: Msr = Msr(0xc000_0082);
+
+/// The code segments that `syscall` and `sysret` load.
+const IA32_STAR: Msr = Msr(0xc000_0081);
+
+/// The RFLAGS bits that `syscall` clears.
+const IA32_FMASK: Msr = Msr(0xc000_0084);
+
+/// The size of the stack that syscalls run on.
+const STACK_SIZE: usize = 16 * 1024;
+
+/* Note [syscall and sysret register conventions]
+
+`syscall` doesn't use the stack or the IDT. It:
+
+* Saves the user's RIP in `rcx` and RFLAGS in `r11`
+* Clears the RFLAGS bits that are set in IA32_FMASK
+* Loads CS from IA32_STAR[47:32] and SS from IA32_STAR[47:32] + 8, at CPL 0
+* Jumps to IA32_LSTAR
+
+`rsp` still points at the user's stack, so the entrypoint has to switch stacks before it pushes
+anything. `sysretq` reverses this: it loads RIP from `rcx` and RFLAGS from `r11`, and loads CS
+from IA32_STAR[63:48] + 16 and SS from IA32_STAR[63:48] + 8, at CPL 3. So the GDT needs the
+kernel's code and data segments next to each other, and the user's data segment followed by
+the user's 64-bit code segment.
+
+Syscalls use the same registers as Linux:
+
+* `rax`: the syscall number, and the result on return
+* `rdi`, `rsi`, `rdx`: the first three arguments (`r10`, `r8` and `r9` are reserved for more)
+
+`rcx` and `r11` are always clobbered by `syscall`. `rdi`, `rsi`, `rdx`, `r8`, `r9` and `r10` are
+clobbered too, because [`dispatch`] is called with the System V calling convention. Every other
+register is preserved.
+*/
+
+/** Encode IA32_STAR.
+
+`kernel_code_selector` is loaded by `syscall`, and `user_selector_base` is the base that
+`sysret` adds to. See Note [syscall and sysret register conventions].
+*/
+pub const fn star(kernel_code_selector: u16, user_selector_base: u16) -> u64 {
+    // `sysret` always sets the selectors' RPL to 3.
+    let user_selector_base = user_selector_base | 3;
+    ((user_selector_base as u64) << 48) | ((kernel_code_selector as u64) << 32)
+}
+
+const _: () = assert!(star(0x08, 0x10) == 0x0013_0008_0000_0000);
+const _: () = assert!(star(0x38, 0x1b) == 0x001b_0038_0000_0000);
+
+/// RFLAGS.TF: single-step.
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// RFLAGS.IF: maskable interrupts.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// RFLAGS.DF: string instructions decrement their pointers.
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// RFLAGS.AC: alignment checking, and user page access when SMAP is enabled.
+const RFLAGS_AC: u64 = 1 << 18;
+
+/** The RFLAGS bits that `syscall` clears.
+
+Interrupts stay disabled until the entrypoint has switched stacks, and the kernel runs with
+the flags that the System V ABI expects.
+*/
+pub const fn fmask() -> u64 {
+    RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC
+}
+
+const _: () = assert!(fmask() == 0x4_0700);
+
+/// The top of the stack that [`syscall_entry`] switches to.
+static mut KERNEL_RSP: u64 = 0;
+
+/// The user's stack pointer, while a syscall is running.
+static mut USER_RSP: u64 = 0;
+
+extern "C" {
+    /// The syscall entrypoint. See Note [syscall and sysret register conventions].
+    fn syscall_entry();
+}
+
+/* Interrupts are disabled by IA32_FMASK, and there's only one processor, so `KERNEL_RSP` and
+`USER_RSP` don't need to be per-CPU yet.
+
+Four registers are pushed so that the stack is 16-byte aligned when `dispatch` is called.
+*/
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {kernel_rsp}]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push rcx",
+    "push r11",
+    "push rbp",
+    // (rax, rdi, rsi, rdx) -> (rdi, rsi, rdx, rcx)
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "pop rbp",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_RSP,
+    dispatch = sym dispatch,
+);
+
+/** Handle a syscall.
+
+Returns the value that the caller gets in `rax`. Unknown syscalls return `u64::MAX`.
+*/
+extern "sysv64" fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    ::log::info!(
+        "syscall {:#x}({:#x}, {:#x}, {:#x})",
+        number,
+        arg0,
+        arg1,
+        arg2
+    );
+
+    u64::MAX
+}
+
+/// The current code segment selector (CS).
+pub fn code_selector() -> u16 {
+    let value: u16;
+    unsafe { asm!("mov {0:x}, cs", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/** Enable the `syscall` instruction.
+
+`kernel_code_selector` and `user_selector_base` are encoded with [`star`], and must match the
+GDT's layout (see Note [syscall and sysret register conventions]). Allocates the syscall stack
+from the heap.
+
+# Safety
+
+The heap must be initialised, and the GDT must have the segments that IA32_STAR selects. Not
+thread safe.
+*/
+pub unsafe fn init(kernel_code_selector: u16, user_selector_base: u16) {
+    let stack = vec![0u8; STACK_SIZE].leak();
+    // The System V ABI requires a 16-byte aligned stack.
+    KERNEL_RSP = (stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+
+    IA32_STAR.write(star(kernel_code_selector, user_selector_base));
+    IA32_LSTAR.write(syscall_entry as usize as u64);
+    IA32_FMASK.write(fmask());
+
+    let mut ia32_efer = IA32_EFER::read();
+    ia32_efer.set_sce(true);
+    ia32_efer.write();
+}
@@ -0,0 +1,157 @@
+/*! Cooperative switching between kernel stacks.
+
+A [`Context`] holds what a suspended task needs to resume: its stack pointer, and the registers
+that the System V ABI requires a function to preserve. [`switch_context`] saves the current
+task's context and resumes another, so a task gives up the processor by calling it. There's no
+scheduler yet: [`demo`] switches between two hand-created contexts.
+
+Reference: System V Application Binary Interface, AMD64 Architecture Processor Supplement,
+Section 3.2.1 (Registers and the Stack Frame)
+*/
+use alloc::vec;
+use core::{arch::global_asm, mem::offset_of, ptr::addr_of_mut};
+
+/* Note [Callee-saved registers]
+
+The System V ABI says that `rbx`, `rbp`, `r12` to `r15` and `rsp` belong to the caller: a
+function has to restore them before it returns. Every other general-purpose register can be
+clobbered by a call. `switch_context` is called like an ordinary function, so the compiler has
+already saved everything else that's live by the time it runs, and a task only needs the
+callee-saved registers to resume.
+
+The ABI also makes the x87 control word and MXCSR's control bits callee-saved. Tasks don't
+change them, so they're not switched. RFLAGS.DF is clear on every call and return, so it doesn't
+need saving either.
+
+The task's instruction pointer isn't stored in its `Context`: the `call` to `switch_context` left
+the return address on the task's stack, and `switch_context` returns to it with `ret` once it
+has switched to that stack.
+*/
+
+/// A suspended task. See Note [Callee-saved registers].
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+}
+
+// `switch_context` accesses the fields by offset.
+const _: () = assert!(offset_of!(Context, rbx) == 0x00);
+const _: () = assert!(offset_of!(Context, rbp) == 0x08);
+const _: () = assert!(offset_of!(Context, r12) == 0x10);
+const _: () = assert!(offset_of!(Context, r13) == 0x18);
+const _: () = assert!(offset_of!(Context, r14) == 0x20);
+const _: () = assert!(offset_of!(Context, r15) == 0x28);
+const _: () = assert!(offset_of!(Context, rsp) == 0x30);
+const _: () = assert!(core::mem::size_of::<Context>() == 0x38);
+
+impl Context {
+    /// A context to save a running task in, before it's first switched away from.
+    pub const fn empty() -> Self {
+        Context {
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rsp: 0,
+        }
+    }
+
+    /** A context that starts running `entry` on the stack that ends at `stack_top`.
+
+    `entry` must never return, because there's nothing to return to.
+
+    # Safety
+
+    `stack_top` must be the (exclusive) end of a writable stack that isn't used by anything else.
+    */
+    pub unsafe fn new(stack_top: u64, entry: extern "sysv64" fn() -> !) -> Self {
+        // The System V ABI requires `rsp + 8` to be 16-byte aligned when a function starts.
+        // `switch_context` pops `entry`, which leaves `rsp` pointing at the dummy return address.
+        let stack_top = stack_top & !0xf;
+        let rsp = stack_top - 16;
+        (rsp as *mut u64).write(entry as usize as u64);
+        ((rsp + 8) as *mut u64).write(0);
+
+        Context {
+            rsp,
+            ..Context::empty()
+        }
+    }
+}
+
+extern "sysv64" {
+    /** Save the current task in `old`, and resume the task in `new`.
+
+    Returns when another task switches back to `old`.
+
+    # Safety
+
+    `new` must be a context that was saved by `switch_context` or created by [`Context::new`],
+    and it mustn't be running.
+    */
+    pub fn switch_context(old: *mut Context, new: *const Context);
+}
+
+// See Note [Callee-saved registers]. The offsets are checked against `Context` above.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "mov [rdi + 0x00], rbx",
+    "mov [rdi + 0x08], rbp",
+    "mov [rdi + 0x10], r12",
+    "mov [rdi + 0x18], r13",
+    "mov [rdi + 0x20], r14",
+    "mov [rdi + 0x28], r15",
+    "mov [rdi + 0x30], rsp",
+    "mov rbx, [rsi + 0x00]",
+    "mov rbp, [rsi + 0x08]",
+    "mov r12, [rsi + 0x10]",
+    "mov r13, [rsi + 0x18]",
+    "mov r14, [rsi + 0x20]",
+    "mov r15, [rsi + 0x28]",
+    "mov rsp, [rsi + 0x30]",
+    "ret",
+);
+
+/// The size of the stack that [`demo`]'s second task runs on.
+const DEMO_STACK_SIZE: usize = 16 * 1024;
+
+static mut DEMO_MAIN: Context = Context::empty();
+
+static mut DEMO_TASK: Context = Context::empty();
+
+extern "sysv64" fn demo_task() -> ! {
+    let mut count = 0;
+    loop {
+        count += 1;
+        ::log::info!("task: pong {}", count);
+        unsafe { switch_context(addr_of_mut!(DEMO_TASK), addr_of_mut!(DEMO_MAIN)) };
+    }
+}
+
+/** Switch back and forth between the caller and a second task, `rounds` times.
+
+Allocates the second task's stack from the heap, and never frees it.
+
+# Safety
+
+The heap must be initialised. Not thread safe, and can only be called once.
+*/
+pub unsafe fn demo(rounds: usize) {
+    let stack = vec![0u8; DEMO_STACK_SIZE].leak();
+    DEMO_TASK = Context::new(stack.as_ptr() as u64 + DEMO_STACK_SIZE as u64, demo_task);
+
+    for round in 1..=rounds {
+        ::log::info!("main: ping {}", round);
+        switch_context(addr_of_mut!(DEMO_MAIN), addr_of_mut!(DEMO_TASK));
+    }
+}
@@ -5,7 +5,16 @@ pub mod raw;
 pub use raw::{PciIoProtocolWidth, PciRootBridgeIoProtocolWidth};
 
 use raw::{PciIoProtocol, PciRootBridgeIoProtocol};
-use uefi::{proto::unsafe_protocol, StatusExt};
+use uefi::{proto::unsafe_protocol, table::boot::BootServices, Status, StatusExt};
+
+/// Command register bit: respond to I/O space accesses.
+pub const COMMAND_IO: u16 = 1 << 0;
+
+/// Command register bit: respond to memory space accesses.
+pub const COMMAND_MEM: u16 = 1 << 1;
+
+/// Command register bit: allow the device to issue memory and I/O requests (e.g. for DMA).
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
 
 #[derive(Debug)]
 #[repr(transparent)]
@@ -72,6 +81,41 @@ impl PciRootBridgeIo {
         Ok(value)
     }
 
+    /// The functions behind this root bridge. See [`functions`].
+    pub fn functions(
+        &self,
+    ) -> Functions<impl FnMut(PciConfigurationAddress) -> uefi::Result<u32> + '_> {
+        functions(|address| self.pci_read_u32(address))
+    }
+
+    /// The functions reachable from `root_bus` through bridges. See [`tree_functions`].
+    pub fn tree_functions(
+        &self,
+        root_bus: u8,
+    ) -> TreeFunctions<impl FnMut(PciConfigurationAddress) -> uefi::Result<u32> + '_> {
+        tree_functions(|address| self.pci_read_u32(address), root_bus)
+    }
+
+    /// The functions behind this root bridge with a class code. See [`find_by_class`].
+    pub fn find_by_class(
+        &self,
+        class: u8,
+        subclass: Option<u8>,
+    ) -> FindByClass<impl FnMut(PciConfigurationAddress) -> uefi::Result<u32> + '_> {
+        find_by_class(|address| self.pci_read_u32(address), class, subclass)
+    }
+
+    pub fn pci_write_u16(&self, address: PciConfigurationAddress, mut value: u16) -> uefi::Result {
+        unsafe {
+            self.pci_write(
+                PciRootBridgeIoProtocolWidth::Uint16,
+                address,
+                1,
+                &mut value as *mut u16 as *mut u8,
+            )
+        }
+    }
+
     pub fn pci_write_u32(&self, address: PciConfigurationAddress, mut value: u32) -> uefi::Result {
         unsafe {
             self.pci_write(
@@ -83,6 +127,425 @@ impl PciRootBridgeIo {
         }
     }
 
+    /* Note [Root bridge memory and I/O addresses]
+
+    The `mem_*` and `io_*` methods access the root bridge's memory and I/O space, not a
+    function's configuration space. Their `address` is a host address: the address the CPU
+    would use, e.g. a memory BAR's value converted with [`Descriptor::bus_to_host_address`], or
+    an I/O port number. See Note [PCI address translation].
+    */
+
+    /** Read from memory space. See Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    `buffer` must be valid for writes of `count` values of `width`.
+    */
+    pub unsafe fn mem_read(
+        &self,
+        width: PciRootBridgeIoProtocolWidth,
+        address: u64,
+        count: usize,
+        buffer: *mut u8,
+    ) -> uefi::Result {
+        (self.0.mem.read)(&self.0, width, address, count, buffer).to_result()
+    }
+
+    /** Write to memory space. See Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    `buffer` must be valid for reads of `count` values of `width`. Writing to a device's
+    registers can have arbitrary side effects, including DMA.
+    */
+    pub unsafe fn mem_write(
+        &self,
+        width: PciRootBridgeIoProtocolWidth,
+        address: u64,
+        count: usize,
+        buffer: *mut u8,
+    ) -> uefi::Result {
+        (self.0.mem.write)(&self.0, width, address, count, buffer).to_result()
+    }
+
+    /** Read from I/O space. See Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    `buffer` must be valid for writes of `count` values of `width`.
+    */
+    pub unsafe fn io_read(
+        &self,
+        width: PciRootBridgeIoProtocolWidth,
+        address: u64,
+        count: usize,
+        buffer: *mut u8,
+    ) -> uefi::Result {
+        (self.0.io.read)(&self.0, width, address, count, buffer).to_result()
+    }
+
+    /** Write to I/O space. See Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    `buffer` must be valid for reads of `count` values of `width`. Writing to a device's
+    registers can have arbitrary side effects, including DMA.
+    */
+    pub unsafe fn io_write(
+        &self,
+        width: PciRootBridgeIoProtocolWidth,
+        address: u64,
+        count: usize,
+        buffer: *mut u8,
+    ) -> uefi::Result {
+        (self.0.io.write)(&self.0, width, address, count, buffer).to_result()
+    }
+
+    /// See Note [Root bridge memory and I/O addresses].
+    pub fn mem_read_u32(&self, address: u64) -> uefi::Result<u32> {
+        let mut value: u32 = 0;
+        unsafe {
+            self.mem_read(
+                PciRootBridgeIoProtocolWidth::Uint32,
+                address,
+                1,
+                &mut value as *mut u32 as *mut u8,
+            )
+        }?;
+        Ok(value)
+    }
+
+    /** See Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    Writing to a device's registers can have arbitrary side effects, including DMA.
+    */
+    pub unsafe fn mem_write_u32(&self, address: u64, mut value: u32) -> uefi::Result {
+        self.mem_write(
+            PciRootBridgeIoProtocolWidth::Uint32,
+            address,
+            1,
+            &mut value as *mut u32 as *mut u8,
+        )
+    }
+
+    /// See Note [Root bridge memory and I/O addresses].
+    pub fn io_read_u32(&self, address: u64) -> uefi::Result<u32> {
+        let mut value: u32 = 0;
+        unsafe {
+            self.io_read(
+                PciRootBridgeIoProtocolWidth::Uint32,
+                address,
+                1,
+                &mut value as *mut u32 as *mut u8,
+            )
+        }?;
+        Ok(value)
+    }
+
+    /** See Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    Writing to a device's registers can have arbitrary side effects, including DMA.
+    */
+    pub unsafe fn io_write_u32(&self, address: u64, mut value: u32) -> uefi::Result {
+        self.io_write(
+            PciRootBridgeIoProtocolWidth::Uint32,
+            address,
+            1,
+            &mut value as *mut u32 as *mut u8,
+        )
+    }
+
+    /* Note [Access widths]
+
+    Each [`PciRootBridgeIoProtocolWidth`] combines a value size with a way of stepping through
+    `address` and `buffer` for `count` values:
+
+    * `Uint*` (normal): both advance by the value size. Reads or writes `count` consecutive
+      locations.
+    * `FifoUint*`: only `buffer` advances. Reads or writes the same location `count` times, e.g.
+      a device's data FIFO.
+    * `FillUint*`: only `address` advances. Writes the single value in `buffer` to `count`
+      consecutive locations, e.g. to clear a device buffer. Fill widths only make sense for
+      writes.
+
+    Reference: UEFI Specification 2.10, Section 14.2
+    (`EFI_PCI_ROOT_BRIDGE_IO_PROTOCOL.Mem.Read()`)
+    */
+
+    /** Write `value` to `count` consecutive `u32`s starting at `address`, in one firmware call.
+    See Note [Access widths] and Note [Root bridge memory and I/O addresses].
+
+    # Safety
+
+    Writing to a device's registers can have arbitrary side effects, including DMA.
+    */
+    pub unsafe fn mem_fill_u32(&self, address: u64, mut value: u32, count: usize) -> uefi::Result {
+        self.mem_write(
+            MEM_FILL_U32_WIDTH,
+            address,
+            count,
+            &mut value as *mut u32 as *mut u8,
+        )
+    }
+
+    /** Fill `buffer` by reading the I/O port at `address` once per byte. See Note [Access
+    widths] and Note [Root bridge memory and I/O addresses].
+    */
+    pub fn io_fifo_read(&self, address: u64, buffer: &mut [u8]) -> uefi::Result {
+        unsafe {
+            self.io_read(
+                IO_FIFO_READ_WIDTH,
+                address,
+                buffer.len(),
+                buffer.as_mut_ptr(),
+            )
+        }
+    }
+
+    /** Read a memory space location until `(result & mask) == value`, or until `timeout_ns`
+    nanoseconds have passed. See Note [Root bridge memory and I/O addresses].
+
+    Returns the last value read. On a timeout, it's in the error's data.
+
+    The firmware's delay argument is in units of 100ns, so `timeout_ns` is rounded up to a
+    multiple of 100ns. A timeout of 0 reads the location once.
+    */
+    pub fn poll_mem(
+        &self,
+        width: PciRootBridgeIoProtocolWidth,
+        address: u64,
+        mask: u64,
+        value: u64,
+        timeout_ns: u64,
+    ) -> uefi::Result<u64, u64> {
+        let mut result: u64 = 0;
+        unsafe {
+            (self.0.poll_mem)(
+                &self.0,
+                width,
+                address,
+                mask,
+                value,
+                timeout_ns.div_ceil(100),
+                &mut result,
+            )
+        }
+        .to_result_with(|| result, |_| result)
+    }
+
+    /** Like [`PciRootBridgeIo::poll_mem`], for I/O space.
+
+    The firmware's delay argument is in units of 100ns, so `timeout_ns` is rounded up to a
+    multiple of 100ns.
+    */
+    pub fn poll_io(
+        &self,
+        width: PciRootBridgeIoProtocolWidth,
+        address: u64,
+        mask: u64,
+        value: u64,
+        timeout_ns: u64,
+    ) -> uefi::Result<u64, u64> {
+        let mut result: u64 = 0;
+        unsafe {
+            (self.0.poll_io)(
+                &self.0,
+                width,
+                address,
+                mask,
+                value,
+                timeout_ns.div_ceil(100),
+                &mut result,
+            )
+        }
+        .to_result_with(|| result, |_| result)
+    }
+
+    /** Read a function's header layout (bits 0-6 of the header type, offset 0xe).
+
+    `address` identifies the function; its `register` is ignored. `None` when the layout is
+    reserved. See [`HeaderType`].
+    */
+    pub fn header_type(
+        &self,
+        address: PciConfigurationAddress,
+    ) -> uefi::Result<Option<HeaderType>> {
+        let register = self.pci_read_u32(PciConfigurationAddress {
+            register: 0xc,
+            ..address
+        })?;
+        Ok(BistHeaderLatencyCacheLine::from_u32(register).layout())
+    }
+
+    /// Fail with `INVALID_PARAMETER` unless the function has the [`HeaderType::Endpoint`] layout.
+    fn require_endpoint(&self, address: PciConfigurationAddress) -> uefi::Result {
+        match self.header_type(address)? {
+            Some(HeaderType::Endpoint) => Ok(()),
+            _ => Err(Status::INVALID_PARAMETER.into()),
+        }
+    }
+
+    /** Read a function's subsystem vendor ID and subsystem ID (offset 0x2c).
+
+    `address` identifies the function; its `register` is ignored. See [`subsystem_ids`].
+
+    Fails with `INVALID_PARAMETER` when the function isn't a [`HeaderType::Endpoint`]. Offset
+    0x2c holds other registers in the other layouts.
+    */
+    pub fn subsystem_ids(&self, mut address: PciConfigurationAddress) -> uefi::Result<(u16, u16)> {
+        self.require_endpoint(address)?;
+
+        address.register = 0x2c;
+        Ok(subsystem_ids(self.pci_read_u32(address)?))
+    }
+
+    /** Set bits in a function's command register (offset 0x4), leaving the others unchanged.
+
+    The command register is 16 bits wide. The 16 bits after it are the status register, whose
+    bits are cleared by writing 1 to them, so the command register must be accessed on its own
+    rather than as part of a 32-bit read-modify-write.
+
+    `address` identifies the function; its `register` is ignored.
+
+    See [`COMMAND_IO`], [`COMMAND_MEM`], and [`COMMAND_BUS_MASTER`].
+    */
+    pub fn set_command_bits(&self, address: PciConfigurationAddress, bits: u16) -> uefi::Result {
+        self.modify_command(address, |command| command | bits)
+    }
+
+    /** Clear bits in a function's command register (offset 0x4), leaving the others unchanged.
+
+    See [`PciRootBridgeIo::set_command_bits`].
+    */
+    pub fn clear_command_bits(&self, address: PciConfigurationAddress, bits: u16) -> uefi::Result {
+        self.modify_command(address, |command| command & !bits)
+    }
+
+    fn modify_command(
+        &self,
+        address: PciConfigurationAddress,
+        f: impl FnOnce(u16) -> u16,
+    ) -> uefi::Result {
+        let command_address = || PciConfigurationAddress {
+            register: 0x4,
+            ..address
+        };
+        let command = self.pci_read_u16(command_address())?;
+        self.pci_write_u16(command_address(), f(command))
+    }
+
+    /** Read a memory BAR's base address, which may be split across two BARs.
+
+    `bar_index` is 0 to 5. When the BAR's type bits (bits 1 and 2) are `0b10`, it's a 64-bit BAR:
+    BAR `bar_index + 1` holds the upper 32 bits of the address.
+
+    Fails with `INVALID_PARAMETER` when `bar_index` is out of range for the function's header
+    layout (see [`HeaderType::bar_count`]), when the BAR is an I/O BAR, when a 64-bit BAR would
+    extend past the last BAR, and when BAR `bar_index` is the upper half of a 64-bit BAR.
+    */
+    pub fn read_bar64(
+        &self,
+        bus: u8,
+        device: u8,
+        function: u8,
+        bar_index: u8,
+    ) -> uefi::Result<u64> {
+        let bar_count = match self.header_type(PciConfigurationAddress {
+            bus,
+            device,
+            function,
+            register: 0,
+        })? {
+            Some(header_type) => header_type.bar_count(),
+            None => 0,
+        };
+        if bar_index >= bar_count {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        let read_bar = |index: u8| {
+            self.pci_read_u32(PciConfigurationAddress {
+                bus,
+                device,
+                function,
+                register: 0x10 + 4 * index,
+            })
+        };
+
+        /* Walk the BARs before `bar_index` from the start, because the upper half of a 64-bit
+        BAR holds address bits that can look like any kind of BAR.
+        */
+        let mut index = 0;
+        while index < bar_index {
+            let bar = read_bar(index)?;
+            index += if bar_is_memory(bar) && bar_is_64bit(bar) {
+                2
+            } else {
+                1
+            };
+        }
+        if index > bar_index {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        let low = read_bar(bar_index)?;
+        if !bar_is_memory(low) {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        if bar_is_64bit(low) {
+            if bar_index + 1 == bar_count {
+                return Err(Status::INVALID_PARAMETER.into());
+            }
+            let high = read_bar(bar_index + 1)?;
+            Ok(bar64_address(low, high))
+        } else {
+            Ok(bar64_address(low, 0))
+        }
+    }
+
+    /** Decode a header type 0 function's BARs, including the size of each one.
+
+    Each BAR's size is found by writing all ones to it and reading back which address bits stick.
+    Memory and I/O decoding are disabled in the command register while the BARs are probed, so
+    that the function doesn't respond to the all-ones address, and then restored. Each BAR is
+    written back with its original value. See [`decode_bars`].
+
+    Fails with `INVALID_PARAMETER` when the function isn't a [`HeaderType::Endpoint`], because
+    the other layouts have fewer BARs.
+    */
+    pub fn bars(&self, bus: u8, device: u8, function: u8) -> uefi::Result<[Option<Bar>; 6]> {
+        let address = |register: u8| PciConfigurationAddress {
+            bus,
+            device,
+            function,
+            register,
+        };
+
+        self.require_endpoint(address(0))?;
+
+        let mut registers = [(0, 0); 6];
+        let command = self.pci_read_u16(address(0x4))?;
+        self.pci_write_u16(address(0x4), command & !(COMMAND_IO | COMMAND_MEM))?;
+        let probed = (0..6).try_for_each(|index: u8| -> uefi::Result {
+            let bar_address = address(0x10 + 4 * index);
+            let value = self.pci_read_u32(bar_address)?;
+            self.pci_write_u32(bar_address, 0xffff_ffff)?;
+            let size_mask = self.pci_read_u32(bar_address)?;
+            self.pci_write_u32(bar_address, value)?;
+            registers[index as usize] = (value, size_mask);
+            Ok(())
+        });
+        self.pci_write_u16(address(0x4), command)?;
+        probed?;
+
+        Ok(decode_bars(registers))
+    }
+
     pub fn configuration(&self) -> uefi::Result<Descriptors> {
         let mut value: *const u8 = core::ptr::null();
         unsafe { (self.0.configuration)(&self.0, &mut value) }.to_result()?;
@@ -93,6 +556,747 @@ impl PciRootBridgeIo {
     }
 }
 
+/// See Note [Access widths].
+const MEM_FILL_U32_WIDTH: PciRootBridgeIoProtocolWidth = PciRootBridgeIoProtocolWidth::FillUint32;
+
+/// See Note [Access widths].
+const IO_FIFO_READ_WIDTH: PciRootBridgeIoProtocolWidth = PciRootBridgeIoProtocolWidth::FifoUint8;
+
+// `EFI_PCI_ROOT_BRIDGE_IO_PROTOCOL_WIDTH` values: `EfiPciWidthFifoUint8` is 4 and
+// `EfiPciWidthFillUint32` is 10.
+const _: () = assert!(MEM_FILL_U32_WIDTH as u32 == 10);
+const _: () = assert!(IO_FIFO_READ_WIDTH as u32 == 4);
+
+/// Whether a BAR value describes a memory BAR (bit 0 clear) rather than an I/O BAR.
+pub const fn bar_is_memory(bar: u32) -> bool {
+    bar & 0x1 == 0
+}
+
+/// Whether a memory BAR value has the 64-bit type (bits 1 and 2 are `0b10`).
+pub const fn bar_is_64bit(bar: u32) -> bool {
+    (bar >> 1) & 0b11 == 0b10
+}
+
+/** Assemble a memory BAR's base address from its lower and upper BAR values.
+
+The low 4 bits of the lower BAR are flags (memory space, type, prefetchable), not address bits.
+*/
+pub const fn bar64_address(low: u32, high: u32) -> u64 {
+    ((high as u64) << 32) | (low & 0xffff_fff0) as u64
+}
+
+const _: () = assert!(bar_is_memory(0xfebf_0000));
+const _: () = assert!(!bar_is_memory(0x0000_c001));
+const _: () = assert!(!bar_is_64bit(0xfebf_0000));
+const _: () = assert!(bar_is_64bit(0xfe00_000c));
+const _: () = assert!(bar64_address(0xfebf_0008, 0) == 0xfebf_0000);
+const _: () = assert!(bar64_address(0xc000_000c, 0x0000_0080) == 0x0000_0080_c000_0000);
+
+/** A decoded base address register. See [`decode_bars`].
+
+Reference: PCI Local Bus Specification, Revision 3.0, Section 6.2.5.1
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// A range of I/O ports.
+    Io { base: u32, size: u32 },
+
+    /// A memory range that must be placed below 4GiB.
+    Memory32 {
+        base: u32,
+        size: u32,
+        prefetchable: bool,
+    },
+
+    /// A memory range that can be placed anywhere. It occupies two BARs.
+    Memory64 {
+        base: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+}
+
+/** Decode a header type 0 function's six BARs.
+
+`registers[i]` is BAR `i`'s value, paired with the value read back after writing all ones to it.
+The read-back value has zeros in the address bits that the device hardwires, which gives the
+size of the range. A BAR whose read-back value is zero isn't implemented, and is `None`.
+
+A 64-bit memory BAR also uses the next BAR for the upper 32 bits of its base address and size.
+That BAR is `None` in the result. A 64-bit BAR in BAR 5 has no upper half, so it's `None` too.
+
+## Example
+
+```rust
+# use uefi_pci::{decode_bars, Bar};
+let bars = decode_bars([
+    // A 16KiB prefetchable 64-bit memory BAR at 0x80_c000_0000.
+    (0xc000_000c, 0xffff_c00c),
+    (0x0000_0080, 0xffff_ffff),
+    // A 4KiB 32-bit memory BAR at 0xfebf_1000.
+    (0xfebf_1000, 0xffff_f000),
+    // 32 I/O ports at 0xc040. The upper 16 bits of an I/O BAR may read back as zero.
+    (0x0000_c041, 0x0000_ffe1),
+    (0, 0),
+    (0, 0),
+]);
+assert_eq!(
+    bars,
+    [
+        Some(Bar::Memory64 { base: 0x80_c000_0000, size: 0x4000, prefetchable: true }),
+        None,
+        Some(Bar::Memory32 { base: 0xfebf_1000, size: 0x1000, prefetchable: false }),
+        Some(Bar::Io { base: 0xc040, size: 0x20 }),
+        None,
+        None,
+    ]
+);
+
+let bars = decode_bars([
+    // 256 I/O ports at 0xd000, with all 32 bits of the mask implemented.
+    (0x0000_d001, 0xffff_ff01),
+    // A 16MiB prefetchable 32-bit memory BAR at 0xe000_0000.
+    (0xe000_0008, 0xff00_0008),
+    // Not implemented.
+    (0, 0),
+    // A 16KiB non-prefetchable 64-bit memory BAR at 0x1_fe00_0000, in BARs 3 and 4.
+    (0xfe00_0004, 0xffff_c004),
+    (0x0000_0001, 0xffff_ffff),
+    // A 64-bit memory BAR in BAR 5 has no upper half.
+    (0xfd00_0004, 0xffff_0004),
+]);
+assert_eq!(
+    bars,
+    [
+        Some(Bar::Io { base: 0xd000, size: 0x100 }),
+        Some(Bar::Memory32 { base: 0xe000_0000, size: 0x100_0000, prefetchable: true }),
+        None,
+        Some(Bar::Memory64 { base: 0x1_fe00_0000, size: 0x4000, prefetchable: false }),
+        None,
+        None,
+    ]
+);
+```
+*/
+pub fn decode_bars(registers: [(u32, u32); 6]) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < 6 {
+        let (value, size_mask) = registers[index];
+        if size_mask == 0 {
+            index += 1;
+            continue;
+        }
+
+        if !bar_is_memory(value) {
+            let mut mask = size_mask & 0xffff_fffc;
+            if mask & 0xffff_0000 == 0 {
+                mask |= 0xffff_0000;
+            }
+            bars[index] = Some(Bar::Io {
+                base: value & 0xffff_fffc,
+                size: (!mask).wrapping_add(1),
+            });
+            index += 1;
+        } else if bar_is_64bit(value) {
+            if index == 5 {
+                break;
+            }
+            let (high, high_size_mask) = registers[index + 1];
+            bars[index] = Some(Bar::Memory64 {
+                base: bar64_address(value, high),
+                size: (!bar64_address(size_mask, high_size_mask)).wrapping_add(1),
+                prefetchable: value & 0x8 != 0,
+            });
+            index += 2;
+        } else {
+            bars[index] = Some(Bar::Memory32 {
+                base: value & 0xffff_fff0,
+                size: (!(size_mask & 0xffff_fff0)).wrapping_add(1),
+                prefetchable: value & 0x8 != 0,
+            });
+            index += 1;
+        }
+    }
+    bars
+}
+
+/** The configuration space register at offset 0xC, which is shared by four byte-wide fields.
+
+Reference: PCI Local Bus Specification, Revision 3.0, Section 6.2.1 and Section 6.2.4
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BistHeaderLatencyCacheLine {
+    /// The system cache line size, in units of 32-bit words.
+    pub cache_line_size: u8,
+
+    /// The bus master's latency timer, in PCI bus clocks.
+    pub latency_timer: u8,
+
+    /** Bits 0-6 are the header layout. Bit 7 is set on multi-function devices.
+
+    See [`BistHeaderLatencyCacheLine::layout`] and [`BistHeaderLatencyCacheLine::multi_function`].
+    */
+    pub header_type: u8,
+
+    /// Built-in self test status and control.
+    pub bist: u8,
+}
+
+impl BistHeaderLatencyCacheLine {
+    pub const fn from_u32(value: u32) -> Self {
+        BistHeaderLatencyCacheLine {
+            cache_line_size: value as u8,
+            latency_timer: (value >> 8) as u8,
+            header_type: (value >> 16) as u8,
+            bist: (value >> 24) as u8,
+        }
+    }
+
+    /// The header layout. `None` when the layout is reserved.
+    pub const fn layout(&self) -> Option<HeaderType> {
+        HeaderType::from_u8(self.header_type)
+    }
+
+    /// Whether the device has more than one function.
+    pub const fn multi_function(&self) -> bool {
+        self.header_type & 0x80 == 0x80
+    }
+}
+
+const _: () = {
+    let register = BistHeaderLatencyCacheLine::from_u32(0x8081_4010);
+    assert!(register.cache_line_size == 0x10);
+    assert!(register.latency_timer == 0x40);
+    assert!(register.header_type == 0x81);
+    assert!(register.bist == 0x80);
+    assert!(matches!(register.layout(), Some(HeaderType::PciBridge)));
+    assert!(register.multi_function());
+};
+
+/** The layout of a function's configuration space header, from bits 0-6 of its header type.
+
+The registers from offset 0x10 onwards depend on the layout. For example, offset 0x18 is BAR2
+for an endpoint, but holds the bus numbers of a PCI-to-PCI bridge.
+
+Reference: PCI Local Bus Specification, Revision 3.0, Section 6.2.1; PCI-to-PCI Bridge
+Architecture Specification, Revision 1.2, Section 3.2
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    /// Header type 0: an ordinary device.
+    Endpoint,
+
+    /// Header type 1: a PCI-to-PCI bridge.
+    PciBridge,
+
+    /// Header type 2: a CardBus bridge.
+    CardBus,
+}
+
+impl HeaderType {
+    /** Decode a header type register. Bit 7 (multi-function) is ignored.
+
+    `None` when the layout is reserved.
+    */
+    pub const fn from_u8(header_type: u8) -> Option<Self> {
+        match header_type & 0x7f {
+            0 => Some(HeaderType::Endpoint),
+            1 => Some(HeaderType::PciBridge),
+            2 => Some(HeaderType::CardBus),
+            _ => None,
+        }
+    }
+
+    /** The number of BARs (starting at offset 0x10) in the layout.
+
+    A CardBus bridge's only BAR-like register is its socket registers' base address.
+    */
+    pub const fn bar_count(self) -> u8 {
+        match self {
+            HeaderType::Endpoint => 6,
+            HeaderType::PciBridge => 2,
+            HeaderType::CardBus => 1,
+        }
+    }
+}
+
+const _: () = {
+    assert!(matches!(
+        HeaderType::from_u8(0x00),
+        Some(HeaderType::Endpoint)
+    ));
+    assert!(matches!(
+        HeaderType::from_u8(0x01),
+        Some(HeaderType::PciBridge)
+    ));
+    assert!(matches!(
+        HeaderType::from_u8(0x02),
+        Some(HeaderType::CardBus)
+    ));
+    assert!(matches!(
+        HeaderType::from_u8(0x80),
+        Some(HeaderType::Endpoint)
+    ));
+    assert!(matches!(
+        HeaderType::from_u8(0x81),
+        Some(HeaderType::PciBridge)
+    ));
+    assert!(matches!(
+        HeaderType::from_u8(0x82),
+        Some(HeaderType::CardBus)
+    ));
+    assert!(HeaderType::from_u8(0x03).is_none());
+    assert!(HeaderType::from_u8(0x7f).is_none());
+};
+
+/** Split a header type 0 function's register at offset 0x2c into its subsystem vendor ID (low 16
+bits) and subsystem ID (high 16 bits).
+
+Reference: PCI Local Bus Specification, Revision 3.0, Section 6.2.4
+*/
+pub const fn subsystem_ids(value: u32) -> (u16, u16) {
+    (value as u16, (value >> 16) as u16)
+}
+
+const _: () = {
+    let (subsystem_vendor_id, subsystem_id) = subsystem_ids(0x1100_1af4);
+    assert!(subsystem_vendor_id == 0x1af4);
+    assert!(subsystem_id == 0x1100);
+};
+
+/** The functions that exist on every bus, in (bus, device, function) order.
+
+`read_u32` reads a dword from configuration space. Each device's function 0 is probed first:
+
+* When its vendor ID is 0xffff, there's no device, and functions 1-7 aren't probed.
+* When bit 7 of its header type is set, the device is multi-function, and functions 1-7 are
+  probed. The ones with a vendor ID of 0xffff don't exist and are skipped.
+* Otherwise, the device only has function 0.
+
+When `read_u32` fails, the iterator returns the error, and then ends.
+
+Reference: PCI Local Bus Specification, Revision 3.0, Section 6.1 and Section 6.2.1
+
+## Example
+
+```rust
+# use std::collections::HashMap;
+# use uefi_pci::{functions, PciConfigurationAddress};
+// A multi-function device with functions 0, 2 and 7, and a single-function device that
+// answers for every function number.
+let mut config_space = HashMap::new();
+for (device, function, header_type) in [(1, 0, 0x80), (1, 2, 0x00), (1, 7, 0x00)] {
+    config_space.insert((0, device, function, 0x0), 0x7020_8086);
+    config_space.insert((0, device, function, 0xc), header_type << 16);
+}
+for function in 0..8 {
+    config_space.insert((0, 3, function, 0x0), 0x0002_1b36);
+    config_space.insert((0, 3, function, 0xc), 0x0000_0000);
+}
+// Function 1 of a device without function 0 is never probed.
+config_space.insert((0, 5, 1, 0x0), 0x0002_1b36);
+
+let read_u32 = |address: PciConfigurationAddress| {
+    let key = (address.bus, address.device, address.function, address.register);
+    Ok(config_space.get(&key).copied().unwrap_or(0xffff_ffff))
+};
+let found: Result<Vec<_>, _> = functions(read_u32).collect();
+assert_eq!(found, Ok(vec![(0, 1, 0), (0, 1, 2), (0, 1, 7), (0, 3, 0)]));
+
+// When device 3 can't be read, the error is returned, and nothing after it.
+let read_u32 = |address: PciConfigurationAddress| {
+    if address.device == 3 {
+        return Err(uefi::Status::DEVICE_ERROR.into());
+    }
+    let key = (address.bus, address.device, address.function, address.register);
+    Ok(config_space.get(&key).copied().unwrap_or(0xffff_ffff))
+};
+let mut found = functions(read_u32).map(|result| result.map_err(|error| error.status()));
+assert_eq!(found.next(), Some(Ok((0, 1, 0))));
+assert_eq!(found.next(), Some(Ok((0, 1, 2))));
+assert_eq!(found.next(), Some(Ok((0, 1, 7))));
+assert_eq!(found.next(), Some(Err(uefi::Status::DEVICE_ERROR)));
+assert_eq!(found.next(), None);
+```
+*/
+pub fn functions<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>>(
+    read_u32: R,
+) -> Functions<R> {
+    Functions {
+        read_u32,
+        bus: 0,
+        last_bus: 255,
+        device: 0,
+        function: 0,
+        function_count: 0,
+    }
+}
+
+/// See [`functions`].
+pub struct Functions<R> {
+    read_u32: R,
+
+    /// Wider than a bus number, so that it can go past the last bus.
+    bus: u16,
+    last_bus: u16,
+    device: u8,
+
+    /// The next function to probe on the current device.
+    function: u8,
+
+    /// How many of the current device's functions can exist (0 until function 0 is probed).
+    function_count: u8,
+}
+
+impl<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>> Functions<R> {
+    /// Read a register, and stop iterating when the read fails.
+    fn read(&mut self, address: PciConfigurationAddress) -> uefi::Result<u32> {
+        let result = (self.read_u32)(address);
+        if result.is_err() {
+            self.bus = self.last_bus + 1;
+        }
+        result
+    }
+
+    fn vendor_id(&mut self, function: u8) -> uefi::Result<u16> {
+        let address = PciConfigurationAddress {
+            bus: self.bus as u8,
+            device: self.device,
+            function,
+            register: 0x0,
+        };
+        Ok(self.read(address)? as u16)
+    }
+
+    /// Start scanning `bus`, and stop after it.
+    fn scan_bus(&mut self, bus: u8) {
+        self.bus = bus as u16;
+        self.last_bus = bus as u16;
+        self.device = 0;
+        self.function = 0;
+        self.function_count = 0;
+    }
+
+    fn next_device(&mut self) {
+        self.function = 0;
+        self.function_count = 0;
+        self.device += 1;
+        if self.device == 32 {
+            self.device = 0;
+            self.bus += 1;
+        }
+    }
+}
+
+impl<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>> Iterator for Functions<R> {
+    type Item = uefi::Result<(u8, u8, u8)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.bus <= self.last_bus {
+            if self.function_count == 0 {
+                match self.vendor_id(0) {
+                    Err(error) => return Some(Err(error)),
+                    Ok(0xffff) => {
+                        self.next_device();
+                        continue;
+                    }
+                    Ok(_) => {}
+                }
+
+                let header = match self.read(PciConfigurationAddress {
+                    bus: self.bus as u8,
+                    device: self.device,
+                    function: 0,
+                    register: 0xc,
+                }) {
+                    Ok(value) => BistHeaderLatencyCacheLine::from_u32(value),
+                    Err(error) => return Some(Err(error)),
+                };
+                self.function_count = if header.multi_function() { 8 } else { 1 };
+            }
+
+            if self.function == self.function_count {
+                self.next_device();
+                continue;
+            }
+
+            let function = self.function;
+            self.function += 1;
+            // Function 0 was checked when the device was probed.
+            if function == 0 {
+                return Some(Ok((self.bus as u8, self.device, function)));
+            }
+            match self.vendor_id(function) {
+                Err(error) => return Some(Err(error)),
+                Ok(0xffff) => {}
+                Ok(_) => return Some(Ok((self.bus as u8, self.device, function))),
+            }
+        }
+
+        None
+    }
+}
+
+/** The functions whose base class is `class`, and whose subclass is `subclass` when it's given.
+
+Filters [`functions`] by the class code register (offset 0x8): bits 24-31 are the base class,
+and bits 16-23 are the subclass. This finds a kind of device regardless of its vendor, e.g.
+mass storage controllers (class 0x01) or serial controllers (class 0x07, subclass 0x00).
+
+When `read_u32` fails, the iterator returns the error, and then ends.
+
+Reference: PCI Code and ID Assignment Specification, Section 1
+
+## Example
+
+```rust
+# use std::collections::HashMap;
+# use uefi_pci::{find_by_class, PciConfigurationAddress};
+// A SATA controller, an NVMe controller, a serial controller and a network controller.
+let mut config_space = HashMap::new();
+for (device, class_code) in [
+    (1, 0x0106_0100),
+    (2, 0x0108_0200),
+    (3, 0x0700_0200),
+    (4, 0x0200_0000),
+] {
+    config_space.insert((0, device, 0, 0x0), 0x7020_8086);
+    config_space.insert((0, device, 0, 0x8), class_code);
+    config_space.insert((0, device, 0, 0xc), 0);
+}
+let read_u32 = |address: PciConfigurationAddress| {
+    let key = (address.bus, address.device, address.function, address.register);
+    Ok(config_space.get(&key).copied().unwrap_or(0xffff_ffff))
+};
+
+let found: Result<Vec<_>, _> = find_by_class(read_u32, 0x01, None).collect();
+assert_eq!(found, Ok(vec![(0, 1, 0), (0, 2, 0)]));
+
+let found: Result<Vec<_>, _> = find_by_class(read_u32, 0x01, Some(0x08)).collect();
+assert_eq!(found, Ok(vec![(0, 2, 0)]));
+
+let found: Result<Vec<_>, _> = find_by_class(read_u32, 0x07, Some(0x00)).collect();
+assert_eq!(found, Ok(vec![(0, 3, 0)]));
+
+assert_eq!(find_by_class(read_u32, 0x03, None).count(), 0);
+
+// When the NVMe controller's class code can't be read, the error is returned, and nothing after
+// it.
+let read_u32 = |address: PciConfigurationAddress| {
+    if (address.device, address.register) == (2, 0x8) {
+        return Err(uefi::Status::DEVICE_ERROR.into());
+    }
+    let key = (address.bus, address.device, address.function, address.register);
+    Ok(config_space.get(&key).copied().unwrap_or(0xffff_ffff))
+};
+let mut found =
+    find_by_class(read_u32, 0x07, None).map(|result| result.map_err(|error| error.status()));
+assert_eq!(found.next(), Some(Err(uefi::Status::DEVICE_ERROR)));
+assert_eq!(found.next(), None);
+```
+*/
+pub fn find_by_class<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>>(
+    read_u32: R,
+    class: u8,
+    subclass: Option<u8>,
+) -> FindByClass<R> {
+    FindByClass {
+        functions: functions(read_u32),
+        class,
+        subclass,
+    }
+}
+
+/// See [`find_by_class`].
+pub struct FindByClass<R> {
+    functions: Functions<R>,
+    class: u8,
+    subclass: Option<u8>,
+}
+
+impl<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>> Iterator for FindByClass<R> {
+    type Item = uefi::Result<(u8, u8, u8)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (bus, device, function) = match self.functions.next()? {
+                Ok(location) => location,
+                Err(error) => return Some(Err(error)),
+            };
+            let class_code = match self.functions.read(PciConfigurationAddress {
+                bus,
+                device,
+                function,
+                register: 0x8,
+            }) {
+                Ok(class_code) => class_code,
+                Err(error) => return Some(Err(error)),
+            };
+
+            let class = (class_code >> 24) as u8;
+            let subclass = (class_code >> 16) as u8;
+            if class == self.class && self.subclass.map_or(true, |expected| subclass == expected) {
+                return Some(Ok((bus, device, function)));
+            }
+        }
+    }
+}
+
+/** The functions reachable from `root_bus`, following PCI-to-PCI bridges to their secondary
+buses.
+
+Unlike [`functions`], which probes all 256 buses, this only probes the buses that exist. Each
+bus is scanned like in [`functions`]. When a function is a [`HeaderType::PciBridge`], its
+secondary bus number (offset 0x19) is queued, and queued buses are scanned in the order that
+they're found. Each bus is scanned at most once, so a misconfigured bridge that points back at
+an earlier bus (or at bus 0, before the firmware assigns bus numbers) can't cause a cycle.
+
+When `read_u32` fails, the iterator returns the error, and then ends.
+
+Reference: PCI-to-PCI Bridge Architecture Specification, Revision 1.2, Section 3.2.5.3
+
+## Example
+
+```rust
+# use std::collections::HashMap;
+# use uefi_pci::{tree_functions, PciConfigurationAddress};
+// A bridge at 0:1.0 to bus 5, with a device at 5:0.0. A second bridge at 5:2.0 points back at
+// bus 0.
+let mut config_space = HashMap::new();
+let bus_layout = [(0, 1, 0x01, 5), (5, 0, 0x00, 0), (5, 2, 0x01, 0)];
+for (bus, device, header_type, secondary_bus) in bus_layout {
+    config_space.insert((bus, device, 0, 0x0), 0x0001_1b36);
+    config_space.insert((bus, device, 0, 0xc), header_type << 16);
+    config_space.insert((bus, device, 0, 0x18), secondary_bus << 8 | bus as u32);
+}
+// No bridge leads to bus 7, so it isn't scanned.
+config_space.insert((7, 0, 0, 0x0), 0x0001_1b36);
+
+let read_u32 = |address: PciConfigurationAddress| {
+    let key = (address.bus, address.device, address.function, address.register);
+    Ok(config_space.get(&key).copied().unwrap_or(0xffff_ffff))
+};
+let found: Result<Vec<_>, _> = tree_functions(read_u32, 0).collect();
+assert_eq!(found, Ok(vec![(0, 1, 0), (5, 0, 0), (5, 2, 0)]));
+
+// When the first bridge's bus numbers can't be read, the error is returned instead of the
+// bridge, and bus 5 is never scanned.
+let read_u32 = |address: PciConfigurationAddress| {
+    if (address.bus, address.register) == (0, 0x18) {
+        return Err(uefi::Status::DEVICE_ERROR.into());
+    }
+    let key = (address.bus, address.device, address.function, address.register);
+    Ok(config_space.get(&key).copied().unwrap_or(0xffff_ffff))
+};
+let mut found =
+    tree_functions(read_u32, 0).map(|result| result.map_err(|error| error.status()));
+assert_eq!(found.next(), Some(Err(uefi::Status::DEVICE_ERROR)));
+assert_eq!(found.next(), None);
+```
+*/
+pub fn tree_functions<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>>(
+    read_u32: R,
+    root_bus: u8,
+) -> TreeFunctions<R> {
+    let mut functions = functions(read_u32);
+    functions.scan_bus(root_bus);
+
+    let mut visited = [0; 4];
+    visited[root_bus as usize / 64] |= 1 << (root_bus % 64);
+
+    TreeFunctions {
+        functions,
+        visited,
+        queue: [0; 256],
+        queue_start: 0,
+        queue_end: 0,
+    }
+}
+
+/// See [`tree_functions`].
+pub struct TreeFunctions<R> {
+    functions: Functions<R>,
+
+    /// A bit for each bus that has been queued.
+    visited: [u64; 4],
+
+    /// The buses that are waiting to be scanned. Each bus is queued at most once, so the queue
+    /// never needs more than 256 entries.
+    queue: [u8; 256],
+    queue_start: usize,
+    queue_end: usize,
+}
+
+impl<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>> TreeFunctions<R> {
+    fn enqueue(&mut self, bus: u8) {
+        let (word, bit) = (bus as usize / 64, bus % 64);
+        if self.visited[word] & (1 << bit) == 0 {
+            self.visited[word] |= 1 << bit;
+            self.queue[self.queue_end] = bus;
+            self.queue_end += 1;
+        }
+    }
+
+    /// Read a register, and stop iterating when the read fails.
+    fn read(&mut self, address: PciConfigurationAddress) -> uefi::Result<u32> {
+        let result = self.functions.read(address);
+        if result.is_err() {
+            self.queue_start = self.queue_end;
+        }
+        result
+    }
+
+    /// Queue the function's secondary bus when it's a PCI-to-PCI bridge.
+    fn enqueue_secondary_bus(&mut self, (bus, device, function): (u8, u8, u8)) -> uefi::Result {
+        let address = |register| PciConfigurationAddress {
+            bus,
+            device,
+            function,
+            register,
+        };
+        let header = BistHeaderLatencyCacheLine::from_u32(self.read(address(0xc))?);
+        if let Some(HeaderType::PciBridge) = header.layout() {
+            let bus_numbers = self.read(address(0x18))?;
+            self.enqueue((bus_numbers >> 8) as u8);
+        }
+        Ok(())
+    }
+}
+
+impl<R: FnMut(PciConfigurationAddress) -> uefi::Result<u32>> Iterator for TreeFunctions<R> {
+    type Item = uefi::Result<(u8, u8, u8)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let location = match self.functions.next() {
+                Some(Ok(location)) => location,
+                Some(Err(error)) => {
+                    self.queue_start = self.queue_end;
+                    return Some(Err(error));
+                }
+                None => {
+                    if self.queue_start == self.queue_end {
+                        return None;
+                    }
+                    self.functions.scan_bus(self.queue[self.queue_start]);
+                    self.queue_start += 1;
+                    continue;
+                }
+            };
+
+            return Some(self.enqueue_secondary_bus(location).map(|()| location));
+        }
+    }
+}
+
+/** The location of a register in a PCI function's configuration space.
+
+The fields are public so that addresses can be written as struct literals, but `device` is a
+5-bit value and `function` is a 3-bit value. [`PciConfigurationAddress::to_u64`] doesn't check
+this, so an out-of-range value silently addresses a different function. Use
+[`PciConfigurationAddress::new`] to have the fields checked.
+*/
 pub struct PciConfigurationAddress {
     pub bus: u8,
     pub device: u8,
@@ -101,6 +1305,29 @@ pub struct PciConfigurationAddress {
 }
 
 impl PciConfigurationAddress {
+    /// `None` when `device >= 32` or `function >= 8`.
+    pub const fn new(bus: u8, device: u8, function: u8, register: u8) -> Option<Self> {
+        if device < 32 && function < 8 {
+            Some(PciConfigurationAddress {
+                bus,
+                device,
+                function,
+                register,
+            })
+        } else {
+            None
+        }
+    }
+
+    /** Whether `register` is aligned for an access of `size` bytes (1, 2, 4 or 8).
+
+    Word and dword configuration reads must be naturally aligned.
+    */
+    pub const fn register_aligned(&self, size: u8) -> bool {
+        assert!(size.is_power_of_two() && size <= 8, "invalid access size");
+        self.register & (size - 1) == 0
+    }
+
     pub fn to_u64(&self) -> u64 {
         let mut value: u64 = self.register as u64;
         value |= (self.function as u64) << 8;
@@ -110,14 +1337,124 @@ impl PciConfigurationAddress {
     }
 }
 
+const _: () = assert!(PciConfigurationAddress::new(0, 31, 7, 0).is_some());
+const _: () = assert!(PciConfigurationAddress::new(0, 32, 0, 0).is_none());
+const _: () = assert!(PciConfigurationAddress::new(0, 0, 8, 0).is_none());
+const _: () = assert!(PciConfigurationAddress {
+    bus: 0,
+    device: 0,
+    function: 0,
+    register: 0x12
+}
+.register_aligned(2));
+const _: () = assert!(!PciConfigurationAddress {
+    bus: 0,
+    device: 0,
+    function: 0,
+    register: 0x12
+}
+.register_aligned(4));
+
+/** The ACPI resource descriptors returned by [`PciRootBridgeIo::configuration`].
+
+The descriptors are a sequence of QWORD address space descriptors (tag 0x8a, 0x2b bytes after
+the 3 byte header) terminated by an end tag (0x79). Nothing else bounds the sequence, so a
+firmware bug or a misread could send iteration off into unmapped memory. Iteration stops after
+[`Descriptors::MAX_DESCRIPTORS`] descriptors even when the end tag is missing, so it reads at
+most `MAX_DESCRIPTORS * 0x2e + 2` bytes.
+
+Iteration also stops after a descriptor that can't be decoded, which is returned as a
+[`DescriptorError`]. Use [`Descriptors::raw_bytes`] to see what it was.
+*/
 #[derive(Clone, Copy)]
 pub struct Descriptors<'a> {
     _owner: &'a PciRootBridgeIo,
     data: *const u8,
 }
 
+impl<'a> Descriptors<'a> {
+    /** The maximum number of descriptors that will be read.
+
+    A root bridge describes one bus number range and a handful of I/O and memory ranges, so a
+    real configuration is far smaller than this.
+    */
+    pub const MAX_DESCRIPTORS: usize = 64;
+
+    /** The raw descriptor bytes, up to and including the end tag, for debugging.
+
+    Unlike iteration, this doesn't interpret the descriptors beyond their headers, so it can
+    dump configurations that iteration rejects. Small and large ACPI resource descriptors of
+    any type are skipped over by their lengths. At most `max_len` bytes are returned, which
+    bounds the read when the end tag is missing.
+
+    The bytes are owned by the firmware, and stay valid as long as the [`PciRootBridgeIo`] that
+    returned these descriptors is borrowed.
+    */
+    pub fn raw_bytes(&self, max_len: usize) -> &'a [u8] {
+        let len = unsafe { descriptors_len(self.data, max_len) };
+        unsafe { core::slice::from_raw_parts(self.data, len) }
+    }
+}
+
+/** The length of a sequence of ACPI resource descriptors, including the end tag, capped at
+`max_len`.
+
+Bit 7 of a descriptor's first byte is set for large descriptors, which have a 3 byte header
+ending in a 16-bit length. Small descriptors have a 1 byte header whose low 3 bits are the
+length. The end tag (0x79) is a small descriptor with a 1 byte checksum.
+
+Reference: ACPI Specification, Version 6.5, Section 6.4.1 and Section 6.4.3
+
+# Safety
+
+`data` must be readable up to the end tag, or for `max_len` bytes, whichever comes first.
+*/
+const unsafe fn descriptors_len(data: *const u8, max_len: usize) -> usize {
+    let mut len = 0;
+    while len < max_len {
+        let tag = *data.add(len);
+        if tag == 0x79 {
+            len += 2;
+            break;
+        }
+
+        if tag & 0x80 == 0 {
+            len += 1 + (tag & 0x7) as usize;
+        } else if len + 3 <= max_len {
+            let size = u16::from_le_bytes([*data.add(len + 1), *data.add(len + 2)]);
+            len += 3 + size as usize;
+        } else {
+            len = max_len;
+        }
+    }
+
+    if len < max_len {
+        len
+    } else {
+        max_len
+    }
+}
+
+const _: () = {
+    // A QWORD address space descriptor (tag 0x8a, 0x2b bytes), then the end tag.
+    let mut bytes = [0; 0x2e + 2];
+    bytes[0] = 0x8a;
+    bytes[1] = 0x2b;
+    bytes[0x2e] = 0x79;
+    assert!(unsafe { descriptors_len(bytes.as_ptr(), 0x100) } == 0x30);
+    assert!(unsafe { descriptors_len(bytes.as_ptr(), 0x10) } == 0x10);
+
+    // A small descriptor with 2 bytes after its header, then the end tag.
+    let bytes = [0x22, 0x01, 0x00, 0x79, 0x00];
+    assert!(unsafe { descriptors_len(bytes.as_ptr(), 0x100) } == 5);
+
+    // The cap applies when the end tag is missing.
+    let bytes = [0x22, 0x01, 0x00, 0x22, 0x01, 0x00];
+    assert!(unsafe { descriptors_len(bytes.as_ptr(), 6) } == 6);
+};
+
 impl<'a> IntoIterator for Descriptors<'a> {
-    type Item = Descriptor;
+    type Item = Result<Descriptor, DescriptorError>;
 
     type IntoIter = IterDescriptors<'a>;
 
@@ -125,6 +1462,7 @@ impl<'a> IntoIterator for Descriptors<'a> {
         IterDescriptors {
             descriptors: self,
             offset: 0,
+            count: 0,
         }
     }
 }
@@ -141,6 +1479,140 @@ pub struct Descriptor {
     pub address_length: u64,
 }
 
+/* Note [PCI address translation]
+
+A root bridge can place a device's addresses somewhere else in the host (CPU) address space.
+The addresses a device decodes (e.g. the values in its BARs) are bus addresses, and the
+addresses the CPU uses to reach them are host addresses. The descriptor's
+`address_translation_offset` relates the two:
+
+    bus address = host address + address_translation_offset
+
+(wrapping, so that a "negative" offset can be represented).
+
+Since UEFI 2.7, `address_range_minimum` and `address_range_maximum` are host addresses, so they
+can be mapped as-is. The offset is 0 on most x86 machines, including QEMU's, where the two
+address spaces are the same.
+
+Reference: UEFI Specification 2.10, Section 14.2 (`EFI_PCI_ROOT_BRIDGE_IO_PROTOCOL.Configuration()`)
+*/
+
+impl Descriptor {
+    /** The `(base, size)` of a memory range descriptor's aperture, as host addresses.
+
+    This is the region that needs to be mapped to access the root bridge's memory-mapped I/O.
+    `None` for other descriptor types.
+
+    See Note [PCI address translation].
+    */
+    pub fn as_memory_region(&self) -> Option<(u64, u64)> {
+        match self.resource_type {
+            DescriptorResourceType::MemoryRange => {
+                Some((self.address_range_minimum, self.address_length))
+            }
+            _ => None,
+        }
+    }
+
+    /** Convert a host address in this descriptor's range to the corresponding bus address.
+
+    See Note [PCI address translation].
+    */
+    pub fn host_to_bus_address(&self, host_address: u64) -> u64 {
+        host_address.wrapping_add(self.address_translation_offset)
+    }
+
+    /** Convert a bus address (e.g. a BAR's value) to the host address the CPU uses for it.
+
+    See Note [PCI address translation].
+    */
+    pub fn bus_to_host_address(&self, bus_address: u64) -> u64 {
+        bus_address.wrapping_sub(self.address_translation_offset)
+    }
+}
+
+/** A one-line summary of the descriptor: its resource type, its (inclusive) range and size, and
+for memory ranges, whether the range is writable and how it can be cached. Use `Debug` to see
+every field.
+
+Reference: ACPI Specification, Version 6.5, Section 6.4.3.5.1 and Section 6.4.3.5.4
+(QWORD Address Space Descriptor, resource type specific flags)
+
+## Example
+
+```rust
+# use uefi_pci::{Descriptor, DescriptorResourceType};
+let descriptor = Descriptor {
+    resource_type: DescriptorResourceType::MemoryRange,
+    general_flags: 0,
+    type_specific_flags: 0x03,
+    address_space_granularity: 0,
+    address_range_minimum: 0xc000_0000,
+    address_range_maximum: 0xc000_ffff,
+    address_translation_offset: 0,
+    address_length: 0x1_0000,
+};
+assert_eq!(
+    format!("{}", descriptor),
+    "Memory 0xc0000000-0xc000ffff (64KiB), read-write, cacheable"
+);
+```
+*/
+impl core::fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let resource_type = match self.resource_type {
+            DescriptorResourceType::MemoryRange => "Memory",
+            DescriptorResourceType::IORange => "I/O",
+            DescriptorResourceType::BusNumberRange => "Bus",
+        };
+        write!(
+            f,
+            "{} {:#x}-{:#x}",
+            resource_type, self.address_range_minimum, self.address_range_maximum
+        )?;
+
+        match self.resource_type {
+            DescriptorResourceType::MemoryRange => {
+                write!(f, " ({})", ByteSize(self.address_length))?;
+
+                let writable = self.type_specific_flags & 0x1 != 0;
+                f.write_str(if writable {
+                    ", read-write"
+                } else {
+                    ", read-only"
+                })?;
+
+                f.write_str(match (self.type_specific_flags >> 1) & 0x3 {
+                    0 => ", non-cacheable",
+                    1 => ", cacheable",
+                    2 => ", write-combining",
+                    _ => ", prefetchable",
+                })
+            }
+            DescriptorResourceType::IORange => write!(f, " ({})", ByteSize(self.address_length)),
+            DescriptorResourceType::BusNumberRange => {
+                write!(f, " ({} buses)", self.address_length)
+            }
+        }
+    }
+}
+
+/// A number of bytes, in the largest unit that divides it exactly (e.g. "64KiB").
+struct ByteSize(u64);
+
+impl core::fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let units = [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")];
+        match units
+            .into_iter()
+            .find(|(unit, _)| self.0 != 0 && self.0 % unit == 0)
+        {
+            Some((unit, suffix)) => write!(f, "{}{}", self.0 / unit, suffix),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum DescriptorResourceType {
     MemoryRange,
@@ -148,99 +1620,256 @@ pub enum DescriptorResourceType {
     BusNumberRange,
 }
 
+/// Why an ACPI resource descriptor couldn't be decoded. See [`Descriptors`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The descriptor isn't a QWORD address space descriptor or an end tag.
+    UnsupportedTag(u8),
+
+    /// A QWORD address space descriptor whose length isn't 0x2b.
+    InvalidLength(u16),
+
+    /// A QWORD address space descriptor with a reserved (3 to 191) or vendor-defined (192 to
+    /// 255) resource type.
+    UnsupportedResourceType(u8),
+}
+
 pub struct IterDescriptors<'a> {
     descriptors: Descriptors<'a>,
     offset: usize,
+
+    /// The number of descriptors read so far.
+    count: usize,
 }
 
 impl<'a> Iterator for IterDescriptors<'a> {
-    type Item = Descriptor;
+    type Item = Result<Descriptor, DescriptorError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        unsafe {
-            /* UEFI wants me to read 2B-aligned (even) addresses, so I have to read 2 bytes
-            at a time.
+        // See [`Descriptors`].
+        if self.count >= Descriptors::MAX_DESCRIPTORS {
+            return None;
+        }
 
-            Because x86_64 is little endian, the most significant byte of `header` is
-            *later* in the structure.
-            */
-            let header: u16 = *(self.descriptors.data.add(self.offset) as *const u16);
-            match header & 0xff {
-                0x8a => {
-                    let size: u8 = (header >> 8) as u8;
-                    assert!(size == 0x2b, "expected 0x2b, got {:#x}", size);
-
-                    let nothing_and_resource_type: u16 =
-                        *(self.descriptors.data.add(self.offset + 0x2) as *const u16);
-                    assert!(
-                        nothing_and_resource_type & 0xff == 0,
-                        "expected 0x0, got {:#x}",
-                        nothing_and_resource_type & 0xff
-                    );
-
-                    let resource_type: u8 = (nothing_and_resource_type >> 8) as u8;
-
-                    let general_flags_and_type_specific_flags: u16 =
-                        *(self.descriptors.data.add(self.offset + 0x4) as *const u16);
-                    let general_flags: u8 = (general_flags_and_type_specific_flags & 0xff) as u8;
-                    let type_specific_flags: u8 =
-                        (general_flags_and_type_specific_flags >> 8) as u8;
-
-                    /*
-                    For some reason I'm only allowed to do aligned reads of u64.
-                    The offsets of these 64-bit fields aren't 8B aligned, so I have
-                    to read the components in smaller chunks.
-                    */
-                    let read_u64 = |offset: usize| {
-                        let byte_0: u8 = *self.descriptors.data.add(self.offset + offset);
-                        let byte_1: u8 = *self.descriptors.data.add(self.offset + offset + 1);
-                        let byte_2: u8 = *self.descriptors.data.add(self.offset + offset + 2);
-                        let byte_3: u8 = *self.descriptors.data.add(self.offset + offset + 3);
-                        let byte_4: u8 = *self.descriptors.data.add(self.offset + offset + 4);
-                        let byte_5: u8 = *self.descriptors.data.add(self.offset + offset + 5);
-                        let byte_6: u8 = *self.descriptors.data.add(self.offset + offset + 6);
-                        let byte_7: u8 = *self.descriptors.data.add(self.offset + offset + 7);
-                        u64::from_le_bytes([
-                            byte_0, byte_1, byte_2, byte_3, byte_4, byte_5, byte_6, byte_7,
-                        ])
-                    };
-
-                    let address_space_granularity = read_u64(0x6);
-
-                    let address_range_minimum: u64 = read_u64(0xe);
-                    let address_range_maximum: u64 = read_u64(0x16);
-                    let address_translation_offset: u64 = read_u64(0x1e);
-                    let address_length: u64 = read_u64(0x26);
-
-                    self.offset += 3 + size as usize;
-                    Some(Descriptor {
-                        resource_type: match resource_type {
-                            0 => DescriptorResourceType::MemoryRange,
-                            1 => DescriptorResourceType::IORange,
-                            2 => DescriptorResourceType::BusNumberRange,
-                            _ => unreachable!(),
-                        },
-                        general_flags,
-                        type_specific_flags,
-                        address_space_granularity,
-                        address_range_minimum,
-                        address_range_maximum,
-                        address_translation_offset,
-                        address_length,
-                    })
-                }
-                0x79 => {
-                    assert!(header >> 8 == 0);
+        match unsafe { read_descriptor(self.descriptors.data.add(self.offset)) } {
+            Ok(Some((descriptor, len))) => {
+                self.offset += len;
+                self.count += 1;
+                Some(Ok(descriptor))
+            }
+            Ok(None) => None,
+            Err(error) => {
+                // The next descriptor's offset is unknown, so iteration can't continue.
+                self.count = Descriptors::MAX_DESCRIPTORS;
+                Some(Err(error))
+            }
+        }
+    }
+}
 
-                    None
-                }
-                tag => panic!("invalid descriptor tag: {:#x}", tag),
+/** Decode the ACPI resource descriptor at `data`, along with its length in bytes.
+
+`Ok(None)` at the end tag. Only QWORD address space descriptors are decoded; any other kind of
+descriptor is an error.
+
+Reference: ACPI Specification, Version 6.5, Section 6.4.3.5.1
+
+# Safety
+
+`data` must be 2-byte aligned, and readable for the descriptor's length, which is 3 bytes more
+than the 16-bit length at `data + 1` for a large descriptor, or 2 bytes for an end tag.
+*/
+unsafe fn read_descriptor(data: *const u8) -> Result<Option<(Descriptor, usize)>, DescriptorError> {
+    /* UEFI wants me to read 2B-aligned (even) addresses, so I have to read 2 bytes
+    at a time.
+
+    Because x86_64 is little endian, the most significant byte of `header` is
+    *later* in the structure.
+    */
+    let header: u16 = *(data as *const u16);
+    match header & 0xff {
+        0x8a => {
+            let size_low: u8 = (header >> 8) as u8;
+            let size_high_and_resource_type: u16 = *(data.add(0x2) as *const u16);
+            let size = u16::from_le_bytes([size_low, size_high_and_resource_type as u8]);
+            if size != 0x2b {
+                return Err(DescriptorError::InvalidLength(size));
             }
+
+            let resource_type = match (size_high_and_resource_type >> 8) as u8 {
+                0 => DescriptorResourceType::MemoryRange,
+                1 => DescriptorResourceType::IORange,
+                2 => DescriptorResourceType::BusNumberRange,
+                resource_type => {
+                    return Err(DescriptorError::UnsupportedResourceType(resource_type))
+                }
+            };
+
+            let general_flags_and_type_specific_flags: u16 = *(data.add(0x4) as *const u16);
+            let general_flags: u8 = (general_flags_and_type_specific_flags & 0xff) as u8;
+            let type_specific_flags: u8 = (general_flags_and_type_specific_flags >> 8) as u8;
+
+            /*
+            For some reason I'm only allowed to do aligned reads of u64.
+            The offsets of these 64-bit fields aren't 8B aligned, so I have
+            to read the components in smaller chunks.
+            */
+            let read_u64 = |offset: usize| {
+                let byte_0: u8 = *data.add(offset);
+                let byte_1: u8 = *data.add(offset + 1);
+                let byte_2: u8 = *data.add(offset + 2);
+                let byte_3: u8 = *data.add(offset + 3);
+                let byte_4: u8 = *data.add(offset + 4);
+                let byte_5: u8 = *data.add(offset + 5);
+                let byte_6: u8 = *data.add(offset + 6);
+                let byte_7: u8 = *data.add(offset + 7);
+                u64::from_le_bytes([
+                    byte_0, byte_1, byte_2, byte_3, byte_4, byte_5, byte_6, byte_7,
+                ])
+            };
+
+            let address_space_granularity = read_u64(0x6);
+
+            let address_range_minimum: u64 = read_u64(0xe);
+            let address_range_maximum: u64 = read_u64(0x16);
+            let address_translation_offset: u64 = read_u64(0x1e);
+            let address_length: u64 = read_u64(0x26);
+
+            Ok(Some((
+                Descriptor {
+                    resource_type,
+                    general_flags,
+                    type_specific_flags,
+                    address_space_granularity,
+                    address_range_minimum,
+                    address_range_maximum,
+                    address_translation_offset,
+                    address_length,
+                },
+                3 + size as usize,
+            )))
         }
+        // The end tag's second byte is a checksum, which isn't checked.
+        0x79 => Ok(None),
+        tag => Err(DescriptorError::UnsupportedTag(tag as u8)),
     }
 }
 
+/// Frees firmware-allocated pool memory when it goes out of scope, on every return path.
+struct PoolGuard<'a> {
+    boot_services: &'a BootServices,
+    address: *mut u8,
+}
+
+impl<'a> Drop for PoolGuard<'a> {
+    fn drop(&mut self) {
+        // There's nothing useful to do when freeing fails.
+        let _ = unsafe { self.boot_services.free_pool(self.address) };
+    }
+}
+
+/// Where a PCI function lives. See [`PciIo::location`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciLocation {
+    /** The PCI segment group.
+
+    Usually 0: most machines have a single segment group, and only large servers have more.
+    */
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
 #[derive(Debug)]
 #[repr(transparent)]
 #[unsafe_protocol(PciIoProtocol::GUID)]
 pub struct PciIo(PciIoProtocol);
+
+impl PciIo {
+    /** The attributes that a BAR supports, and the range it decodes.
+
+    The range is described by an ACPI resource descriptor, like the ones returned by
+    [`PciRootBridgeIo::configuration`]. The firmware allocates it from pool memory, so it's
+    freed with `boot_services` before this returns, whether or not it could be decoded.
+
+    Fails with `UNSUPPORTED` when `bar_index` isn't a valid BAR, or when the descriptor can't be
+    decoded (see [`DescriptorError`]).
+
+    Reference: UEFI Specification 2.10, Section 14.4 (`EFI_PCI_IO_PROTOCOL.GetBarAttributes()`)
+    */
+    pub fn get_bar_attributes(
+        &self,
+        boot_services: &BootServices,
+        bar_index: u8,
+    ) -> uefi::Result<(u64, Descriptor)> {
+        let mut supports: u64 = 0;
+        let mut resources: *const u8 = core::ptr::null();
+        unsafe { (self.0.get_bar_attributes)(&self.0, bar_index, &mut supports, &mut resources) }
+            .to_result()?;
+        if resources.is_null() {
+            return Err(Status::NOT_FOUND.into());
+        }
+        let _resources_guard = PoolGuard {
+            boot_services,
+            address: resources as *mut u8,
+        };
+
+        // Safety: the firmware returns a QWORD address space descriptor and an end tag.
+        let descriptor = unsafe { read_descriptor(resources) };
+        match descriptor {
+            Ok(None) => Err(Status::NOT_FOUND.into()),
+            Ok(Some((descriptor, _))) => Ok((supports, descriptor)),
+            Err(_) => Err(Status::UNSUPPORTED.into()),
+        }
+    }
+
+    /** Set attributes on a range of a BAR.
+
+    `offset` and `length` select the range, relative to the start of the BAR. The firmware may
+    round the range to a granularity that it supports, so the range that was actually changed
+    is returned as `(offset, length)`.
+
+    Reference: UEFI Specification 2.10, Section 14.4 (`EFI_PCI_IO_PROTOCOL.SetBarAttributes()`)
+    */
+    pub fn set_bar_attributes(
+        &self,
+        attributes: u64,
+        bar_index: u8,
+        mut offset: u64,
+        mut length: u64,
+    ) -> uefi::Result<(u64, u64)> {
+        unsafe {
+            (self.0.set_bar_attributes)(&self.0, attributes, bar_index, &mut offset, &mut length)
+        }
+        .to_result()?;
+        Ok((offset, length))
+    }
+
+    /** The segment, bus, device and function numbers of the PCI function.
+
+    Use these to correlate a [`PciIo`] with the configuration space accesses of
+    [`PciRootBridgeIo`].
+
+    Reference: UEFI Specification 2.10, Section 14.4 (`EFI_PCI_IO_PROTOCOL.GetLocation()`)
+    */
+    pub fn location(&self) -> uefi::Result<PciLocation> {
+        let mut segment: usize = 0;
+        let mut bus: usize = 0;
+        let mut device: usize = 0;
+        let mut function: usize = 0;
+        unsafe {
+            (self.0.get_location)(&self.0, &mut segment, &mut bus, &mut device, &mut function)
+        }
+        .to_result()?;
+
+        // Segment group numbers are 16 bits (ACPI MCFG), bus numbers 8 bits, device numbers 5
+        // bits and function numbers 3 bits.
+        Ok(PciLocation {
+            segment: segment as u16,
+            bus: bus as u8,
+            device: device as u8,
+            function: function as u8,
+        })
+    }
+}
@@ -180,7 +180,8 @@ pub struct PciIoProtocol {
         u64,
         *mut u64,
     ) -> Status,
-    pub get_bar_attributes: unsafe extern "efiapi" fn(*const Self, u8, *mut u64, *mut u8) -> Status,
+    pub get_bar_attributes:
+        unsafe extern "efiapi" fn(*const Self, u8, *mut u64, *mut *const u8) -> Status,
     pub set_bar_attributes:
         unsafe extern "efiapi" fn(*const Self, u64, u8, *mut u64, *mut u64) -> Status,
 }